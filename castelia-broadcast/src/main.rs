@@ -1,17 +1,84 @@
+use std::sync::Arc;
+
+use castelia_rtmp::{
+    app::AppRegistry,
+    config::ServerConfig,
+    connection_registry::ConnectionRegistry,
+    logging::{self, LogFormat},
+    rtmp::RTMPSever,
+    stream_registry::StreamRegistry,
+};
+use tokio::sync::mpsc;
 use tower_http::trace::TraceLayer;
-use tracing::info;
+use tracing::{debug, error, info};
 
 mod routes;
+mod shutdown;
+
+/// How many RTMP server events can be waiting to be handled before new ones are dropped
+const EVENT_CHANNEL_CAPACITY: usize = 256;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-    let app = routes::router().layer(TraceLayer::new_for_http());
+    logging::init(LogFormat::from_env());
+
+    // CASTELIA_CONFIG points at a JSON server config, see ServerConfig
+    let config = ServerConfig::from_env()?;
+
+    // the RTMP server runs in the same process so published streams can be served over HTTP
+    let mut streams = StreamRegistry::new();
+    if let Some(node_id) = &config.node_id {
+        streams = streams.with_node_id(node_id);
+    }
+    let apps = AppRegistry::from(&config);
+    let (events, mut event_receiver) = mpsc::channel(EVENT_CHANNEL_CAPACITY);
+    let connections = ConnectionRegistry::new();
+    let mut rtmp_server = RTMPSever::builder()
+        .build()
+        .await?
+        .with_apps(apps.clone())
+        .with_stream_registry(streams.clone())
+        .with_events(events.clone());
+    // tracking connections costs a little on every message, so only do it if anything reads them
+    if config.http.debug_state {
+        rtmp_server = rtmp_server.with_connection_registry(connections.clone());
+    }
+    info!("RTMP server listening on {}", rtmp_server.local_addr()?);
+    let rtmp_drain = rtmp_server.drain_handle();
+    tokio::spawn(async move {
+        if let Err(e) = rtmp_server.run().await {
+            error!("RTMP server stopped: {e}");
+        }
+    });
+
+    tokio::spawn(async move {
+        while let Some(event) = event_receiver.recv().await {
+            debug!("RTMP server event: {event:?}");
+        }
+    });
+
+    let state = routes::AppState::new(streams)
+        .with_apps(Arc::new(apps))
+        .with_events(events)
+        .with_connections(connections)
+        .with_http_config(config.http);
+    let shutdown = state.shutdown.clone();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown::signal().await;
+            info!("Shutting down");
+            rtmp_drain.drain();
+            shutdown.start();
+        }
+    });
+
+    let app = routes::router(state).layer(TraceLayer::new_for_http());
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
     info!("Listening on {}", listener.local_addr()?);
 
-    axum::serve(listener, app).await?;
+    shutdown::serve(listener, app, shutdown, shutdown::DRAIN_TIMEOUT).await?;
 
     Ok(())
 }
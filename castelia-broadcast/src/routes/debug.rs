@@ -0,0 +1,249 @@
+use std::{
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{Json, extract::State};
+use castelia_rtmp::{
+    connection_registry::{ConnectionErrorInfo, ConnectionInfo},
+    stats::ConnectionStats,
+};
+use serde::Serialize;
+
+use super::AppState;
+
+#[derive(Debug, Serialize)]
+pub struct StateResponse {
+    node_id: Option<String>,
+    uptime_secs: u64,
+    connections: Vec<ConnectionResponse>,
+    streams: Vec<StreamStateResponse>,
+    recent_errors: Vec<ErrorResponse>,
+}
+
+#[derive(Debug, Serialize)]
+struct ConnectionResponse {
+    id: u64,
+    peer_addr: Option<SocketAddr>,
+    state: String,
+    app: Option<String>,
+    in_chunk_size: u32,
+    out_chunk_size: u32,
+    control_chunk_stream_id: Option<u32>,
+    ack_window_size: u32,
+    peer_bandwidth: Option<u32>,
+    buffered_bytes: usize,
+    unacked_bytes: u32,
+    publishing: Vec<String>,
+    playing: Vec<String>,
+    audio_bytes: u64,
+    video_bytes: u64,
+    control_bytes: u64,
+    dropped_packets: u64,
+}
+
+impl From<ConnectionInfo> for ConnectionResponse {
+    fn from(connection: ConnectionInfo) -> Self {
+        let ConnectionStats {
+            audio_bytes,
+            video_bytes,
+            control_bytes,
+        } = connection.stats;
+
+        Self {
+            id: connection.id,
+            peer_addr: connection.peer_addr,
+            state: format!("{:?}", connection.state),
+            app: connection.app,
+            in_chunk_size: connection.in_chunk_size,
+            out_chunk_size: connection.out_chunk_size,
+            control_chunk_stream_id: connection.control_chunk_stream_id,
+            ack_window_size: connection.ack_window_size,
+            peer_bandwidth: connection.peer_bandwidth,
+            buffered_bytes: connection.buffered_bytes,
+            unacked_bytes: connection.unacked_bytes,
+            publishing: connection.publishing,
+            playing: connection.playing,
+            audio_bytes,
+            video_bytes,
+            control_bytes,
+            dropped_packets: connection.dropped_packets,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct StreamStateResponse {
+    key: String,
+    /// Unix time in milliseconds
+    published_at: u64,
+    viewers: usize,
+    /// The id of the connection publishing the stream, [`None`] if it isn't published over RTMP,
+    /// e.g. when it is relayed
+    publisher: Option<u64>,
+    /// The ids of the connections playing the stream
+    players: Vec<u64>,
+    /// Whether the stream is being segmented for HLS
+    hls: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponse {
+    connection_id: u64,
+    peer_addr: Option<SocketAddr>,
+    kind: String,
+    message: String,
+    /// Unix time in milliseconds
+    at: u64,
+}
+
+impl From<ConnectionErrorInfo> for ErrorResponse {
+    fn from(error: ConnectionErrorInfo) -> Self {
+        Self {
+            connection_id: error.connection_id,
+            peer_addr: error.peer_addr,
+            kind: format!("{:?}", error.kind),
+            message: error.message,
+            at: unix_millis(error.at),
+        }
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Everything the server knows about its connections and streams, for support cases. Only
+/// routed when the `debug_state` HTTP setting is on
+pub async fn state(State(state): State<AppState>) -> Json<StateResponse> {
+    let connections = state.connections.connections();
+    let streams = state
+        .streams
+        .live_streams()
+        .into_iter()
+        .map(|stream| StreamStateResponse {
+            publisher: connections
+                .iter()
+                .find(|connection| connection.publishing.contains(&stream.key))
+                .map(|connection| connection.id),
+            players: connections
+                .iter()
+                .filter(|connection| connection.playing.contains(&stream.key))
+                .map(|connection| connection.id)
+                .collect(),
+            hls: state.hls.contains(&stream.key),
+            published_at: unix_millis(stream.published_at),
+            viewers: stream.viewers,
+            key: stream.key,
+        })
+        .collect();
+
+    Json(StateResponse {
+        node_id: state.streams.node_id().map(str::to_owned),
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        connections: connections
+            .into_iter()
+            .map(ConnectionResponse::from)
+            .collect(),
+        streams,
+        recent_errors: state
+            .connections
+            .recent_errors()
+            .into_iter()
+            .map(ErrorResponse::from)
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{Body, to_bytes},
+        http::{Request, StatusCode},
+    };
+    use castelia_rtmp::{
+        config::HttpConfig,
+        connection_registry::{ConnectionRegistry, ConnectionState},
+        stream_registry::StreamRegistry,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::routes::router;
+
+    fn debug_state() -> HttpConfig {
+        HttpConfig {
+            debug_state: true,
+            ..HttpConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publisher_and_player() {
+        let streams = StreamRegistry::new();
+        let connections = ConnectionRegistry::new();
+        let _publisher = streams.publish("live/mystream").unwrap();
+        let _player = streams.subscribe("live/mystream").unwrap();
+        let publishing = connections.register(Some("10.0.0.1:50000".parse().unwrap()));
+        publishing.update(|info| {
+            info.state = ConnectionState::Publishing;
+            info.app = Some("live".to_owned());
+            info.publishing = vec!["live/mystream".to_owned()];
+        });
+        let playing = connections.register(Some("10.0.0.2:50000".parse().unwrap()));
+        playing.update(|info| {
+            info.state = ConnectionState::Playing;
+            info.playing = vec!["live/mystream".to_owned()];
+        });
+
+        let state = AppState::new(streams)
+            .with_connections(connections)
+            .with_http_config(debug_state());
+        let response = router(state)
+            .oneshot(
+                Request::get("/api/debug/state")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        let connections = json["connections"].as_array().unwrap();
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[0]["id"], publishing.id());
+        assert_eq!(connections[0]["peer_addr"], "10.0.0.1:50000");
+        assert_eq!(connections[0]["state"], "Publishing");
+        assert_eq!(connections[0]["publishing"][0], "live/mystream");
+        assert_eq!(connections[1]["id"], playing.id());
+        assert_eq!(connections[1]["state"], "Playing");
+        assert_eq!(connections[1]["playing"][0], "live/mystream");
+
+        let [stream] = json["streams"].as_array().unwrap().as_slice() else {
+            assert_eq!(json["streams"].as_array().unwrap().len(), 1);
+            return;
+        };
+        assert_eq!(stream["key"], "live/mystream");
+        assert_eq!(stream["viewers"], 1);
+        assert_eq!(stream["publisher"], publishing.id());
+        assert_eq!(stream["players"], serde_json::json!([playing.id()]));
+        assert_eq!(json["recent_errors"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_not_routed_by_default() {
+        let response = router(AppState::new(StreamRegistry::new()))
+            .oneshot(
+                Request::get("/api/debug/state")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
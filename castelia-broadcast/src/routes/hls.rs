@@ -0,0 +1,313 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use castelia_rtmp::{
+    app::AppRegistry,
+    hls::Segmenter,
+    stream_registry::{StreamRegistry, Subscriber},
+    sync::lock,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+use super::{AppState, stream_key};
+
+/// How long the playlist of a stream that ended is still served, so players catch the end of the
+/// stream rather than getting an error
+const ENDED_STREAM_GRACE: Duration = Duration::from_secs(30);
+
+/// The segmenters of the streams being played over HLS. A stream's segmenter is started by the
+/// first request for it and runs until the stream ends
+#[derive(Debug, Clone, Default)]
+pub struct HlsStreams {
+    segmenters: Arc<Mutex<HashMap<String, Arc<Mutex<Segmenter>>>>>,
+}
+
+impl HlsStreams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether `key` is being segmented, or ended recently enough its playlist is still served
+    pub fn contains(&self, key: &str) -> bool {
+        lock(&self.segmenters).contains_key(key)
+    }
+
+    /// The segmenter of the live stream `name` in `app`, [`None`] if it isn't live or didn't
+    /// end recently. Segments are as long as the stream's `segment_duration_secs` setting, and
+    /// the playlist lists `playlist_length` of them
+    fn segmenter(
+        &self,
+        streams: &StreamRegistry,
+        apps: &AppRegistry,
+        app: &str,
+        name: &str,
+    ) -> Option<Arc<Mutex<Segmenter>>> {
+        let key = stream_key(app, name);
+        let mut segmenters = lock(&self.segmenters);
+        let ended = segmenters.get(&key).cloned();
+        if let Some(segmenter) = &ended
+            && !lock(segmenter).is_ended()
+        {
+            return ended;
+        }
+
+        // a stream that ended and was published again gets a new segmenter
+        let Some(subscriber) = streams.subscribe_internal(&key) else {
+            return ended;
+        };
+        let settings = apps.settings_for(app, name);
+        let segmenter = Arc::new(Mutex::new(
+            Segmenter::new()
+                .with_target_duration(Duration::from_secs(settings.segment_duration_secs.into()))
+                .with_playlist_length(settings.playlist_length),
+        ));
+        segmenters.insert(key, segmenter.clone());
+        tokio::spawn(run_segmenter(self.clone(), subscriber, segmenter.clone()));
+        Some(segmenter)
+    }
+}
+
+/// Feed the packets of a stream to its segmenter until the stream ends, then end its playlist
+/// and keep it around for [`ENDED_STREAM_GRACE`]
+async fn run_segmenter(
+    hls: HlsStreams,
+    mut subscriber: Subscriber,
+    segmenter: Arc<Mutex<Segmenter>>,
+) {
+    loop {
+        match subscriber.recv().await {
+            Ok(packet) => lock(&segmenter).push(&packet),
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(
+                    "HLS segmenter of {} fell behind, skipped {skipped} packets",
+                    subscriber.key()
+                );
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+    lock(&segmenter).end();
+    tokio::time::sleep(ENDED_STREAM_GRACE).await;
+
+    // the stream may have been published again with a new segmenter by now
+    let mut segmenters = lock(&hls.segmenters);
+    if segmenters
+        .get(subscriber.key())
+        .is_some_and(|current| Arc::ptr_eq(current, &segmenter))
+    {
+        segmenters.remove(subscriber.key());
+    }
+}
+
+/// The live playlist of a stream, e.g. `/hls/live/mystream/index.m3u8` for the stream
+/// published to `rtmp://host/live/mystream`
+pub async fn playlist(
+    State(state): State<AppState>,
+    Path((app, name)): Path<(String, String)>,
+) -> Response {
+    let Some(segmenter) = state
+        .hls
+        .segmenter(&state.streams, &state.apps, &app, &name)
+    else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let playlist = lock(&segmenter).playlist();
+    (
+        [
+            (header::CONTENT_TYPE, "application/vnd.apple.mpegurl"),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        playlist,
+    )
+        .into_response()
+}
+
+/// A segment listed in the playlist, e.g. `/hls/live/mystream/3.ts`
+pub async fn segment(
+    State(state): State<AppState>,
+    Path((app, name, file)): Path<(String, String, String)>,
+) -> Response {
+    let data = file
+        .strip_suffix(".ts")
+        .and_then(|sequence| sequence.parse().ok())
+        .zip(
+            state
+                .hls
+                .segmenter(&state.streams, &state.apps, &app, &name),
+        )
+        .and_then(|(sequence, segmenter)| {
+            lock(&segmenter)
+                .segment(sequence)
+                .map(|segment| segment.data.clone())
+        });
+    let Some(data) = data else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    ([(header::CONTENT_TYPE, "video/mp2t")], data).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{
+        body::{Body, to_bytes},
+        http::Request,
+    };
+    use castelia_rtmp::{
+        config::ServerConfig,
+        test_support::{KEYFRAME, VIDEO_SEQUENCE_HEADER, video},
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::routes::router;
+
+    async fn get(state: &AppState, uri: &str) -> Response {
+        router(state.clone())
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_playlist_and_segments() {
+        let state = AppState::new(StreamRegistry::new());
+        let publisher = state.streams.publish("live/mystream").unwrap();
+
+        // the first request starts segmenting, there are no segments yet
+        let response = get(&state, "/hls/live/mystream/index.m3u8").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/vnd.apple.mpegurl"
+        );
+        // the segmenter isn't a viewer
+        assert_eq!(state.streams.viewers("live/mystream"), Some(0));
+
+        publisher.send(video(0, VIDEO_SEQUENCE_HEADER));
+        for timestamp in [0, 4000, 8000] {
+            publisher.send(video(timestamp, KEYFRAME));
+        }
+
+        let mut playlist = String::new();
+        for _ in 0..100 {
+            let response = get(&state, "/hls/live/mystream/index.m3u8").await;
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            playlist = String::from_utf8(body.to_vec()).unwrap();
+            if playlist.contains("1.ts") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(playlist.contains("#EXTINF:4.000,\n0.ts\n#EXTINF:4.000,\n1.ts\n"));
+
+        let response = get(&state, "/hls/live/mystream/0.ts").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "video/mp2t");
+        let segment = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(segment[0], 0x47);
+
+        assert_eq!(
+            get(&state, "/hls/live/mystream/2.ts").await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_configured_hls_settings() {
+        let config = ServerConfig::parse(
+            r#"{ "apps": { "live": { "streams": { "mystream": { "segment_duration_secs": 2, "playlist_length": 1 } } } } }"#,
+        )
+        .unwrap();
+        let state =
+            AppState::new(StreamRegistry::new()).with_apps(Arc::new(AppRegistry::from(&config)));
+        let publisher = state.streams.publish("live/mystream").unwrap();
+        get(&state, "/hls/live/mystream/index.m3u8").await;
+
+        publisher.send(video(0, VIDEO_SEQUENCE_HEADER));
+        for timestamp in [0, 2000, 4000] {
+            publisher.send(video(timestamp, KEYFRAME));
+        }
+
+        let mut playlist = String::new();
+        for _ in 0..100 {
+            let response = get(&state, "/hls/live/mystream/index.m3u8").await;
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            playlist = String::from_utf8(body.to_vec()).unwrap();
+            if playlist.contains("1.ts") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(playlist.contains("#EXT-X-TARGETDURATION:2\n"));
+        // only the latest segment is listed
+        assert!(playlist.ends_with("#EXT-X-MEDIA-SEQUENCE:1\n#EXTINF:2.000,\n1.ts\n"));
+    }
+
+    #[tokio::test]
+    async fn test_unpublish_ends_playlist() {
+        let state = AppState::new(StreamRegistry::new());
+        let publisher = state.streams.publish("live/mystream").unwrap();
+        get(&state, "/hls/live/mystream/index.m3u8").await;
+
+        publisher.send(video(0, VIDEO_SEQUENCE_HEADER));
+        for timestamp in [0, 4000, 6000] {
+            publisher.send(video(timestamp, KEYFRAME));
+        }
+        drop(publisher);
+
+        let mut playlist = String::new();
+        for _ in 0..100 {
+            let response = get(&state, "/hls/live/mystream/index.m3u8").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            playlist = String::from_utf8(body.to_vec()).unwrap();
+            if playlist.contains("#EXT-X-ENDLIST") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // the open segment is finished at the last frame
+        assert!(playlist.ends_with("#EXTINF:4.000,\n0.ts\n#EXTINF:2.000,\n1.ts\n#EXT-X-ENDLIST\n"));
+        assert_eq!(
+            get(&state, "/hls/live/mystream/1.ts").await.status(),
+            StatusCode::OK
+        );
+
+        // publishing again starts over
+        let _publisher = state.streams.publish("live/mystream").unwrap();
+        let response = get(&state, "/hls/live/mystream/index.m3u8").await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(
+            !String::from_utf8(body.to_vec())
+                .unwrap()
+                .contains("#EXT-X-ENDLIST")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_not_live() {
+        let state = AppState::new(StreamRegistry::new());
+
+        assert_eq!(
+            get(&state, "/hls/live/mystream/index.m3u8").await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get(&state, "/hls/live/mystream/0.ts").await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+}
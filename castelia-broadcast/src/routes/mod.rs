@@ -1,9 +1,551 @@
-use axum::{Router, http::StatusCode, routing::get};
+use std::{
+    sync::Arc,
+    time::{Instant, UNIX_EPOCH},
+};
 
-pub fn router() -> Router {
-    Router::new().route("/health", get(health_check))
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderValue, Method, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use bytes::Bytes;
+use castelia_rtmp::{
+    app::AppRegistry,
+    config::HttpConfig,
+    connection_registry::ConnectionRegistry,
+    events::ServerEvent,
+    flv::{self, FlvMuxer},
+    stream_registry::{LiveStreamInfo, StreamMetadata, StreamRegistry, Subscriber},
+};
+use futures_util::stream::{self, StreamExt};
+use serde::Serialize;
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::warn;
+
+use crate::{routes::hls::HlsStreams, shutdown::Shutdown};
+
+mod debug;
+mod hls;
+mod ws;
+
+/// State shared by every route
+#[derive(Debug, Clone)]
+pub struct AppState {
+    /// The streams published to the RTMP server running alongside this service
+    pub streams: StreamRegistry,
+    /// The apps the RTMP server serves, for the settings of each stream
+    pub apps: Arc<AppRegistry>,
+    pub hls: HlsStreams,
+    /// Where the RTMP server sends its events, the service isn't ready once nothing receives them
+    pub events: Option<mpsc::Sender<ServerEvent>>,
+    /// The connections to the RTMP server, only tracked when the debug state route is enabled
+    pub connections: ConnectionRegistry,
+    pub started_at: Instant,
+    /// Ends streaming responses so they don't hold up shutting down
+    pub shutdown: Shutdown,
+    pub http: HttpConfig,
+}
+
+impl AppState {
+    pub fn new(streams: StreamRegistry) -> Self {
+        Self {
+            streams,
+            apps: Arc::new(AppRegistry::new()),
+            hls: HlsStreams::new(),
+            events: None,
+            connections: ConnectionRegistry::new(),
+            started_at: Instant::now(),
+            shutdown: Shutdown::new(),
+            http: HttpConfig::default(),
+        }
+    }
+
+    pub fn with_apps(mut self, apps: Arc<AppRegistry>) -> Self {
+        self.apps = apps;
+        self
+    }
+
+    pub fn with_http_config(mut self, http: HttpConfig) -> Self {
+        self.http = http;
+        self
+    }
+
+    pub fn with_events(mut self, events: mpsc::Sender<ServerEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn with_connections(mut self, connections: ConnectionRegistry) -> Self {
+        self.connections = connections;
+        self
+    }
+}
+
+pub fn router(state: AppState) -> Router {
+    let cors = cors_layer(state.http.cors_allowed_origins.as_deref());
+    // the layer only applies to the media routes added before it
+    let mut router = Router::new()
+        .route("/{app}/{file}", get(play_flv))
+        .route("/hls/{app}/{name}/index.m3u8", get(hls::playlist))
+        .route("/hls/{app}/{name}/{segment}", get(hls::segment))
+        .layer(cors)
+        .route("/health", get(health_check))
+        .route("/streams", get(list_streams))
+        .route("/streams/{app}/{name}/viewers", get(stream_viewers))
+        .route("/ws/{app}/{name}", get(ws::play));
+    if state.http.debug_state {
+        router = router.route("/api/debug/state", get(debug::state));
+    }
+    router.with_state(state)
+}
+
+/// Lets browser players on other origins fetch the media routes, only from `allowed_origins`
+/// if it is set
+fn cors_layer(allowed_origins: Option<&[String]>) -> CorsLayer {
+    let allow_origin = match allowed_origins {
+        None => AllowOrigin::any(),
+        Some(origins) => AllowOrigin::list(origins.iter().filter_map(|origin| {
+            HeaderValue::from_str(origin)
+                .inspect_err(|_| warn!("Ignoring invalid CORS origin {origin:?}"))
+                .ok()
+        })),
+    };
+    CorsLayer::new()
+        .allow_methods([Method::GET, Method::HEAD])
+        .allow_origin(allow_origin)
+}
+
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    healthy: bool,
+    uptime_secs: u64,
+    live_streams: usize,
+}
+
+/// Readiness check for load balancers, 503 when the stream registry or the RTMP server's event
+/// receiver is broken
+async fn health_check(State(state): State<AppState>) -> (StatusCode, Json<HealthResponse>) {
+    let healthy =
+        !state.streams.is_poisoned() && !state.events.as_ref().is_some_and(mpsc::Sender::is_closed);
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(HealthResponse {
+            healthy,
+            uptime_secs: state.started_at.elapsed().as_secs(),
+            live_streams: state.streams.live_streams().len(),
+        }),
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct StreamResponse {
+    key: String,
+    /// Unix time in milliseconds
+    published_at: u64,
+    metadata: MetadataResponse,
+    viewers: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct MetadataResponse {
+    width: Option<f64>,
+    height: Option<f64>,
+    frame_rate: Option<f64>,
+    video_codec_id: Option<f64>,
+    audio_codec_id: Option<f64>,
+    encoder: Option<String>,
+}
+
+impl From<LiveStreamInfo> for StreamResponse {
+    fn from(stream: LiveStreamInfo) -> Self {
+        let StreamMetadata {
+            width,
+            height,
+            frame_rate,
+            video_codec_id,
+            audio_codec_id,
+            encoder,
+        } = stream.metadata;
+
+        Self {
+            key: stream.key,
+            published_at: stream
+                .published_at
+                .duration_since(UNIX_EPOCH)
+                .map(|since_epoch| since_epoch.as_millis() as u64)
+                .unwrap_or_default(),
+            metadata: MetadataResponse {
+                width,
+                height,
+                frame_rate,
+                video_codec_id,
+                audio_codec_id,
+                encoder,
+            },
+            viewers: stream.viewers,
+        }
+    }
+}
+
+/// List the streams that are currently live
+async fn list_streams(State(state): State<AppState>) -> Json<Vec<StreamResponse>> {
+    Json(
+        state
+            .streams
+            .live_streams()
+            .into_iter()
+            .map(StreamResponse::from)
+            .collect(),
+    )
+}
+
+/// How many players are watching a stream, e.g. `/streams/live/mystream/viewers` for the stream
+/// keyed `live/mystream`
+async fn stream_viewers(
+    State(state): State<AppState>,
+    Path((app, name)): Path<(String, String)>,
+) -> Result<Json<usize>, StatusCode> {
+    state
+        .streams
+        .viewers(&format!("{app}/{name}"))
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Play a live stream as HTTP-FLV, e.g. `/live/mystream.flv` for the stream published to
+/// `rtmp://host/live/mystream`
+async fn play_flv(
+    State(state): State<AppState>,
+    Path((app, file)): Path<(String, String)>,
+) -> Response {
+    let Some(subscriber) = file
+        .strip_suffix(".flv")
+        .and_then(|name| state.streams.subscribe(&stream_key(&app, name)))
+    else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let header = stream::once(async { Ok::<_, RecvError>(flv::encode_header(true, true)) });
+    let tags = stream::unfold(
+        (subscriber, FlvMuxer::new()),
+        |(mut subscriber, mut muxer)| async move {
+            let tag = next_tag(&mut subscriber, &mut muxer).await?;
+            Some((Ok(tag), (subscriber, muxer)))
+        },
+    )
+    .take_until(async move { state.shutdown.started().await });
+
+    (
+        [(header::CONTENT_TYPE, "video/x-flv")],
+        Body::from_stream(header.chain(tags)),
+    )
+        .into_response()
 }
 
-async fn health_check() -> StatusCode {
-    StatusCode::OK
+/// The registry key of the stream `name` published to `app`
+fn stream_key(app: &str, name: &str) -> String {
+    format!("{app}/{name}")
+}
+
+/// The next tag to send to an HTTP-FLV player, [`None`] once the stream ends. After falling
+/// behind, video resumes at the next keyframe
+async fn next_tag(subscriber: &mut Subscriber, muxer: &mut FlvMuxer) -> Option<Bytes> {
+    loop {
+        match subscriber.recv().await {
+            Ok(packet) => {
+                if let Some(tag) = muxer.tag(&packet) {
+                    return Some(tag);
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(
+                    "HTTP-FLV player of {} fell behind, skipped {skipped} packets and resuming at the next keyframe",
+                    subscriber.key()
+                );
+                muxer.resync();
+            }
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{Body, to_bytes},
+        http::Request,
+    };
+    use castelia_rtmp::test_support::video;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn health(state: AppState) -> (StatusCode, serde_json::Value) {
+        let response = router(state)
+            .oneshot(Request::get("/health").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let streams = StreamRegistry::new();
+        let _publisher = streams.publish("live/mystream").unwrap();
+        let (events, _receiver) = mpsc::channel(1);
+
+        let (status, json) = health(AppState::new(streams).with_events(events)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json["healthy"], true);
+        assert_eq!(json["live_streams"], 1);
+        assert!(json["uptime_secs"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn test_health_check_with_closed_events() {
+        let (events, receiver) = mpsc::channel(1);
+        drop(receiver);
+
+        let (status, json) = health(AppState::new(StreamRegistry::new()).with_events(events)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json["healthy"], false);
+        assert_eq!(json["live_streams"], 0);
+    }
+
+    fn preflight(uri: &str, origin: &str) -> Request<Body> {
+        Request::options(uri)
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight() {
+        let response = router(AppState::new(StreamRegistry::new()))
+            .oneshot(preflight(
+                "/hls/live/mystream/index.m3u8",
+                "https://player.example.com",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+
+    #[tokio::test]
+    async fn test_cors_allowlist() {
+        let app = router(
+            AppState::new(StreamRegistry::new()).with_http_config(HttpConfig {
+                cors_allowed_origins: Some(vec!["https://player.example.com".to_owned()]),
+                ..HttpConfig::default()
+            }),
+        );
+
+        let response = app
+            .clone()
+            .oneshot(preflight(
+                "/live/mystream.flv",
+                "https://player.example.com",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://player.example.com"
+        );
+
+        let response = app
+            .oneshot(preflight("/live/mystream.flv", "https://other.example.com"))
+            .await
+            .unwrap();
+        assert!(
+            !response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_streams() {
+        let streams = StreamRegistry::new();
+        let publisher = streams.publish("live/mystream").unwrap();
+        publisher.set_metadata(StreamMetadata {
+            width: Some(1280.0),
+            height: Some(720.0),
+            video_codec_id: Some(7.0),
+            ..StreamMetadata::default()
+        });
+        let _player = streams.subscribe("live/mystream").unwrap();
+
+        let response = router(AppState::new(streams))
+            .oneshot(Request::get("/streams").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let [stream] = json.as_array().unwrap().as_slice() else {
+            assert_eq!(json.as_array().unwrap().len(), 1);
+            return;
+        };
+        assert_eq!(stream["key"], "live/mystream");
+        assert_eq!(stream["viewers"], 1);
+        assert_eq!(stream["metadata"]["width"], 1280.0);
+        assert_eq!(
+            stream["metadata"]["audio_codec_id"],
+            serde_json::Value::Null
+        );
+        assert!(stream["published_at"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_stream_viewers() {
+        let streams = StreamRegistry::new();
+        let _publisher = streams.publish("live/mystream").unwrap();
+        let first = streams.subscribe("live/mystream").unwrap();
+        let _second = streams.subscribe("live/mystream").unwrap();
+        let app = router(AppState::new(streams));
+
+        let viewers = |app: Router| async move {
+            let response = app
+                .oneshot(
+                    Request::get("/streams/live/mystream/viewers")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<usize>(&body).unwrap()
+        };
+        assert_eq!(viewers(app.clone()).await, 2);
+
+        drop(first);
+        assert_eq!(viewers(app.clone()).await, 1);
+
+        let response = app
+            .oneshot(
+                Request::get("/streams/live/other/viewers")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_play_flv() {
+        let streams = StreamRegistry::new();
+        let publisher = streams.publish("live/mystream").unwrap();
+        publisher.send(video(0, &[0x17, 0x00, 0x00, 0x00, 0x00, 0x01]));
+        publisher.send(video(40, &[0x17, 0x01, 0x00, 0x00, 0x00, 0x65]));
+
+        let response = router(AppState::new(streams))
+            .oneshot(
+                Request::get("/live/mystream.flv")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "video/x-flv");
+
+        let mut body = response.into_body().into_data_stream();
+        assert_eq!(
+            body.next().await.unwrap().unwrap(),
+            flv::encode_header(true, true)
+        );
+        let sequence_header = body.next().await.unwrap().unwrap();
+        assert_eq!(sequence_header[0], 9);
+        assert_eq!(
+            sequence_header[11..17],
+            [0x17, 0x00, 0x00, 0x00, 0x00, 0x01]
+        );
+        let keyframe = body.next().await.unwrap().unwrap();
+        assert_eq!(keyframe[4..7], [0x00, 0x00, 40]);
+
+        drop(publisher);
+        assert!(body.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_play_flv_of_other_app() {
+        let streams = StreamRegistry::new();
+        let _publisher = streams.publish("studio/mystream").unwrap();
+        let app = router(AppState::new(streams));
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/studio/mystream.flv")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .oneshot(
+                Request::get("/live/mystream.flv")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_play_flv_ends_on_shutdown() {
+        let state = AppState::new(StreamRegistry::new());
+        let _publisher = state.streams.publish("live/mystream").unwrap();
+        let shutdown = state.shutdown.clone();
+
+        let response = router(state)
+            .oneshot(
+                Request::get("/live/mystream.flv")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let mut body = response.into_body().into_data_stream();
+        assert_eq!(
+            body.next().await.unwrap().unwrap(),
+            flv::encode_header(true, true)
+        );
+
+        shutdown.start();
+        assert!(body.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_play_flv_not_live() {
+        let response = router(AppState::new(StreamRegistry::new()))
+            .oneshot(
+                Request::get("/live/mystream.flv")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
 }
@@ -0,0 +1,147 @@
+use axum::{
+    extract::{
+        Path, State, WebSocketUpgrade,
+        ws::{CloseFrame, Message, WebSocket, close_code},
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use castelia_rtmp::{
+    flv::{self, FlvMuxer},
+    stream_registry::Subscriber,
+};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+use super::{AppState, stream_key};
+use crate::shutdown::Shutdown;
+
+/// Play a live stream over a WebSocket, e.g. `/ws/live/mystream` for the stream published to
+/// `rtmp://host/live/mystream`. The first message is the FLV header, every message after it is
+/// a single FLV tag
+pub async fn play(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Path((app, name)): Path<(String, String)>,
+) -> Response {
+    let Some(subscriber) = state.streams.subscribe(&stream_key(&app, &name)) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    ws.on_upgrade(move |socket| relay(socket, subscriber, state.shutdown))
+}
+
+/// Send the stream's tags until it ends, the player goes away or the service shuts down.
+/// Players that fall too far behind are disconnected instead of buffering for them
+async fn relay(mut socket: WebSocket, mut subscriber: Subscriber, shutdown: Shutdown) {
+    if socket
+        .send(Message::Binary(flv::encode_header(true, true)))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    let mut muxer = FlvMuxer::new();
+    let close_frame = loop {
+        tokio::select! {
+            packet = subscriber.recv() => match packet {
+                Ok(packet) => {
+                    let Some(tag) = muxer.tag(&packet) else {
+                        continue;
+                    };
+                    if socket.send(Message::Binary(tag)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(
+                        "WebSocket player of {} fell behind by {skipped} packets, disconnecting",
+                        subscriber.key()
+                    );
+                    break CloseFrame {
+                        code: close_code::AGAIN,
+                        reason: "Fell too far behind the stream".into(),
+                    };
+                }
+                Err(RecvError::Closed) => {
+                    break CloseFrame {
+                        code: close_code::NORMAL,
+                        reason: "Stream ended".into(),
+                    };
+                }
+            },
+            () = shutdown.started() => {
+                break CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "Server is shutting down".into(),
+                };
+            }
+            message = socket.recv() => match message {
+                // players have nothing to say, anything but closing is ignored
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    };
+
+    let _ = socket.send(Message::Close(Some(close_frame))).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use castelia_rtmp::{stream_registry::StreamRegistry, test_support::video};
+    use futures_util::StreamExt;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::{connect_async, tungstenite};
+
+    use super::*;
+    use crate::routes::router;
+
+    #[tokio::test]
+    async fn test_play() {
+        let state = AppState::new(StreamRegistry::new());
+        let publisher = state.streams.publish("live/mystream").unwrap();
+        publisher.send(video(0, &[0x17, 0x00, 0x00, 0x00, 0x00, 0x01]));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(state)).await });
+
+        let (mut socket, _) = connect_async(format!("ws://{address}/ws/live/mystream"))
+            .await
+            .unwrap();
+        assert_eq!(
+            socket.next().await.unwrap().unwrap(),
+            tungstenite::Message::Binary(flv::encode_header(true, true))
+        );
+        // the sequence header from the GOP cache
+        let message = socket.next().await.unwrap().unwrap();
+        let tungstenite::Message::Binary(tag) = &message else {
+            assert!(matches!(message, tungstenite::Message::Binary(_)));
+            return;
+        };
+        assert_eq!(tag[0], 9);
+        assert_eq!(tag[11..17], [0x17, 0x00, 0x00, 0x00, 0x00, 0x01]);
+
+        drop(publisher);
+        assert!(matches!(
+            socket.next().await.unwrap().unwrap(),
+            tungstenite::Message::Close(Some(frame)) if frame.reason == "Stream ended"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_play_not_live() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let state = AppState::new(StreamRegistry::new());
+        tokio::spawn(async move { axum::serve(listener, router(state)).await });
+
+        assert!(
+            connect_async(format!("ws://{address}/ws/live/mystream"))
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::{future::IntoFuture, io, sync::Arc, time::Duration};
+
+use axum::Router;
+use tokio::{net::TcpListener, sync::watch, time::sleep};
+use tracing::warn;
+
+/// How long in-flight responses get to finish once the service starts shutting down
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tells the server and long-lived responses like HTTP-FLV streams that the service is shutting
+/// down
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    started: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            started: Arc::new(watch::Sender::new(false)),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn start(&self) {
+        self.started.send_replace(true);
+    }
+
+    /// Completes once shutdown starts, straight away if it already has
+    pub async fn started(&self) {
+        // the sender is kept alive by self, so waiting can't fail
+        let _ = self.started.subscribe().wait_for(|started| *started).await;
+    }
+}
+
+/// Completes on Ctrl-C, or SIGTERM on unix
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Unable to listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Unable to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
+
+/// Serve `app` until `shutdown` starts, then stop accepting connections and wait up to
+/// `drain_timeout` for in-flight responses to finish
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    shutdown: Shutdown,
+    drain_timeout: Duration,
+) -> io::Result<()> {
+    let signal = shutdown.clone();
+    let serve = axum::serve(listener, app)
+        .with_graceful_shutdown(async move { signal.started().await })
+        .into_future();
+
+    tokio::select! {
+        result = serve => result,
+        () = async {
+            shutdown.started().await;
+            sleep(drain_timeout).await;
+        } => {
+            warn!("Responses still in flight after {drain_timeout:?}, shutting down anyway");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::Notify,
+        time::timeout,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_in_flight_request() {
+        let received = Arc::new(Notify::new());
+        let respond = Arc::new(Notify::new());
+        let app = Router::new().route(
+            "/slow",
+            get({
+                let received = received.clone();
+                let respond = respond.clone();
+                move || {
+                    let received = received.clone();
+                    let respond = respond.clone();
+                    async move {
+                        received.notify_one();
+                        respond.notified().await;
+                        "done"
+                    }
+                }
+            }),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let server = tokio::spawn(serve(listener, app, shutdown.clone(), DRAIN_TIMEOUT));
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"GET /slow HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        received.notified().await;
+
+        shutdown.start();
+        sleep(Duration::from_millis(50)).await;
+        assert!(!server.is_finished());
+
+        respond.notify_one();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("done"));
+
+        timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout() {
+        let app = Router::new().route("/hang", get(std::future::pending::<()>));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let server = tokio::spawn(serve(
+            listener,
+            app,
+            shutdown.clone(),
+            Duration::from_millis(50),
+        ));
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"GET /hang HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(50)).await;
+
+        shutdown.start();
+        timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+}
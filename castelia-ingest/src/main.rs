@@ -1,13 +1,25 @@
-use castelia_rtmp::rtmp::RTMPSever;
+use castelia_rtmp::{
+    app::AppRegistry,
+    config::ServerConfig,
+    logging::{self, LogFormat},
+    rtmp::RTMPSever,
+};
 use tracing::info;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:1935").await?;
-    info!("Listening on {}", listener.local_addr()?);
+    // RUST_LOG_FORMAT=json writes JSON lines for log aggregators
+    logging::init(LogFormat::from_env());
 
-    RTMPSever::new(listener).run().await?;
+    // CASTELIA_CONFIG points at a JSON server config, see ServerConfig
+    let config = ServerConfig::from_env()?;
+    let server = RTMPSever::builder()
+        .build()
+        .await?
+        .with_apps(AppRegistry::from(&config));
+    info!("Listening on {}", server.local_addr()?);
+
+    server.run().await?;
 
     Ok(())
 }
@@ -3,7 +3,6 @@
 // seems like not the full specification/all the types are used in the protocol
 
 use std::{
-    collections::HashMap,
     io::{Cursor, Seek},
     str,
 };
@@ -15,20 +14,207 @@ mod amf0_type_marker {
     pub const BOOL: u8 = 0x01;
     pub const STRING: u8 = 0x02;
     pub const OBJECT_START: u8 = 0x03;
+    pub const ECMA_ARRAY: u8 = 0x08;
+    pub const STRICT_ARRAY: u8 = 0x0A;
+    pub const DATE: u8 = 0x0B;
+    pub const LONG_STRING: u8 = 0x0C;
+    pub const TYPED_OBJECT: u8 = 0x10;
 
     // needs to be preceeded by 2 0x00s
     // so actual object end is 0x00, 0x00, 0x09
     pub const OBJECT_END: u8 = 0x09;
     pub const NULL: u8 = 0x05;
+    pub const UNDEFINED: u8 = 0x06;
+
+    // defined by the spec, but not implemented
+    pub const MOVIECLIP: u8 = 0x04;
+    pub const REFERENCE: u8 = 0x07;
+    pub const UNSUPPORTED: u8 = 0x0D;
+    pub const RECORDSET: u8 = 0x0E;
+    pub const XML_DOCUMENT: u8 = 0x0F;
+    pub const AVMPLUS_OBJECT: u8 = 0x11;
+
+    /// Name of a marker that is defined by the spec but not implemented
+    pub fn unsupported_name(marker: u8) -> Option<&'static str> {
+        Some(match marker {
+            MOVIECLIP => "movieclip",
+            REFERENCE => "reference",
+            UNSUPPORTED => "unsupported",
+            RECORDSET => "recordset",
+            XML_DOCUMENT => "xml document",
+            AVMPLUS_OBJECT => "avmplus object",
+            _ => return None,
+        })
+    }
 }
 
+/// Map used for object properties.
+///
+/// With the `preserve_order` feature this is an [`indexmap::IndexMap`], so properties iterate in
+/// the order they were decoded and re-encode to the same bytes.
+#[cfg(feature = "preserve_order")]
+pub type Properties<K, V> = indexmap::IndexMap<K, V>;
+#[cfg(not(feature = "preserve_order"))]
+pub type Properties<K, V> = std::collections::HashMap<K, V>;
+
 #[derive(Debug, PartialEq)]
 pub enum AMF0Value<'a> {
     Number(f64),
     Boolean(bool),
     String(&'a str),
-    Object(HashMap<&'a str, AMF0Value<'a>>),
+    Object(Properties<&'a str, AMF0Value<'a>>),
+    EcmaArray {
+        /// Number of properties declared by the sender, this is advisory only
+        count: u32,
+        properties: Properties<&'a str, AMF0Value<'a>>,
+    },
+    StrictArray(Vec<AMF0Value<'a>>),
+    TypedObject {
+        class_name: &'a str,
+        properties: Properties<&'a str, AMF0Value<'a>>,
+    },
+    Date {
+        /// Milliseconds since the unix epoch, the timezone is always ignored
+        millis: f64,
+    },
+    Null,
+    Undefined,
+}
+
+/// Owned version of [`AMF0Value`] that can outlive the buffer it was decoded from
+#[derive(Debug, Clone, PartialEq)]
+pub enum AMF0ValueOwned {
+    Number(f64),
+    Boolean(bool),
+    String(String),
+    Object(Properties<String, AMF0ValueOwned>),
+    EcmaArray {
+        count: u32,
+        properties: Properties<String, AMF0ValueOwned>,
+    },
+    StrictArray(Vec<AMF0ValueOwned>),
+    TypedObject {
+        class_name: String,
+        properties: Properties<String, AMF0ValueOwned>,
+    },
+    Date {
+        millis: f64,
+    },
     Null,
+    Undefined,
+}
+
+impl AMF0Value<'_> {
+    pub fn to_owned(&self) -> AMF0ValueOwned {
+        fn owned_properties(
+            properties: &Properties<&str, AMF0Value>,
+        ) -> Properties<String, AMF0ValueOwned> {
+            properties
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_owned()))
+                .collect()
+        }
+
+        match self {
+            AMF0Value::Number(number) => AMF0ValueOwned::Number(*number),
+            AMF0Value::Boolean(b) => AMF0ValueOwned::Boolean(*b),
+            AMF0Value::String(s) => AMF0ValueOwned::String(s.to_string()),
+            AMF0Value::Object(properties) => AMF0ValueOwned::Object(owned_properties(properties)),
+            AMF0Value::EcmaArray { count, properties } => AMF0ValueOwned::EcmaArray {
+                count: *count,
+                properties: owned_properties(properties),
+            },
+            AMF0Value::StrictArray(values) => {
+                AMF0ValueOwned::StrictArray(values.iter().map(AMF0Value::to_owned).collect())
+            }
+            AMF0Value::TypedObject {
+                class_name,
+                properties,
+            } => AMF0ValueOwned::TypedObject {
+                class_name: class_name.to_string(),
+                properties: owned_properties(properties),
+            },
+            AMF0Value::Date { millis } => AMF0ValueOwned::Date { millis: *millis },
+            AMF0Value::Null => AMF0ValueOwned::Null,
+            AMF0Value::Undefined => AMF0ValueOwned::Undefined,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl AMF0Value<'_> {
+    /// Render the value as compact JSON, for debugging
+    pub fn to_json_string(&self) -> String {
+        // keys are always strings and numbers that JSON can't represent become null,
+        // so serializing to a string can't actually fail
+        serde_json::to_string(self).unwrap_or_else(|e| format!("\"{e}\""))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for AMF0Value<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            AMF0Value::Number(number) => serializer.serialize_f64(*number),
+            AMF0Value::Boolean(b) => serializer.serialize_bool(*b),
+            AMF0Value::String(s) => serializer.serialize_str(s),
+            AMF0Value::Object(properties)
+            | AMF0Value::EcmaArray { properties, .. }
+            | AMF0Value::TypedObject { properties, .. } => properties.serialize(serializer),
+            AMF0Value::StrictArray(values) => values.serialize(serializer),
+            AMF0Value::Date { millis } => serializer.serialize_f64(*millis),
+            AMF0Value::Null | AMF0Value::Undefined => serializer.serialize_unit(),
+        }
+    }
+}
+
+impl AMF0Value<'_> {
+    /// Name of the value's type, used for error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            AMF0Value::Number(_) => "number",
+            AMF0Value::Boolean(_) => "boolean",
+            AMF0Value::String(_) => "string",
+            AMF0Value::Object(_) => "object",
+            AMF0Value::EcmaArray { .. } => "ECMA array",
+            AMF0Value::StrictArray(_) => "strict array",
+            AMF0Value::TypedObject { .. } => "typed object",
+            AMF0Value::Date { .. } => "date",
+            AMF0Value::Null => "null",
+            AMF0Value::Undefined => "undefined",
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            AMF0Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            AMF0Value::Number(num) => Some(*num),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            AMF0Value::Boolean(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    /// Look up a property on an object, ECMA array or typed object
+    pub fn get(&self, key: &str) -> Option<&AMF0Value<'_>> {
+        match self {
+            AMF0Value::Object(properties)
+            | AMF0Value::EcmaArray { properties, .. }
+            | AMF0Value::TypedObject { properties, .. } => properties.get(key),
+            _ => None,
+        }
+    }
 }
 
 impl<'a> TryFrom<AMF0Value<'a>> for &'a str {
@@ -37,9 +223,7 @@ impl<'a> TryFrom<AMF0Value<'a>> for &'a str {
     fn try_from(value: AMF0Value<'a>) -> Result<Self, Self::Error> {
         match value {
             AMF0Value::String(s) => Ok(s),
-            found => Err(CastError::TypeMismatch(format!(
-                "Expected string, found {found:?}"
-            ))),
+            found => Err(CastError::ExpectedString(found.type_name())),
         }
     }
 }
@@ -50,30 +234,45 @@ impl<'a> TryFrom<AMF0Value<'a>> for f64 {
     fn try_from(value: AMF0Value<'a>) -> Result<Self, Self::Error> {
         match value {
             AMF0Value::Number(num) => Ok(num),
-            found => Err(CastError::TypeMismatch(format!(
-                "Expected string, found {found:?}"
-            ))),
+            found => Err(CastError::ExpectedNumber(found.type_name())),
         }
     }
 }
 
+impl<'a> TryFrom<AMF0Value<'a>> for u32 {
+    type Error = CastError;
+
+    fn try_from(value: AMF0Value<'a>) -> Result<Self, Self::Error> {
+        let num: f64 = value.try_into()?;
+        if num.fract() != 0.0 || !(0.0..=u32::MAX as f64).contains(&num) {
+            return Err(CastError::OutOfRange(num));
+        }
+
+        Ok(num as u32)
+    }
+}
+
 impl<'a> TryFrom<AMF0Value<'a>> for bool {
     type Error = CastError;
 
     fn try_from(value: AMF0Value<'a>) -> Result<Self, Self::Error> {
         match value {
             AMF0Value::Boolean(b) => Ok(b),
-            found => Err(CastError::TypeMismatch(format!(
-                "Expected string, found {found:?}"
-            ))),
+            found => Err(CastError::ExpectedBool(found.type_name())),
         }
     }
 }
 
-#[derive(Debug, Error)]
+#[derive(Debug, Error, PartialEq)]
 pub enum CastError {
-    #[error("{0}")]
-    TypeMismatch(String),
+    #[error("Expected number, found {0}")]
+    ExpectedNumber(&'static str),
+    #[error("Expected string, found {0}")]
+    ExpectedString(&'static str),
+    #[error("Expected bool, found {0}")]
+    ExpectedBool(&'static str),
+    #[error("{0} is not a valid u32")]
+    OutOfRange(f64),
 }
 
 #[derive(Debug, Error, PartialEq)]
@@ -82,6 +281,11 @@ pub enum DecodeError {
     UnexpectedEOF,
     #[error("Unknown marker {0:#04x}")]
     UnknownMarker(u8),
+    #[error(
+        "Unsupported marker {0:#04x}, {name} type is not supported",
+        name = amf0_type_marker::unsupported_name(*.0).unwrap_or("unknown")
+    )]
+    UnsupportedMarker(u8),
     #[error("String contains invalid utf8")]
     InvalidUtf8(#[from] str::Utf8Error),
     #[error("Invalid object key")]
@@ -92,19 +296,40 @@ pub enum DecodeError {
     InvalidNumber,
     #[error("Invalid bool")]
     InvalidBool,
+    #[error("String length {length} exceeds the maximum of {max}")]
+    StringTooLong { length: usize, max: usize },
+    #[error("Values are nested more than {0} deep")]
+    TooDeeplyNested(usize),
 }
 
+/// Default cap on declared string lengths
+pub const DEFAULT_MAX_STRING_LEN: usize = 16 * 1024 * 1024;
+
+/// How deep objects and arrays can nest, decoding recurses into them so this bounds the stack
+pub const MAX_DEPTH: usize = 64;
+
 pub struct Decoder<'a> {
     cursor: Cursor<&'a [u8]>,
+    max_string_len: usize,
+    /// Objects and arrays currently being decoded
+    depth: usize,
 }
 
 impl<'a> Decoder<'a> {
     pub fn new(buf: &'a [u8]) -> Self {
         Self {
             cursor: Cursor::new(buf),
+            max_string_len: DEFAULT_MAX_STRING_LEN,
+            depth: 0,
         }
     }
 
+    /// Reject any string that declares a length longer than `max_string_len`
+    pub fn with_max_string_len(mut self, max_string_len: usize) -> Self {
+        self.max_string_len = max_string_len;
+        self
+    }
+
     pub fn get_buf(&self) -> Result<&'a [u8], DecodeError> {
         self.cursor
             .get_ref()
@@ -124,15 +349,56 @@ impl<'a> Decoder<'a> {
             amf0_type_marker::NUMBER => self.decode_number()?,
             amf0_type_marker::BOOL => self.decode_bool()?,
             amf0_type_marker::STRING => self.decode_string()?,
-            amf0_type_marker::OBJECT_START => self.decode_object()?,
+            amf0_type_marker::OBJECT_START => self.nested(Self::decode_object)?,
+            amf0_type_marker::ECMA_ARRAY => self.nested(Self::decode_ecma_array)?,
+            amf0_type_marker::STRICT_ARRAY => self.nested(Self::decode_strict_array)?,
+            amf0_type_marker::DATE => self.decode_date()?,
+            amf0_type_marker::LONG_STRING => self.decode_long_string()?,
+            amf0_type_marker::TYPED_OBJECT => self.nested(Self::decode_typed_object)?,
             amf0_type_marker::NULL => AMF0Value::Null,
+            amf0_type_marker::UNDEFINED => AMF0Value::Undefined,
+            marker if amf0_type_marker::unsupported_name(marker).is_some() => {
+                return Err(DecodeError::UnsupportedMarker(marker));
+            }
             marker => return Err(DecodeError::UnknownMarker(marker)),
         };
 
         Ok(value)
     }
 
+    /// Decode values until the end of the buffer is reached, stopping after the first error
+    pub fn decode_all(&mut self) -> impl Iterator<Item = Result<AMF0Value<'a>, DecodeError>> {
+        let mut failed = false;
+        std::iter::from_fn(move || {
+            if failed || self.get_buf().map_or(true, |buf| buf.is_empty()) {
+                return None;
+            }
+
+            let value = self.decode();
+            failed = value.is_err();
+            Some(value)
+        })
+    }
+
+    /// Decode a value that contains other values, failing instead of recursing past [`MAX_DEPTH`]
+    fn nested(
+        &mut self,
+        decode: fn(&mut Self) -> Result<AMF0Value<'a>, DecodeError>,
+    ) -> Result<AMF0Value<'a>, DecodeError> {
+        if self.depth >= MAX_DEPTH {
+            return Err(DecodeError::TooDeeplyNested(MAX_DEPTH));
+        }
+        self.depth += 1;
+        let value = decode(self);
+        self.depth -= 1;
+        value
+    }
+
     fn decode_number(&mut self) -> Result<AMF0Value<'a>, DecodeError> {
+        Ok(AMF0Value::Number(self.read_f64()?))
+    }
+
+    fn read_f64(&mut self) -> Result<f64, DecodeError> {
         let number_size = 8;
         let number = f64::from_be_bytes(
             self.get_buf()?
@@ -145,7 +411,7 @@ impl<'a> Decoder<'a> {
             .seek_relative(number_size as i64)
             .map_err(|_| DecodeError::UnexpectedEOF)?;
 
-        Ok(AMF0Value::Number(number))
+        Ok(number)
     }
 
     fn decode_bool(&mut self) -> Result<AMF0Value<'a>, DecodeError> {
@@ -169,9 +435,25 @@ impl<'a> Decoder<'a> {
             .seek_relative(2)
             .map_err(|_| DecodeError::UnexpectedEOF)?;
 
+        self.read_utf8(length as usize)
+    }
+
+    fn decode_long_string(&mut self) -> Result<AMF0Value<'a>, DecodeError> {
+        let length = self.read_u32()?;
+        self.read_utf8(length as usize)
+    }
+
+    fn read_utf8(&mut self, length: usize) -> Result<AMF0Value<'a>, DecodeError> {
+        if length > self.max_string_len {
+            return Err(DecodeError::StringTooLong {
+                length,
+                max: self.max_string_len,
+            });
+        }
+
         let value = self
             .get_buf()?
-            .get(..length as usize)
+            .get(..length)
             .ok_or(DecodeError::UnexpectedEOF)?;
 
         self.cursor
@@ -182,23 +464,217 @@ impl<'a> Decoder<'a> {
     }
 
     fn decode_object(&mut self) -> Result<AMF0Value<'a>, DecodeError> {
+        Ok(AMF0Value::Object(self.decode_properties()?))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        let value = u32::from_be_bytes(
+            self.get_buf()?
+                .get(..4)
+                .ok_or(DecodeError::UnexpectedEOF)?
+                .try_into()
+                .map_err(|_| DecodeError::UnexpectedEOF)?,
+        );
+        self.cursor
+            .seek_relative(4)
+            .map_err(|_| DecodeError::UnexpectedEOF)?;
+
+        Ok(value)
+    }
+
+    fn decode_typed_object(&mut self) -> Result<AMF0Value<'a>, DecodeError> {
+        let AMF0Value::String(class_name) = self.decode_string()? else {
+            return Err(DecodeError::InvalidObjectKey);
+        };
+
+        Ok(AMF0Value::TypedObject {
+            class_name,
+            properties: self.decode_properties()?,
+        })
+    }
+
+    fn decode_ecma_array(&mut self) -> Result<AMF0Value<'a>, DecodeError> {
+        let count = self.read_u32()?;
+
+        // the count can't be trusted, so the end marker is what terminates the array
+        Ok(AMF0Value::EcmaArray {
+            count,
+            properties: self.decode_properties()?,
+        })
+    }
+
+    fn decode_strict_array(&mut self) -> Result<AMF0Value<'a>, DecodeError> {
+        let count = self.read_u32()?;
+
+        // there's no end marker, so the count is all we have to go on
+        let mut values = Vec::new();
+        for _ in 0..count {
+            if self.get_buf()?.is_empty() {
+                return Err(DecodeError::UnexpectedEOF);
+            }
+            values.push(self.decode()?);
+        }
+
+        Ok(AMF0Value::StrictArray(values))
+    }
+
+    fn decode_date(&mut self) -> Result<AMF0Value<'a>, DecodeError> {
+        let millis = self.read_f64()?;
+
+        // skip the timezone, the spec says it should be ignored
+        let timezone_size = 2;
+        if self.get_buf()?.len() < timezone_size {
+            return Err(DecodeError::UnexpectedEOF);
+        }
+        self.cursor
+            .seek_relative(timezone_size as i64)
+            .map_err(|_| DecodeError::UnexpectedEOF)?;
+
+        Ok(AMF0Value::Date { millis })
+    }
+
+    /// Decode key value pairs until the object end marker is reached
+    fn decode_properties(&mut self) -> Result<Properties<&'a str, AMF0Value<'a>>, DecodeError> {
         let end_marker = [0x00, 0x00, amf0_type_marker::OBJECT_END];
-        let mut obj = HashMap::new();
+        let mut properties = Properties::new();
         while self.get_buf()?.get(..3) != Some(&end_marker) {
             let AMF0Value::String(key) = self.decode_string()? else {
                 return Err(DecodeError::InvalidObjectKey);
             };
             let value = self.decode()?;
-            obj.insert(key, value);
+            properties.insert(key, value);
         }
+        self.cursor
+            .seek_relative(end_marker.len() as i64)
+            .map_err(|_| DecodeError::UnexpectedEOF)?;
 
-        Ok(AMF0Value::Object(obj))
+        Ok(properties)
     }
 
-    #[cfg(test)]
-    fn position(&self) -> u64 {
+    /// Number of bytes consumed so far
+    pub fn position(&self) -> u64 {
         self.cursor.position()
     }
+
+    /// The bytes that haven't been decoded yet
+    pub fn remaining(&self) -> &'a [u8] {
+        self.get_buf().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum EncodeError {
+    #[error("String of length {0} is too long to encode")]
+    StringTooLong(usize),
+    #[error("Array of length {0} is too long to encode")]
+    ArrayTooLong(usize),
+}
+
+/// Encodes [`AMF0Value`]s into their wire representation
+#[derive(Debug, Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Consume the encoder, returning the encoded bytes
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn encode(&mut self, value: &AMF0Value) -> Result<(), EncodeError> {
+        match value {
+            AMF0Value::Number(number) => {
+                self.buf.push(amf0_type_marker::NUMBER);
+                self.buf.extend_from_slice(&number.to_be_bytes());
+            }
+            AMF0Value::Boolean(b) => {
+                self.buf.push(amf0_type_marker::BOOL);
+                self.buf.push(*b as u8);
+            }
+            AMF0Value::String(s) if s.len() > u16::MAX as usize => {
+                self.buf.push(amf0_type_marker::LONG_STRING);
+                let length: u32 = s
+                    .len()
+                    .try_into()
+                    .map_err(|_| EncodeError::StringTooLong(s.len()))?;
+                self.buf.extend_from_slice(&length.to_be_bytes());
+                self.buf.extend_from_slice(s.as_bytes());
+            }
+            AMF0Value::String(s) => {
+                self.buf.push(amf0_type_marker::STRING);
+                self.encode_string(s)?;
+            }
+            AMF0Value::Object(obj) => {
+                self.buf.push(amf0_type_marker::OBJECT_START);
+                self.encode_properties(obj)?;
+            }
+            AMF0Value::EcmaArray { count, properties } => {
+                self.buf.push(amf0_type_marker::ECMA_ARRAY);
+                self.buf.extend_from_slice(&count.to_be_bytes());
+                self.encode_properties(properties)?;
+            }
+            AMF0Value::TypedObject {
+                class_name,
+                properties,
+            } => {
+                self.buf.push(amf0_type_marker::TYPED_OBJECT);
+                self.encode_string(class_name)?;
+                self.encode_properties(properties)?;
+            }
+            AMF0Value::StrictArray(values) => {
+                self.buf.push(amf0_type_marker::STRICT_ARRAY);
+                let count: u32 = values
+                    .len()
+                    .try_into()
+                    .map_err(|_| EncodeError::ArrayTooLong(values.len()))?;
+                self.buf.extend_from_slice(&count.to_be_bytes());
+                for value in values {
+                    self.encode(value)?;
+                }
+            }
+            AMF0Value::Date { millis } => {
+                self.buf.push(amf0_type_marker::DATE);
+                self.buf.extend_from_slice(&millis.to_be_bytes());
+                // timezone, which is always 0
+                self.buf.extend_from_slice(&[0x00, 0x00]);
+            }
+            AMF0Value::Null => self.buf.push(amf0_type_marker::NULL),
+            AMF0Value::Undefined => self.buf.push(amf0_type_marker::UNDEFINED),
+        }
+
+        Ok(())
+    }
+
+    fn encode_properties(
+        &mut self,
+        properties: &Properties<&str, AMF0Value>,
+    ) -> Result<(), EncodeError> {
+        for (key, value) in properties {
+            self.encode_string(key)?;
+            self.encode(value)?;
+        }
+        self.buf
+            .extend_from_slice(&[0x00, 0x00, amf0_type_marker::OBJECT_END]);
+
+        Ok(())
+    }
+
+    /// Encode a string without its type marker, used for both string values and object keys
+    fn encode_string(&mut self, s: &str) -> Result<(), EncodeError> {
+        let length: u16 = s
+            .len()
+            .try_into()
+            .map_err(|_| EncodeError::StringTooLong(s.len()))?;
+        self.buf.extend_from_slice(&length.to_be_bytes());
+        self.buf.extend_from_slice(s.as_bytes());
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -270,4 +746,701 @@ mod tests {
         assert_eq!(decoder.decode(), Ok(AMF0Value::Boolean(false)));
         assert_eq!(decoder.position(), 2);
     }
+
+    fn round_trip(value: &AMF0Value) {
+        let mut encoder = Encoder::new();
+        encoder.encode(value).unwrap();
+        let bytes = encoder.finish();
+
+        let mut decoder = Decoder::new(&bytes);
+        assert_eq!(&decoder.decode().unwrap(), value);
+        assert_eq!(decoder.position(), bytes.len() as u64);
+    }
+
+    #[test]
+    fn test_round_trip_primitives() {
+        round_trip(&AMF0Value::Number(rand::random()));
+        round_trip(&AMF0Value::Boolean(true));
+        round_trip(&AMF0Value::Boolean(false));
+        round_trip(&AMF0Value::String("hello world"));
+        round_trip(&AMF0Value::String(""));
+        round_trip(&AMF0Value::Null);
+        round_trip(&AMF0Value::Undefined);
+    }
+
+    #[test]
+    fn test_round_trip_object() {
+        round_trip(&AMF0Value::Object(Properties::from([
+            ("level", AMF0Value::String("status")),
+            ("code", AMF0Value::String("NetConnection.Connect.Success")),
+            ("objectEncoding", AMF0Value::Number(0.0)),
+            (
+                "nested",
+                AMF0Value::Object(Properties::from([("flag", AMF0Value::Boolean(true))])),
+            ),
+        ])));
+        round_trip(&AMF0Value::Object(Properties::new()));
+    }
+
+    #[test]
+    fn test_round_trip_ecma_array() {
+        round_trip(&AMF0Value::EcmaArray {
+            count: 2,
+            properties: Properties::from([
+                ("width", AMF0Value::Number(1920.0)),
+                ("height", AMF0Value::Number(1080.0)),
+            ]),
+        });
+    }
+
+    #[test]
+    fn test_decode_ecma_array() {
+        let bytes = [
+            &[amf0_type_marker::ECMA_ARRAY],
+            1u32.to_be_bytes().as_slice(),
+            &5u16.to_be_bytes(),
+            b"width",
+            &[amf0_type_marker::NUMBER],
+            &1920f64.to_be_bytes(),
+            &[0x00, 0x00, amf0_type_marker::OBJECT_END],
+        ]
+        .concat();
+        let mut decoder = Decoder::new(&bytes);
+        assert_eq!(
+            decoder.decode(),
+            Ok(AMF0Value::EcmaArray {
+                count: 1,
+                properties: Properties::from([("width", AMF0Value::Number(1920.0))]),
+            })
+        );
+        assert_eq!(decoder.position(), bytes.len() as u64);
+    }
+
+    #[test]
+    fn test_decode_ecma_array_count_mismatch() {
+        // declares 5 properties but only contains 1, followed by another value
+        let bytes = [
+            &[amf0_type_marker::ECMA_ARRAY],
+            5u32.to_be_bytes().as_slice(),
+            &3u16.to_be_bytes(),
+            b"fps",
+            &[amf0_type_marker::NUMBER],
+            &30f64.to_be_bytes(),
+            &[0x00, 0x00, amf0_type_marker::OBJECT_END],
+            &[amf0_type_marker::NULL],
+        ]
+        .concat();
+        let mut decoder = Decoder::new(&bytes);
+        assert_eq!(
+            decoder.decode(),
+            Ok(AMF0Value::EcmaArray {
+                count: 5,
+                properties: Properties::from([("fps", AMF0Value::Number(30.0))]),
+            })
+        );
+        assert_eq!(decoder.decode(), Ok(AMF0Value::Null));
+    }
+
+    #[test]
+    fn test_decode_empty_ecma_array() {
+        let bytes = [
+            &[amf0_type_marker::ECMA_ARRAY],
+            0u32.to_be_bytes().as_slice(),
+            &[0x00, 0x00, amf0_type_marker::OBJECT_END],
+        ]
+        .concat();
+        let mut decoder = Decoder::new(&bytes);
+        assert_eq!(
+            decoder.decode(),
+            Ok(AMF0Value::EcmaArray {
+                count: 0,
+                properties: Properties::new(),
+            })
+        );
+        assert_eq!(decoder.position(), bytes.len() as u64);
+    }
+
+    #[test]
+    fn test_decode_strict_array_preserves_order() {
+        let bytes = [
+            &[amf0_type_marker::STRICT_ARRAY],
+            3u32.to_be_bytes().as_slice(),
+            &[amf0_type_marker::NUMBER],
+            &1f64.to_be_bytes(),
+            &[amf0_type_marker::STRING],
+            &3u16.to_be_bytes(),
+            b"two",
+            &[amf0_type_marker::NUMBER],
+            &3f64.to_be_bytes(),
+        ]
+        .concat();
+        let mut decoder = Decoder::new(&bytes);
+        assert_eq!(
+            decoder.decode(),
+            Ok(AMF0Value::StrictArray(vec![
+                AMF0Value::Number(1.0),
+                AMF0Value::String("two"),
+                AMF0Value::Number(3.0),
+            ]))
+        );
+        assert_eq!(decoder.position(), bytes.len() as u64);
+    }
+
+    #[test]
+    fn test_decode_strict_array_too_short() {
+        let bytes = [
+            &[amf0_type_marker::STRICT_ARRAY],
+            2u32.to_be_bytes().as_slice(),
+            &[amf0_type_marker::NULL],
+        ]
+        .concat();
+        assert_eq!(
+            Decoder::new(&bytes).decode(),
+            Err(DecodeError::UnexpectedEOF)
+        );
+    }
+
+    #[test]
+    fn test_round_trip_strict_array() {
+        round_trip(&AMF0Value::StrictArray(vec![
+            AMF0Value::String("a"),
+            AMF0Value::Boolean(true),
+            AMF0Value::StrictArray(vec![]),
+        ]));
+    }
+
+    #[test]
+    fn test_decode_date_followed_by_number() {
+        let millis = 1_700_000_000_000f64;
+        let bytes = [
+            &[amf0_type_marker::DATE],
+            millis.to_be_bytes().as_slice(),
+            &[0x01, 0xe0], // timezone offset, ignored
+            &[amf0_type_marker::NUMBER],
+            &42f64.to_be_bytes(),
+        ]
+        .concat();
+        let mut decoder = Decoder::new(&bytes);
+        assert_eq!(decoder.decode(), Ok(AMF0Value::Date { millis }));
+        assert_eq!(decoder.position(), 11);
+        assert_eq!(decoder.decode(), Ok(AMF0Value::Number(42.0)));
+        assert_eq!(decoder.position(), bytes.len() as u64);
+    }
+
+    #[test]
+    fn test_decode_date_missing_timezone() {
+        let bytes = [&[amf0_type_marker::DATE], 0f64.to_be_bytes().as_slice()].concat();
+        assert_eq!(
+            Decoder::new(&bytes).decode(),
+            Err(DecodeError::UnexpectedEOF)
+        );
+    }
+
+    #[test]
+    fn test_round_trip_date() {
+        round_trip(&AMF0Value::Date {
+            millis: 1_700_000_000_000.0,
+        });
+    }
+
+    #[test]
+    fn test_decode_typed_object() {
+        let bytes = [
+            &[amf0_type_marker::TYPED_OBJECT],
+            10u16.to_be_bytes().as_slice(),
+            b"com.Person",
+            &4u16.to_be_bytes(),
+            b"name",
+            &[amf0_type_marker::STRING],
+            &5u16.to_be_bytes(),
+            b"alice",
+            &3u16.to_be_bytes(),
+            b"age",
+            &[amf0_type_marker::NUMBER],
+            &30f64.to_be_bytes(),
+            &[0x00, 0x00, amf0_type_marker::OBJECT_END],
+        ]
+        .concat();
+        let mut decoder = Decoder::new(&bytes);
+        assert_eq!(
+            decoder.decode(),
+            Ok(AMF0Value::TypedObject {
+                class_name: "com.Person",
+                properties: Properties::from([
+                    ("name", AMF0Value::String("alice")),
+                    ("age", AMF0Value::Number(30.0)),
+                ]),
+            })
+        );
+        assert_eq!(decoder.position(), bytes.len() as u64);
+    }
+
+    #[test]
+    fn test_round_trip_typed_object() {
+        round_trip(&AMF0Value::TypedObject {
+            class_name: "flex.messaging.io.ArrayCollection",
+            properties: Properties::from([("source", AMF0Value::StrictArray(vec![]))]),
+        });
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize_nested_object() {
+        let value = AMF0Value::Object(Properties::from([
+            ("app", AMF0Value::String("live")),
+            ("fpad", AMF0Value::Boolean(false)),
+            ("capabilities", AMF0Value::Number(239.0)),
+            ("pageUrl", AMF0Value::Undefined),
+            ("swfUrl", AMF0Value::Null),
+            (
+                "metadata",
+                AMF0Value::EcmaArray {
+                    count: 1,
+                    properties: Properties::from([(
+                        "codecs",
+                        AMF0Value::StrictArray(vec![
+                            AMF0Value::String("avc1"),
+                            AMF0Value::String("mp4a"),
+                        ]),
+                    )]),
+                },
+            ),
+        ]));
+
+        assert_eq!(
+            serde_json::to_value(&value).unwrap(),
+            serde_json::json!({
+                "app": "live",
+                "fpad": false,
+                "capabilities": 239.0,
+                "pageUrl": null,
+                "swfUrl": null,
+                "metadata": { "codecs": ["avc1", "mp4a"] },
+            })
+        );
+    }
+
+    #[test]
+    fn test_to_owned() {
+        let bytes = {
+            let mut encoder = Encoder::new();
+            encoder
+                .encode(&AMF0Value::Object(Properties::from([
+                    ("app", AMF0Value::String("live")),
+                    ("objectEncoding", AMF0Value::Number(0.0)),
+                    ("fpad", AMF0Value::Boolean(false)),
+                    (
+                        "codecs",
+                        AMF0Value::StrictArray(vec![AMF0Value::String("avc1"), AMF0Value::Null]),
+                    ),
+                ])))
+                .unwrap();
+            encoder.finish()
+        };
+
+        let owned = Decoder::new(&bytes).decode().unwrap().to_owned();
+        drop(bytes);
+
+        assert_eq!(
+            owned,
+            AMF0ValueOwned::Object(Properties::from([
+                (
+                    "app".to_string(),
+                    AMF0ValueOwned::String("live".to_string())
+                ),
+                ("objectEncoding".to_string(), AMF0ValueOwned::Number(0.0)),
+                ("fpad".to_string(), AMF0ValueOwned::Boolean(false)),
+                (
+                    "codecs".to_string(),
+                    AMF0ValueOwned::StrictArray(vec![
+                        AMF0ValueOwned::String("avc1".to_string()),
+                        AMF0ValueOwned::Null
+                    ])
+                ),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_cast_str() {
+        assert_eq!(AMF0Value::String("live").try_into(), Ok("live"));
+        assert_eq!(
+            <&str>::try_from(AMF0Value::Number(1.0)),
+            Err(CastError::ExpectedString("number"))
+        );
+    }
+
+    #[test]
+    fn test_cast_f64() {
+        assert_eq!(AMF0Value::Number(1.5).try_into(), Ok(1.5));
+        assert_eq!(
+            f64::try_from(AMF0Value::Null),
+            Err(CastError::ExpectedNumber("null"))
+        );
+    }
+
+    #[test]
+    fn test_cast_bool() {
+        assert_eq!(AMF0Value::Boolean(true).try_into(), Ok(true));
+        assert_eq!(
+            bool::try_from(AMF0Value::String("true")),
+            Err(CastError::ExpectedBool("string"))
+        );
+    }
+
+    #[test]
+    fn test_cast_u32() {
+        assert_eq!(AMF0Value::Number(3.0).try_into(), Ok(3u32));
+        assert_eq!(
+            u32::try_from(AMF0Value::Number(1.5)),
+            Err(CastError::OutOfRange(1.5))
+        );
+        assert_eq!(
+            u32::try_from(AMF0Value::Number(-1.0)),
+            Err(CastError::OutOfRange(-1.0))
+        );
+        assert_eq!(
+            u32::try_from(AMF0Value::Boolean(false)),
+            Err(CastError::ExpectedNumber("boolean"))
+        );
+    }
+
+    #[test]
+    fn test_accessors() {
+        assert_eq!(AMF0Value::String("live").as_str(), Some("live"));
+        assert_eq!(AMF0Value::Number(3.0).as_f64(), Some(3.0));
+        assert_eq!(AMF0Value::Boolean(true).as_bool(), Some(true));
+
+        assert_eq!(AMF0Value::Number(3.0).as_str(), None);
+        assert_eq!(AMF0Value::String("3").as_f64(), None);
+        assert_eq!(AMF0Value::Null.as_bool(), None);
+    }
+
+    #[test]
+    fn test_get() {
+        let command_object = AMF0Value::Object(Properties::from([
+            ("app", AMF0Value::String("live")),
+            ("tcUrl", AMF0Value::String("rtmp://localhost/live")),
+        ]));
+        assert_eq!(
+            command_object.get("app").and_then(|v| v.as_str()),
+            Some("live")
+        );
+        assert_eq!(command_object.get("swfUrl"), None);
+
+        let ecma_array = AMF0Value::EcmaArray {
+            count: 1,
+            properties: Properties::from([("width", AMF0Value::Number(1280.0))]),
+        };
+        assert_eq!(
+            ecma_array.get("width").and_then(|v| v.as_f64()),
+            Some(1280.0)
+        );
+
+        let typed_object = AMF0Value::TypedObject {
+            class_name: "Point",
+            properties: Properties::from([("x", AMF0Value::Number(1.0))]),
+        };
+        assert_eq!(typed_object.get("x"), Some(&AMF0Value::Number(1.0)));
+
+        assert_eq!(AMF0Value::String("app").get("app"), None);
+        assert_eq!(AMF0Value::StrictArray(vec![]).get("app"), None);
+    }
+
+    #[test]
+    fn test_decode_all() {
+        let values = [
+            AMF0Value::String("releaseStream"),
+            AMF0Value::Number(2.0),
+            AMF0Value::Null,
+            AMF0Value::String("stream key"),
+        ];
+        let mut encoder = Encoder::new();
+        for value in &values {
+            encoder.encode(value).unwrap();
+        }
+        let bytes = encoder.finish();
+
+        let decoded: Result<Vec<_>, _> = Decoder::new(&bytes).decode_all().collect();
+        assert_eq!(decoded.unwrap(), values);
+    }
+
+    #[test]
+    fn test_decode_all_stops_on_error() {
+        let bytes = [amf0_type_marker::NULL, 0xff, amf0_type_marker::NULL];
+        let mut decoder = Decoder::new(&bytes);
+        let mut values = decoder.decode_all();
+        assert_eq!(values.next(), Some(Ok(AMF0Value::Null)));
+        assert_eq!(values.next(), Some(Err(DecodeError::UnknownMarker(0xff))));
+        assert_eq!(values.next(), None);
+    }
+
+    #[test]
+    fn test_decode_all_empty() {
+        assert_eq!(Decoder::new(&[]).decode_all().count(), 0);
+    }
+
+    #[cfg(feature = "preserve_order")]
+    #[test]
+    fn test_object_preserves_key_order() {
+        let bytes = [
+            &[amf0_type_marker::OBJECT_START],
+            5u16.to_be_bytes().as_slice(),
+            b"tcUrl",
+            &[amf0_type_marker::NULL],
+            &3u16.to_be_bytes(),
+            b"app",
+            &[amf0_type_marker::NULL],
+            &8u16.to_be_bytes(),
+            b"flashVer",
+            &[amf0_type_marker::NULL],
+            &[0x00, 0x00, amf0_type_marker::OBJECT_END],
+        ]
+        .concat();
+
+        let value = Decoder::new(&bytes).decode().unwrap();
+        let keys = match &value {
+            AMF0Value::Object(properties) => properties.keys().copied().collect(),
+            _ => Vec::new(),
+        };
+        assert_eq!(keys, ["tcUrl", "app", "flashVer"]);
+
+        let mut encoder = Encoder::new();
+        encoder.encode(&value).unwrap();
+        assert_eq!(encoder.finish(), bytes);
+    }
+
+    #[test]
+    fn test_position_and_remaining() {
+        let bytes = [
+            &[amf0_type_marker::STRING],
+            5u16.to_be_bytes().as_slice(),
+            b"hello",
+            &[amf0_type_marker::BOOL, 0x01],
+            &[amf0_type_marker::NULL],
+        ]
+        .concat();
+        let mut decoder = Decoder::new(&bytes);
+        assert_eq!(decoder.position(), 0);
+        assert_eq!(decoder.remaining(), bytes.as_slice());
+
+        decoder.decode().unwrap();
+        assert_eq!(decoder.position(), 8);
+        assert_eq!(decoder.remaining(), &bytes[8..]);
+
+        decoder.decode().unwrap();
+        assert_eq!(decoder.position(), 10);
+        assert_eq!(decoder.remaining(), [amf0_type_marker::NULL]);
+
+        decoder.decode().unwrap();
+        assert_eq!(decoder.position(), bytes.len() as u64);
+        assert!(decoder.remaining().is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_to_json_string() {
+        let value = AMF0Value::Object(Properties::from([
+            ("code", AMF0Value::String("NetStream.Publish.Start")),
+            ("created", AMF0Value::Date { millis: 1000.0 }),
+            (
+                "info",
+                AMF0Value::Object(Properties::from([
+                    ("list", AMF0Value::StrictArray(vec![AMF0Value::Number(1.0)])),
+                    ("missing", AMF0Value::Undefined),
+                ])),
+            ),
+        ]));
+
+        let json = value.to_json_string();
+        assert!(!json.contains('\n'));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            serde_json::json!({
+                "code": "NetStream.Publish.Start",
+                "created": 1000.0,
+                "info": { "list": [1.0], "missing": null },
+            })
+        );
+        assert_eq!(AMF0Value::String("a\"b").to_json_string(), r#""a\"b""#);
+    }
+
+    #[test]
+    fn test_decode_string_above_cap() {
+        // the declared length is checked before the buffer, so this isn't an UnexpectedEOF
+        let bytes = [
+            &[amf0_type_marker::STRING],
+            1000u16.to_be_bytes().as_slice(),
+        ]
+        .concat();
+        assert_eq!(
+            Decoder::new(&bytes).with_max_string_len(100).decode(),
+            Err(DecodeError::StringTooLong {
+                length: 1000,
+                max: 100
+            })
+        );
+
+        let bytes = [
+            &[amf0_type_marker::LONG_STRING],
+            (DEFAULT_MAX_STRING_LEN as u32 + 1).to_be_bytes().as_slice(),
+        ]
+        .concat();
+        assert_eq!(
+            Decoder::new(&bytes).decode(),
+            Err(DecodeError::StringTooLong {
+                length: DEFAULT_MAX_STRING_LEN + 1,
+                max: DEFAULT_MAX_STRING_LEN
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode_string_at_cap() {
+        let bytes = [
+            &[amf0_type_marker::STRING],
+            5u16.to_be_bytes().as_slice(),
+            b"hello",
+        ]
+        .concat();
+        assert_eq!(
+            Decoder::new(&bytes).with_max_string_len(5).decode(),
+            Ok(AMF0Value::String("hello"))
+        );
+    }
+
+    #[test]
+    fn test_object_key_above_cap() {
+        let bytes = [
+            &[amf0_type_marker::OBJECT_START],
+            6u16.to_be_bytes().as_slice(),
+            b"toolong",
+        ]
+        .concat();
+        assert_eq!(
+            Decoder::new(&bytes).with_max_string_len(5).decode(),
+            Err(DecodeError::StringTooLong { length: 6, max: 5 })
+        );
+    }
+
+    #[test]
+    fn test_unsupported_markers() {
+        for marker in [
+            amf0_type_marker::MOVIECLIP,
+            amf0_type_marker::REFERENCE,
+            amf0_type_marker::UNSUPPORTED,
+            amf0_type_marker::RECORDSET,
+            amf0_type_marker::XML_DOCUMENT,
+            amf0_type_marker::AVMPLUS_OBJECT,
+        ] {
+            assert_eq!(
+                Decoder::new(&[marker, 0x00, 0x01]).decode(),
+                Err(DecodeError::UnsupportedMarker(marker))
+            );
+        }
+
+        assert_eq!(
+            DecodeError::UnsupportedMarker(amf0_type_marker::REFERENCE).to_string(),
+            "Unsupported marker 0x07, reference type is not supported"
+        );
+    }
+
+    #[test]
+    fn test_unknown_markers() {
+        for marker in [0x12, 0x42, 0xff] {
+            assert_eq!(
+                Decoder::new(&[marker]).decode(),
+                Err(DecodeError::UnknownMarker(marker))
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_sequence() {
+        let values = [
+            AMF0Value::String("_result"),
+            AMF0Value::Number(1.0),
+            AMF0Value::Object(Properties::from([(
+                "fmsVer",
+                AMF0Value::String("FMS/3,0,1,123"),
+            )])),
+            AMF0Value::Null,
+        ];
+        let mut encoder = Encoder::new();
+        for value in &values {
+            encoder.encode(value).unwrap();
+        }
+        let bytes = encoder.finish();
+
+        let mut decoder = Decoder::new(&bytes);
+        for value in values {
+            assert_eq!(decoder.decode().unwrap(), value);
+        }
+        assert!(decoder.get_buf().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_encode_key_too_long() {
+        let key = "a".repeat(u16::MAX as usize + 1);
+        assert_eq!(
+            Encoder::new().encode(&AMF0Value::Object(Properties::from([(
+                key.as_str(),
+                AMF0Value::Null
+            )]))),
+            Err(EncodeError::StringTooLong(key.len()))
+        );
+    }
+
+    #[test]
+    fn test_decode_long_string() {
+        let actual = "a".repeat(u16::MAX as usize + 1);
+        let bytes = [
+            &[amf0_type_marker::LONG_STRING],
+            (actual.len() as u32).to_be_bytes().as_slice(),
+            actual.as_bytes(),
+        ]
+        .concat();
+        let mut decoder = Decoder::new(&bytes);
+        assert_eq!(decoder.decode(), Ok(AMF0Value::String(&actual)));
+        assert_eq!(decoder.position(), bytes.len() as u64);
+    }
+
+    #[test]
+    fn test_decode_long_string_bogus_length() {
+        let bytes = [
+            &[amf0_type_marker::LONG_STRING],
+            (DEFAULT_MAX_STRING_LEN as u32).to_be_bytes().as_slice(),
+            b"short",
+        ]
+        .concat();
+        assert_eq!(
+            Decoder::new(&bytes).decode(),
+            Err(DecodeError::UnexpectedEOF)
+        );
+    }
+
+    #[test]
+    fn test_round_trip_long_string() {
+        let s = "b".repeat(u16::MAX as usize + 1);
+        round_trip(&AMF0Value::String(&s));
+
+        let mut encoder = Encoder::new();
+        encoder.encode(&AMF0Value::String(&s)).unwrap();
+        assert_eq!(encoder.finish()[0], amf0_type_marker::LONG_STRING);
+    }
+
+    #[test]
+    fn test_deep_nesting_is_rejected() {
+        let strict_array = [&[amf0_type_marker::STRICT_ARRAY][..], &1u32.to_be_bytes()].concat();
+
+        let bytes = [strict_array.repeat(MAX_DEPTH), vec![amf0_type_marker::NULL]].concat();
+        assert!(Decoder::new(&bytes).decode().is_ok());
+
+        // deep enough to overflow the stack without the limit
+        let bytes = strict_array.repeat(1_000_000);
+        assert_eq!(
+            Decoder::new(&bytes).decode(),
+            Err(DecodeError::TooDeeplyNested(MAX_DEPTH))
+        );
+    }
 }
@@ -0,0 +1,156 @@
+use std::{collections::HashMap, fmt, path::Path, sync::Arc};
+
+use crate::{
+    config::{ServerConfig, StreamSettings},
+    rtmp::DEFAULT_APP,
+};
+
+/// How connections to an app are handled
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppOptions {
+    pub allow_publish: bool,
+    pub allow_play: bool,
+}
+
+impl Default for AppOptions {
+    fn default() -> Self {
+        Self {
+            allow_publish: true,
+            allow_play: true,
+        }
+    }
+}
+
+/// Decides whether a publisher can publish a stream that has `auth_required` set
+pub trait Authorizer: fmt::Debug + Send + Sync {
+    /// `query` holds the parameters appended to the stream name, where encoders put
+    /// credentials, e.g. `{"token": "abc123"}` for `mystream?token=abc123`
+    fn authorize_publish(&self, app: &str, stream_name: &str, query: &HashMap<&str, &str>) -> bool;
+}
+
+/// The apps clients are allowed to connect to, keyed by the `app` in the connect command.
+/// Connects to an app that isn't registered are rejected
+#[derive(Debug, Clone, Default)]
+pub struct AppRegistry {
+    apps: HashMap<String, AppOptions>,
+    /// Where the settings of each stream are resolved from
+    config: ServerConfig,
+    authorizer: Option<Arc<dyn Authorizer>>,
+}
+
+impl AppRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_app(mut self, name: impl Into<String>, options: AppOptions) -> Self {
+        self.apps.insert(name.into(), options);
+        self
+    }
+
+    /// Check publishers to streams that require authentication with `authorizer`
+    pub fn with_authorizer(mut self, authorizer: impl Authorizer + 'static) -> Self {
+        self.authorizer = Some(Arc::new(authorizer));
+        self
+    }
+
+    pub fn get(&self, app: &str) -> Option<&AppOptions> {
+        self.apps.get(app)
+    }
+
+    /// The settings of the stream published as `stream_name` to `app`, see
+    /// [`ServerConfig::settings_for`]
+    pub fn settings_for(&self, app: &str, stream_name: &str) -> StreamSettings {
+        self.config.settings_for(app, Some(stream_name))
+    }
+
+    /// Where streams with `record` set are recorded to
+    pub fn record_dir(&self) -> &Path {
+        &self.config.record_dir
+    }
+
+    /// Whether the publisher of a stream that requires authentication is let through, never
+    /// without an [`Authorizer`]
+    pub fn authorize_publish(
+        &self,
+        app: &str,
+        stream_name: &str,
+        query: &HashMap<&str, &str>,
+    ) -> bool {
+        self.authorizer
+            .as_ref()
+            .is_some_and(|authorizer| authorizer.authorize_publish(app, stream_name, query))
+    }
+}
+
+impl From<&ServerConfig> for AppRegistry {
+    /// Register every app in the config, whose streams get the settings the config resolves for
+    /// them. A config that lists no apps serves [`DEFAULT_APP`]
+    fn from(config: &ServerConfig) -> Self {
+        let registry = Self {
+            config: config.clone(),
+            ..Self::new()
+        };
+        if config.apps.is_empty() {
+            return registry.with_app(DEFAULT_APP, AppOptions::default());
+        }
+
+        config.apps.keys().fold(registry, |registry, app| {
+            registry.with_app(app, AppOptions::default())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_registry_from_config() {
+        let config = ServerConfig::parse(
+            r#"{
+                "defaults": { "auth_required": true },
+                "apps": { "live": { "auth_required": false }, "private": { "record": true } },
+                "record_dir": "recordings"
+            }"#,
+        )
+        .unwrap();
+        let registry = AppRegistry::from(&config);
+
+        assert!(!registry.settings_for("live", "mystream").auth_required);
+        assert!(registry.settings_for("private", "mystream").auth_required);
+        assert!(!registry.settings_for("live", "mystream").record);
+        assert!(registry.settings_for("private", "mystream").record);
+        assert_eq!(registry.record_dir(), Path::new("recordings"));
+        assert!(registry.get("live").is_some());
+        assert_eq!(registry.get("vod"), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_stream_settings() {
+        let config = ServerConfig::parse(
+            r#"{
+                "defaults": { "segment_duration_secs": 6 },
+                "apps": { "live": { "streams": { "special": { "max_bitrate_kbps": 8000 } } } }
+            }"#,
+        )
+        .unwrap();
+        let registry = AppRegistry::from(&config);
+
+        let special = registry.settings_for("live", "special");
+        assert_eq!(special.max_bitrate_kbps, Some(8000));
+        assert_eq!(special.segment_duration_secs, 6);
+        assert_eq!(
+            registry.settings_for("live", "other").max_bitrate_kbps,
+            None
+        );
+    }
+
+    #[test]
+    fn test_config_without_apps_serves_default_app() {
+        let registry = AppRegistry::from(&ServerConfig::default());
+        assert_eq!(registry.get(DEFAULT_APP), Some(&AppOptions::default()));
+    }
+}
@@ -1,64 +1,537 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, io};
 
 use bytes::{Bytes, BytesMut};
-use tracing::error;
+use thiserror::Error;
+use tracing::{debug, error};
 
-use crate::chunks::{CSId, Chunk};
+use crate::{
+    chunks::{CSId, Chunk, header::ChunkHeader},
+    messages::command::command_message_type,
+};
 
-#[derive(Debug)]
-struct PartialMessage {
+/// Largest message accepted by default, big enough for high bitrate keyframes
+pub const DEFAULT_MAX_MESSAGE_LENGTH: u32 = 8 * 1024 * 1024;
+
+/// Default ceiling on bytes buffered across all partially received messages
+pub const DEFAULT_MAX_BUFFERED_BYTES: usize = 32 * 1024 * 1024;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ReceiveChunkError {
+    #[error("Message length {length} exceeds the maximum of {max}")]
+    MessageTooLong { length: u32, max: u32 },
+    #[error("Buffering {buffered} bytes of partial messages exceeds the maximum of {max}")]
+    TooManyBufferedBytes { buffered: usize, max: usize },
+    #[error("Message of type {message_type} can't be empty")]
+    EmptyMessage { message_type: u8 },
+    #[error("Chunk takes the message to {received} bytes, past its length of {length}")]
+    PayloadExceedsLength { length: u32, received: usize },
+}
+
+impl From<ReceiveChunkError> for io::Error {
+    fn from(value: ReceiveChunkError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, value)
+    }
+}
+
+/// The message header fields last seen on a chunk stream.
+///
+/// Type 1, 2 and 3 chunks leave out some or all of these, and inherit them from the previous chunk
+/// on the same chunk stream
+#[derive(Debug, Clone, Copy)]
+struct MessageFields {
     length: u32,
     message_type: u8,
     message_stream_id: u32,
-    bytes: BytesMut,
+    timestamp_delta: u32,
+}
+
+#[derive(Debug, Default)]
+struct ChunkStream {
+    previous: Option<MessageFields>,
+    /// Absolute timestamp of the latest message
+    timestamp: u32,
+    /// Whether the previous chunk carried an extended timestamp, in which case Type 3 chunks do too
+    extended_timestamp: bool,
+    /// The payload of the message currently being assembled
+    partial: Option<BytesMut>,
+}
+
+impl ChunkStream {
+    fn resolve(&self, header: &ChunkHeader) -> Option<MessageFields> {
+        Some(MessageFields {
+            length: header
+                .get_message_length()
+                .or(self.previous.map(|fields| fields.length))?,
+            message_type: header
+                .get_message_type()
+                .or(self.previous.map(|fields| fields.message_type))?,
+            message_stream_id: header
+                .get_message_stream_id()
+                .or(self.previous.map(|fields| fields.message_stream_id))?,
+            // a Type 3 chunk after a Type 0 chunk uses the Type 0 timestamp as its delta
+            timestamp_delta: header
+                .get_timestamp_delta()
+                .or(header.get_timestamp())
+                .or(self.previous.map(|fields| fields.timestamp_delta))?,
+        })
+    }
+}
+
+/// A message reassembled from its chunks
+#[derive(Debug, PartialEq)]
+pub struct AssembledMessage {
+    pub payload: Bytes,
+    pub message_type_id: u8,
+    pub message_stream_id: u32,
+    /// Absolute timestamp in milliseconds
+    pub timestamp: u32,
+    /// The chunk stream the message was sent on
+    pub chunk_stream_id: CSId,
 }
 
 /// Receives chunks and multiplexes it to the correct chunk stream
 #[derive(Debug)]
 pub struct ChunkMultiplexer {
-    chunk_streams: HashMap<CSId, PartialMessage>,
+    chunk_streams: HashMap<CSId, ChunkStream>,
+    max_message_length: u32,
+    max_buffered_bytes: usize,
+    /// Bytes held across all partial messages
+    buffered_bytes: usize,
 }
 
 impl ChunkMultiplexer {
-    pub fn receive_chunk(&mut self, chunk: Chunk) -> Option<(Bytes, u8, u32)> {
-        let cs_id = chunk.header.chunk_stream_id();
-        if let Some(partial) = self.chunk_streams.get_mut(&cs_id) {
-            partial.bytes.extend(chunk.payload);
-        } else if let Some(length) = chunk.header.get_message_length()
-            && let Some(message_type) = chunk.header.get_message_type()
-            && let Some(message_stream_id) = chunk.header.get_message_stream_id()
-        {
-            self.chunk_streams.insert(
-                cs_id,
-                PartialMessage {
-                    length,
-                    message_type,
-                    message_stream_id,
-                    bytes: chunk.payload.into(),
-                },
-            );
-        } else {
+    /// Receive a chunk, returning the message it completes if there is one.
+    ///
+    /// Fails if the message is longer than allowed, or buffering the chunk would take the partial
+    /// messages over their limit. The declared length is checked too: commands and media can't
+    /// be empty, and a chunk can't carry more payload than is left in its message
+    pub fn receive_chunk(
+        &mut self,
+        chunk: Chunk,
+    ) -> Result<Option<AssembledMessage>, ReceiveChunkError> {
+        let chunk_stream = self
+            .chunk_streams
+            .entry(chunk.header.chunk_stream_id())
+            .or_default();
+
+        let Some(fields) = chunk_stream.resolve(&chunk.header) else {
             error!("Incomplete message header, dropping chunk");
-            return None;
+            return Ok(None);
+        };
+
+        if chunk_stream.partial.is_none() && fields.length > self.max_message_length {
+            return Err(ReceiveChunkError::MessageTooLong {
+                length: fields.length,
+                max: self.max_message_length,
+            });
+        }
+
+        if fields.length == 0
+            && matches!(
+                fields.message_type,
+                command_message_type::COMMAND_AMF0
+                    | command_message_type::COMMAND_AMF3
+                    | command_message_type::AUDIO
+                    | command_message_type::VIDEO
+            )
+        {
+            return Err(ReceiveChunkError::EmptyMessage {
+                message_type: fields.message_type,
+            });
         }
 
-        if let Some(partial) = self.chunk_streams.get(&cs_id)
-            && partial.length as usize == partial.bytes.len()
-            && let Some(partial) = self.chunk_streams.remove(&cs_id)
+        // a Type 0 or 1 chunk continuing a message can declare a length shorter than what has
+        // already arrived
+        let received =
+            chunk_stream.partial.as_ref().map_or(0, |bytes| bytes.len()) + chunk.payload.len();
+        if received > fields.length as usize {
+            return Err(ReceiveChunkError::PayloadExceedsLength {
+                length: fields.length,
+                received,
+            });
+        }
+
+        let buffered = self.buffered_bytes + chunk.payload.len();
+        if buffered > self.max_buffered_bytes {
+            return Err(ReceiveChunkError::TooManyBufferedBytes {
+                buffered,
+                max: self.max_buffered_bytes,
+            });
+        }
+
+        chunk_stream.previous = Some(fields);
+        chunk_stream.extended_timestamp = chunk.header.extended_timestamp().is_some();
+
+        // the chunks of a message all share its timestamp
+        if chunk_stream.partial.is_none() {
+            chunk_stream.timestamp = match chunk.header.get_timestamp() {
+                Some(timestamp) => timestamp,
+                None => chunk_stream.timestamp.wrapping_add(fields.timestamp_delta),
+            };
+        }
+
+        let bytes = chunk_stream.partial.get_or_insert_with(BytesMut::new);
+        bytes.extend(chunk.payload);
+        self.buffered_bytes = buffered;
+
+        if bytes.len() >= fields.length as usize
+            && let Some(bytes) = chunk_stream.partial.take()
         {
-            Some((
-                partial.bytes.into(),
-                partial.message_type,
-                partial.message_stream_id,
-            ))
+            self.buffered_bytes -= bytes.len();
+            Ok(Some(AssembledMessage {
+                payload: bytes.into(),
+                message_type_id: fields.message_type,
+                message_stream_id: fields.message_stream_id,
+                timestamp: chunk_stream.timestamp,
+                chunk_stream_id: chunk.header.chunk_stream_id(),
+            }))
         } else {
-            None
+            Ok(None)
         }
     }
 
+    /// Number of payload bytes left in the message that the chunk with this header belongs to.
+    ///
+    /// Never more than the longest message allowed, so a chunk declaring a huge message can't make
+    /// the reader allocate for it before [`Self::receive_chunk`] rejects it
+    pub fn message_bytes_remaining(&self, header: &ChunkHeader) -> usize {
+        let remaining = match self.chunk_streams.get(&header.chunk_stream_id()) {
+            Some(chunk_stream) => {
+                let length = chunk_stream
+                    .resolve(header)
+                    .map(|fields| fields.length)
+                    .unwrap_or(0) as usize;
+                let received = chunk_stream.partial.as_ref().map_or(0, |bytes| bytes.len());
+                length.saturating_sub(received)
+            }
+            None => header.get_message_length().unwrap_or(0) as usize,
+        };
+        remaining.min(self.max_message_length as usize)
+    }
+
+    /// Discard the partially received message on a chunk stream
+    pub fn abort(&mut self, cs_id: CSId) {
+        if let Some(chunk_stream) = self.chunk_streams.get_mut(&cs_id)
+            && let Some(partial) = chunk_stream.partial.take()
+        {
+            self.buffered_bytes -= partial.len();
+            debug!(
+                "Aborted message on chunk stream {cs_id} after {} bytes",
+                partial.len()
+            );
+        }
+    }
+
+    /// Whether a Type 3 chunk on this chunk stream will be followed by an extended timestamp
+    pub fn uses_extended_timestamp(&self, cs_id: CSId) -> bool {
+        self.chunk_streams
+            .get(&cs_id)
+            .is_some_and(|chunk_stream| chunk_stream.extended_timestamp)
+    }
+
+    /// Bytes held across all partial messages
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
     pub fn new() -> Self {
+        Self::with_limits(DEFAULT_MAX_MESSAGE_LENGTH, DEFAULT_MAX_BUFFERED_BYTES)
+    }
+
+    /// Create a multiplexer that rejects messages longer than `max_message_length`, and stops
+    /// buffering partial messages past `max_buffered_bytes` in total
+    pub fn with_limits(max_message_length: u32, max_buffered_bytes: usize) -> Self {
         Self {
             chunk_streams: HashMap::new(),
+            max_message_length,
+            max_buffered_bytes,
+            buffered_bytes: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(
+        chunk_stream_id: CSId,
+        payload: &'static [u8],
+        message_type_id: u8,
+        message_stream_id: u32,
+        timestamp: u32,
+    ) -> Option<AssembledMessage> {
+        Some(AssembledMessage {
+            payload: Bytes::from_static(payload),
+            message_type_id,
+            message_stream_id,
+            timestamp,
+            chunk_stream_id,
+        })
+    }
+
+    #[test]
+    fn test_multi_chunk_message_keeps_stream_id() {
+        let mut mux = ChunkMultiplexer::new();
+        let payload = Bytes::from_static(b"abcdefghij");
+
+        let first = Chunk {
+            header: ChunkHeader::new_type0(4, 0, payload.len() as u32, 9, 7),
+            payload: payload.slice(..4),
+        };
+        let second = Chunk {
+            header: ChunkHeader::new_type3(4),
+            payload: payload.slice(4..8),
+        };
+        let last = Chunk {
+            header: ChunkHeader::new_type3(4),
+            payload: payload.slice(8..),
+        };
+
+        assert_eq!(mux.receive_chunk(first).unwrap(), None);
+        assert_eq!(mux.receive_chunk(second).unwrap(), None);
+        assert_eq!(
+            mux.receive_chunk(last).unwrap(),
+            message(4, b"abcdefghij", 9, 7, 0)
+        );
+    }
+
+    #[test]
+    fn test_type3_inherits_previous_header() {
+        let mut mux = ChunkMultiplexer::new();
+
+        let first = Chunk {
+            header: ChunkHeader::new_type0(6, 0, 3, 8, 1),
+            payload: Bytes::from_static(b"abc"),
+        };
+        assert_eq!(
+            mux.receive_chunk(first).unwrap(),
+            message(6, b"abc", 8, 1, 0)
+        );
+
+        // a whole new message with the same length, type and stream id
+        let next = ChunkHeader::new_type3(6);
+        assert_eq!(mux.message_bytes_remaining(&next), 3);
+        let second = Chunk {
+            header: next,
+            payload: Bytes::from_static(b"def"),
+        };
+        assert_eq!(
+            mux.receive_chunk(second).unwrap(),
+            message(6, b"def", 8, 1, 0)
+        );
+    }
+
+    #[test]
+    fn test_type3_without_previous_header() {
+        let mut mux = ChunkMultiplexer::new();
+        let chunk = Chunk {
+            header: ChunkHeader::new_type3(6),
+            payload: Bytes::from_static(b"abc"),
+        };
+
+        assert_eq!(mux.receive_chunk(chunk).unwrap(), None);
+    }
+
+    #[test]
+    fn test_timestamp_deltas_accumulate() {
+        let mut mux = ChunkMultiplexer::new();
+        let headers = [
+            ChunkHeader::new_type0(5, 1000, 1, 9, 1),
+            ChunkHeader::new_type2(5, 40),
+            ChunkHeader::new_type3(5),
+            ChunkHeader::new_type2(5, 20),
+        ];
+
+        let timestamps: Vec<_> = headers
+            .into_iter()
+            .filter_map(|header| {
+                mux.receive_chunk(Chunk {
+                    header,
+                    payload: Bytes::from_static(b"x"),
+                })
+                .unwrap()
+            })
+            .map(|message| message.timestamp)
+            .collect();
+
+        assert_eq!(timestamps, [1000, 1040, 1080, 1100]);
+    }
+
+    #[test]
+    fn test_type3_after_type0_uses_timestamp_as_delta() {
+        let mut mux = ChunkMultiplexer::new();
+        let first = Chunk {
+            header: ChunkHeader::new_type0(5, 500, 1, 9, 1),
+            payload: Bytes::from_static(b"x"),
+        };
+        let second = Chunk {
+            header: ChunkHeader::new_type3(5),
+            payload: Bytes::from_static(b"y"),
+        };
+
+        assert_eq!(
+            mux.receive_chunk(first).unwrap(),
+            message(5, b"x", 9, 1, 500)
+        );
+        assert_eq!(
+            mux.receive_chunk(second).unwrap(),
+            message(5, b"y", 9, 1, 1000)
+        );
+    }
+
+    #[test]
+    fn test_continuation_chunks_share_timestamp() {
+        let mut mux = ChunkMultiplexer::new();
+        let first = Chunk {
+            header: ChunkHeader::new_type0(5, 500, 2, 9, 1),
+            payload: Bytes::from_static(b"x"),
+        };
+        let second = Chunk {
+            header: ChunkHeader::new_type3(5),
+            payload: Bytes::from_static(b"y"),
+        };
+
+        assert_eq!(mux.receive_chunk(first).unwrap(), None);
+        assert_eq!(
+            mux.receive_chunk(second).unwrap(),
+            message(5, b"xy", 9, 1, 500)
+        );
+    }
+
+    #[test]
+    fn test_abort_discards_partial_message() {
+        let mut mux = ChunkMultiplexer::new();
+        let first = Chunk {
+            header: ChunkHeader::new_type0(4, 0, 6, 9, 1),
+            payload: Bytes::from_static(b"abc"),
+        };
+        assert_eq!(mux.receive_chunk(first).unwrap(), None);
+
+        mux.abort(4);
+
+        let restarted = ChunkHeader::new_type0(4, 0, 6, 9, 1);
+        assert_eq!(mux.message_bytes_remaining(&restarted), 6);
+        let restarted = Chunk {
+            header: restarted,
+            payload: Bytes::from_static(b"uvwxyz"),
+        };
+        assert_eq!(
+            mux.receive_chunk(restarted).unwrap(),
+            message(4, b"uvwxyz", 9, 1, 0)
+        );
+    }
+
+    #[test]
+    fn test_empty_media_and_commands_are_rejected() {
+        let mut mux = ChunkMultiplexer::new();
+
+        for message_type in [
+            command_message_type::COMMAND_AMF0,
+            command_message_type::AUDIO,
+            command_message_type::VIDEO,
+        ] {
+            let chunk = Chunk {
+                header: ChunkHeader::new_type0(4, 0, 0, message_type, 1),
+                payload: Bytes::new(),
+            };
+            assert_eq!(
+                mux.receive_chunk(chunk),
+                Err(ReceiveChunkError::EmptyMessage { message_type })
+            );
+        }
+    }
+
+    #[test]
+    fn test_payload_past_message_length_is_rejected() {
+        let mut mux = ChunkMultiplexer::new();
+
+        let chunk = Chunk {
+            header: ChunkHeader::new_type0(4, 0, 4, 9, 1),
+            payload: Bytes::from_static(b"abcdefgh"),
+        };
+        assert_eq!(
+            mux.receive_chunk(chunk),
+            Err(ReceiveChunkError::PayloadExceedsLength {
+                length: 4,
+                received: 8,
+            })
+        );
+
+        // a new header midway through a message shrinking it below what has arrived
+        let first = Chunk {
+            header: ChunkHeader::new_type0(6, 0, 10, 9, 1),
+            payload: Bytes::from_static(b"abcdef"),
+        };
+        assert_eq!(mux.receive_chunk(first), Ok(None));
+        let second = Chunk {
+            header: ChunkHeader::new_type0(6, 0, 4, 9, 1),
+            payload: Bytes::from_static(b"gh"),
+        };
+        assert_eq!(
+            mux.receive_chunk(second),
+            Err(ReceiveChunkError::PayloadExceedsLength {
+                length: 4,
+                received: 8,
+            })
+        );
+        assert_eq!(mux.message_bytes_remaining(&ChunkHeader::new_type3(6)), 4);
+    }
+
+    #[test]
+    fn test_absurd_message_length_is_rejected() {
+        let mut mux = ChunkMultiplexer::new();
+        let chunk = Chunk {
+            header: ChunkHeader::new_type0(4, 0, 0xFFFFFF, 9, 1),
+            payload: Bytes::from_static(b"abc"),
+        };
+
+        // the reader never sizes a chunk's payload past the limit
+        assert_eq!(
+            mux.message_bytes_remaining(&chunk.header),
+            DEFAULT_MAX_MESSAGE_LENGTH as usize
+        );
+        assert_eq!(
+            mux.receive_chunk(chunk),
+            Err(ReceiveChunkError::MessageTooLong {
+                length: 0xFFFFFF,
+                max: DEFAULT_MAX_MESSAGE_LENGTH
+            })
+        );
+    }
+
+    #[test]
+    fn test_buffered_bytes_are_capped_across_chunk_streams() {
+        let mut mux = ChunkMultiplexer::with_limits(100, 8);
+        let partial = |cs_id| Chunk {
+            header: ChunkHeader::new_type0(cs_id, 0, 100, 9, 1),
+            payload: Bytes::from_static(b"abcd"),
+        };
+
+        assert_eq!(mux.receive_chunk(partial(4)), Ok(None));
+        assert_eq!(mux.receive_chunk(partial(5)), Ok(None));
+        assert_eq!(
+            mux.receive_chunk(partial(6)),
+            Err(ReceiveChunkError::TooManyBufferedBytes {
+                buffered: 12,
+                max: 8
+            })
+        );
+
+        // aborting frees up space again
+        mux.abort(4);
+        assert_eq!(mux.receive_chunk(partial(6)), Ok(None));
+    }
+
+    #[test]
+    fn test_completed_messages_free_buffered_bytes() {
+        let mut mux = ChunkMultiplexer::with_limits(100, 8);
+        for _ in 0..10 {
+            let chunk = Chunk {
+                header: ChunkHeader::new_type0(4, 0, 4, 9, 1),
+                payload: Bytes::from_static(b"abcd"),
+            };
+            assert!(mux.receive_chunk(chunk).unwrap().is_some());
         }
     }
 }
@@ -0,0 +1,239 @@
+use std::io;
+
+use bytes::{BufMut, BytesMut};
+use thiserror::Error;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::chunks::{CSId, DEFAULT_CHUNK_SIZE};
+
+/// Largest value that fits in a 3 byte header field
+const MAX_U24: u32 = 0xFFFFFF;
+
+#[derive(Error, Debug)]
+pub enum WriteChunkError {
+    #[error("Chunk stream id {0} cannot be encoded")]
+    InvalidChunkStreamId(CSId),
+    #[error("Message of {0} bytes is too long to fit in a chunk header")]
+    MessageTooLong(usize),
+    #[error("Failed to write chunk")]
+    WriteError(
+        #[source]
+        #[from]
+        io::Error,
+    ),
+}
+
+impl From<WriteChunkError> for io::Error {
+    fn from(value: WriteChunkError) -> Self {
+        match value {
+            WriteChunkError::InvalidChunkStreamId(_) | WriteChunkError::MessageTooLong(_) => {
+                io::Error::new(io::ErrorKind::InvalidInput, value)
+            }
+            WriteChunkError::WriteError(ref error) => io::Error::new(error.kind(), value),
+        }
+    }
+}
+
+/// Splits outgoing messages into chunks
+#[derive(Debug)]
+pub struct ChunkWriter {
+    chunk_size: usize,
+}
+
+impl ChunkWriter {
+    pub fn new() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    /// Use `chunk_size` for every message written after this.
+    ///
+    /// The peer has to be told about it with a SetChunkSize message first
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size.max(1);
+    }
+
+    /// Write a message as a Type 0 chunk, followed by as many Type 3 chunks as needed,
+    /// returning how many bytes were written
+    pub async fn write_message<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        cs_id: CSId,
+        message_type_id: u8,
+        message_stream_id: u32,
+        timestamp: u32,
+        payload: &[u8],
+    ) -> Result<usize, WriteChunkError> {
+        let message_length: u32 = payload
+            .len()
+            .try_into()
+            .ok()
+            .filter(|length| *length <= MAX_U24)
+            .ok_or(WriteChunkError::MessageTooLong(payload.len()))?;
+        let extended_timestamp = timestamp >= MAX_U24;
+
+        let mut buf = BytesMut::with_capacity(payload.len() + 18);
+        for (i, chunk_payload) in payload
+            .chunks(self.chunk_size)
+            .chain(payload.is_empty().then_some(payload))
+            .enumerate()
+        {
+            if i == 0 {
+                put_basic_header(&mut buf, 0, cs_id)?;
+                put_u24(&mut buf, timestamp.min(MAX_U24));
+                put_u24(&mut buf, message_length);
+                buf.put_u8(message_type_id);
+                buf.put_u32_le(message_stream_id);
+            } else {
+                put_basic_header(&mut buf, 3, cs_id)?;
+            }
+
+            // Type 3 chunks repeat the extended timestamp of the chunk they continue
+            if extended_timestamp {
+                buf.put_u32(timestamp);
+            }
+
+            buf.put_slice(chunk_payload);
+        }
+
+        writer.write_all(&buf).await?;
+        Ok(buf.len())
+    }
+}
+
+fn put_u24(buf: &mut BytesMut, value: u32) {
+    buf.put_slice(&value.to_be_bytes()[1..]);
+}
+
+fn put_basic_header(
+    buf: &mut BytesMut,
+    chunk_type: u8,
+    cs_id: CSId,
+) -> Result<(), WriteChunkError> {
+    let chunk_type = chunk_type << 6;
+    match cs_id {
+        2..=63 => buf.put_u8(chunk_type | cs_id as u8),
+        64..=319 => {
+            buf.put_u8(chunk_type);
+            buf.put_u8((cs_id - 64) as u8);
+        }
+        320..=65599 => {
+            buf.put_u8(chunk_type | 1);
+            buf.put_u16_le((cs_id - 64) as u16);
+        }
+        _ => return Err(WriteChunkError::InvalidChunkStreamId(cs_id)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::{
+        io::BufReader,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::chunks::{Chunk, chunk_mux::ChunkMultiplexer};
+
+    async fn setup(bytes: &[u8]) -> TcpStream {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let (stream, _) = server.accept().await.unwrap();
+        client.write_all(bytes).await.unwrap();
+
+        stream
+    }
+
+    async fn round_trip(
+        chunk_size: usize,
+        cs_id: CSId,
+        timestamp: u32,
+        payload: &[u8],
+    ) -> (Vec<usize>, Bytes, u32) {
+        let mut chunk_writer = ChunkWriter::new();
+        chunk_writer.set_chunk_size(chunk_size);
+        let mut bytes = vec![];
+        chunk_writer
+            .write_message(&mut bytes, cs_id, 9, 1, timestamp, payload)
+            .await
+            .unwrap();
+
+        let mut stream = setup(&bytes).await;
+        let mut reader = BufReader::new(&mut stream);
+        let mut chunk_mux = ChunkMultiplexer::new();
+        let mut chunk_sizes = vec![];
+        loop {
+            let chunk = Chunk::read_chunk(&mut reader, &chunk_size, &chunk_mux)
+                .await
+                .unwrap();
+            assert_eq!(chunk.header.chunk_stream_id(), cs_id);
+            chunk_sizes.push(chunk.payload.len());
+
+            if let Some(message) = chunk_mux.receive_chunk(chunk).unwrap() {
+                assert_eq!(message.message_type_id, 9);
+                assert_eq!(message.message_stream_id, 1);
+                return (chunk_sizes, message.payload, message.timestamp);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_large_message() {
+        let mut payload = vec![0; 10_000];
+        rand::fill(&mut payload[..]);
+
+        let (chunk_sizes, received, timestamp) = round_trip(4096, 3, 1234, &payload).await;
+
+        assert_eq!(chunk_sizes, [4096, 4096, 1808]);
+        assert_eq!(received, payload);
+        assert_eq!(timestamp, 1234);
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_basic_header_forms() {
+        for cs_id in [2, 63, 64, 319, 320, 65599] {
+            let (_, received, _) = round_trip(128, cs_id, 0, b"hello").await;
+            assert_eq!(received, b"hello".as_slice());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_extended_timestamp() {
+        let payload = [7; 300];
+        let (chunk_sizes, received, timestamp) = round_trip(128, 4, 0x01234567, &payload).await;
+
+        assert_eq!(chunk_sizes, [128, 128, 44]);
+        assert_eq!(received, payload.as_slice());
+        assert_eq!(timestamp, 0x01234567);
+    }
+
+    #[test]
+    fn test_basic_header_bytes() {
+        let cases: [(CSId, &[u8]); 3] = [(3, &[0x03]), (100, &[0x00, 36]), (400, &[0x01, 80, 1])];
+        for (cs_id, expected) in cases {
+            let mut buf = BytesMut::new();
+            put_basic_header(&mut buf, 0, cs_id).unwrap();
+            assert_eq!(&buf[..], expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_chunk_stream_id() {
+        let mut bytes = vec![];
+        let result = ChunkWriter::new()
+            .write_message(&mut bytes, 1, 9, 1, 0, b"hello")
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(WriteChunkError::InvalidChunkStreamId(1))
+        ));
+        assert!(bytes.is_empty());
+    }
+}
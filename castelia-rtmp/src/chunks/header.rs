@@ -1,10 +1,7 @@
 use std::io;
 
 use thiserror::Error;
-use tokio::{
-    io::{AsyncReadExt, BufReader},
-    net::TcpStream,
-};
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::trace;
 
 use crate::chunks::CSId;
@@ -113,8 +110,8 @@ impl MessageHeader {
             }
     }
 
-    async fn parse_type0(
-        reader: &mut BufReader<&mut TcpStream>,
+    async fn parse_type0<R: AsyncRead + Unpin>(
+        reader: &mut R,
     ) -> Result<Self, ParseChunkHeaderError> {
         let timestamp = read_3_be_bytes_to_u32(reader).await?;
         let message_length = read_3_be_bytes_to_u32(reader).await?;
@@ -129,8 +126,8 @@ impl MessageHeader {
         })
     }
 
-    async fn parse_type1(
-        reader: &mut BufReader<&mut TcpStream>,
+    async fn parse_type1<R: AsyncRead + Unpin>(
+        reader: &mut R,
     ) -> Result<Self, ParseChunkHeaderError> {
         let timestamp_delta = read_3_be_bytes_to_u32(reader).await?;
         let message_length = read_3_be_bytes_to_u32(reader).await?;
@@ -141,8 +138,8 @@ impl MessageHeader {
             message_type_id,
         })
     }
-    async fn parse_type2(
-        reader: &mut BufReader<&mut TcpStream>,
+    async fn parse_type2<R: AsyncRead + Unpin>(
+        reader: &mut R,
     ) -> Result<Self, ParseChunkHeaderError> {
         Ok(Self::Type2 {
             timestamp_delta: read_3_be_bytes_to_u32(reader).await?,
@@ -152,8 +149,8 @@ impl MessageHeader {
         Ok(Self::Type3)
     }
 
-    async fn parse(
-        reader: &mut BufReader<&mut TcpStream>,
+    async fn parse<R: AsyncRead + Unpin>(
+        reader: &mut R,
         chunk_type: &u8,
     ) -> Result<Self, ParseChunkHeaderError> {
         trace!("parsing chunk message header");
@@ -167,8 +164,8 @@ impl MessageHeader {
     }
 }
 
-pub async fn read_3_be_bytes_to_u32(
-    reader: &mut BufReader<&mut TcpStream>,
+pub async fn read_3_be_bytes_to_u32<R: AsyncRead + Unpin>(
+    reader: &mut R,
 ) -> Result<u32, io::Error> {
     Ok(u32::from_be_bytes([
         0x00,
@@ -194,7 +191,7 @@ impl BasicHeader {
         self.chunk_stream_id
     }
 
-    async fn parse(reader: &mut BufReader<&mut TcpStream>) -> Result<Self, ParseChunkHeaderError> {
+    async fn parse<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, ParseChunkHeaderError> {
         trace!("parsing chunk basic header");
         let byte1 = reader.read_u8().await?;
 
@@ -210,7 +207,7 @@ impl BasicHeader {
             1 => {
                 let byte2 = reader.read_u8().await?;
                 let byte3 = reader.read_u8().await?;
-                (((byte3 as u16) << 8) + (byte2 as u16 + 64)).into()
+                ((byte3 as u32) << 8) + byte2 as u32 + 64
             }
             _ => header_type.into(),
         };
@@ -249,13 +246,48 @@ impl ChunkHeader {
         self.message_header.get_message_stream_id()
     }
 
-    pub async fn read_header(
-        reader: &mut BufReader<&mut TcpStream>,
+    pub fn extended_timestamp(&self) -> Option<u32> {
+        self.extended_timestamp
+    }
+
+    /// The absolute timestamp of a Type 0 header
+    pub fn get_timestamp(&self) -> Option<u32> {
+        match self.message_header {
+            MessageHeader::Type0 { timestamp, .. } => {
+                Some(self.extended_timestamp.unwrap_or(timestamp))
+            }
+            _ => None,
+        }
+    }
+
+    /// The timestamp delta of a Type 1 or Type 2 header
+    pub fn get_timestamp_delta(&self) -> Option<u32> {
+        match self.message_header {
+            MessageHeader::Type1 {
+                timestamp_delta, ..
+            }
+            | MessageHeader::Type2 { timestamp_delta } => {
+                Some(self.extended_timestamp.unwrap_or(timestamp_delta))
+            }
+            _ => None,
+        }
+    }
+
+    /// Read a chunk header from the stream.
+    ///
+    /// Type 3 headers carry an extended timestamp if the previous chunk on their chunk stream did,
+    /// which `uses_extended_timestamp` reports
+    pub async fn read_header<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        uses_extended_timestamp: impl FnOnce(CSId) -> bool,
     ) -> Result<Self, ParseChunkHeaderError> {
         trace!("reading chunk header");
         let basic_header = BasicHeader::parse(reader).await?;
         let message_header = MessageHeader::parse(reader, &basic_header.chunk_type()).await?;
-        let extended_timestamp = if message_header.has_extended_timestamp() {
+        let extended_timestamp = if message_header.has_extended_timestamp()
+            || (message_header == MessageHeader::Type3
+                && uses_extended_timestamp(basic_header.chunk_stream_id()))
+        {
             trace!("reading chunk extended timestamp");
             Some(reader.read_u32().await?)
         } else {
@@ -279,19 +311,68 @@ impl ChunkHeader {
     }
 }
 
+#[cfg(test)]
+impl BasicHeader {
+    fn for_chunk_stream(chunk_type: u8, chunk_stream_id: CSId) -> Self {
+        Self {
+            chunk_type,
+            chunk_stream_id,
+            header_type: match chunk_stream_id {
+                2..=63 => chunk_stream_id as u8,
+                64..=319 => 0,
+                _ => 1,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+impl ChunkHeader {
+    pub fn new_type0(
+        chunk_stream_id: CSId,
+        timestamp: u32,
+        message_length: u32,
+        message_type_id: u8,
+        message_stream_id: u32,
+    ) -> Self {
+        Self {
+            basic_header: BasicHeader::for_chunk_stream(0, chunk_stream_id),
+            message_header: MessageHeader::Type0 {
+                timestamp,
+                message_length,
+                message_type_id,
+                message_stream_id,
+            },
+            extended_timestamp: None,
+        }
+    }
+
+    pub fn new_type2(chunk_stream_id: CSId, timestamp_delta: u32) -> Self {
+        Self {
+            basic_header: BasicHeader::for_chunk_stream(2, chunk_stream_id),
+            message_header: MessageHeader::Type2 { timestamp_delta },
+            extended_timestamp: None,
+        }
+    }
+
+    pub fn new_type3(chunk_stream_id: CSId) -> Self {
+        Self {
+            basic_header: BasicHeader::for_chunk_stream(3, chunk_stream_id),
+            message_header: MessageHeader::Type3,
+            extended_timestamp: None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use tokio::{io::AsyncWriteExt, net::TcpListener};
+    use tokio::io::{AsyncWriteExt, BufReader, DuplexStream, duplex};
 
     use super::*;
 
-    async fn setup(bytes: &[u8]) -> TcpStream {
-        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let mut client = TcpStream::connect(server.local_addr().unwrap())
-            .await
-            .unwrap();
-
-        let (stream, _) = server.accept().await.unwrap();
+    /// A stream that yields `bytes` and then EOF
+    async fn setup(bytes: &[u8]) -> DuplexStream {
+        let (mut client, stream) = duplex(bytes.len().max(1));
         client.write_all(bytes).await.unwrap();
 
         stream
@@ -436,4 +517,89 @@ mod tests {
         );
         assert!(!header.has_extended_timestamp());
     }
+
+    #[tokio::test]
+    async fn test_header_getters_type0() {
+        let bytes = [
+            0x03, // type 0, chunk stream 3
+            0x00, 0x00, 0x10, // timestamp
+            0x00, 0x01, 0x00, // length
+            0x14, // message type id
+            0x01, 0x00, 0x00, 0x00, // message stream id
+        ];
+        let mut stream = setup(&bytes).await;
+        let mut reader = BufReader::new(&mut stream);
+        let header = ChunkHeader::read_header(&mut reader, |_| false)
+            .await
+            .expect("should return header");
+
+        assert_eq!(header.len(), 12);
+        assert_eq!(header.chunk_stream_id(), 3);
+        assert_eq!(header.get_message_length(), Some(0x100));
+        assert_eq!(header.get_message_type(), Some(0x14));
+        assert_eq!(header.get_message_stream_id(), Some(1));
+        assert_eq!(header.get_timestamp(), Some(0x10));
+        assert_eq!(header.get_timestamp_delta(), None);
+    }
+
+    #[tokio::test]
+    async fn test_header_getters_type1() {
+        let bytes = [
+            0x40, 0x0A, // type 1, chunk stream 74
+            0x00, 0x00, 0x21, // delta
+            0x00, 0x00, 0x20, // length
+            0x09, // message type id
+        ];
+        let mut stream = setup(&bytes).await;
+        let mut reader = BufReader::new(&mut stream);
+        let header = ChunkHeader::read_header(&mut reader, |_| false)
+            .await
+            .expect("should return header");
+
+        assert_eq!(header.len(), 9);
+        assert_eq!(header.chunk_stream_id(), 74);
+        assert_eq!(header.get_message_length(), Some(0x20));
+        assert_eq!(header.get_message_type(), Some(0x09));
+        assert_eq!(header.get_message_stream_id(), None);
+        assert_eq!(header.get_timestamp_delta(), Some(0x21));
+    }
+
+    #[tokio::test]
+    async fn test_header_getters_type2() {
+        let bytes = [
+            0x84, // type 2, chunk stream 4
+            0xFF, 0xFF, 0xFF, // delta, look at the extended timestamp
+            0x01, 0x00, 0x00, 0x00, // extended timestamp
+        ];
+        let mut stream = setup(&bytes).await;
+        let mut reader = BufReader::new(&mut stream);
+        let header = ChunkHeader::read_header(&mut reader, |_| false)
+            .await
+            .expect("should return header");
+
+        assert_eq!(header.len(), 8);
+        assert_eq!(header.chunk_stream_id(), 4);
+        assert_eq!(header.get_message_length(), None);
+        assert_eq!(header.get_message_type(), None);
+        assert_eq!(header.get_message_stream_id(), None);
+        assert_eq!(header.get_timestamp_delta(), Some(0x01000000));
+    }
+
+    #[tokio::test]
+    async fn test_header_getters_type3() {
+        let bytes = [0xC1, 0x2d, 0x01]; // type 3, chunk stream 365
+        let mut stream = setup(&bytes).await;
+        let mut reader = BufReader::new(&mut stream);
+        let header = ChunkHeader::read_header(&mut reader, |_| false)
+            .await
+            .expect("should return header");
+
+        assert_eq!(header.len(), 3);
+        assert_eq!(header.chunk_stream_id(), 365);
+        assert_eq!(header.get_message_length(), None);
+        assert_eq!(header.get_message_type(), None);
+        assert_eq!(header.get_message_stream_id(), None);
+        assert_eq!(header.get_timestamp(), None);
+        assert_eq!(header.get_timestamp_delta(), None);
+    }
 }
@@ -1,21 +1,27 @@
-use std::{io, time::Duration};
+use std::io;
 
 use bytes::{Bytes, BytesMut};
 use thiserror::Error;
-use tokio::{
-    io::{AsyncReadExt, BufReader},
-    net::TcpStream,
-    time::timeout,
-};
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::{debug, trace};
 
-use crate::chunks::header::{ChunkHeader, ParseChunkHeaderError};
+use crate::chunks::{
+    chunk_mux::ChunkMultiplexer,
+    header::{ChunkHeader, ParseChunkHeaderError},
+};
 
 pub mod chunk_mux;
+pub mod chunk_writer;
 mod header;
 
 type CSId = u32;
 
+/// The chunk size every RTMP connection starts with, until a SetChunkSize message changes it
+pub const DEFAULT_CHUNK_SIZE: usize = 128;
+
+/// The largest chunk size a SetChunkSize message can set, the top bit must be zero
+pub const MAX_CHUNK_SIZE: u32 = 0x7FFFFFFF;
+
 pub struct Chunk {
     pub header: ChunkHeader,
     pub payload: Bytes,
@@ -39,6 +45,18 @@ pub enum ParseChunkError {
     MessageReadFailure(#[from] tokio::io::Error),
 }
 
+impl ParseChunkError {
+    /// The socket error behind this, if reading the chunk failed rather than parsing it
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match self {
+            ParseChunkError::BadHeader(ParseChunkHeaderError::ReadError(error))
+            | ParseChunkError::MessageReadFailure(error) => Some(error),
+            ParseChunkError::BadHeader(ParseChunkHeaderError::InvalidChunkType(_))
+            | ParseChunkError::Timeout(_) => None,
+        }
+    }
+}
+
 impl From<ParseChunkError> for io::Error {
     fn from(value: ParseChunkError) -> Self {
         match value {
@@ -58,16 +76,20 @@ impl From<ParseChunkError> for io::Error {
 
 impl Chunk {
     /// Read a Chunk from the stream
-    pub async fn read_chunk(
-        reader: &mut BufReader<&mut TcpStream>,
+    ///
+    /// The payload is at most `max_chunk_size` bytes, and stops early at the end of the message
+    /// that `chunk_mux` is assembling on the chunk's stream
+    pub async fn read_chunk<R: AsyncRead + Unpin>(
+        reader: &mut R,
         max_chunk_size: &usize,
+        chunk_mux: &ChunkMultiplexer,
     ) -> Result<Self, ParseChunkError> {
-        let header = timeout(Duration::from_secs(30), ChunkHeader::read_header(reader)).await??;
+        let header =
+            ChunkHeader::read_header(reader, |cs_id| chunk_mux.uses_extended_timestamp(cs_id))
+                .await?;
         debug!("chunk header has been parsed:\n{:#?}", header);
 
-        let max_bytes_remaining = max_chunk_size - header.len();
-        let payload_size =
-            max_bytes_remaining.min(header.get_message_length().unwrap_or(0) as usize);
+        let payload_size = (*max_chunk_size).min(chunk_mux.message_bytes_remaining(&header));
 
         let mut payload = BytesMut::zeroed(payload_size);
         reader.read_exact(&mut payload).await?;
@@ -79,3 +101,95 @@ impl Chunk {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncWriteExt, BufReader, DuplexStream, duplex};
+
+    use super::*;
+
+    /// A stream that yields `bytes` and then EOF
+    async fn setup(bytes: &[u8]) -> DuplexStream {
+        let (mut client, stream) = duplex(bytes.len().max(1));
+        client.write_all(bytes).await.unwrap();
+
+        stream
+    }
+
+    #[tokio::test]
+    async fn test_message_split_across_three_chunks() {
+        let payload = b"abcdefghij";
+        let bytes = [
+            &[
+                0x03, // type 0, chunk stream 3
+                0x00, 0x00, 0x00, // timestamp
+                0x00, 0x00, 0x0A, // length
+                0x09, // message type id
+                0x01, 0x00, 0x00, 0x00, // message stream id
+            ],
+            &payload[..4],
+            &[0xC3], // type 3, chunk stream 3
+            &payload[4..8],
+            &[0xC3],
+            &payload[8..],
+        ]
+        .concat();
+        let mut stream = setup(&bytes).await;
+        let mut reader = BufReader::new(&mut stream);
+        let mut chunk_mux = ChunkMultiplexer::new();
+
+        let mut sizes = vec![];
+        let mut message = None;
+        for _ in 0..3 {
+            let chunk = Chunk::read_chunk(&mut reader, &4, &chunk_mux)
+                .await
+                .unwrap();
+            sizes.push(chunk.payload.len());
+            message = chunk_mux.receive_chunk(chunk).unwrap();
+        }
+
+        assert_eq!(sizes, [4, 4, 2]);
+        let message = message.unwrap();
+        assert_eq!(message.payload, Bytes::from_static(payload));
+        assert_eq!(message.message_type_id, 9);
+        assert_eq!(message.message_stream_id, 1);
+    }
+
+    #[tokio::test]
+    async fn test_type3_extended_timestamp() {
+        let bytes = [
+            &[
+                0x04, // type 0, chunk stream 4
+                0xFF, 0xFF, 0xFF, // timestamp, look at the extended timestamp
+                0x00, 0x00, 0x06, // length
+                0x08, // message type id
+                0x01, 0x00, 0x00, 0x00, // message stream id
+                0x01, 0x00, 0x00, 0x00, // extended timestamp
+            ],
+            b"abc".as_slice(),
+            &[
+                0xC4, // type 3, chunk stream 4
+                0x01, 0x00, 0x00, 0x00, // extended timestamp
+            ],
+            b"def",
+        ]
+        .concat();
+        let mut stream = setup(&bytes).await;
+        let mut reader = BufReader::new(&mut stream);
+        let mut chunk_mux = ChunkMultiplexer::new();
+
+        let first = Chunk::read_chunk(&mut reader, &3, &chunk_mux)
+            .await
+            .unwrap();
+        assert_eq!(first.header.extended_timestamp(), Some(0x01000000));
+        assert_eq!(chunk_mux.receive_chunk(first).unwrap(), None);
+
+        let second = Chunk::read_chunk(&mut reader, &3, &chunk_mux)
+            .await
+            .unwrap();
+        assert_eq!(second.header.extended_timestamp(), Some(0x01000000));
+        let message = chunk_mux.receive_chunk(second).unwrap().unwrap();
+        assert_eq!(message.payload, Bytes::from_static(b"abcdef"));
+        assert_eq!(message.timestamp, 0x01000000);
+    }
+}
@@ -0,0 +1,48 @@
+use std::time::SystemTime;
+
+#[cfg(test)]
+use std::{sync::Mutex, time::Duration};
+
+/// A source of the current time.
+///
+/// Anything that depends on the wall clock should take a [`Clock`] so tests can control time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// [`Clock`] backed by [`SystemTime::now`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// [`Clock`] that only moves when advanced manually
+#[cfg(test)]
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<SystemTime>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,300 @@
+use std::{collections::HashMap, io, path::PathBuf};
+#[cfg(feature = "serde")]
+use std::{env, fs, path::Path};
+
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file")]
+    ReadError(
+        #[source]
+        #[from]
+        io::Error,
+    ),
+    #[cfg(feature = "serde")]
+    #[error("Invalid config: {0}")]
+    InvalidConfig(
+        #[source]
+        #[from]
+        serde_json::Error,
+    ),
+}
+
+/// Settings that can be applied to an app or a single stream
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct StreamSettings {
+    /// Record published streams to disk
+    pub record: bool,
+    /// Maximum bitrate a publisher is allowed to send, in kbps
+    pub max_bitrate_kbps: Option<u32>,
+    /// Require publishers to authenticate
+    pub auth_required: bool,
+    /// Target HLS segment duration in seconds
+    pub segment_duration_secs: u32,
+    /// How many of the latest segments the HLS playlist lists
+    pub playlist_length: usize,
+    /// Let players access the raw audio samples, sent in `|RtmpSampleAccess` when play starts
+    pub audio_sample_access: bool,
+    /// Let players access the raw video samples, sent in `|RtmpSampleAccess` when play starts
+    pub video_sample_access: bool,
+}
+
+impl Default for StreamSettings {
+    fn default() -> Self {
+        Self {
+            record: false,
+            max_bitrate_kbps: None,
+            auth_required: false,
+            segment_duration_secs: 4,
+            playlist_length: 6,
+            audio_sample_access: true,
+            video_sample_access: true,
+        }
+    }
+}
+
+/// A partial [`StreamSettings`], any field that is set replaces the inherited value
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct SettingsOverride {
+    pub record: Option<bool>,
+    pub max_bitrate_kbps: Option<u32>,
+    pub auth_required: Option<bool>,
+    pub segment_duration_secs: Option<u32>,
+    pub playlist_length: Option<usize>,
+    pub audio_sample_access: Option<bool>,
+    pub video_sample_access: Option<bool>,
+}
+
+impl SettingsOverride {
+    fn apply(&self, settings: &mut StreamSettings) {
+        if let Some(record) = self.record {
+            settings.record = record;
+        }
+        if let Some(max_bitrate_kbps) = self.max_bitrate_kbps {
+            settings.max_bitrate_kbps = Some(max_bitrate_kbps);
+        }
+        if let Some(auth_required) = self.auth_required {
+            settings.auth_required = auth_required;
+        }
+        if let Some(segment_duration_secs) = self.segment_duration_secs {
+            settings.segment_duration_secs = segment_duration_secs;
+        }
+        if let Some(playlist_length) = self.playlist_length {
+            settings.playlist_length = playlist_length;
+        }
+        if let Some(audio_sample_access) = self.audio_sample_access {
+            settings.audio_sample_access = audio_sample_access;
+        }
+        if let Some(video_sample_access) = self.video_sample_access {
+            settings.video_sample_access = video_sample_access;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct AppConfig {
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub settings: SettingsOverride,
+    /// Overrides for individual stream keys within the app
+    pub streams: HashMap<String, SettingsOverride>,
+}
+
+/// Settings for serving streams over HTTP
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct HttpConfig {
+    /// Origins browsers can fetch streams from, any origin can if this isn't set
+    pub cors_allowed_origins: Option<Vec<String>>,
+    /// Serve a dump of every connection and stream at `/api/debug/state`, it is verbose and
+    /// shows every client's address so it is off by default
+    pub debug_state: bool,
+}
+
+/// Server configuration, mapping app names to their overrides on top of the global defaults.
+///
+/// ```json
+/// {
+///     "defaults": { "record": true },
+///     "apps": {
+///         "live": { "record": false, "streams": { "special": { "record": true } } }
+///     },
+///     "http": { "cors_allowed_origins": ["https://player.example.com"] }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct ServerConfig {
+    pub defaults: StreamSettings,
+    pub apps: HashMap<String, AppConfig>,
+    pub http: HttpConfig,
+    /// Where streams with `record` set write their recordings, the working directory if not set
+    pub record_dir: PathBuf,
+    /// Identifies this server among the nodes of a cluster, see
+    /// [`StreamRegistry::with_node_id`](crate::stream_registry::StreamRegistry::with_node_id)
+    pub node_id: Option<String>,
+}
+
+/// The environment variable pointing at the config file
+#[cfg(feature = "serde")]
+pub const CONFIG_ENV: &str = "CASTELIA_CONFIG";
+
+impl ServerConfig {
+    #[cfg(feature = "serde")]
+    /// Load the config file [`CONFIG_ENV`] points at, or the defaults if it isn't set
+    pub fn from_env() -> Result<Self, ConfigError> {
+        match env::var(CONFIG_ENV) {
+            Ok(path) => Self::load(path),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn parse(config: &str) -> Result<Self, ConfigError> {
+        Ok(serde_json::from_str(config)?)
+    }
+
+    /// Resolve the settings for a stream, falling back from stream to app to global defaults
+    pub fn settings_for(&self, app: &str, stream_key: Option<&str>) -> StreamSettings {
+        let mut settings = self.defaults.clone();
+        if let Some(app_config) = self.apps.get(app) {
+            app_config.settings.apply(&mut settings);
+            if let Some(stream_override) = stream_key.and_then(|key| app_config.streams.get(key)) {
+                stream_override.apply(&mut settings);
+            }
+        }
+
+        settings
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"{
+        "defaults": { "record": true, "segment_duration_secs": 6 },
+        "apps": {
+            "live": {
+                "record": false,
+                "streams": { "special": { "record": true, "max_bitrate_kbps": 8000 } }
+            },
+            "private": { "auth_required": true, "video_sample_access": false }
+        }
+    }"#;
+
+    #[test]
+    fn test_app_override() {
+        let config = ServerConfig::parse(CONFIG).unwrap();
+
+        let live = config.settings_for("live", Some("stream"));
+        assert!(!live.record);
+        assert_eq!(live.segment_duration_secs, 6);
+
+        let private = config.settings_for("private", None);
+        assert!(private.record);
+        assert!(private.auth_required);
+        assert!(private.audio_sample_access);
+        assert!(!private.video_sample_access);
+    }
+
+    #[test]
+    fn test_stream_override() {
+        let config = ServerConfig::parse(CONFIG).unwrap();
+        assert_eq!(
+            config.settings_for("live", Some("special")),
+            StreamSettings {
+                record: true,
+                max_bitrate_kbps: Some(8000),
+                auth_required: false,
+                segment_duration_secs: 6,
+                playlist_length: 6,
+                audio_sample_access: true,
+                video_sample_access: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_unknown_app_uses_defaults() {
+        let config = ServerConfig::parse(CONFIG).unwrap();
+        assert_eq!(config.settings_for("vod", None), config.defaults);
+    }
+
+    #[test]
+    fn test_http_config() {
+        assert_eq!(
+            ServerConfig::parse(CONFIG)
+                .unwrap()
+                .http
+                .cors_allowed_origins,
+            None
+        );
+
+        let config = ServerConfig::parse(
+            r#"{ "http": { "cors_allowed_origins": ["https://example.com"] } }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.http.cors_allowed_origins,
+            Some(vec!["https://example.com".to_owned()])
+        );
+        assert!(!config.http.debug_state);
+        assert!(
+            ServerConfig::parse(r#"{ "http": { "debug_state": true } }"#)
+                .unwrap()
+                .http
+                .debug_state
+        );
+    }
+
+    #[test]
+    fn test_node_id() {
+        assert_eq!(ServerConfig::parse(CONFIG).unwrap().node_id, None);
+        assert_eq!(
+            ServerConfig::parse(r#"{ "node_id": "edge-1" }"#)
+                .unwrap()
+                .node_id
+                .as_deref(),
+            Some("edge-1")
+        );
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(matches!(
+            ServerConfig::parse(r#"{ "defaults": { "recrod": true } }"#),
+            Err(ConfigError::InvalidConfig(_))
+        ));
+    }
+}
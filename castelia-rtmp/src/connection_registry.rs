@@ -0,0 +1,244 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    error::Error,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+pub use crate::netconnection::state::ConnectionState;
+use crate::{events::ConnectionErrorKind, stats::ConnectionStats, sync::lock};
+
+/// How many of the latest connection errors are kept
+pub const MAX_RECENT_ERRORS: usize = 32;
+
+/// What a connection looked like the last time it received or sent something
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    /// Unique among the connections of a registry
+    pub id: u64,
+    /// [`None`] for connections that aren't over TCP
+    pub peer_addr: Option<SocketAddr>,
+    pub state: ConnectionState,
+    /// The app the client connected to
+    pub app: Option<String>,
+    /// The chunk size the peer sends chunks with
+    pub in_chunk_size: u32,
+    /// The chunk size the server sends chunks with
+    pub out_chunk_size: u32,
+    /// The chunk stream the client sent connect on
+    pub control_chunk_stream_id: Option<u32>,
+    /// How many bytes the peer can send before it expects an Acknowledgement
+    pub ack_window_size: u32,
+    /// The window the peer set with SetPeerBandwidth
+    pub peer_bandwidth: Option<u32>,
+    /// Bytes of partially received messages
+    pub buffered_bytes: usize,
+    /// Bytes sent that the peer hasn't acknowledged yet
+    pub unacked_bytes: u32,
+    /// Keys of the streams published on this connection
+    pub publishing: Vec<String>,
+    /// Keys of the streams played on this connection
+    pub playing: Vec<String>,
+    pub stats: ConnectionStats,
+    /// Packets skipped because a player on this connection fell behind
+    pub dropped_packets: u64,
+}
+
+impl ConnectionInfo {
+    fn new(id: u64, peer_addr: Option<SocketAddr>) -> Self {
+        Self {
+            id,
+            peer_addr,
+            state: ConnectionState::Handshaking,
+            app: None,
+            in_chunk_size: 0,
+            out_chunk_size: 0,
+            control_chunk_stream_id: None,
+            ack_window_size: 0,
+            peer_bandwidth: None,
+            buffered_bytes: 0,
+            unacked_bytes: 0,
+            publishing: Vec::new(),
+            playing: Vec::new(),
+            stats: ConnectionStats::default(),
+            dropped_packets: 0,
+        }
+    }
+}
+
+/// A connection that ended with an error
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionErrorInfo {
+    pub connection_id: u64,
+    pub peer_addr: Option<SocketAddr>,
+    pub kind: ConnectionErrorKind,
+    pub message: String,
+    pub at: SystemTime,
+}
+
+#[derive(Debug, Default)]
+struct Connections {
+    next_id: u64,
+    open: BTreeMap<u64, ConnectionInfo>,
+    recent_errors: VecDeque<ConnectionErrorInfo>,
+}
+
+/// Keeps track of the connections a server is handling and the errors they recently ended with,
+/// so they can be inspected while the server runs. See
+/// [`crate::rtmp::RTMPSever::with_connection_registry`]
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionRegistry {
+    connections: Arc<Mutex<Connections>>,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start tracking a connection, it is removed once the returned handle is dropped
+    pub fn register(&self, peer_addr: Option<SocketAddr>) -> ConnectionHandle {
+        let mut connections = lock(&self.connections);
+        let id = connections.next_id;
+        connections.next_id += 1;
+        connections
+            .open
+            .insert(id, ConnectionInfo::new(id, peer_addr));
+        ConnectionHandle {
+            id,
+            peer_addr,
+            connections: self.connections.clone(),
+        }
+    }
+
+    /// The connections currently open, in the order they were opened
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        lock(&self.connections).open.values().cloned().collect()
+    }
+
+    /// The latest errors connections ended with, oldest first
+    pub fn recent_errors(&self) -> Vec<ConnectionErrorInfo> {
+        lock(&self.connections)
+            .recent_errors
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+/// A connection tracked by a [`ConnectionRegistry`]
+#[derive(Debug)]
+pub struct ConnectionHandle {
+    id: u64,
+    peer_addr: Option<SocketAddr>,
+    connections: Arc<Mutex<Connections>>,
+}
+
+impl ConnectionHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Update what the registry knows about the connection
+    pub fn update(&self, update: impl FnOnce(&mut ConnectionInfo)) {
+        if let Some(info) = lock(&self.connections).open.get_mut(&self.id) {
+            update(info);
+        }
+    }
+
+    /// Keep the error the connection ended with among the registry's recent errors, along with
+    /// the errors that caused it
+    pub fn record_error(&self, kind: ConnectionErrorKind, error: &dyn Error) {
+        let mut message = error.to_string();
+        let mut source = error.source();
+        while let Some(cause) = source {
+            message = format!("{message}: {cause}");
+            source = cause.source();
+        }
+
+        let mut connections = lock(&self.connections);
+        if connections.recent_errors.len() == MAX_RECENT_ERRORS {
+            connections.recent_errors.pop_front();
+        }
+        connections.recent_errors.push_back(ConnectionErrorInfo {
+            connection_id: self.id,
+            peer_addr: self.peer_addr,
+            kind,
+            message,
+            at: SystemTime::now(),
+        });
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        lock(&self.connections).open.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::chunks::ParseChunkError;
+
+    #[test]
+    fn test_register_and_update() {
+        let registry = ConnectionRegistry::new();
+        let peer_addr = "127.0.0.1:50000".parse().unwrap();
+        let first = registry.register(Some(peer_addr));
+        let second = registry.register(None);
+        assert_ne!(first.id(), second.id());
+
+        first.update(|info| {
+            info.state = ConnectionState::Publishing;
+            info.publishing.push("live/mystream".to_owned());
+        });
+        let connections = registry.connections();
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[0].peer_addr, Some(peer_addr));
+        assert_eq!(connections[0].state, ConnectionState::Publishing);
+        assert_eq!(connections[0].publishing, ["live/mystream"]);
+        assert_eq!(connections[1].state, ConnectionState::Handshaking);
+
+        drop(first);
+        assert_eq!(
+            registry
+                .connections()
+                .iter()
+                .map(|info| info.id)
+                .collect::<Vec<_>>(),
+            [second.id()]
+        );
+    }
+
+    #[test]
+    fn test_error_message_includes_sources() {
+        let registry = ConnectionRegistry::new();
+        let connection = registry.register(None);
+        let error = ParseChunkError::from(io::Error::new(io::ErrorKind::InvalidData, "bad"));
+        connection.record_error(ConnectionErrorKind::Io, &error);
+
+        let [recorded] = registry.recent_errors().try_into().unwrap();
+        assert_eq!(recorded.connection_id, connection.id());
+        assert_eq!(recorded.message, format!("{error}: bad"));
+    }
+
+    #[test]
+    fn test_recent_errors_are_capped() {
+        let registry = ConnectionRegistry::new();
+        let connection = registry.register(None);
+        for i in 0..MAX_RECENT_ERRORS + 1 {
+            let error = io::Error::new(io::ErrorKind::InvalidData, format!("error {i}"));
+            connection.record_error(ConnectionErrorKind::Protocol, &error);
+        }
+        drop(connection);
+
+        let errors = registry.recent_errors();
+        assert_eq!(errors.len(), MAX_RECENT_ERRORS);
+        assert_eq!(errors[0].message, "error 1");
+        assert_eq!(errors[0].kind, ConnectionErrorKind::Protocol);
+    }
+}
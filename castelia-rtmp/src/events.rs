@@ -0,0 +1,75 @@
+use std::net::SocketAddr;
+
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::warn;
+
+use crate::stats::ConnectionStats;
+
+/// Something that happened on the server, see [`crate::rtmp::RTMPSever::with_events`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerEvent {
+    ConnectionOpened {
+        peer_addr: SocketAddr,
+    },
+    /// The connection ended with an error, sent just before it is reported closed
+    ConnectionFailed {
+        peer_addr: SocketAddr,
+        kind: ConnectionErrorKind,
+    },
+    ConnectionClosed {
+        peer_addr: SocketAddr,
+        /// Bytes received over the connection's lifetime
+        stats: ConnectionStats,
+    },
+    StreamPublished {
+        peer_addr: SocketAddr,
+        stream_key: String,
+    },
+    StreamUnpublished {
+        peer_addr: SocketAddr,
+        stream_key: String,
+    },
+    PlayerJoined {
+        peer_addr: SocketAddr,
+        stream_key: String,
+    },
+    PlayerLeft {
+        peer_addr: SocketAddr,
+        stream_key: String,
+    },
+}
+
+/// What went wrong on a connection that ended with an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionErrorKind {
+    /// The handshake failed or timed out
+    Handshake,
+    /// The peer stopped sending anything partway through the connection
+    Timeout,
+    /// The peer sent something that couldn't be read as chunks or messages
+    Protocol,
+    /// Reading from or writing to the socket failed
+    Io,
+}
+
+/// Sends the events of a single connection
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionEvents {
+    sender: mpsc::Sender<ServerEvent>,
+    peer_addr: SocketAddr,
+}
+
+impl ConnectionEvents {
+    pub fn new(sender: mpsc::Sender<ServerEvent>, peer_addr: SocketAddr) -> Self {
+        Self { sender, peer_addr }
+    }
+
+    /// Send the event built from the peer's address. Connections never wait on the receiver,
+    /// so events are dropped if it falls behind
+    pub fn send(&self, event: impl FnOnce(SocketAddr) -> ServerEvent) {
+        match self.sender.try_send(event(self.peer_addr)) {
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full(event)) => warn!("Event channel is full, dropping {event:?}"),
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::{
+    messages::{
+        command::command_message_type,
+        media::{VideoTagHeader, avc_packet_type},
+    },
+    stream_registry::MediaPacket,
+};
+
+/// Size of the header in front of every tag
+const TAG_HEADER_SIZE: usize = 11;
+
+/// Size of the PreviousTagSize after the file header and every tag
+const PREVIOUS_TAG_SIZE_SIZE: usize = 4;
+
+/// Size of the file header, not counting the PreviousTagSize that follows it
+const FILE_HEADER_SIZE: u32 = 9;
+
+/// Flags in the file header saying which kinds of tags the file has
+mod type_flags {
+    pub const AUDIO: u8 = 0b100;
+    pub const VIDEO: u8 = 0b001;
+}
+
+/// The FLV file header, followed by the PreviousTagSize of the non-existent tag before the
+/// first one
+pub fn encode_header(has_audio: bool, has_video: bool) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FILE_HEADER_SIZE as usize + PREVIOUS_TAG_SIZE_SIZE);
+    buf.put_slice(b"FLV");
+    buf.put_u8(1);
+    let mut flags = 0;
+    if has_audio {
+        flags |= type_flags::AUDIO;
+    }
+    if has_video {
+        flags |= type_flags::VIDEO;
+    }
+    buf.put_u8(flags);
+    buf.put_u32(FILE_HEADER_SIZE);
+    buf.put_u32(0);
+    buf.freeze()
+}
+
+/// Encode a packet as an FLV tag, followed by its PreviousTagSize.
+///
+/// The tag header is laid out like an aggregate sub-message:
+/// - 1 byte tag type, which matches the RTMP message type id
+/// - 3 byte data size
+/// - 3 byte timestamp, followed by 1 byte holding the upper 8 bits of the timestamp
+/// - 3 byte stream id, always 0
+pub fn encode_tag(packet: &MediaPacket) -> Bytes {
+    let data_size = packet.payload.len();
+    let mut buf = BytesMut::with_capacity(TAG_HEADER_SIZE + data_size + PREVIOUS_TAG_SIZE_SIZE);
+    buf.put_u8(packet.message_type_id);
+    // RTMP message lengths are 3 bytes as well, so the payload always fits
+    buf.put_uint(data_size as u64, 3);
+    buf.put_uint(u64::from(packet.timestamp & 0xFFFFFF), 3);
+    buf.put_u8((packet.timestamp >> 24) as u8);
+    buf.put_uint(0, 3);
+    buf.put_slice(&packet.payload);
+    buf.put_u32((TAG_HEADER_SIZE + data_size) as u32);
+    buf.freeze()
+}
+
+/// Turns the packets of a live stream into FLV tags, starting at a keyframe so the player can
+/// decode the first frame it gets
+#[derive(Debug, Default)]
+pub struct FlvMuxer {
+    seen_keyframe: bool,
+}
+
+impl FlvMuxer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encode `packet` as a tag, returns [`None`] for video frames before the first keyframe.
+    /// Metadata, sequence headers and audio are always passed through
+    pub fn tag(&mut self, packet: &MediaPacket) -> Option<Bytes> {
+        if packet.message_type_id == command_message_type::VIDEO && !self.seen_keyframe {
+            let header = VideoTagHeader::parse(&packet.payload).ok()?;
+            if header.avc_packet_type != Some(avc_packet_type::SEQUENCE_HEADER) {
+                if !header.is_keyframe() {
+                    return None;
+                }
+                self.seen_keyframe = true;
+            }
+        }
+        Some(encode_tag(packet))
+    }
+
+    /// Drop video until the next keyframe again, after packets were skipped
+    pub fn resync(&mut self) {
+        self.seen_keyframe = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::video;
+
+    #[test]
+    fn test_encode_header() {
+        assert_eq!(
+            encode_header(true, true)[..],
+            [b'F', b'L', b'V', 1, 0x05, 0, 0, 0, 9, 0, 0, 0, 0]
+        );
+        assert_eq!(encode_header(false, true)[4], 0x01);
+    }
+
+    #[test]
+    fn test_encode_tag() {
+        let tag = encode_tag(&video(0x12345678, &[0x17, 0x01, 0x00, 0x00, 0x00]));
+
+        assert_eq!(
+            tag[..],
+            [
+                command_message_type::VIDEO,
+                0x00,
+                0x00,
+                0x05,
+                0x34,
+                0x56,
+                0x78,
+                0x12,
+                0x00,
+                0x00,
+                0x00,
+                0x17,
+                0x01,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                16,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_muxer_starts_at_keyframe() {
+        let mut muxer = FlvMuxer::new();
+        let sequence_header = video(0, &[0x17, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        let inter_frame = video(40, &[0x27, 0x01, 0x00, 0x00, 0x00, 0x41]);
+        let keyframe = video(80, &[0x17, 0x01, 0x00, 0x00, 0x00, 0x65]);
+
+        assert!(muxer.tag(&sequence_header).is_some());
+        assert!(muxer.tag(&inter_frame).is_none());
+        assert!(muxer.tag(&keyframe).is_some());
+        assert!(muxer.tag(&inter_frame).is_some());
+    }
+
+    #[test]
+    fn test_muxer_resync() {
+        let mut muxer = FlvMuxer::new();
+        let sequence_header = video(0, &[0x17, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        let inter_frame = video(40, &[0x27, 0x01, 0x00, 0x00, 0x00, 0x41]);
+        let keyframe = video(80, &[0x17, 0x01, 0x00, 0x00, 0x00, 0x65]);
+        assert!(muxer.tag(&keyframe).is_some());
+
+        muxer.resync();
+        assert!(muxer.tag(&inter_frame).is_none());
+        assert!(muxer.tag(&sequence_header).is_some());
+        assert!(muxer.tag(&keyframe).is_some());
+        assert!(muxer.tag(&inter_frame).is_some());
+    }
+}
@@ -0,0 +1,251 @@
+//! The digest (a.k.a. "complex") handshake introduced with Flash Player 9.
+//!
+//! C1 and S1 are split into two 764 byte blocks after the time and version fields: one holds a
+//! key, the other holds a HMAC-SHA256 digest of the rest of the chunk. The position of the digest
+//! inside its block is derived from the 4 bytes at the start of the block. Which block comes first
+//! is the `scheme`, and the server answers with the same scheme the client used.
+//!
+//! S2 is random data signed with a key derived from the C1 digest, so the client can verify that
+//! it is talking to a "genuine" server.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::{HANDSHAKE_CHUNK_SIZE, HandshakeError};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const DIGEST_LENGTH: usize = 32;
+
+/// The size of each of the key and digest blocks
+const BLOCK_SIZE: usize = 764;
+
+/// Digest offsets are taken modulo this, so the digest always fits inside its block
+const DIGEST_OFFSET_MODULO: usize = BLOCK_SIZE - DIGEST_LENGTH - 4;
+
+const KEY_SUFFIX: [u8; 32] = [
+    0xF0, 0xEE, 0xC2, 0x4A, 0x80, 0x68, 0xBE, 0xE8, 0x2E, 0x00, 0xD0, 0xD1, 0x02, 0x9E, 0x7E, 0x57,
+    0x6E, 0xEC, 0x5D, 0x2D, 0x29, 0x80, 0x6F, 0xAB, 0x93, 0xB8, 0xE6, 0x36, 0xCF, 0xEB, 0x31, 0xAE,
+];
+
+const FP_KEY_TEXT: &[u8; 30] = b"Genuine Adobe Flash Player 001";
+const FMS_KEY_TEXT: &[u8; 36] = b"Genuine Adobe Flash Media Server 001";
+
+/// Version advertised in S1. Any non-zero value tells the client we speak the digest handshake.
+pub const SERVER_VERSION: [u8; 4] = [0x0D, 0x0E, 0x0A, 0x0D];
+
+/// The layout of the key and digest blocks in C1/S1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestScheme {
+    /// Scheme 0: the key block comes first, followed by the digest block
+    KeyFirst,
+    /// Scheme 1: the digest block comes first, followed by the key block
+    DigestFirst,
+}
+
+impl DigestScheme {
+    fn digest_block_start(self) -> usize {
+        match self {
+            DigestScheme::KeyFirst => 8 + BLOCK_SIZE,
+            DigestScheme::DigestFirst => 8,
+        }
+    }
+
+    /// Position of the digest within the whole chunk
+    fn digest_position(self, chunk: &[u8]) -> usize {
+        let start = self.digest_block_start();
+        let offset: usize = chunk[start..start + 4].iter().map(|&b| b as usize).sum();
+        start + 4 + offset % DIGEST_OFFSET_MODULO
+    }
+}
+
+fn key(text: &[u8]) -> Vec<u8> {
+    [text, &KEY_SUFFIX].concat()
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> Result<[u8; DIGEST_LENGTH], HandshakeError> {
+    let mut mac = HmacSha256::new_from_slice(key).map_err(|_| {
+        // should never happen, HMAC accepts keys of any length
+        HandshakeError::InvalidHandshake("Invalid HMAC key length".into())
+    })?;
+    for part in parts {
+        mac.update(part);
+    }
+    Ok(mac.finalize().into_bytes().into())
+}
+
+/// Computes the digest of `chunk` with the digest bytes themselves left out
+fn compute_digest(
+    chunk: &[u8],
+    position: usize,
+    key: &[u8],
+) -> Result<[u8; DIGEST_LENGTH], HandshakeError> {
+    hmac(
+        key,
+        &[&chunk[..position], &chunk[position + DIGEST_LENGTH..]],
+    )
+}
+
+fn find_digest(
+    chunk: &[u8; HANDSHAKE_CHUNK_SIZE],
+    key: &[u8],
+) -> Result<Option<(DigestScheme, [u8; DIGEST_LENGTH])>, HandshakeError> {
+    for scheme in [DigestScheme::KeyFirst, DigestScheme::DigestFirst] {
+        let position = scheme.digest_position(chunk);
+        let digest = compute_digest(chunk, position, key)?;
+        if chunk[position..position + DIGEST_LENGTH] == digest {
+            return Ok(Some((scheme, digest)));
+        }
+    }
+
+    Ok(None)
+}
+
+fn write_digest(
+    chunk: &mut [u8; HANDSHAKE_CHUNK_SIZE],
+    scheme: DigestScheme,
+    key: &[u8],
+) -> Result<[u8; DIGEST_LENGTH], HandshakeError> {
+    let position = scheme.digest_position(chunk);
+    let digest = compute_digest(chunk, position, key)?;
+    chunk[position..position + DIGEST_LENGTH].copy_from_slice(&digest);
+    Ok(digest)
+}
+
+/// Looks for a valid client digest in C1, returning the scheme used and the digest
+pub fn find_client_digest(
+    c1: &[u8; HANDSHAKE_CHUNK_SIZE],
+) -> Result<Option<(DigestScheme, [u8; DIGEST_LENGTH])>, HandshakeError> {
+    find_digest(c1, FP_KEY_TEXT)
+}
+
+/// Writes the server digest into S1, which must already hold its time and random data
+pub fn sign_s1(
+    s1: &mut [u8; HANDSHAKE_CHUNK_SIZE],
+    scheme: DigestScheme,
+) -> Result<(), HandshakeError> {
+    s1[4..8].copy_from_slice(&SERVER_VERSION);
+    write_digest(s1, scheme, FMS_KEY_TEXT)?;
+    Ok(())
+}
+
+/// Signs the random data in S2 with a key derived from the client digest
+pub fn sign_s2(
+    s2: &mut [u8; HANDSHAKE_CHUNK_SIZE],
+    client_digest: &[u8; DIGEST_LENGTH],
+) -> Result<(), HandshakeError> {
+    let signature_start = HANDSHAKE_CHUNK_SIZE - DIGEST_LENGTH;
+    let signing_key = hmac(&key(FMS_KEY_TEXT), &[client_digest])?;
+    let signature = hmac(&signing_key, &[&s2[..signature_start]])?;
+    s2[signature_start..].copy_from_slice(&signature);
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Signs C1 the way a Flash Player client would
+    pub fn sign_c1(
+        c1: &mut [u8; HANDSHAKE_CHUNK_SIZE],
+        scheme: DigestScheme,
+    ) -> [u8; DIGEST_LENGTH] {
+        write_digest(c1, scheme, FP_KEY_TEXT).unwrap()
+    }
+
+    /// Returns the digest S1 carries if it was signed by a genuine server
+    pub fn find_server_digest(s1: &[u8; HANDSHAKE_CHUNK_SIZE]) -> Option<[u8; DIGEST_LENGTH]> {
+        find_digest(s1, FMS_KEY_TEXT)
+            .unwrap()
+            .map(|(_, digest)| digest)
+    }
+
+    /// Checks the S2 signature the way a Flash Player client would
+    pub fn verify_s2(s2: &[u8; HANDSHAKE_CHUNK_SIZE], client_digest: &[u8; DIGEST_LENGTH]) -> bool {
+        let mut expected = *s2;
+        sign_s2(&mut expected, client_digest).unwrap();
+        expected == *s2
+    }
+
+    fn patterned_chunk(multiplier: usize, increment: usize) -> [u8; HANDSHAKE_CHUNK_SIZE] {
+        let mut chunk = [0; HANDSHAKE_CHUNK_SIZE];
+        for (i, byte) in chunk.iter_mut().enumerate() {
+            *byte = ((i * multiplier + increment) % 256) as u8;
+        }
+        chunk
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(key(FP_KEY_TEXT).len(), 62);
+        assert_eq!(key(FMS_KEY_TEXT).len(), 68);
+    }
+
+    #[test]
+    fn test_digest_positions() {
+        let chunk = patterned_chunk(7, 3);
+
+        // sum of bytes 772..776 is 166
+        assert_eq!(DigestScheme::KeyFirst.digest_position(&chunk), 776 + 166);
+        // sum of bytes 8..12 is 278
+        assert_eq!(DigestScheme::DigestFirst.digest_position(&chunk), 12 + 278);
+    }
+
+    #[test]
+    fn test_client_digest_vectors() {
+        let vectors = [
+            (
+                DigestScheme::KeyFirst,
+                "492c04ebd265e6343c4c12f5580a46399bec2724821f27f321b95ca75b63b318",
+            ),
+            (
+                DigestScheme::DigestFirst,
+                "36d27b365b84e5e55f376e2f24f3a7dd6b8b5e9b002f035f99366c07e43b8d41",
+            ),
+        ];
+
+        for (scheme, expected) in vectors {
+            let mut c1 = patterned_chunk(7, 3);
+            assert_eq!(hex(&sign_c1(&mut c1, scheme)), expected);
+            assert_eq!(
+                find_client_digest(&c1).unwrap().map(|(s, d)| (s, hex(&d))),
+                Some((scheme, expected.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_s2_signature_vector() {
+        let mut client_digest = [0; DIGEST_LENGTH];
+        client_digest.copy_from_slice(&patterned_chunk(7, 3)[..DIGEST_LENGTH]);
+
+        let mut s2 = patterned_chunk(13, 1);
+        sign_s2(&mut s2, &client_digest).unwrap();
+
+        assert_eq!(
+            hex(&s2[HANDSHAKE_CHUNK_SIZE - DIGEST_LENGTH..]),
+            "ebf84ea667d80cf1687b02476953bfc9c25e5297a8cc11692efdc32e6af5d78e"
+        );
+    }
+
+    #[test]
+    fn test_unsigned_c1_has_no_digest() {
+        assert_eq!(find_client_digest(&patterned_chunk(7, 3)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_server_digest_uses_client_scheme() {
+        for scheme in [DigestScheme::KeyFirst, DigestScheme::DigestFirst] {
+            let mut s1 = patterned_chunk(11, 5);
+            sign_s1(&mut s1, scheme).unwrap();
+
+            assert_eq!(s1[4..8], SERVER_VERSION);
+            let (found_scheme, _) = find_digest(&s1, FMS_KEY_TEXT).unwrap().unwrap();
+            assert_eq!(found_scheme, scheme);
+        }
+    }
+}
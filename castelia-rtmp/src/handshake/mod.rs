@@ -0,0 +1,657 @@
+use std::{
+    io,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::timeout,
+};
+use tracing::trace;
+
+use crate::clock::{Clock, SystemClock};
+
+mod digest;
+
+/// The size of the C1/C2/S1/S2 chunks:
+///
+/// C1/S1 chunks consist of:
+/// - 4 byte time field
+/// - 4 byte zeroes
+/// - 1528 bytes of random data
+///
+/// C2/S2 chunks consist of:
+/// - 4 byte time field
+/// - 4 byte time 2 field
+/// - 1528 byte echo field
+const HANDSHAKE_CHUNK_SIZE: usize = 4 + 4 + 1528;
+
+const RTMP_VERSION: u8 = 0x03;
+
+/// How long [`handshake`] waits for the client to complete the handshake
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The flavour of handshake the client started
+#[derive(Debug)]
+enum HandshakeMode {
+    /// The handshake from the spec, where S2 echoes C1
+    Simple,
+    /// The Flash Player 9+ handshake, where C1 carries a digest that S1 and S2 have to answer
+    Digest {
+        scheme: digest::DigestScheme,
+        client_digest: [u8; digest::DIGEST_LENGTH],
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum HandshakeError {
+    #[error("RTMP version {0} is unsupported")]
+    UnsupportedVersion(u8),
+    #[error("Failed to read from socket")]
+    ReadError(#[source] io::Error),
+    #[error("Failed to write to socket")]
+    WriteError(#[source] io::Error),
+    #[error("Invalid handshake: {0}")]
+    InvalidHandshake(String),
+    #[error("Handshake timed out")]
+    Timeout(
+        #[source]
+        #[from]
+        tokio::time::error::Elapsed,
+    ),
+}
+
+impl From<HandshakeError> for io::Error {
+    fn from(value: HandshakeError) -> Self {
+        match value {
+            HandshakeError::UnsupportedVersion(_) => {
+                io::Error::new(io::ErrorKind::Unsupported, value)
+            }
+            HandshakeError::InvalidHandshake(s) => io::Error::new(io::ErrorKind::InvalidData, s),
+            HandshakeError::ReadError(ref error) => io::Error::new(error.kind(), value),
+            HandshakeError::WriteError(ref error) => io::Error::new(error.kind(), value),
+            HandshakeError::Timeout(elapsed) => elapsed.into(),
+        }
+    }
+}
+
+/// Performs a RTMP handshake on the provided socket
+/// Returns [`Ok`] if handshake succeeded, otherwise returns the error
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+) -> Result<(), HandshakeError> {
+    handshake_with_timeout(socket, DEFAULT_HANDSHAKE_TIMEOUT).await
+}
+
+/// Performs a RTMP handshake, failing with [`HandshakeError::Timeout`] if the whole handshake
+/// takes longer than `duration`
+pub async fn handshake_with_timeout<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    duration: Duration,
+) -> Result<(), HandshakeError> {
+    timeout(duration, handshake_with_clock(socket, &SystemClock)).await?
+}
+
+/// Performs a RTMP handshake, using `clock` to generate the handshake timestamps
+pub async fn handshake_with_clock<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    clock: &dyn Clock,
+) -> Result<(), HandshakeError> {
+    trace!("starting handshake");
+    read_version(socket).await?;
+    trace!("read c0");
+
+    let mut client_buf = [0; HANDSHAKE_CHUNK_SIZE];
+    let mut server_buf = [0; 1 + HANDSHAKE_CHUNK_SIZE];
+
+    let mode = read_c1(socket, &mut client_buf).await?;
+    let read_timestamp = get_timestamp(clock)?;
+    trace!("read c1, using {mode:?} handshake");
+
+    send_s0_s1(socket, &mut server_buf, &mode, clock).await?;
+    trace!("sent s0 and s1");
+
+    send_s2(socket, &mut client_buf, &read_timestamp, &mode).await?;
+    trace!("sent s2");
+
+    read_c2(
+        socket,
+        server_buf[1..].try_into().map_err(|_| {
+            // should never happen...
+            HandshakeError::InvalidHandshake("Could not cast S1 into correct size".into())
+        })?,
+        &mut client_buf,
+        &mode,
+    )
+    .await?;
+    trace!("read c2");
+
+    trace!("completed handshake");
+    Ok(())
+}
+
+/// Performs the client side of a RTMP handshake on the provided socket, e.g. when relaying to an
+/// upstream server
+/// Returns [`Ok`] if handshake succeeded, otherwise returns the error
+pub async fn client_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+) -> Result<(), HandshakeError> {
+    trace!("starting client handshake");
+    let mut client_buf = [0; 1 + HANDSHAKE_CHUNK_SIZE];
+    client_buf[0] = RTMP_VERSION;
+    client_buf[1..5].copy_from_slice(&get_timestamp(&SystemClock)?);
+    // zeroes field is left as zeroes, we only speak the simple handshake
+    rand::fill(&mut client_buf[9..]);
+
+    socket
+        .write_all(&client_buf)
+        .await
+        .map_err(HandshakeError::WriteError)?;
+    trace!("sent c0 and c1");
+
+    read_version(socket).await?;
+    trace!("read s0");
+
+    let mut server_buf = [0; HANDSHAKE_CHUNK_SIZE];
+    read_chunk(socket, &mut server_buf).await?;
+    trace!("read s1");
+
+    // C2 echoes S1
+    socket
+        .write_all(&server_buf)
+        .await
+        .map_err(HandshakeError::WriteError)?;
+    trace!("sent c2");
+
+    // Servers doing the digest handshake send signed random data instead of echoing C1, so S2 is
+    // not checked
+    read_chunk(socket, &mut server_buf).await?;
+    trace!("read s2");
+
+    trace!("completed client handshake");
+    Ok(())
+}
+
+async fn read_chunk<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    buf: &mut [u8; HANDSHAKE_CHUNK_SIZE],
+) -> Result<(), HandshakeError> {
+    let mut total_bytes_read = 0;
+    while total_bytes_read < HANDSHAKE_CHUNK_SIZE {
+        let bytes_read = socket
+            .read(&mut buf[total_bytes_read..])
+            .await
+            .map_err(HandshakeError::ReadError)?;
+
+        if bytes_read == 0 {
+            return Err(HandshakeError::ReadError(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Peer closed the connection mid handshake",
+            )));
+        }
+
+        total_bytes_read += bytes_read;
+    }
+
+    Ok(())
+}
+
+/// Reads C0/S0 and checks the peer speaks our RTMP version
+async fn read_version<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+) -> Result<(), HandshakeError> {
+    let version = socket.read_u8().await.map_err(HandshakeError::ReadError)?;
+    trace!("RTMP version: {version}");
+    if version != RTMP_VERSION {
+        return Err(HandshakeError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+async fn read_c1<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    client_buf: &mut [u8; HANDSHAKE_CHUNK_SIZE],
+) -> Result<HandshakeMode, HandshakeError> {
+    read_chunk(socket, client_buf).await?;
+
+    // the simple handshake has all zeroes here, the digest handshake puts the client version here
+    let zeroes = &client_buf[4..8];
+    if zeroes.iter().all(|x| *x == 0) {
+        return Ok(HandshakeMode::Simple);
+    }
+
+    match digest::find_client_digest(client_buf)? {
+        Some((scheme, client_digest)) => Ok(HandshakeMode::Digest {
+            scheme,
+            client_digest,
+        }),
+        None => Err(HandshakeError::InvalidHandshake(
+            "Zeroes field in handshake must be all zeroes, or C1 must carry a valid digest".into(),
+        )),
+    }
+}
+
+async fn send_s0_s1<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    server_buf: &mut [u8; 1 + HANDSHAKE_CHUNK_SIZE],
+    mode: &HandshakeMode,
+    clock: &dyn Clock,
+) -> Result<(), HandshakeError> {
+    // send version along
+    server_buf[0] = RTMP_VERSION;
+
+    // timestamp
+    server_buf[1..5].copy_from_slice(&get_timestamp(clock)?);
+
+    // random data
+    rand::fill(&mut server_buf[9..]);
+
+    if let HandshakeMode::Digest { scheme, .. } = mode {
+        let s1 = (&mut server_buf[1..]).try_into().map_err(|_| {
+            // should never happen...
+            HandshakeError::InvalidHandshake("Could not cast S1 into correct size".into())
+        })?;
+        digest::sign_s1(s1, *scheme)?;
+    }
+
+    socket
+        .write_all(server_buf)
+        .await
+        .map_err(HandshakeError::WriteError)
+}
+
+async fn send_s2<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    c1: &mut [u8; HANDSHAKE_CHUNK_SIZE],
+    read_timestamp: &[u8; 4],
+    mode: &HandshakeMode,
+) -> Result<(), HandshakeError> {
+    match mode {
+        HandshakeMode::Simple => c1[4..8].copy_from_slice(read_timestamp),
+        HandshakeMode::Digest { client_digest, .. } => {
+            // S2 is fresh random data signed with a key derived from the C1 digest
+            rand::fill(&mut c1[..]);
+            digest::sign_s2(c1, client_digest)?;
+        }
+    }
+
+    socket
+        .write_all(c1)
+        .await
+        .map_err(HandshakeError::WriteError)?;
+
+    Ok(())
+}
+
+async fn read_c2<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    s1: &[u8; HANDSHAKE_CHUNK_SIZE],
+    client_buf: &mut [u8; HANDSHAKE_CHUNK_SIZE],
+    mode: &HandshakeMode,
+) -> Result<(), HandshakeError> {
+    read_chunk(socket, client_buf).await?;
+
+    // Clients differ in how they sign C2 in the digest handshake, so like most servers we don't
+    // verify it. The client has already proven itself with the C1 digest.
+    if let HandshakeMode::Digest { .. } = mode {
+        return Ok(());
+    }
+
+    if client_buf[..4] != s1[..4] {
+        return Err(HandshakeError::InvalidHandshake(
+            "Echoed timestamp does not match".into(),
+        ));
+    }
+
+    if client_buf[8..] != s1[8..] {
+        return Err(HandshakeError::InvalidHandshake(
+            "Random data echo does not match".into(),
+        ));
+    }
+
+    Ok(())
+}
+
+fn get_timestamp(clock: &dyn Clock) -> Result<[u8; 4], HandshakeError> {
+    let bytes = clock
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| {
+            HandshakeError::InvalidHandshake(
+                "Could not generate timestamp for handshake, clock may have gone backwards".into(),
+            )
+        })?
+        .as_millis()
+        .to_be_bytes();
+
+    // u128 is 16 bytes, it is safe to take the last 4
+    Ok([bytes[12], bytes[13], bytes[14], bytes[15]])
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        tokio::spawn(async move {
+            client.write_u8(3).await.unwrap();
+
+            // send all zeroes as a mock value
+            let mut buf = [0; HANDSHAKE_CHUNK_SIZE];
+            client.write_all(&buf).await.unwrap();
+
+            assert_eq!(client.read_u8().await.unwrap(), 3);
+            client.read_exact(&mut buf).await.unwrap();
+
+            // check that the timestamp's been set to *something*
+            assert_ne!(buf[..4], [0, 0, 0, 0]);
+
+            assert_eq!(buf[4..8], [0, 0, 0, 0]);
+
+            client.write_all(&buf).await.unwrap();
+        });
+
+        let (mut stream, _) = server.accept().await.unwrap();
+
+        let result = handshake(&mut stream).await;
+        assert!(result.is_ok(), "Handshake failed with error: {:#?}", result);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_with_mock_clock() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let client_task = tokio::spawn(async move {
+            client.write_u8(3).await.unwrap();
+            let mut buf = [0; HANDSHAKE_CHUNK_SIZE];
+            client.write_all(&buf).await.unwrap();
+
+            client.read_u8().await.unwrap();
+            let mut s1 = [0; HANDSHAKE_CHUNK_SIZE];
+            client.read_exact(&mut s1).await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+            client.write_all(&s1).await.unwrap();
+
+            (s1, buf)
+        });
+
+        let (mut stream, _) = server.accept().await.unwrap();
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_millis(0x12345678));
+        handshake_with_clock(&mut stream, &clock).await.unwrap();
+
+        let (s1, s2) = client_task.await.unwrap();
+        assert_eq!(s1[..4], [0x12, 0x34, 0x56, 0x78]);
+        // time 2 of S2 is the time C1 was read
+        assert_eq!(s2[4..8], [0x12, 0x34, 0x56, 0x78]);
+    }
+
+    #[tokio::test]
+    async fn test_digest_handshake() {
+        for scheme in [
+            digest::DigestScheme::KeyFirst,
+            digest::DigestScheme::DigestFirst,
+        ] {
+            let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(server.local_addr().unwrap())
+                .await
+                .unwrap();
+
+            let client_task = tokio::spawn(async move {
+                let mut c1 = [0; HANDSHAKE_CHUNK_SIZE];
+                rand::fill(&mut c1[8..]);
+                // Flash Player 10.0.45.2
+                c1[4..8].copy_from_slice(&[0x0A, 0x00, 0x2D, 0x02]);
+                let client_digest = digest::tests::sign_c1(&mut c1, scheme);
+
+                client.write_u8(3).await.unwrap();
+                client.write_all(&c1).await.unwrap();
+
+                assert_eq!(client.read_u8().await.unwrap(), 3);
+                let mut s1 = [0; HANDSHAKE_CHUNK_SIZE];
+                client.read_exact(&mut s1).await.unwrap();
+                let mut s2 = [0; HANDSHAKE_CHUNK_SIZE];
+                client.read_exact(&mut s2).await.unwrap();
+
+                assert!(digest::tests::find_server_digest(&s1).is_some());
+                assert!(digest::tests::verify_s2(&s2, &client_digest));
+
+                client.write_all(&s1).await.unwrap();
+            });
+
+            let (mut stream, _) = server.accept().await.unwrap();
+
+            let result = handshake(&mut stream).await;
+            assert!(result.is_ok(), "Handshake failed with error: {:#?}", result);
+            client_task.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_handshake() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let client_task = tokio::spawn(async move { client_handshake(&mut client).await });
+
+        let (mut stream, _) = server.accept().await.unwrap();
+        let result = handshake(&mut stream).await;
+        assert!(result.is_ok(), "Handshake failed with error: {:#?}", result);
+
+        let result = client_task.await.unwrap();
+        assert!(
+            result.is_ok(),
+            "Client handshake failed with error: {:#?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_handshake_unsupported_version() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let (mut stream, _) = server.accept().await.unwrap();
+        tokio::spawn(async move {
+            let mut c0_c1 = [0; 1 + HANDSHAKE_CHUNK_SIZE];
+            stream.read_exact(&mut c0_c1).await.unwrap();
+            stream.write_u8(6).await.unwrap();
+        });
+
+        assert_eq!(
+            client_handshake(&mut client).await.unwrap_err().to_string(),
+            "RTMP version 6 is unsupported"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // connected, but never sends anything
+        let _client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let (mut stream, _) = server.accept().await.unwrap();
+
+        let error = handshake_with_timeout(&mut stream, Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, HandshakeError::Timeout(_)));
+        assert_eq!(io::Error::from(error).kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_handshake() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+        client.set_nodelay(true).unwrap();
+
+        tokio::spawn(async move {
+            client.write_u8(3).await.unwrap();
+            let mut buf = [0; HANDSHAKE_CHUNK_SIZE];
+            client.write_all(&buf).await.unwrap();
+
+            client.read_u8().await.unwrap();
+            let mut s1 = [0; HANDSHAKE_CHUNK_SIZE];
+            client.read_exact(&mut s1).await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+
+            // deliver C2 in two segments so the server sees a short read
+            let (head, tail) = s1.split_at(HANDSHAKE_CHUNK_SIZE / 2);
+            client.write_all(head).await.unwrap();
+            client.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.write_all(tail).await.unwrap();
+        });
+
+        let (mut stream, _) = server.accept().await.unwrap();
+
+        let result = handshake(&mut stream).await;
+        assert!(result.is_ok(), "Handshake failed with error: {:#?}", result);
+    }
+
+    #[tokio::test]
+    async fn test_eof_mid_chunk() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        tokio::spawn(async move {
+            client.write_u8(3).await.unwrap();
+            client.write_all(&[0; 100]).await.unwrap();
+            // client is dropped here, closing the connection
+        });
+
+        let (mut stream, _) = server.accept().await.unwrap();
+
+        let error = handshake(&mut stream).await.unwrap_err();
+        assert!(matches!(
+            error,
+            HandshakeError::ReadError(ref e) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn test_timestamp_wraps_to_4_bytes() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_millis(u32::MAX as u64));
+        assert_eq!(get_timestamp(&clock).unwrap(), u32::MAX.to_be_bytes());
+
+        clock.advance(Duration::from_millis(2));
+        assert_eq!(get_timestamp(&clock).unwrap(), 1u32.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_version() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let (mut stream, _) = server.accept().await.unwrap();
+        client.write_u8(1).await.unwrap();
+
+        assert_eq!(
+            handshake(&mut stream).await.unwrap_err().to_string(),
+            "RTMP version 1 is unsupported"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_c1_not_zeroed() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        tokio::spawn(async move {
+            client.write_u8(3).await.unwrap();
+
+            let mut buf = [0; HANDSHAKE_CHUNK_SIZE];
+            buf[4] = 1;
+            client.write_all(&buf).await.unwrap();
+        });
+
+        let (mut stream, _) = server.accept().await.unwrap();
+
+        assert_eq!(
+            handshake(&mut stream).await.unwrap_err().to_string(),
+            "Invalid handshake: Zeroes field in handshake must be all zeroes, or C1 must carry a valid digest"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_c2_different_timestamp() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        tokio::spawn(async move {
+            client.write_u8(3).await.unwrap();
+
+            let mut buf = [0; HANDSHAKE_CHUNK_SIZE];
+            client.write_all(&buf).await.unwrap();
+
+            client.read_u8().await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+
+            buf[1] ^= 1; // guaranteed to be different
+            client.write_all(&buf).await.unwrap();
+        });
+
+        let (mut stream, _) = server.accept().await.unwrap();
+
+        assert_eq!(
+            handshake(&mut stream).await.unwrap_err().to_string(),
+            "Invalid handshake: Echoed timestamp does not match"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_c2_different_random_data() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        tokio::spawn(async move {
+            client.write_u8(3).await.unwrap();
+
+            let mut buf = [0; HANDSHAKE_CHUNK_SIZE];
+            client.write_all(&buf).await.unwrap();
+
+            client.read_u8().await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+
+            buf[8] ^= 1; // guaranteed to be different
+            client.write_all(&buf).await.unwrap();
+        });
+
+        let (mut stream, _) = server.accept().await.unwrap();
+
+        assert_eq!(
+            handshake(&mut stream).await.unwrap_err().to_string(),
+            "Invalid handshake: Random data echo does not match"
+        );
+    }
+}
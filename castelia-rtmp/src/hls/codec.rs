@@ -0,0 +1,208 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::messages::media::{NalUnits, ParseError};
+
+/// Start code in front of every NAL unit in an Annex B byte stream
+const START_CODE: [u8; 4] = [0x00, 0x00, 0x00, 0x01];
+
+/// Access unit delimiter NAL unit, which MPEG-TS requires at the start of every frame
+const ACCESS_UNIT_DELIMITER: [u8; 2] = [0x09, 0xF0];
+
+/// Size of an ADTS header without a CRC
+const ADTS_HEADER_SIZE: usize = 7;
+
+/// The parameter sets of an AVC stream, taken from the AVCDecoderConfigurationRecord that RTMP
+/// sends as the video sequence header
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvcConfig {
+    /// Size of the length in front of each NAL unit of a frame
+    nal_length_size: usize,
+    sps: Vec<Bytes>,
+    pps: Vec<Bytes>,
+}
+
+impl AvcConfig {
+    /// Parse an AVCDecoderConfigurationRecord:
+    /// - 1 byte version, profile, compatibility and level each
+    /// - 6 reserved bits, followed by 2 bits holding the NAL unit length size minus one
+    /// - 3 reserved bits, followed by 5 bits holding the number of SPS
+    /// - each SPS prefixed with its 2 byte length
+    /// - 1 byte number of PPS
+    /// - each PPS prefixed with its 2 byte length
+    pub fn parse(record: &[u8]) -> Result<Self, ParseError> {
+        let header = record.get(..6).ok_or(ParseError::Truncated)?;
+        let nal_length_size = usize::from(header[4] & 0b11) + 1;
+
+        let mut rest = &record[6..];
+        let sps = parse_parameter_sets(&mut rest, usize::from(header[5] & 0b11111))?;
+        let (&pps_count, mut rest) = rest.split_first().ok_or(ParseError::Truncated)?;
+        let pps = parse_parameter_sets(&mut rest, usize::from(pps_count))?;
+
+        Ok(Self {
+            nal_length_size,
+            sps,
+            pps,
+        })
+    }
+
+    /// Size of the length in front of each NAL unit of a frame
+    pub fn nal_length_size(&self) -> usize {
+        self.nal_length_size
+    }
+
+    /// Convert the NAL units of a frame to an Annex B access unit, starting with an access unit
+    /// delimiter. Keyframes get the parameter sets in front of them so decoders can start at any
+    /// segment
+    pub fn to_annex_b(&self, nal_units: NalUnits<'_>, keyframe: bool) -> Result<Bytes, ParseError> {
+        let mut buf = BytesMut::with_capacity(64);
+        buf.put_slice(&START_CODE);
+        buf.put_slice(&ACCESS_UNIT_DELIMITER);
+        if keyframe {
+            for parameter_set in self.sps.iter().chain(&self.pps) {
+                buf.put_slice(&START_CODE);
+                buf.put_slice(parameter_set);
+            }
+        }
+
+        for nal_unit in nal_units {
+            buf.put_slice(&START_CODE);
+            buf.put_slice(nal_unit?);
+        }
+        Ok(buf.freeze())
+    }
+}
+
+fn parse_parameter_sets(buf: &mut &[u8], count: usize) -> Result<Vec<Bytes>, ParseError> {
+    (0..count)
+        .map(|_| {
+            let length = buf.get(..2).ok_or(ParseError::Truncated)?;
+            let length = usize::from(u16::from_be_bytes([length[0], length[1]]));
+            let parameter_set = buf.get(2..2 + length).ok_or(ParseError::Truncated)?;
+            let parameter_set = Bytes::copy_from_slice(parameter_set);
+            *buf = &buf[2 + length..];
+            Ok(parameter_set)
+        })
+        .collect()
+}
+
+/// What an ADTS header needs from the AudioSpecificConfig that RTMP sends as the audio sequence
+/// header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AacConfig {
+    object_type: u8,
+    sampling_frequency_index: u8,
+    channel_configuration: u8,
+}
+
+impl AacConfig {
+    /// Parse the start of an AudioSpecificConfig: 5 bits object type, 4 bits sampling frequency
+    /// index and 4 bits channel configuration
+    pub fn parse(config: &[u8]) -> Result<Self, ParseError> {
+        let config = config.get(..2).ok_or(ParseError::Truncated)?;
+        Ok(Self {
+            object_type: config[0] >> 3,
+            sampling_frequency_index: (config[0] & 0b111) << 1 | config[1] >> 7,
+            channel_configuration: (config[1] >> 3) & 0b1111,
+        })
+    }
+
+    /// Prefix a raw AAC frame with an ADTS header, which is how MPEG-TS carries AAC
+    pub fn to_adts(self, frame: &[u8]) -> Bytes {
+        // the frame length is 13 bits and counts the header
+        let frame_length = ADTS_HEADER_SIZE + frame.len();
+        // ADTS can only signal the profiles of the first four object types
+        let profile = self.object_type.saturating_sub(1) & 0b11;
+
+        let mut buf = BytesMut::with_capacity(frame_length);
+        // 12 bit syncword, MPEG-4, layer 0 and no CRC
+        buf.put_slice(&[0xFF, 0xF1]);
+        buf.put_u8(
+            profile << 6 | self.sampling_frequency_index << 2 | self.channel_configuration >> 2,
+        );
+        buf.put_u8((self.channel_configuration & 0b11) << 6 | (frame_length >> 11) as u8 & 0b11);
+        buf.put_u8((frame_length >> 3) as u8);
+        // the rest is the buffer fullness, all ones for variable bitrate, and the number of raw
+        // data blocks minus one
+        buf.put_u8((frame_length as u8 & 0b111) << 5 | 0b11111);
+        buf.put_u8(0xFC);
+        buf.put_slice(frame);
+        buf.freeze()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn avc_config() -> AvcConfig {
+        AvcConfig::parse(&[
+            0x01, 0x64, 0x00, 0x1F, 0xFF, 0xE1, 0x00, 0x03, 0x67, 0x64, 0x00, 0x01, 0x00, 0x02,
+            0x68, 0xEE,
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_avc_config() {
+        assert_eq!(
+            avc_config(),
+            AvcConfig {
+                nal_length_size: 4,
+                sps: vec![Bytes::from_static(&[0x67, 0x64, 0x00])],
+                pps: vec![Bytes::from_static(&[0x68, 0xEE])],
+            }
+        );
+        assert!(matches!(
+            AvcConfig::parse(&[0x01, 0x64, 0x00, 0x1F, 0xFF, 0xE1, 0x00, 0x03, 0x67]),
+            Err(ParseError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn test_to_annex_b() {
+        let config = avc_config();
+        let frame = [
+            0x00, 0x00, 0x00, 0x02, 0x65, 0x88, 0x00, 0x00, 0x00, 0x01, 0x06,
+        ];
+
+        assert_eq!(
+            config.to_annex_b(NalUnits::new(&frame, 4), true).unwrap()[..],
+            [
+                0x00, 0x00, 0x00, 0x01, 0x09, 0xF0, 0x00, 0x00, 0x00, 0x01, 0x67, 0x64, 0x00, 0x00,
+                0x00, 0x00, 0x01, 0x68, 0xEE, 0x00, 0x00, 0x00, 0x01, 0x65, 0x88, 0x00, 0x00, 0x00,
+                0x01, 0x06,
+            ]
+        );
+        assert_eq!(
+            config
+                .to_annex_b(NalUnits::new(&frame[..6], 4), false)
+                .unwrap()[..],
+            [
+                0x00, 0x00, 0x00, 0x01, 0x09, 0xF0, 0x00, 0x00, 0x00, 0x01, 0x65, 0x88
+            ]
+        );
+        assert!(matches!(
+            config.to_annex_b(NalUnits::new(&frame[..5], 4), false),
+            Err(ParseError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn test_to_adts() {
+        // AAC LC, 44.1kHz, stereo
+        let config = AacConfig::parse(&[0x12, 0x10]).unwrap();
+        assert_eq!(
+            config,
+            AacConfig {
+                object_type: 2,
+                sampling_frequency_index: 4,
+                channel_configuration: 2,
+            }
+        );
+
+        assert_eq!(
+            config.to_adts(&[0x21, 0x10, 0x04])[..],
+            [0xFF, 0xF1, 0x50, 0x80, 0x01, 0x5F, 0xFC, 0x21, 0x10, 0x04]
+        );
+    }
+}
@@ -0,0 +1,446 @@
+use std::{collections::VecDeque, time::Duration};
+
+use bytes::{Bytes, BytesMut};
+use tracing::warn;
+
+use crate::{
+    hls::{
+        codec::{AacConfig, AvcConfig},
+        ts::TsMuxer,
+    },
+    messages::{
+        command::command_message_type,
+        media::{
+            AudioTagHeader, AvcPacket, ParseError, aac_packet_type, avc_packet_type, sound_format,
+        },
+    },
+    stream_registry::MediaPacket,
+};
+
+pub(crate) mod codec;
+mod ts;
+
+/// Segments are cut at the first keyframe after they are this long
+pub const DEFAULT_TARGET_DURATION: Duration = Duration::from_secs(4);
+
+/// How many of the latest segments the playlist lists
+pub const DEFAULT_PLAYLIST_LENGTH: usize = 6;
+
+/// Size of the header in front of AAC data in audio messages
+const AUDIO_TAG_HEADER_SIZE: usize = 2;
+
+/// A finished MPEG-TS segment
+#[derive(Debug, Clone)]
+pub struct Segment {
+    /// The media sequence number, which is also the segment's file name
+    pub sequence: u64,
+    pub duration: Duration,
+    pub data: Bytes,
+}
+
+/// The segment packets are being written to
+#[derive(Debug)]
+struct OpenSegment {
+    sequence: u64,
+    /// Timestamp of the keyframe the segment starts at
+    start: u32,
+    data: BytesMut,
+}
+
+/// Cuts the packets of a live H.264/AAC stream into MPEG-TS segments that start at keyframes,
+/// keeping a sliding window of the latest ones for the live playlist. Other codecs are dropped
+#[derive(Debug)]
+pub struct Segmenter {
+    target_duration: Duration,
+    playlist_length: usize,
+    muxer: TsMuxer,
+    avc: Option<AvcConfig>,
+    aac: Option<AacConfig>,
+    /// [`None`] until the first keyframe
+    current: Option<OpenSegment>,
+    segments: VecDeque<Segment>,
+    next_sequence: u64,
+    /// The longest segment so far, including ones that left the window
+    longest_segment: Duration,
+    /// Timestamp of the latest packet, where the open segment ends if the stream does
+    last_timestamp: u32,
+    /// Whether the stream has ended, so the playlist won't get any more segments
+    ended: bool,
+}
+
+impl Default for Segmenter {
+    fn default() -> Self {
+        Self {
+            target_duration: DEFAULT_TARGET_DURATION,
+            playlist_length: DEFAULT_PLAYLIST_LENGTH,
+            muxer: TsMuxer::new(),
+            avc: None,
+            aac: None,
+            current: None,
+            segments: VecDeque::new(),
+            next_sequence: 0,
+            longest_segment: Duration::ZERO,
+            last_timestamp: 0,
+            ended: false,
+        }
+    }
+}
+
+impl Segmenter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_target_duration(mut self, target_duration: Duration) -> Self {
+        self.target_duration = target_duration;
+        self
+    }
+
+    pub fn with_playlist_length(mut self, playlist_length: usize) -> Self {
+        self.playlist_length = playlist_length.max(1);
+        self
+    }
+
+    /// Mux a packet into the current segment, starting a new segment if it is a keyframe and
+    /// the current one is long enough. Malformed packets are dropped
+    pub fn push(&mut self, packet: &MediaPacket) {
+        if self.ended {
+            return;
+        }
+        self.last_timestamp = packet.timestamp;
+        let result = match packet.message_type_id {
+            command_message_type::VIDEO => self.push_video(packet),
+            command_message_type::AUDIO => self.push_audio(packet),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!("Dropping media packet the HLS segmenter can't mux: {e}");
+        }
+    }
+
+    /// Finish the open segment at the latest packet and end the playlist, once the stream has
+    /// ended
+    pub fn end(&mut self) {
+        if let Some(segment) = self.current.take() {
+            self.finish(segment, self.last_timestamp);
+        }
+        self.ended = true;
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.ended
+    }
+
+    /// The finished segment with this sequence number, if it is still in the window
+    pub fn segment(&self, sequence: u64) -> Option<&Segment> {
+        self.segments
+            .iter()
+            .find(|segment| segment.sequence == sequence)
+    }
+
+    /// The live media playlist of the finished segments, which are at `{sequence}.ts` relative
+    /// to it
+    pub fn playlist(&self) -> String {
+        // every segment's duration has to round to at most the target duration, which must not
+        // change over the life of the playlist, so it covers segments no longer in the window too
+        let target_duration = (self.longest_segment.as_secs_f64().ceil() as u64)
+            .max(self.target_duration.as_secs())
+            .max(1);
+        let media_sequence = self.segments.front().map_or(0, |segment| segment.sequence);
+
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{target_duration}\n#EXT-X-MEDIA-SEQUENCE:{media_sequence}\n"
+        );
+        for segment in &self.segments {
+            playlist.push_str(&format!(
+                "#EXTINF:{:.3},\n{}.ts\n",
+                segment.duration.as_secs_f64(),
+                segment.sequence
+            ));
+        }
+        if self.ended {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+        }
+        playlist
+    }
+
+    fn push_video(&mut self, packet: &MediaPacket) -> Result<(), ParseError> {
+        let video = match AvcPacket::parse(&packet.payload) {
+            Ok(video) => video,
+            Err(ParseError::NotAvc(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        match video.packet_type {
+            avc_packet_type::SEQUENCE_HEADER => self.avc = Some(AvcConfig::parse(video.data)?),
+            avc_packet_type::NALU => {
+                let Some(avc) = &self.avc else {
+                    return Ok(());
+                };
+                let keyframe = video.is_keyframe();
+                let access_unit = avc.to_annex_b(video.nal_units(avc), keyframe)?;
+                if keyframe {
+                    self.keyframe(packet.timestamp);
+                }
+                let Some(segment) = &mut self.current else {
+                    return Ok(());
+                };
+                self.muxer.write_video(
+                    &mut segment.data,
+                    packet.timestamp,
+                    video.composition_time,
+                    keyframe,
+                    &access_unit,
+                );
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn push_audio(&mut self, packet: &MediaPacket) -> Result<(), ParseError> {
+        let header = AudioTagHeader::parse(&packet.payload)?;
+        if header.codec_id != sound_format::AAC {
+            return Ok(());
+        }
+        let data = &packet.payload[AUDIO_TAG_HEADER_SIZE..];
+
+        match header.aac_packet_type {
+            Some(aac_packet_type::SEQUENCE_HEADER) => self.aac = Some(AacConfig::parse(data)?),
+            Some(aac_packet_type::RAW) => {
+                let (Some(aac), Some(segment)) = (&self.aac, &mut self.current) else {
+                    return Ok(());
+                };
+                self.muxer
+                    .write_audio(&mut segment.data, packet.timestamp, &aac.to_adts(data));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Start a new segment at the keyframe at `timestamp`, unless the current one is too short
+    fn keyframe(&mut self, timestamp: u32) {
+        if let Some(segment) = &self.current
+            && elapsed(segment.start, timestamp) < self.target_duration
+        {
+            return;
+        }
+
+        if let Some(segment) = self.current.take() {
+            self.finish(segment, timestamp);
+        }
+
+        // every segment starts with the tables so players can start at any of them
+        let mut data = BytesMut::new();
+        self.muxer.write_tables(&mut data);
+        self.current = Some(OpenSegment {
+            sequence: self.next_sequence,
+            start: timestamp,
+            data,
+        });
+        self.next_sequence += 1;
+    }
+
+    /// Add a segment that ends at `timestamp` to the window
+    fn finish(&mut self, segment: OpenSegment, timestamp: u32) {
+        let duration = elapsed(segment.start, timestamp);
+        self.longest_segment = self.longest_segment.max(duration);
+        self.segments.push_back(Segment {
+            sequence: segment.sequence,
+            duration,
+            data: segment.data.freeze(),
+        });
+        while self.segments.len() > self.playlist_length {
+            self.segments.pop_front();
+        }
+    }
+}
+
+/// Time between two timestamps in milliseconds, which wrap around
+fn elapsed(start: u32, end: u32) -> Duration {
+    Duration::from_millis(end.wrapping_sub(start).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{
+        AUDIO_FRAME, AUDIO_SEQUENCE_HEADER, INTER_FRAME, KEYFRAME, VIDEO_SEQUENCE_HEADER, audio,
+        video,
+    };
+
+    /// Push a stream with keyframes at the given times and an inter frame and audio frame
+    /// every 500ms in between
+    fn push_stream(segmenter: &mut Segmenter, keyframes: &[u32], end: u32) {
+        segmenter.push(&video(0, VIDEO_SEQUENCE_HEADER));
+        segmenter.push(&audio(0, AUDIO_SEQUENCE_HEADER));
+        for timestamp in (0..=end).step_by(500) {
+            let frame = if keyframes.contains(&timestamp) {
+                KEYFRAME
+            } else {
+                INTER_FRAME
+            };
+            segmenter.push(&video(timestamp, frame));
+            segmenter.push(&audio(timestamp, AUDIO_FRAME));
+        }
+    }
+
+    fn durations(segmenter: &Segmenter) -> Vec<(u64, Duration)> {
+        segmenter
+            .segments
+            .iter()
+            .map(|segment| (segment.sequence, segment.duration))
+            .collect()
+    }
+
+    #[test]
+    fn test_segments_are_cut_at_keyframes() {
+        let mut segmenter = Segmenter::new().with_target_duration(Duration::from_secs(2));
+        // the keyframes at 1s and 3.5s are too early to cut at
+        push_stream(&mut segmenter, &[0, 1000, 2000, 3500, 4000, 6000], 7000);
+
+        assert_eq!(
+            durations(&segmenter),
+            [
+                (0, Duration::from_secs(2)),
+                (1, Duration::from_secs(2)),
+                (2, Duration::from_secs(2)),
+            ]
+        );
+        assert_eq!(segmenter.current.as_ref().unwrap().start, 6000);
+
+        let segment = segmenter.segment(1).unwrap();
+        assert_eq!(segment.data.len() % ts::PACKET_SIZE, 0);
+        // tables first, then the keyframe the segment starts at
+        assert_eq!(segment.data[..4], [0x47, 0x40, 0x00, 0x11]);
+        assert_eq!(segment.data[2 * ts::PACKET_SIZE + 5], 0x50);
+        assert!(segmenter.segment(3).is_none());
+    }
+
+    #[test]
+    fn test_late_keyframe_makes_longer_segment() {
+        let mut segmenter = Segmenter::new().with_target_duration(Duration::from_secs(2));
+        push_stream(&mut segmenter, &[0, 3500, 5500], 6000);
+
+        assert_eq!(
+            durations(&segmenter),
+            [
+                (0, Duration::from_millis(3500)),
+                (1, Duration::from_secs(2))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_packets_before_first_keyframe_are_dropped() {
+        let mut segmenter = Segmenter::new().with_target_duration(Duration::from_secs(1));
+        push_stream(&mut segmenter, &[1500, 3000], 3000);
+
+        let [segment] = durations(&segmenter)[..] else {
+            assert_eq!(durations(&segmenter).len(), 1);
+            return;
+        };
+        assert_eq!(segment, (0, Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let mut segmenter = Segmenter::new()
+            .with_target_duration(Duration::from_secs(1))
+            .with_playlist_length(3);
+        push_stream(&mut segmenter, &[0, 1000, 2000, 3000, 4000, 5000], 5000);
+
+        assert_eq!(
+            durations(&segmenter)
+                .iter()
+                .map(|(sequence, _)| *sequence)
+                .collect::<Vec<_>>(),
+            [2, 3, 4]
+        );
+        assert!(segmenter.segment(1).is_none());
+    }
+
+    #[test]
+    fn test_playlist() {
+        let mut segmenter = Segmenter::new()
+            .with_target_duration(Duration::from_secs(2))
+            .with_playlist_length(2);
+        assert_eq!(
+            segmenter.playlist(),
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:0\n"
+        );
+
+        push_stream(&mut segmenter, &[0, 2000, 4500, 6500], 6500);
+        assert_eq!(
+            segmenter.playlist(),
+            "#EXTM3U\n\
+             #EXT-X-VERSION:3\n\
+             #EXT-X-TARGETDURATION:3\n\
+             #EXT-X-MEDIA-SEQUENCE:1\n\
+             #EXTINF:2.500,\n\
+             1.ts\n\
+             #EXTINF:2.000,\n\
+             2.ts\n"
+        );
+    }
+
+    #[test]
+    fn test_target_duration_is_pinned() {
+        let mut segmenter = Segmenter::new()
+            .with_target_duration(Duration::from_secs(2))
+            .with_playlist_length(2);
+        // the 3.5s segment leaves the window, the target duration stays
+        push_stream(&mut segmenter, &[0, 3500, 5500, 7500, 9500], 9500);
+
+        assert_eq!(
+            durations(&segmenter),
+            [(2, Duration::from_secs(2)), (3, Duration::from_secs(2))]
+        );
+        assert!(segmenter.playlist().contains("#EXT-X-TARGETDURATION:4\n"));
+    }
+
+    #[test]
+    fn test_segments_span_several_gops() {
+        let mut segmenter = Segmenter::new().with_target_duration(Duration::from_secs(4));
+        // a keyframe every 2s
+        push_stream(
+            &mut segmenter,
+            &[0, 2000, 4000, 6000, 8000, 10000, 12000],
+            12000,
+        );
+
+        assert_eq!(
+            durations(&segmenter),
+            [
+                (0, Duration::from_secs(4)),
+                (1, Duration::from_secs(4)),
+                (2, Duration::from_secs(4)),
+            ]
+        );
+        assert!(segmenter.playlist().contains("#EXT-X-TARGETDURATION:4\n"));
+    }
+
+    #[test]
+    fn test_end() {
+        let mut segmenter = Segmenter::new().with_target_duration(Duration::from_secs(2));
+        push_stream(&mut segmenter, &[0, 2000], 3500);
+        assert!(!segmenter.playlist().contains("#EXT-X-ENDLIST"));
+
+        segmenter.end();
+        // the open segment is finished at the last packet
+        assert_eq!(
+            durations(&segmenter),
+            [
+                (0, Duration::from_secs(2)),
+                (1, Duration::from_millis(1500))
+            ]
+        );
+        assert!(segmenter.playlist().ends_with("1.ts\n#EXT-X-ENDLIST\n"));
+
+        // nothing is added once the stream has ended
+        segmenter.push(&video(4000, KEYFRAME));
+        assert_eq!(durations(&segmenter).len(), 2);
+        assert!(segmenter.current.is_none());
+    }
+}
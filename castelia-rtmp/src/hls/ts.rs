@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+
+use bytes::{BufMut, BytesMut};
+
+/// Every transport stream packet is this size
+pub const PACKET_SIZE: usize = 188;
+
+/// Size of the header in front of every packet
+const PACKET_HEADER_SIZE: usize = 4;
+
+const SYNC_BYTE: u8 = 0x47;
+
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+const AUDIO_PID: u16 = 0x0101;
+
+/// The only program in the stream
+const PROGRAM_NUMBER: u16 = 1;
+
+mod table_id {
+    pub const PAT: u8 = 0x00;
+    pub const PMT: u8 = 0x02;
+}
+
+mod stream_type {
+    pub const AVC: u8 = 0x1B;
+    /// AAC with ADTS headers
+    pub const AAC: u8 = 0x0F;
+}
+
+mod stream_id {
+    pub const VIDEO: u8 = 0xE0;
+    pub const AUDIO: u8 = 0xC0;
+}
+
+/// PES and PCR timestamps count at 90kHz
+const TICKS_PER_MS: u64 = 90;
+
+/// Timestamps are 33 bits and wrap around
+const TIMESTAMP_MASK: u64 = (1 << 33) - 1;
+
+/// Writes AVC and AAC access units as transport stream packets
+#[derive(Debug, Default)]
+pub struct TsMuxer {
+    /// The continuity counter of each PID, incremented for every packet with a payload
+    continuity_counters: HashMap<u16, u8>,
+}
+
+impl TsMuxer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write the PAT and PMT, which players need before they can find the audio and video
+    pub fn write_tables(&mut self, buf: &mut BytesMut) {
+        // program number followed by the PMT PID
+        let mut pat = BytesMut::new();
+        pat.put_u16(PROGRAM_NUMBER);
+        pat.put_u16(0xE000 | PMT_PID);
+        self.write_section(buf, PAT_PID, table_id::PAT, 1, &pat);
+
+        // PCR PID and an empty program info, followed by the type and PID of each stream
+        let mut pmt = BytesMut::new();
+        pmt.put_u16(0xE000 | VIDEO_PID);
+        pmt.put_u16(0xF000);
+        for (stream_type, pid) in [(stream_type::AVC, VIDEO_PID), (stream_type::AAC, AUDIO_PID)] {
+            pmt.put_u8(stream_type);
+            pmt.put_u16(0xE000 | pid);
+            pmt.put_u16(0xF000);
+        }
+        self.write_section(buf, PMT_PID, table_id::PMT, PROGRAM_NUMBER, &pmt);
+    }
+
+    /// Write an Annex B access unit. Timestamps are in milliseconds, keyframes carry the PCR
+    /// and are marked as random access points
+    pub fn write_video(
+        &mut self,
+        buf: &mut BytesMut,
+        dts: u32,
+        composition_time: i32,
+        keyframe: bool,
+        access_unit: &[u8],
+    ) {
+        let dts = u64::from(dts) * TICKS_PER_MS;
+        let pts = dts.saturating_add_signed(i64::from(composition_time) * TICKS_PER_MS as i64);
+        let pes = pes_packet(stream_id::VIDEO, pts, Some(dts), access_unit);
+        let pcr = keyframe.then_some(dts);
+        self.write_payload(buf, VIDEO_PID, pcr, keyframe, &pes);
+    }
+
+    /// Write an ADTS frame, `pts` is in milliseconds
+    pub fn write_audio(&mut self, buf: &mut BytesMut, pts: u32, frame: &[u8]) {
+        let pes = pes_packet(stream_id::AUDIO, u64::from(pts) * TICKS_PER_MS, None, frame);
+        self.write_payload(buf, AUDIO_PID, None, false, &pes);
+    }
+
+    /// Write a PSI section in a single packet:
+    /// - 1 byte pointer field, always 0 since the section starts right after it
+    /// - 1 byte table id
+    /// - 4 bits flags, followed by 12 bits section length
+    /// - 2 byte table id extension
+    /// - 1 byte version, 1 byte section number and 1 byte last section number
+    /// - the table, followed by the 4 byte CRC
+    fn write_section(
+        &mut self,
+        buf: &mut BytesMut,
+        pid: u16,
+        table_id: u8,
+        table_id_extension: u16,
+        table: &[u8],
+    ) {
+        let mut section = BytesMut::with_capacity(PACKET_SIZE);
+        section.put_u8(table_id);
+        // the section length counts everything after it, including the CRC
+        section.put_u16(0xB000 | (5 + table.len() + 4) as u16);
+        section.put_u16(table_id_extension);
+        // version 0, current
+        section.put_u8(0xC1);
+        section.put_u8(0);
+        section.put_u8(0);
+        section.put_slice(table);
+        section.put_u32(crc32(&section));
+
+        let counter = self.next_continuity_counter(pid);
+        buf.put_slice(&packet_header(pid, true, false, counter));
+        buf.put_u8(0);
+        buf.put_slice(&section);
+        // the rest of the packet is stuffed
+        buf.put_bytes(0xFF, PACKET_SIZE - PACKET_HEADER_SIZE - 1 - section.len());
+    }
+
+    /// Split a PES packet over as many packets as it takes. The first packet gets an adaptation
+    /// field if there is a PCR or random access point, the last one is padded out with stuffing
+    /// in its adaptation field
+    fn write_payload(
+        &mut self,
+        buf: &mut BytesMut,
+        pid: u16,
+        pcr: Option<u64>,
+        random_access: bool,
+        mut payload: &[u8],
+    ) {
+        let mut first = true;
+        while !payload.is_empty() {
+            // the adaptation field without its length
+            let mut adaptation_field = Vec::new();
+            if first && (pcr.is_some() || random_access) {
+                let mut flags = 0;
+                if random_access {
+                    flags |= 0x40;
+                }
+                if pcr.is_some() {
+                    flags |= 0x10;
+                }
+                adaptation_field.push(flags);
+                if let Some(pcr) = pcr {
+                    // 33 bit base, 6 reserved bits and a 9 bit extension, which is left at 0
+                    let pcr = (pcr & TIMESTAMP_MASK) << 15 | 0x7E00;
+                    adaptation_field.extend_from_slice(&pcr.to_be_bytes()[2..]);
+                }
+            }
+
+            let mut adaptation_field_size = if adaptation_field.is_empty() {
+                0
+            } else {
+                1 + adaptation_field.len()
+            };
+            let space = PACKET_SIZE - PACKET_HEADER_SIZE - adaptation_field_size;
+            let chunk_size = payload.len().min(space);
+            let stuffing = space - chunk_size;
+            if stuffing > 0 {
+                // an adaptation field that is only its length byte is one byte of stuffing
+                if adaptation_field.is_empty() {
+                    if stuffing > 1 {
+                        adaptation_field.push(0);
+                        adaptation_field.resize(stuffing - 1, 0xFF);
+                    }
+                } else {
+                    adaptation_field.resize(adaptation_field.len() + stuffing, 0xFF);
+                }
+                adaptation_field_size += stuffing;
+            }
+
+            let counter = self.next_continuity_counter(pid);
+            buf.put_slice(&packet_header(
+                pid,
+                first,
+                adaptation_field_size > 0,
+                counter,
+            ));
+            if adaptation_field_size > 0 {
+                buf.put_u8(adaptation_field.len() as u8);
+                buf.put_slice(&adaptation_field);
+            }
+            buf.put_slice(&payload[..chunk_size]);
+
+            payload = &payload[chunk_size..];
+            first = false;
+        }
+    }
+
+    fn next_continuity_counter(&mut self, pid: u16) -> u8 {
+        let counter = self.continuity_counters.entry(pid).or_default();
+        let current = *counter;
+        *counter = (*counter + 1) & 0x0F;
+        current
+    }
+}
+
+/// The packet header:
+/// - 1 byte sync byte
+/// - 3 bits flags, the middle one set on the packet a PES packet or section starts in
+/// - 13 bits PID
+/// - 2 bits scrambling, 2 bits saying whether there is an adaptation field and a payload,
+///   followed by 4 bits continuity counter
+fn packet_header(pid: u16, unit_start: bool, adaptation_field: bool, counter: u8) -> [u8; 4] {
+    let [pid_high, pid_low] = (pid & 0x1FFF).to_be_bytes();
+    [
+        SYNC_BYTE,
+        if unit_start { 0x40 } else { 0 } | pid_high,
+        pid_low,
+        if adaptation_field { 0x30 } else { 0x10 } | counter,
+    ]
+}
+
+/// A PES packet with a PTS, and a DTS if it differs from the PTS:
+/// - 3 byte start code prefix, followed by 1 byte stream id
+/// - 2 byte packet length, 0 if it is too long to fit
+/// - 2 bytes flags, the second saying which timestamps there are
+/// - 1 byte header data length, followed by the timestamps
+fn pes_packet(stream_id: u8, pts: u64, dts: Option<u64>, data: &[u8]) -> BytesMut {
+    let dts = dts.filter(|&dts| dts != pts);
+    let header_data_length = if dts.is_some() { 10 } else { 5 };
+    // the packet length counts everything after it
+    let packet_length = 3 + header_data_length + data.len();
+
+    let mut pes = BytesMut::with_capacity(9 + header_data_length + data.len());
+    pes.put_slice(&[0x00, 0x00, 0x01, stream_id]);
+    pes.put_u16(u16::try_from(packet_length).unwrap_or(0));
+    pes.put_u8(0x80);
+    match dts {
+        Some(dts) => {
+            pes.put_u8(0xC0);
+            pes.put_u8(header_data_length as u8);
+            put_timestamp(&mut pes, 0b0011, pts);
+            put_timestamp(&mut pes, 0b0001, dts);
+        }
+        None => {
+            pes.put_u8(0x80);
+            pes.put_u8(header_data_length as u8);
+            put_timestamp(&mut pes, 0b0010, pts);
+        }
+    }
+    pes.put_slice(data);
+    pes
+}
+
+/// A 33 bit timestamp spread over 5 bytes, after a 4 bit prefix and with a marker bit after
+/// every part
+fn put_timestamp(buf: &mut BytesMut, prefix: u8, timestamp: u64) {
+    let timestamp = timestamp & TIMESTAMP_MASK;
+    buf.put_u8(prefix << 4 | ((timestamp >> 29) as u8 & 0b1110) | 1);
+    buf.put_u16(((timestamp >> 14) as u16 & 0xFFFE) | 1);
+    buf.put_u16(((timestamp << 1) as u16 & 0xFFFE) | 1);
+}
+
+/// The CRC-32/MPEG-2 that ends every PSI section
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0xFFFFFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte) << 24, |crc, _| {
+            if crc & 0x80000000 == 0 {
+                crc << 1
+            } else {
+                crc << 1 ^ 0x04C11DB7
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0x0376E6E7);
+    }
+
+    #[test]
+    fn test_write_tables() {
+        let mut buf = BytesMut::new();
+        TsMuxer::new().write_tables(&mut buf);
+
+        assert_eq!(buf.len(), 2 * PACKET_SIZE);
+        // the well known PAT of a single program with its PMT on 0x1000
+        assert_eq!(
+            buf[..21],
+            [
+                0x47, 0x40, 0x00, 0x10, 0x00, 0x00, 0xB0, 0x0D, 0x00, 0x01, 0xC1, 0x00, 0x00, 0x00,
+                0x01, 0xF0, 0x00, 0x2A, 0xB1, 0x04, 0xB2
+            ]
+        );
+        assert!(buf[21..PACKET_SIZE].iter().all(|&byte| byte == 0xFF));
+        assert_eq!(buf[PACKET_SIZE..PACKET_SIZE + 4], [0x47, 0x50, 0x00, 0x10]);
+    }
+
+    #[test]
+    fn test_write_video() {
+        let mut muxer = TsMuxer::new();
+        let mut buf = BytesMut::new();
+        let access_unit = vec![0xAB; 400];
+        muxer.write_video(&mut buf, 1000, 40, true, &access_unit);
+
+        assert_eq!(buf.len() % PACKET_SIZE, 0);
+        let packets: Vec<_> = buf.chunks(PACKET_SIZE).collect();
+        assert_eq!(packets.len(), 3);
+
+        // the first packet starts the PES packet and has the PCR
+        assert_eq!(packets[0][..4], [0x47, 0x41, 0x00, 0x30]);
+        assert_eq!(packets[0][4..6], [7, 0x50]);
+        let pcr_base = u64::from_be_bytes([
+            0,
+            0,
+            0,
+            packets[0][6],
+            packets[0][7],
+            packets[0][8],
+            packets[0][9],
+            packets[0][10],
+        ]) >> 7;
+        assert_eq!(pcr_base, 90_000);
+        let pes = &packets[0][12..];
+        assert_eq!(pes[..4], [0x00, 0x00, 0x01, stream_id::VIDEO]);
+        assert_eq!(pes[7..9], [0xC0, 10]);
+
+        // the rest continue it, with stuffing in the last one
+        assert_eq!(packets[1][..4], [0x47, 0x01, 0x00, 0x11]);
+        assert_eq!(packets[2][..4], [0x47, 0x01, 0x00, 0x32]);
+        assert_eq!(*packets[2].last().unwrap(), 0xAB);
+        let last_chunk = PACKET_SIZE - PACKET_HEADER_SIZE - 1 - usize::from(packets[2][4]);
+        let pes_size = 19 + access_unit.len();
+        assert_eq!(
+            last_chunk,
+            pes_size - (PACKET_SIZE - 12) - (PACKET_SIZE - 4)
+        );
+    }
+
+    #[test]
+    fn test_single_byte_stuffing() {
+        let mut buf = BytesMut::new();
+        // a 14 byte PES header plus this fills a packet but one byte
+        let frame = vec![0xAB; PACKET_SIZE - PACKET_HEADER_SIZE - 14 - 1];
+        TsMuxer::new().write_audio(&mut buf, 0, &frame);
+
+        assert_eq!(buf.len(), PACKET_SIZE);
+        assert_eq!(buf[3], 0x30);
+        assert_eq!(buf[4], 0);
+        assert_eq!(buf[5..9], [0x00, 0x00, 0x01, stream_id::AUDIO]);
+    }
+
+    #[test]
+    fn test_put_timestamp() {
+        let mut buf = BytesMut::new();
+        put_timestamp(&mut buf, 0b0010, 90_000);
+        assert_eq!(buf[..], [0x21, 0x00, 0x05, 0xBF, 0x21]);
+    }
+}
@@ -1,8 +1,29 @@
+pub mod amf;
+pub mod app;
+pub mod config;
+pub mod connection_registry;
+pub mod events;
+pub mod flv;
+pub mod hls;
+pub mod logging;
+pub mod record;
+pub mod relay;
 pub mod rtmp;
 
-mod amf;
 mod chunks;
+mod clock;
 mod handshake;
 mod messages;
 mod netconnection;
 mod netstream;
+mod output_window;
+pub mod stats;
+pub mod stream_registry;
+pub mod sync;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod url;
+
+/// The message parsers, for fuzz targets to feed arbitrary bytes
+#[cfg(feature = "fuzzing")]
+pub use messages::{Message, ParseMessageError, parse_message_from_bytes};
@@ -0,0 +1,88 @@
+use std::env;
+
+/// How log lines are written, picked with the `RUST_LOG_FORMAT` environment variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines, the default
+    Pretty,
+    /// One JSON object per line, with span fields like the RTMP peer address kept as fields for
+    /// log aggregators
+    Json,
+}
+
+impl LogFormat {
+    pub fn from_env() -> Self {
+        Self::parse(env::var("RUST_LOG_FORMAT").ok().as_deref())
+    }
+
+    fn parse(format: Option<&str>) -> Self {
+        match format {
+            Some(format) if format.eq_ignore_ascii_case("json") => Self::Json,
+            _ => Self::Pretty,
+        }
+    }
+}
+
+pub fn init(format: LogFormat) {
+    let subscriber = tracing_subscriber::fmt();
+    match format {
+        LogFormat::Pretty => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::{info, info_span};
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(LogFormat::parse(None), LogFormat::Pretty);
+        assert_eq!(LogFormat::parse(Some("text")), LogFormat::Pretty);
+        assert_eq!(LogFormat::parse(Some("json")), LogFormat::Json);
+        assert_eq!(LogFormat::parse(Some("JSON")), LogFormat::Json);
+    }
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = info_span!("RTMP connection", address = "127.0.0.1:1935").entered();
+            info!("Publishing live/mystream");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(output.trim_end()).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "Publishing live/mystream");
+        assert_eq!(line["span"]["name"], "RTMP connection");
+        assert_eq!(line["span"]["address"], "127.0.0.1:1935");
+    }
+}
@@ -0,0 +1,145 @@
+use thiserror::Error;
+
+/// Size of the FLV tag style header in front of every sub-message
+const SUB_MESSAGE_HEADER_SIZE: usize = 11;
+
+/// Size of the back pointer after every sub-message
+const BACK_POINTER_SIZE: usize = 4;
+
+#[derive(Error, Debug)]
+pub enum ParseError {
+    #[error("Aggregate message ended in the middle of a sub-message")]
+    Truncated,
+    #[error("Back pointer {found} does not match the sub-message size {expected}")]
+    BackPointerMismatch { expected: u32, found: u32 },
+}
+
+/// One of the messages bundled into an aggregate message
+#[derive(Debug, PartialEq)]
+pub struct SubMessage<'a> {
+    pub message_type_id: u8,
+    pub timestamp: u32,
+    pub payload: &'a [u8],
+}
+
+/// Split an aggregate message into its sub-messages.
+///
+/// Each sub-message is laid out like an FLV tag:
+/// - 1 byte message type id
+/// - 3 byte payload size
+/// - 3 byte timestamp, followed by 1 byte holding the upper 8 bits of the timestamp
+/// - 3 byte stream id
+/// - the payload
+/// - 4 byte back pointer, the size of the header and payload
+pub fn parse(buf: &[u8]) -> Result<Vec<SubMessage<'_>>, ParseError> {
+    let mut sub_messages = vec![];
+    let mut rest = buf;
+    while !rest.is_empty() {
+        let header = rest
+            .get(..SUB_MESSAGE_HEADER_SIZE)
+            .ok_or(ParseError::Truncated)?;
+        let size = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+        let timestamp = u32::from_be_bytes([header[7], header[4], header[5], header[6]]);
+
+        let payload_end = SUB_MESSAGE_HEADER_SIZE + size as usize;
+        let payload = rest
+            .get(SUB_MESSAGE_HEADER_SIZE..payload_end)
+            .ok_or(ParseError::Truncated)?;
+
+        let back_pointer = rest
+            .get(payload_end..payload_end + BACK_POINTER_SIZE)
+            .ok_or(ParseError::Truncated)?;
+        let back_pointer = u32::from_be_bytes([
+            back_pointer[0],
+            back_pointer[1],
+            back_pointer[2],
+            back_pointer[3],
+        ]);
+        let expected = payload_end as u32;
+        if back_pointer != expected {
+            return Err(ParseError::BackPointerMismatch {
+                expected,
+                found: back_pointer,
+            });
+        }
+
+        sub_messages.push(SubMessage {
+            message_type_id: header[0],
+            timestamp,
+            payload,
+        });
+        rest = &rest[payload_end + BACK_POINTER_SIZE..];
+    }
+
+    Ok(sub_messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sub_message(message_type_id: u8, timestamp: u32, payload: &[u8]) -> Vec<u8> {
+        let size = payload.len() as u32;
+        let timestamp = timestamp.to_be_bytes();
+        [
+            &[message_type_id],
+            &size.to_be_bytes()[1..],
+            &timestamp[1..],
+            &timestamp[..1],
+            &[0, 0, 0],
+            payload,
+            &(SUB_MESSAGE_HEADER_SIZE as u32 + size).to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_parse_two_sub_messages() {
+        let buf = [
+            sub_message(9, 1000, &[0x17, 0x01, 0x00, 0x00, 0x00]),
+            sub_message(8, 0x01000020, &[0xAF, 0x01, 0x21]),
+        ]
+        .concat();
+
+        assert_eq!(
+            parse(&buf).unwrap(),
+            [
+                SubMessage {
+                    message_type_id: 9,
+                    timestamp: 1000,
+                    payload: &[0x17, 0x01, 0x00, 0x00, 0x00],
+                },
+                SubMessage {
+                    message_type_id: 8,
+                    timestamp: 0x01000020,
+                    payload: &[0xAF, 0x01, 0x21],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_bad_back_pointer() {
+        let mut buf = sub_message(9, 0, &[0x27, 0x01]);
+        let last = buf.len() - 1;
+        buf[last] += 1;
+
+        assert!(matches!(
+            parse(&buf),
+            Err(ParseError::BackPointerMismatch {
+                expected: 13,
+                found: 14
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let buf = sub_message(9, 0, &[0x27, 0x01, 0x02]);
+
+        assert!(matches!(
+            parse(&buf[..buf.len() - 5]),
+            Err(ParseError::Truncated)
+        ));
+    }
+}
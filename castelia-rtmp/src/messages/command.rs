@@ -1,7 +1,17 @@
+use bytes::Bytes;
 use thiserror::Error;
 use tracing::warn;
 
-use crate::{amf, netconnection::NetConnectionCommandType, netstream::NetStreamCommand};
+use crate::{
+    amf,
+    chunks::chunk_mux::DEFAULT_MAX_MESSAGE_LENGTH,
+    messages::{
+        aggregate,
+        media::{self, AudioTagHeader, VideoTagHeader},
+    },
+    netconnection::NetConnectionCommandType,
+    netstream::NetStreamCommand,
+};
 
 pub mod command_message_type {
     pub const COMMAND_AMF0: u8 = 20;
@@ -13,6 +23,37 @@ pub mod command_message_type {
     pub const AUDIO: u8 = 8;
     pub const VIDEO: u8 = 9;
     pub const AGGREGATE: u8 = 22;
+
+    /// Whether messages of this type are parsed as a [`super::CommandMessage`]
+    pub fn is_command(message_type_id: u8) -> bool {
+        matches!(
+            message_type_id,
+            COMMAND_AMF0
+                | COMMAND_AMF3
+                | DATA_AMF0
+                | DATA_AMF3
+                | SHARED_OBJECT_AMF0
+                | SHARED_OBJECT_AMF3
+                | AUDIO
+                | VIDEO
+                | AGGREGATE
+        )
+    }
+}
+
+/// Decoder for command and data payloads. No string in one can be longer than the largest message
+/// the chunk multiplexer accepts, so a longer declared length is rejected up front
+pub fn command_decoder(buf: &[u8]) -> amf::Decoder<'_> {
+    amf::Decoder::new(buf).with_max_string_len(DEFAULT_MAX_MESSAGE_LENGTH as usize)
+}
+
+/// Encode the values of a command message, starting with the command name
+pub fn encode_command(values: &[amf::AMF0Value]) -> Result<Bytes, amf::EncodeError> {
+    let mut encoder = amf::Encoder::new();
+    for value in values {
+        encoder.encode(value)?;
+    }
+    Ok(encoder.finish().into())
 }
 
 #[derive(Error, Debug)]
@@ -35,8 +76,23 @@ pub enum ParseError {
         #[from]
         amf::CastError,
     ),
+    #[error("Invalid aggregate message")]
+    BadAggregate(
+        #[source]
+        #[from]
+        aggregate::ParseError,
+    ),
+    #[error("Invalid media tag")]
+    BadMediaTag(
+        #[source]
+        #[from]
+        media::ParseError,
+    ),
 }
 
+/// A command, data or media message. Every field of the message is parsed, including the ones
+/// nothing is handled with yet
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum CommandMessage<'a> {
     NetConnectionCommand {
@@ -49,12 +105,25 @@ pub enum CommandMessage<'a> {
         transaction_id: f64,
         command_object: amf::AMF0Value<'a>,
     },
-    Data(Vec<amf::AMF0Value<'a>>),
-    Audio(&'a [u8]),
-    Video(&'a [u8]),
+    /// A data message, such as the `onMetaData` describing a published stream
+    Data {
+        name: &'a str,
+        value: amf::AMF0Value<'a>,
+    },
+    Audio {
+        header: AudioTagHeader,
+        /// The whole tag, including the header
+        payload: &'a [u8],
+    },
+    Video {
+        header: VideoTagHeader,
+        /// The whole tag, including the header
+        payload: &'a [u8],
+    },
+    /// Several audio, video or data messages bundled into one
+    Aggregate(Vec<aggregate::SubMessage<'a>>),
     // leave unsupported for now, unless we see it in use
     // SharedObject,
-    // Aggregate,
 }
 
 impl<'a> CommandMessage<'a> {
@@ -65,16 +134,21 @@ impl<'a> CommandMessage<'a> {
         match *message_type_id {
             command_message_type::COMMAND_AMF0 => CommandMessage::parse_command(buf),
             command_message_type::DATA_AMF0 => CommandMessage::parse_data_message(buf),
-            command_message_type::AUDIO => Ok(CommandMessage::Audio(buf)),
-            command_message_type::VIDEO => Ok(CommandMessage::Video(buf)),
+            command_message_type::AUDIO => Ok(CommandMessage::Audio {
+                header: AudioTagHeader::parse(buf)?,
+                payload: buf,
+            }),
+            command_message_type::VIDEO => Ok(CommandMessage::Video {
+                header: VideoTagHeader::parse(buf)?,
+                payload: buf,
+            }),
 
             command_message_type::SHARED_OBJECT_AMF0 => {
                 warn!("Unhandled shared object message found");
                 Err(ParseError::InvalidMessageType(*message_type_id))
             }
             command_message_type::AGGREGATE => {
-                warn!("Unhandled aggregate message found");
-                Err(ParseError::InvalidMessageType(*message_type_id))
+                Ok(CommandMessage::Aggregate(aggregate::parse(buf)?))
             }
             command_message_type::COMMAND_AMF3
             | command_message_type::DATA_AMF3
@@ -87,13 +161,21 @@ impl<'a> CommandMessage<'a> {
     }
 
     fn parse_data_message(buf: &'a [u8]) -> Result<CommandMessage<'a>, ParseError> {
-        let mut data = Vec::new();
-        let mut decoder = amf::Decoder::new(buf);
-        while !decoder.get_buf()?.is_empty() {
-            data.push(decoder.decode()?);
+        let mut decoder = command_decoder(buf);
+        let mut name = decoder.decode()?.try_into()?;
+
+        // publishers wrap the data they want stored on the stream, e.g. onMetaData, in @setDataFrame
+        if name == "@setDataFrame" {
+            name = decoder.decode()?.try_into()?;
         }
 
-        Ok(CommandMessage::Data(data))
+        let value = if decoder.remaining().is_empty() {
+            amf::AMF0Value::Undefined
+        } else {
+            decoder.decode()?
+        };
+
+        Ok(CommandMessage::Data { name, value })
     }
 
     fn parse_command(buf: &'a [u8]) -> Result<CommandMessage<'a>, ParseError> {
@@ -102,11 +184,11 @@ impl<'a> CommandMessage<'a> {
     }
 
     fn parse_netstream_command(buf: &'a [u8]) -> Result<CommandMessage<'a>, ParseError> {
-        let mut decoder = amf::Decoder::new(buf);
+        let mut decoder = command_decoder(buf);
         let (command_type, transaction_id, command_object) =
             CommandMessage::parse_base_command(&mut decoder)?;
 
-        let command = NetStreamCommand::parse(command_type, decoder.get_buf()?)?;
+        let command = NetStreamCommand::parse(command_type, decoder.remaining())?;
 
         Ok(CommandMessage::NetStreamCommand {
             command,
@@ -125,13 +207,162 @@ impl<'a> CommandMessage<'a> {
     }
 
     fn parse_netconnection_command(buf: &'a [u8]) -> Result<CommandMessage<'a>, ParseError> {
-        let mut decoder = amf::Decoder::new(buf);
+        let mut decoder = command_decoder(buf);
         let (command_type, transaction_id, command_object) =
             CommandMessage::parse_base_command(&mut decoder)?;
+        let command_type = NetConnectionCommandType::parse(command_type, decoder.remaining())?;
+
         Ok(CommandMessage::NetConnectionCommand {
-            command_type: command_type.into(),
+            command_type,
             transaction_id,
             command_object,
         })
     }
 }
+
+/// A command message that keeps the payload it was parsed from, so it can be forwarded as is,
+/// e.g. passing a connect or publish through to an upstream server, without being encoded again
+#[derive(Debug)]
+pub struct RetainedCommand<'a> {
+    pub message: CommandMessage<'a>,
+    raw: Bytes,
+}
+
+impl<'a> RetainedCommand<'a> {
+    pub fn parse(payload: &'a Bytes, message_type_id: u8) -> Result<Self, ParseError> {
+        Ok(Self {
+            message: CommandMessage::parse_message(payload, &message_type_id)?,
+            raw: payload.clone(),
+        })
+    }
+
+    /// The payload the command was parsed from, sharing its memory rather than copying it
+    pub fn raw_bytes(&self) -> &Bytes {
+        &self.raw
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amf::{AMF0Value, Properties};
+
+    fn encode(values: &[AMF0Value]) -> Bytes {
+        encode_command(values).unwrap()
+    }
+
+    fn obs_metadata() -> AMF0Value<'static> {
+        let properties = Properties::from([
+            ("duration", AMF0Value::Number(0.0)),
+            ("fileSize", AMF0Value::Number(0.0)),
+            ("width", AMF0Value::Number(1920.0)),
+            ("height", AMF0Value::Number(1080.0)),
+            ("videocodecid", AMF0Value::Number(7.0)),
+            ("videodatarate", AMF0Value::Number(6000.0)),
+            ("framerate", AMF0Value::Number(60.0)),
+            ("audiocodecid", AMF0Value::Number(10.0)),
+            ("audiodatarate", AMF0Value::Number(160.0)),
+            ("audiosamplerate", AMF0Value::Number(48000.0)),
+            ("audiosamplesize", AMF0Value::Number(16.0)),
+            ("audiochannels", AMF0Value::Number(2.0)),
+            ("stereo", AMF0Value::Boolean(true)),
+            ("2.1", AMF0Value::Boolean(false)),
+            ("3.1", AMF0Value::Boolean(false)),
+            ("4.0", AMF0Value::Boolean(false)),
+            ("4.1", AMF0Value::Boolean(false)),
+            ("5.1", AMF0Value::Boolean(false)),
+            ("7.1", AMF0Value::Boolean(false)),
+            (
+                "encoder",
+                AMF0Value::String("obs-output module (libobs version 30.0.2)"),
+            ),
+        ]);
+        AMF0Value::EcmaArray {
+            count: properties.len() as u32,
+            properties,
+        }
+    }
+
+    #[test]
+    fn test_parse_obs_set_data_frame() {
+        let buf = encode(&[
+            AMF0Value::String("@setDataFrame"),
+            AMF0Value::String("onMetaData"),
+            obs_metadata(),
+        ]);
+
+        let message =
+            CommandMessage::parse_message(&buf, &command_message_type::DATA_AMF0).unwrap();
+        assert!(matches!(
+            message,
+            CommandMessage::Data {
+                name: "onMetaData",
+                ..
+            }
+        ));
+
+        let CommandMessage::Data { value, .. } = message else {
+            return;
+        };
+        assert_eq!(value.get("width").and_then(|v| v.as_f64()), Some(1920.0));
+        assert_eq!(value.get("height").and_then(|v| v.as_f64()), Some(1080.0));
+        assert_eq!(
+            value.get("videocodecid").and_then(|v| v.as_f64()),
+            Some(7.0)
+        );
+        assert_eq!(
+            value.get("audiocodecid").and_then(|v| v.as_f64()),
+            Some(10.0)
+        );
+        assert_eq!(value.get("framerate").and_then(|v| v.as_f64()), Some(60.0));
+    }
+
+    #[test]
+    fn test_parse_data_without_value() {
+        let buf = encode(&[AMF0Value::String("onFI")]);
+
+        assert!(matches!(
+            CommandMessage::parse_message(&buf, &command_message_type::DATA_AMF0),
+            Ok(CommandMessage::Data {
+                name: "onFI",
+                value: AMF0Value::Undefined
+            })
+        ));
+    }
+
+    #[test]
+    fn test_string_longer_than_a_message_is_rejected() {
+        // a long string declaring 9 MiB, longer than any message the chunk multiplexer accepts
+        let payload = [0x0C, 0x00, 0x90, 0x00, 0x00];
+        assert!(matches!(
+            CommandMessage::parse_message(&payload, &command_message_type::COMMAND_AMF0),
+            Err(ParseError::DecodeError(amf::DecodeError::StringTooLong {
+                length: 0x90_0000,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_retained_command_keeps_payload() {
+        let payload = encode(&[
+            AMF0Value::String("publish"),
+            AMF0Value::Number(5.0),
+            AMF0Value::Null,
+            AMF0Value::String("mystream"),
+            AMF0Value::String("live"),
+        ]);
+
+        let command = RetainedCommand::parse(&payload, command_message_type::COMMAND_AMF0).unwrap();
+        assert!(matches!(
+            command.message,
+            CommandMessage::NetStreamCommand {
+                transaction_id: 5.0,
+                ..
+            }
+        ));
+        assert_eq!(command.raw_bytes(), &payload);
+        // the same memory, not a copy
+        assert_eq!(command.raw_bytes().as_ptr(), payload.as_ptr());
+    }
+}
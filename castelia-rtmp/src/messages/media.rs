@@ -0,0 +1,394 @@
+use thiserror::Error;
+
+use crate::hls::codec::AvcConfig;
+
+/// Values of the codec id in the audio tag header, every codec the FLV spec defines rather than
+/// just the ones that are handled
+#[allow(dead_code)]
+pub mod sound_format {
+    pub const MP3: u8 = 2;
+    pub const NELLYMOSER: u8 = 6;
+    pub const G711_A_LAW: u8 = 7;
+    pub const G711_MU_LAW: u8 = 8;
+    pub const AAC: u8 = 10;
+    pub const SPEEX: u8 = 11;
+}
+
+pub mod aac_packet_type {
+    pub const SEQUENCE_HEADER: u8 = 0;
+    pub const RAW: u8 = 1;
+}
+
+/// Values of the codec id in the video tag header, every codec the FLV spec defines rather than
+/// just the ones that are handled
+#[allow(dead_code)]
+pub mod video_codec {
+    pub const SORENSON_H263: u8 = 2;
+    pub const SCREEN_VIDEO: u8 = 3;
+    pub const VP6: u8 = 4;
+    pub const VP6_ALPHA: u8 = 5;
+    pub const SCREEN_VIDEO_V2: u8 = 6;
+    pub const AVC: u8 = 7;
+}
+
+#[allow(dead_code)]
+pub mod frame_type {
+    pub const KEYFRAME: u8 = 1;
+    pub const INTER_FRAME: u8 = 2;
+    pub const DISPOSABLE_INTER_FRAME: u8 = 3;
+    pub const GENERATED_KEYFRAME: u8 = 4;
+    pub const VIDEO_INFO: u8 = 5;
+}
+
+#[allow(dead_code)]
+pub mod avc_packet_type {
+    pub const SEQUENCE_HEADER: u8 = 0;
+    pub const NALU: u8 = 1;
+    pub const END_OF_SEQUENCE: u8 = 2;
+}
+
+/// Size of the FLV video tag header in front of the data of an AVC video message
+pub const AVC_TAG_HEADER_SIZE: usize = 5;
+
+#[derive(Error, Debug)]
+pub enum ParseError {
+    #[error("Media tag is too short")]
+    Truncated,
+    #[error("Video codec {0} is not AVC")]
+    NotAvc(u8),
+}
+
+/// The FLV audio tag header at the start of every audio message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioTagHeader {
+    pub codec_id: u8,
+    pub sample_rate: u32,
+    /// Bits per sample
+    pub sample_size: u8,
+    pub channels: u8,
+    /// Whether this is the AudioSpecificConfig or a raw frame, only set for AAC
+    pub aac_packet_type: Option<u8>,
+}
+
+impl AudioTagHeader {
+    pub fn parse(buf: &[u8]) -> Result<Self, ParseError> {
+        let flags = *buf.first().ok_or(ParseError::Truncated)?;
+        let codec_id = flags >> 4;
+
+        let aac_packet_type = if codec_id == sound_format::AAC {
+            Some(*buf.get(1).ok_or(ParseError::Truncated)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            codec_id,
+            sample_rate: match (flags >> 2) & 0b11 {
+                0 => 5512,
+                1 => 11025,
+                2 => 22050,
+                _ => 44100,
+            },
+            sample_size: if flags & 0b10 == 0 { 8 } else { 16 },
+            channels: if flags & 0b1 == 0 { 1 } else { 2 },
+            aac_packet_type,
+        })
+    }
+}
+
+/// The FLV video tag header at the start of every video message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VideoTagHeader {
+    pub frame_type: u8,
+    pub codec_id: u8,
+    /// Whether this is the decoder configuration or a frame, only set for AVC
+    pub avc_packet_type: Option<u8>,
+    /// Offset from the decode timestamp to the presentation timestamp in milliseconds, only set
+    /// for AVC
+    pub composition_time: Option<i32>,
+}
+
+impl VideoTagHeader {
+    pub fn parse(buf: &[u8]) -> Result<Self, ParseError> {
+        let flags = *buf.first().ok_or(ParseError::Truncated)?;
+        let codec_id = flags & 0x0F;
+
+        let (avc_packet_type, composition_time) = if codec_id == video_codec::AVC {
+            let avc_header = buf.get(1..5).ok_or(ParseError::Truncated)?;
+            // composition time is a signed 24 bit integer, shift it down to sign extend it
+            let composition_time =
+                i32::from_be_bytes([avc_header[1], avc_header[2], avc_header[3], 0]) >> 8;
+            (Some(avc_header[0]), Some(composition_time))
+        } else {
+            (None, None)
+        };
+
+        Ok(Self {
+            frame_type: flags >> 4,
+            codec_id,
+            avc_packet_type,
+            composition_time,
+        })
+    }
+
+    /// Whether a decoder can start decoding from this frame
+    pub fn is_keyframe(&self) -> bool {
+        matches!(
+            self.frame_type,
+            frame_type::KEYFRAME | frame_type::GENERATED_KEYFRAME
+        )
+    }
+}
+
+/// An AVC video message split into its tag header fields and the data after them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AvcPacket<'a> {
+    pub frame_type: u8,
+    /// Whether the data is the decoder configuration record or a frame, see [`avc_packet_type`]
+    pub packet_type: u8,
+    /// Offset from the decode timestamp to the presentation timestamp in milliseconds
+    pub composition_time: i32,
+    pub data: &'a [u8],
+}
+
+impl<'a> AvcPacket<'a> {
+    pub fn parse(payload: &'a [u8]) -> Result<Self, ParseError> {
+        let header = VideoTagHeader::parse(payload)?;
+        let (Some(packet_type), Some(composition_time)) =
+            (header.avc_packet_type, header.composition_time)
+        else {
+            return Err(ParseError::NotAvc(header.codec_id));
+        };
+
+        Ok(Self {
+            frame_type: header.frame_type,
+            packet_type,
+            composition_time,
+            data: payload
+                .get(AVC_TAG_HEADER_SIZE..)
+                .ok_or(ParseError::Truncated)?,
+        })
+    }
+
+    /// Whether a decoder can start decoding from this frame
+    pub fn is_keyframe(&self) -> bool {
+        matches!(
+            self.frame_type,
+            frame_type::KEYFRAME | frame_type::GENERATED_KEYFRAME
+        )
+    }
+
+    /// The NAL units of a frame, with the length size from the stream's decoder configuration
+    pub fn nal_units(&self, config: &AvcConfig) -> NalUnits<'a> {
+        NalUnits::new(self.data, config.nal_length_size())
+    }
+}
+
+/// Iterator over the length prefixed NAL units of an AVCC frame. Stops after the first error, when
+/// a length runs past the end of the frame
+#[derive(Debug, Clone)]
+pub struct NalUnits<'a> {
+    buf: &'a [u8],
+    length_size: usize,
+}
+
+impl<'a> NalUnits<'a> {
+    /// `length_size` is the size of the big endian length in front of each NAL unit, taken from
+    /// the decoder configuration record
+    pub fn new(buf: &'a [u8], length_size: usize) -> Self {
+        Self { buf, length_size }
+    }
+}
+
+impl<'a> Iterator for NalUnits<'a> {
+    type Item = Result<&'a [u8], ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        let nal_unit = self.buf.get(..self.length_size).and_then(|length| {
+            let length = length
+                .iter()
+                .fold(0, |length, &byte| length << 8 | usize::from(byte));
+            self.buf
+                .get(self.length_size..)
+                .and_then(|rest| rest.get(..length))
+        });
+        let Some(nal_unit) = nal_unit else {
+            self.buf = &[];
+            return Some(Err(ParseError::Truncated));
+        };
+        self.buf = &self.buf[self.length_size + nal_unit.len()..];
+        Some(Ok(nal_unit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_aac_sequence_header() {
+        // AAC, 44kHz, 16 bit, stereo, followed by the AudioSpecificConfig
+        let buf = [0xAF, 0x00, 0x12, 0x10];
+
+        assert_eq!(
+            AudioTagHeader::parse(&buf).unwrap(),
+            AudioTagHeader {
+                codec_id: sound_format::AAC,
+                sample_rate: 44100,
+                sample_size: 16,
+                channels: 2,
+                aac_packet_type: Some(aac_packet_type::SEQUENCE_HEADER),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_aac_raw_frame() {
+        let buf = [0xAF, 0x01, 0x21, 0x10, 0x04];
+
+        assert_eq!(
+            AudioTagHeader::parse(&buf).unwrap().aac_packet_type,
+            Some(aac_packet_type::RAW)
+        );
+    }
+
+    #[test]
+    fn test_parse_mp3() {
+        // MP3, 22kHz, 8 bit, mono
+        let buf = [0x28, 0xFF, 0xFB];
+
+        assert_eq!(
+            AudioTagHeader::parse(&buf).unwrap(),
+            AudioTagHeader {
+                codec_id: sound_format::MP3,
+                sample_rate: 22050,
+                sample_size: 8,
+                channels: 1,
+                aac_packet_type: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_truncated_audio() {
+        assert!(matches!(
+            AudioTagHeader::parse(&[]),
+            Err(ParseError::Truncated)
+        ));
+        assert!(matches!(
+            AudioTagHeader::parse(&[0xAF]),
+            Err(ParseError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn test_parse_avc_sequence_header() {
+        // keyframe, AVC, sequence header, followed by the AVCDecoderConfigurationRecord
+        let buf = [0x17, 0x00, 0x00, 0x00, 0x00, 0x01, 0x64, 0x00, 0x28];
+
+        let header = VideoTagHeader::parse(&buf).unwrap();
+        assert_eq!(
+            header,
+            VideoTagHeader {
+                frame_type: frame_type::KEYFRAME,
+                codec_id: video_codec::AVC,
+                avc_packet_type: Some(avc_packet_type::SEQUENCE_HEADER),
+                composition_time: Some(0),
+            }
+        );
+        assert!(header.is_keyframe());
+    }
+
+    #[test]
+    fn test_parse_avc_keyframe() {
+        let buf = [0x17, 0x01, 0x00, 0x00, 0x42, 0x00, 0x00, 0x00, 0x10, 0x65];
+
+        let header = VideoTagHeader::parse(&buf).unwrap();
+        assert!(header.is_keyframe());
+        assert_eq!(header.avc_packet_type, Some(avc_packet_type::NALU));
+        assert_eq!(header.composition_time, Some(0x42));
+    }
+
+    #[test]
+    fn test_parse_avc_inter_frame() {
+        // composition time of -40
+        let buf = [0x27, 0x01, 0xFF, 0xFF, 0xD8, 0x00, 0x00, 0x00, 0x08, 0x41];
+
+        let header = VideoTagHeader::parse(&buf).unwrap();
+        assert!(!header.is_keyframe());
+        assert_eq!(header.frame_type, frame_type::INTER_FRAME);
+        assert_eq!(header.composition_time, Some(-40));
+    }
+
+    #[test]
+    fn test_parse_non_avc_video() {
+        // keyframe, VP6
+        let header = VideoTagHeader::parse(&[0x14, 0x00]).unwrap();
+
+        assert!(header.is_keyframe());
+        assert_eq!(header.codec_id, video_codec::VP6);
+        assert_eq!(header.avc_packet_type, None);
+        assert_eq!(header.composition_time, None);
+    }
+
+    #[test]
+    fn test_parse_truncated_video() {
+        assert!(matches!(
+            VideoTagHeader::parse(&[0x17, 0x01, 0x00]),
+            Err(ParseError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn test_parse_avc_packet() {
+        // keyframe with a composition time of 80, holding an SEI and an IDR slice
+        let buf = [
+            0x17, 0x01, 0x00, 0x00, 0x50, //
+            0x00, 0x00, 0x00, 0x03, 0x06, 0x05, 0x01, //
+            0x00, 0x00, 0x00, 0x02, 0x65, 0x88,
+        ];
+
+        let packet = AvcPacket::parse(&buf).unwrap();
+        assert!(packet.is_keyframe());
+        assert_eq!(packet.packet_type, avc_packet_type::NALU);
+        assert_eq!(packet.composition_time, 80);
+        // no parameter sets, 4 byte NAL unit lengths
+        let config = AvcConfig::parse(&[0x01, 0x64, 0x00, 0x1F, 0xFF, 0xE0, 0x00]).unwrap();
+        let nal_units: Vec<_> = packet.nal_units(&config).map(Result::unwrap).collect();
+        assert_eq!(nal_units, [&[0x06, 0x05, 0x01][..], &[0x65, 0x88]]);
+    }
+
+    #[test]
+    fn test_nal_units_length_size_from_config() {
+        let buf = [0x27, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x41, 0x9A];
+        let packet = AvcPacket::parse(&buf).unwrap();
+
+        // 2 byte NAL unit lengths
+        let config = AvcConfig::parse(&[0x01, 0x64, 0x00, 0x1F, 0xFD, 0xE0, 0x00]).unwrap();
+        let nal_units: Vec<_> = packet.nal_units(&config).map(Result::unwrap).collect();
+        assert_eq!(nal_units, [&[0x41, 0x9A][..]]);
+    }
+
+    #[test]
+    fn test_parse_avc_packet_not_avc() {
+        assert!(matches!(
+            AvcPacket::parse(&[0x14, 0x00]),
+            Err(ParseError::NotAvc(video_codec::VP6))
+        ));
+    }
+
+    #[test]
+    fn test_nal_units_truncated() {
+        // the second NAL unit claims 4 bytes but only has 1
+        let buf = [0x00, 0x01, 0x65, 0x00, 0x04, 0x41];
+
+        let mut nal_units = NalUnits::new(&buf, 2);
+        assert_eq!(nal_units.next().unwrap().unwrap(), [0x65]);
+        assert!(matches!(nal_units.next(), Some(Err(ParseError::Truncated))));
+        assert!(nal_units.next().is_none());
+    }
+}
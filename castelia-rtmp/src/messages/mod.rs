@@ -1,12 +1,18 @@
+use bytes::Bytes;
 use thiserror::Error;
 
-use crate::messages::{
-    command::{CommandMessage, command_message_type},
-    protocol_control::{ProtolControlMessage, protocol_control_type},
-    user_control::{USER_CONTROL_TYPE, UserControlMessage},
+use crate::{
+    messages::{
+        command::{CommandMessage, command_message_type},
+        protocol_control::{ProtolControlMessage, protocol_control_type},
+        user_control::{USER_CONTROL_TYPE, UserControlMessage},
+    },
+    stream_registry::MediaPacket,
 };
 
+pub mod aggregate;
 pub mod command;
+pub mod media;
 pub mod protocol_control;
 pub mod user_control;
 
@@ -30,6 +36,92 @@ pub enum ParseMessageError {
     ),
 }
 
+/// Chunk streams the server sends messages on
+pub mod chunk_stream {
+    pub const PROTOCOL_CONTROL: u32 = 2;
+    pub const COMMAND: u32 = 3;
+    pub const AUDIO: u32 = 4;
+    pub const VIDEO: u32 = 6;
+}
+
+/// A message for the server to send to the peer
+#[derive(Debug, PartialEq)]
+pub enum OutgoingMessage {
+    Protocol(ProtolControlMessage),
+    UserControl(UserControlMessage),
+    /// An AMF0 encoded command, on the given message stream
+    Command {
+        message_stream_id: u32,
+        payload: Bytes,
+    },
+    /// An AMF0 encoded data message, on the given message stream
+    Data {
+        message_stream_id: u32,
+        payload: Bytes,
+    },
+    /// Audio, video or data forwarded from a publisher
+    Media {
+        message_stream_id: u32,
+        packet: MediaPacket,
+    },
+}
+
+impl OutgoingMessage {
+    pub fn chunk_stream_id(&self) -> u32 {
+        match self {
+            Self::Protocol(_) | Self::UserControl(_) => chunk_stream::PROTOCOL_CONTROL,
+            Self::Command { .. } | Self::Data { .. } => chunk_stream::COMMAND,
+            Self::Media { packet, .. } => match packet.message_type_id {
+                command_message_type::AUDIO => chunk_stream::AUDIO,
+                command_message_type::VIDEO => chunk_stream::VIDEO,
+                _ => chunk_stream::COMMAND,
+            },
+        }
+    }
+
+    pub fn message_type_id(&self) -> u8 {
+        match self {
+            Self::Protocol(message) => message.message_type_id(),
+            Self::UserControl(_) => USER_CONTROL_TYPE,
+            Self::Command { .. } => command_message_type::COMMAND_AMF0,
+            Self::Data { .. } => command_message_type::DATA_AMF0,
+            Self::Media { packet, .. } => packet.message_type_id,
+        }
+    }
+
+    pub fn message_stream_id(&self) -> u32 {
+        match self {
+            // protocol control and user control messages always go on message stream 0
+            Self::Protocol(_) | Self::UserControl(_) => 0,
+            Self::Command {
+                message_stream_id, ..
+            }
+            | Self::Data {
+                message_stream_id, ..
+            }
+            | Self::Media {
+                message_stream_id, ..
+            } => *message_stream_id,
+        }
+    }
+
+    pub fn timestamp(&self) -> u32 {
+        match self {
+            Self::Media { packet, .. } => packet.timestamp,
+            _ => 0,
+        }
+    }
+
+    pub fn payload(&self) -> Bytes {
+        match self {
+            Self::Protocol(message) => message.encode(),
+            Self::UserControl(message) => message.encode(),
+            Self::Command { payload, .. } | Self::Data { payload, .. } => payload.clone(),
+            Self::Media { packet, .. } => packet.payload.clone(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Message<'a> {
     Protocol(ProtolControlMessage),
@@ -50,18 +142,135 @@ impl<'a> Message<'a> {
 
             USER_CONTROL_TYPE => Self::UserControl(UserControlMessage::parse_message(buf)?),
 
-            command_message_type::COMMAND_AMF0
-            | command_message_type::COMMAND_AMF3
-            | command_message_type::DATA_AMF0
-            | command_message_type::DATA_AMF3
-            | command_message_type::SHARED_OBJECT_AMF0
-            | command_message_type::SHARED_OBJECT_AMF3
-            | command_message_type::AUDIO
-            | command_message_type::VIDEO
-            | command_message_type::AGGREGATE => {
+            id if command_message_type::is_command(id) => {
                 Self::Command(CommandMessage::parse_message(buf, &message_type_id)?)
             }
             id => return Err(ParseMessageError::InvalidMessageTypeId(id)),
         })
     }
 }
+
+/// Parse a complete, reassembled message without going through a connection, e.g. to fuzz the
+/// parsers. Arbitrary input fails with an error rather than panicking
+#[cfg(any(test, feature = "fuzzing"))]
+pub fn parse_message_from_bytes(
+    buf: &[u8],
+    message_type_id: u8,
+) -> Result<Message<'_>, ParseMessageError> {
+    Message::parse_message(buf, message_type_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+    use crate::amf::{AMF0Value, Properties};
+
+    /// Seeded so a failure is reproduced by running the test again
+    fn rng() -> StdRng {
+        StdRng::seed_from_u64(1935)
+    }
+
+    fn valid_messages() -> Vec<(u8, Vec<u8>)> {
+        let connect = command::encode_command(&[
+            AMF0Value::String("connect"),
+            AMF0Value::Number(1.0),
+            AMF0Value::Object(Properties::from([
+                ("app", AMF0Value::String("live")),
+                ("tcUrl", AMF0Value::String("rtmp://localhost/live")),
+            ])),
+        ])
+        .unwrap();
+        let metadata = command::encode_command(&[
+            AMF0Value::String("@setDataFrame"),
+            AMF0Value::String("onMetaData"),
+            AMF0Value::EcmaArray {
+                count: 1,
+                properties: Properties::from([("width", AMF0Value::Number(1920.0))]),
+            },
+        ])
+        .unwrap();
+        let aggregate = [
+            &[command_message_type::VIDEO, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0][..],
+            &[0x17, 0x01],
+            &13u32.to_be_bytes(),
+        ]
+        .concat();
+
+        vec![
+            (protocol_control_type::SET_CHUNK_SIZE, vec![0, 0, 0x10, 0]),
+            (
+                protocol_control_type::SET_PEER_BANDWIDTH,
+                vec![0, 0, 0x10, 0, 2],
+            ),
+            (USER_CONTROL_TYPE, vec![0, 3, 0, 0, 0, 1, 0, 0, 0x0B, 0xB8]),
+            (command_message_type::COMMAND_AMF0, connect.to_vec()),
+            (command_message_type::DATA_AMF0, metadata.to_vec()),
+            (command_message_type::AUDIO, vec![0xAF, 0x01]),
+            (command_message_type::VIDEO, vec![0x17, 0x01, 0, 0, 0]),
+            (command_message_type::AGGREGATE, aggregate),
+        ]
+    }
+
+    #[test]
+    fn test_truncated_messages() {
+        for (message_type_id, buf) in valid_messages() {
+            assert!(
+                parse_message_from_bytes(&buf, message_type_id).is_ok(),
+                "{message_type_id}"
+            );
+            // AMF messages can still parse when cut after a value, so those only must not panic
+            let amf = matches!(
+                message_type_id,
+                command_message_type::COMMAND_AMF0 | command_message_type::DATA_AMF0
+            );
+            // an empty aggregate holds no sub-messages
+            let start = usize::from(message_type_id == command_message_type::AGGREGATE);
+            for length in start..buf.len() {
+                let result = parse_message_from_bytes(&buf[..length], message_type_id);
+                if !amf {
+                    assert!(result.is_err(), "{message_type_id} cut to {length} bytes");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed_messages() {
+        assert!(matches!(
+            parse_message_from_bytes(&[0, 0, 0, 1], 0xFF),
+            Err(ParseMessageError::InvalidMessageTypeId(0xFF))
+        ));
+        // a command whose name isn't a string
+        assert!(parse_message_from_bytes(&[0x05, 0x05, 0x05], 20).is_err());
+        // an aggregate sub-message declaring more payload than there is
+        assert!(parse_message_from_bytes(&[9, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0, 0, 0, 0], 22).is_err());
+    }
+
+    #[test]
+    fn test_random_bytes_do_not_panic() {
+        let mut rng = rng();
+        for _ in 0..2000 {
+            let length = rng.random_range(0..64);
+            let mut buf = vec![0; length];
+            rng.fill(&mut buf[..]);
+            for message_type_id in 0..=u8::MAX {
+                let _ = parse_message_from_bytes(&buf, message_type_id);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mutated_messages_do_not_panic() {
+        let mut rng = rng();
+        for (message_type_id, buf) in valid_messages() {
+            for _ in 0..500 {
+                let mut buf = buf.clone();
+                let index = rng.random_range(0..buf.len());
+                buf[index] = rng.random();
+                let _ = parse_message_from_bytes(&buf, message_type_id);
+            }
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use bytes::{BufMut, Bytes, BytesMut};
 use thiserror::Error;
 
 use crate::messages::ParseMessageError;
@@ -10,6 +11,13 @@ pub mod protocol_control_type {
     pub const SET_PEER_BANDWIDTH: u8 = 6;
 }
 
+/// Values of the limit type in SetPeerBandwidth
+pub mod peer_bandwidth_limit {
+    pub const HARD: u8 = 0;
+    pub const SOFT: u8 = 1;
+    pub const DYNAMIC: u8 = 2;
+}
+
 #[derive(Error, Debug)]
 pub enum ParseError {
     #[error("Invalid message size")]
@@ -27,7 +35,7 @@ impl From<ParseError> for ParseMessageError {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum ProtolControlMessage {
     SetChunkSize(u32),
     Abort(u32),
@@ -51,9 +59,101 @@ impl ProtolControlMessage {
             protocol_control_type::WINDOW_ACK_SIZE => Self::AckWindowSize(data),
             protocol_control_type::SET_PEER_BANDWIDTH => Self::SetPeerBandwidth {
                 window_size: data,
-                limit_type: *buf.get(5).ok_or(ParseError::InvalidMessageSize)?,
+                limit_type: *buf.get(4).ok_or(ParseError::InvalidMessageSize)?,
             },
             _ => return Err(ParseError::InvalidMessageTypeId(*message_type_id)),
         })
     }
+
+    pub fn message_type_id(&self) -> u8 {
+        match self {
+            Self::SetChunkSize(_) => protocol_control_type::SET_CHUNK_SIZE,
+            Self::Abort(_) => protocol_control_type::ABORT,
+            Self::Ack(_) => protocol_control_type::ACK,
+            Self::AckWindowSize(_) => protocol_control_type::WINDOW_ACK_SIZE,
+            Self::SetPeerBandwidth { .. } => protocol_control_type::SET_PEER_BANDWIDTH,
+        }
+    }
+
+    /// Encode the message payload, 4 bytes for everything but SetPeerBandwidth which is 5.
+    /// Protocol control messages are sent on chunk stream 2 and message stream 0
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(5);
+        match *self {
+            Self::SetChunkSize(data)
+            | Self::Abort(data)
+            | Self::Ack(data)
+            | Self::AckWindowSize(data) => buf.put_u32(data),
+            Self::SetPeerBandwidth {
+                limit_type,
+                window_size,
+            } => {
+                buf.put_u32(window_size);
+                buf.put_u8(limit_type);
+            }
+        }
+        buf.freeze()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_set_peer_bandwidth() {
+        let buf = [0x00, 0x26, 0x25, 0xA0, 0x02];
+        assert!(matches!(
+            ProtolControlMessage::parse_message(&buf, &protocol_control_type::SET_PEER_BANDWIDTH),
+            Ok(ProtolControlMessage::SetPeerBandwidth {
+                window_size: 2_500_000,
+                limit_type: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_set_peer_bandwidth_missing_limit_type() {
+        let buf = [0x00, 0x26, 0x25, 0xA0];
+        assert!(matches!(
+            ProtolControlMessage::parse_message(&buf, &protocol_control_type::SET_PEER_BANDWIDTH),
+            Err(ParseError::InvalidMessageSize)
+        ));
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let messages = [
+            ProtolControlMessage::SetChunkSize(4096),
+            ProtolControlMessage::Abort(3),
+            ProtolControlMessage::Ack(0xDEADBEEF),
+            ProtolControlMessage::AckWindowSize(2_500_000),
+            ProtolControlMessage::SetPeerBandwidth {
+                limit_type: peer_bandwidth_limit::DYNAMIC,
+                window_size: 2_500_000,
+            },
+        ];
+
+        for message in messages {
+            let encoded = message.encode();
+            let expected_len = match message {
+                ProtolControlMessage::SetPeerBandwidth { .. } => 5,
+                _ => 4,
+            };
+            assert_eq!(encoded.len(), expected_len);
+            assert_eq!(
+                ProtolControlMessage::parse_message(&encoded, &message.message_type_id()).unwrap(),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_set_peer_bandwidth() {
+        let message = ProtolControlMessage::SetPeerBandwidth {
+            limit_type: peer_bandwidth_limit::HARD,
+            window_size: 2_500_000,
+        };
+        assert_eq!(message.encode()[..], [0x00, 0x26, 0x25, 0xA0, 0x00]);
+    }
 }
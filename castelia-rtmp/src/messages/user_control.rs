@@ -1,3 +1,4 @@
+use bytes::{BufMut, Bytes, BytesMut};
 use thiserror::Error;
 
 pub const USER_CONTROL_TYPE: u8 = 4;
@@ -10,7 +11,7 @@ pub enum ParseError {
     InvalidMessageSize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum UserControlMessage {
     StreamBegin(u32),
     StreamEOF(u32),
@@ -20,8 +21,10 @@ pub enum UserControlMessage {
         buffer_size_in_millis: u32,
     },
     StreamIsRecord(u32),
+    /// Sent by either end to check the other is still there, with the sender's timestamp
     PingRequest(u32),
-    PingRepsonse(u32),
+    /// The reply to a PingRequest, echoing its timestamp
+    PingResponse(u32),
 }
 
 impl UserControlMessage {
@@ -47,7 +50,7 @@ impl UserControlMessage {
             3 => Self::SetBufferLength {
                 message_stream_id: data,
                 buffer_size_in_millis: u32::from_be_bytes(
-                    buf.get(2..6)
+                    buf.get(6..10)
                         .ok_or(ParseError::InvalidMessageSize)?
                         .try_into()
                         .map_err(|_| ParseError::InvalidMessageSize)?,
@@ -55,8 +58,147 @@ impl UserControlMessage {
             },
             4 => Self::StreamIsRecord(data),
             5 => Self::PingRequest(data),
-            6 => Self::PingRepsonse(data),
+            6 => Self::PingResponse(data),
             _ => return Err(ParseError::InvalidEventType(event_type)),
         })
     }
+
+    /// Encode the 2 byte event type followed by the event data
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(10);
+        match *self {
+            Self::StreamBegin(data) => {
+                buf.put_u16(0);
+                buf.put_u32(data);
+            }
+            Self::StreamEOF(data) => {
+                buf.put_u16(1);
+                buf.put_u32(data);
+            }
+            Self::StreamDry(data) => {
+                buf.put_u16(2);
+                buf.put_u32(data);
+            }
+            Self::SetBufferLength {
+                message_stream_id,
+                buffer_size_in_millis,
+            } => {
+                buf.put_u16(3);
+                buf.put_u32(message_stream_id);
+                buf.put_u32(buffer_size_in_millis);
+            }
+            Self::StreamIsRecord(data) => {
+                buf.put_u16(4);
+                buf.put_u32(data);
+            }
+            Self::PingRequest(data) => {
+                buf.put_u16(5);
+                buf.put_u32(data);
+            }
+            Self::PingResponse(data) => {
+                buf.put_u16(6);
+                buf.put_u32(data);
+            }
+        }
+        buf.freeze()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event_type: u16, data: &[u32]) -> Vec<u8> {
+        let mut buf = event_type.to_be_bytes().to_vec();
+        for value in data {
+            buf.extend(value.to_be_bytes());
+        }
+        buf
+    }
+
+    #[test]
+    fn test_parse_events() {
+        assert!(matches!(
+            UserControlMessage::parse_message(&event(0, &[1])),
+            Ok(UserControlMessage::StreamBegin(1))
+        ));
+        assert!(matches!(
+            UserControlMessage::parse_message(&event(1, &[2])),
+            Ok(UserControlMessage::StreamEOF(2))
+        ));
+        assert!(matches!(
+            UserControlMessage::parse_message(&event(2, &[3])),
+            Ok(UserControlMessage::StreamDry(3))
+        ));
+        assert!(matches!(
+            UserControlMessage::parse_message(&event(4, &[4])),
+            Ok(UserControlMessage::StreamIsRecord(4))
+        ));
+        assert!(matches!(
+            UserControlMessage::parse_message(&event(5, &[5])),
+            Ok(UserControlMessage::PingRequest(5))
+        ));
+        assert!(matches!(
+            UserControlMessage::parse_message(&event(6, &[6])),
+            Ok(UserControlMessage::PingResponse(6))
+        ));
+    }
+
+    #[test]
+    fn test_encode_stream_begin() {
+        assert_eq!(
+            UserControlMessage::StreamBegin(1).encode()[..],
+            event(0, &[1])[..]
+        );
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let messages = [
+            UserControlMessage::StreamBegin(1),
+            UserControlMessage::StreamEOF(1),
+            UserControlMessage::StreamDry(1),
+            UserControlMessage::SetBufferLength {
+                message_stream_id: 1,
+                buffer_size_in_millis: 3000,
+            },
+            UserControlMessage::StreamIsRecord(1),
+            UserControlMessage::PingRequest(0x12345678),
+            UserControlMessage::PingResponse(0x12345678),
+        ];
+
+        for message in messages {
+            assert_eq!(
+                UserControlMessage::parse_message(&message.encode()).unwrap(),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_set_buffer_length() {
+        assert!(matches!(
+            UserControlMessage::parse_message(&event(3, &[1, 3000])),
+            Ok(UserControlMessage::SetBufferLength {
+                message_stream_id: 1,
+                buffer_size_in_millis: 3000
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_set_buffer_length_missing_buffer_size() {
+        assert!(matches!(
+            UserControlMessage::parse_message(&event(3, &[1])),
+            Err(ParseError::InvalidMessageSize)
+        ));
+    }
+
+    #[test]
+    fn test_parse_invalid_event_type() {
+        assert!(matches!(
+            UserControlMessage::parse_message(&event(42, &[1])),
+            Err(ParseError::InvalidEventType(42))
+        ));
+    }
 }
@@ -0,0 +1,182 @@
+use thiserror::Error;
+
+use crate::amf::{AMF0Value, CastError};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ConnectParamsError {
+    #[error("Connect command object must be an object, found {0}")]
+    NotAnObject(&'static str),
+    #[error("Connect command object is missing the app")]
+    MissingApp,
+    #[error("Invalid {field} in connect command object")]
+    InvalidField {
+        field: &'static str,
+        #[source]
+        source: CastError,
+    },
+}
+
+/// The properties of the connect command object that the server cares about
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectParams {
+    /// The application the client wants to connect to, used for routing
+    pub app: String,
+    pub tc_url: Option<String>,
+    pub flash_ver: Option<String>,
+    pub swf_url: Option<String>,
+    pub page_url: Option<String>,
+    /// The AMF version the client wants to use, see [`super::object_encoding`]
+    pub object_encoding: Option<f64>,
+}
+
+impl TryFrom<&AMF0Value<'_>> for ConnectParams {
+    type Error = ConnectParamsError;
+
+    fn try_from(command_object: &AMF0Value<'_>) -> Result<Self, Self::Error> {
+        if !matches!(
+            command_object,
+            AMF0Value::Object(_) | AMF0Value::EcmaArray { .. }
+        ) {
+            return Err(ConnectParamsError::NotAnObject(command_object.type_name()));
+        }
+
+        Ok(Self {
+            app: optional_string(command_object, "app")?.ok_or(ConnectParamsError::MissingApp)?,
+            tc_url: optional_string(command_object, "tcUrl")?,
+            flash_ver: optional_string(command_object, "flashVer")?,
+            swf_url: optional_string(command_object, "swfUrl")?,
+            page_url: optional_string(command_object, "pageUrl")?,
+            object_encoding: optional_number(command_object, "objectEncoding")?,
+        })
+    }
+}
+
+/// Get a property that may be left out. Some clients send null for properties they don't have
+/// a value for, so it is treated the same as a missing property
+fn optional_property<'a>(
+    command_object: &'a AMF0Value<'_>,
+    field: &str,
+) -> Option<&'a AMF0Value<'a>> {
+    command_object
+        .get(field)
+        .filter(|value| !matches!(value, AMF0Value::Null | AMF0Value::Undefined))
+}
+
+fn optional_string(
+    command_object: &AMF0Value<'_>,
+    field: &'static str,
+) -> Result<Option<String>, ConnectParamsError> {
+    optional_property(command_object, field)
+        .map(|value| {
+            value
+                .as_str()
+                .map(str::to_owned)
+                .ok_or(ConnectParamsError::InvalidField {
+                    field,
+                    source: CastError::ExpectedString(value.type_name()),
+                })
+        })
+        .transpose()
+}
+
+fn optional_number(
+    command_object: &AMF0Value<'_>,
+    field: &'static str,
+) -> Result<Option<f64>, ConnectParamsError> {
+    optional_property(command_object, field)
+        .map(|value| {
+            value.as_f64().ok_or(ConnectParamsError::InvalidField {
+                field,
+                source: CastError::ExpectedNumber(value.type_name()),
+            })
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amf::Properties;
+
+    #[test]
+    fn test_parse_obs_connect() {
+        let command_object = AMF0Value::Object(Properties::from([
+            ("app", AMF0Value::String("live")),
+            ("type", AMF0Value::String("nonprivate")),
+            (
+                "flashVer",
+                AMF0Value::String("FMLE/3.0 (compatible; FMSc/1.0)"),
+            ),
+            ("swfUrl", AMF0Value::String("rtmp://localhost/live")),
+            ("tcUrl", AMF0Value::String("rtmp://localhost/live")),
+        ]));
+
+        assert_eq!(
+            ConnectParams::try_from(&command_object).unwrap(),
+            ConnectParams {
+                app: "live".to_owned(),
+                tc_url: Some("rtmp://localhost/live".to_owned()),
+                flash_ver: Some("FMLE/3.0 (compatible; FMSc/1.0)".to_owned()),
+                swf_url: Some("rtmp://localhost/live".to_owned()),
+                page_url: None,
+                object_encoding: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_flash_player_connect() {
+        let command_object = AMF0Value::Object(Properties::from([
+            ("app", AMF0Value::String("vod")),
+            ("flashVer", AMF0Value::String("WIN 32,0,0,465")),
+            ("swfUrl", AMF0Value::Null),
+            ("tcUrl", AMF0Value::String("rtmp://localhost/vod")),
+            ("fpad", AMF0Value::Boolean(false)),
+            ("capabilities", AMF0Value::Number(239.0)),
+            ("audioCodecs", AMF0Value::Number(3575.0)),
+            ("videoCodecs", AMF0Value::Number(252.0)),
+            ("videoFunction", AMF0Value::Number(1.0)),
+            ("pageUrl", AMF0Value::Undefined),
+            ("objectEncoding", AMF0Value::Number(3.0)),
+        ]));
+
+        let params = ConnectParams::try_from(&command_object).unwrap();
+        assert_eq!(params.app, "vod");
+        assert_eq!(params.object_encoding, Some(3.0));
+        assert_eq!(params.swf_url, None);
+        assert_eq!(params.page_url, None);
+    }
+
+    #[test]
+    fn test_missing_app() {
+        let command_object = AMF0Value::Object(Properties::from([(
+            "tcUrl",
+            AMF0Value::String("rtmp://localhost/live"),
+        )]));
+
+        assert_eq!(
+            ConnectParams::try_from(&command_object),
+            Err(ConnectParamsError::MissingApp)
+        );
+    }
+
+    #[test]
+    fn test_invalid_field() {
+        let command_object = AMF0Value::Object(Properties::from([
+            ("app", AMF0Value::String("live")),
+            ("objectEncoding", AMF0Value::String("0")),
+        ]));
+
+        assert_eq!(
+            ConnectParams::try_from(&command_object),
+            Err(ConnectParamsError::InvalidField {
+                field: "objectEncoding",
+                source: CastError::ExpectedNumber("string"),
+            })
+        );
+        assert_eq!(
+            ConnectParams::try_from(&AMF0Value::Null),
+            Err(ConnectParamsError::NotAnObject("null"))
+        );
+    }
+}
@@ -1,4 +1,56 @@
-use crate::messages::Message;
+use std::{collections::HashMap, sync::Arc, task::Poll};
+
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, error, warn};
+
+use crate::{
+    amf::{AMF0Value, EncodeError, Encoder, Properties},
+    app::AppRegistry,
+    chunks::{DEFAULT_CHUNK_SIZE, MAX_CHUNK_SIZE},
+    connection_registry::ConnectionInfo,
+    events::{ConnectionEvents, ServerEvent},
+    messages::{
+        self, Message, OutgoingMessage, chunk_stream,
+        command::{CommandMessage, command_decoder, command_message_type, encode_command},
+        protocol_control::{ProtolControlMessage, peer_bandwidth_limit},
+        user_control::UserControlMessage,
+    },
+    netconnection::{
+        connect::ConnectParams,
+        state::{ConnectionState, Violation},
+    },
+    netstream::{self, NetStream, NetStreamCommand, stream_name::StreamName},
+    output_window::OutputWindow,
+    record, relay,
+    rtmp::DrainHandle,
+    stream_registry::{MediaPacket, Publisher, StreamMetadata, StreamRegistry, Subscriber},
+    url::RtmpUrl,
+};
+
+pub mod connect;
+pub mod state;
+
+/// Window acknowledgement size and peer bandwidth the server asks for on connect
+const WINDOW_ACK_SIZE: u32 = 2_500_000;
+
+/// The chunk size the server sends chunks with after connect, unless configured otherwise
+pub const SERVER_CHUNK_SIZE: u32 = 4096;
+
+/// Server version reported in the connect `_result`, clients expect an FMS style version
+const FMS_VERSION: &str = "FMS/3,0,1,123";
+
+/// Capabilities reported in the connect `_result`
+const CAPABILITIES: f64 = 31.0;
+
+/// How many times a player can fall behind a stream, each within
+/// [`netstream::PLAYER_LAG_WINDOW`] of the last, before it is disconnected
+const MAX_PLAYER_LAGS: u32 = 3;
+
+/// Values of the `objectEncoding` property in the connect command object
+pub mod object_encoding {
+    pub const AMF0: f64 = 0.0;
+    pub const AMF3: f64 = 3.0;
+}
 
 #[derive(Debug)]
 pub enum NetConnectionCommandType<'a> {
@@ -6,6 +58,23 @@ pub enum NetConnectionCommandType<'a> {
     Call(&'a str),
     Close,
     CreateStream,
+    /// Sent by VOD players to find the duration of a stream before playing it
+    GetStreamLength {
+        stream_name: &'a str,
+    },
+    /// Sent by FMLE style encoders such as OBS before publishing, to free up a stream name they
+    /// may have left published
+    ReleaseStream {
+        stream_name: &'a str,
+    },
+    /// Sent by FMLE style encoders before publishing
+    FCPublish {
+        stream_name: &'a str,
+    },
+    /// Sent by FMLE style encoders when they stop publishing
+    FCUnpublish {
+        stream_name: &'a str,
+    },
 }
 
 impl<'a> From<&'a str> for NetConnectionCommandType<'a> {
@@ -19,21 +88,2341 @@ impl<'a> From<&'a str> for NetConnectionCommandType<'a> {
     }
 }
 
+impl<'a> NetConnectionCommandType<'a> {
+    fn parse_get_stream_length(buf: &'a [u8]) -> Result<Self, messages::command::ParseError> {
+        Ok(Self::GetStreamLength {
+            stream_name: command_decoder(buf).decode()?.try_into()?,
+        })
+    }
+
+    fn parse_stream_name(buf: &'a [u8]) -> Result<&'a str, messages::command::ParseError> {
+        Ok(command_decoder(buf).decode()?.try_into()?)
+    }
+
+    pub fn parse(command: &'a str, buf: &'a [u8]) -> Result<Self, messages::command::ParseError> {
+        Ok(match command {
+            "getStreamLength" | "getMovLen" => Self::parse_get_stream_length(buf)?,
+            "releaseStream" => Self::ReleaseStream {
+                stream_name: Self::parse_stream_name(buf)?,
+            },
+            "FCPublish" => Self::FCPublish {
+                stream_name: Self::parse_stream_name(buf)?,
+            },
+            "FCUnpublish" => Self::FCUnpublish {
+                stream_name: Self::parse_stream_name(buf)?,
+            },
+            procedure_name => procedure_name.into(),
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct NetConnection {
+    apps: Arc<AppRegistry>,
+    stream_registry: StreamRegistry,
+    state: ConnectionState,
+    /// Set once the connection should be closed after sending the pending responses
+    closing: bool,
     max_chunk_size: u32,
+    /// The chunk size the server sends chunks with after connect
+    chunk_size: u32,
+    /// The chunk stream the client sent connect on, usually [`chunk_stream::COMMAND`]
+    control_chunk_stream_id: Option<u32>,
+    connect_params: Option<ConnectParams>,
+    /// Message streams handed out by createStream that haven't been deleted yet
+    streams: HashMap<u32, NetStream>,
+    next_stream_id: u32,
+    events: Option<ConnectionEvents>,
+    /// How many bytes the peer can send before it expects an Acknowledgement
+    ack_window_size: u32,
+    bytes_received: u64,
+    /// Bytes received since the last Acknowledgement was sent
+    unacked_bytes: u64,
+    /// Packets skipped because a player fell behind
+    dropped_packets: u64,
+    /// How much can be sent before the peer acknowledges it
+    output_window: OutputWindow,
+    /// Rejects new publishes once the server is draining
+    drain: Option<DrainHandle>,
 }
 
 impl NetConnection {
-    pub fn new() -> Self {
+    pub fn new(apps: Arc<AppRegistry>, stream_registry: StreamRegistry) -> Self {
         NetConnection {
-            max_chunk_size: 4096,
+            apps,
+            stream_registry,
+            state: ConnectionState::Handshaking,
+            closing: false,
+            max_chunk_size: DEFAULT_CHUNK_SIZE as u32,
+            chunk_size: SERVER_CHUNK_SIZE,
+            control_chunk_stream_id: None,
+            connect_params: None,
+            streams: HashMap::new(),
+            // message stream 0 is reserved for the NetConnection itself
+            next_stream_id: 1,
+            events: None,
+            ack_window_size: WINDOW_ACK_SIZE,
+            bytes_received: 0,
+            unacked_bytes: 0,
+            dropped_packets: 0,
+            output_window: OutputWindow::new(),
+            drain: None,
+        }
+    }
+
+    /// Set the chunk size the server switches to after connect
+    pub fn with_chunk_size(mut self, chunk_size: u32) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Reject publishes once `drain` is put into drain mode
+    pub fn with_drain(mut self, drain: DrainHandle) -> Self {
+        self.drain = Some(drain);
+        self
+    }
+
+    /// Report streams being published and played on this connection
+    pub fn with_events(mut self, events: ConnectionEvents) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    #[cfg(test)]
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Start accepting messages once the RTMP handshake is done
+    pub fn handshake_complete(&mut self) {
+        if self.state == ConnectionState::Handshaking {
+            self.state = ConnectionState::Connecting;
         }
     }
 
+    /// Whether the connection should be closed once the responses to the last message are sent
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    /// The chunk size the peer sends chunks with
     pub fn max_chunk_size(&self) -> u32 {
         self.max_chunk_size
     }
 
-    pub fn handle_message() {}
+    /// Record the chunk stream the client sent connect on, and the message stream, which has to
+    /// be 0. Responses always go out on the conventional chunk streams, whichever the client uses
+    pub fn record_connect_chunk_stream(&mut self, chunk_stream_id: u32, message_stream_id: u32) {
+        if message_stream_id != 0 {
+            warn!("Client sent connect on message stream {message_stream_id} instead of 0");
+        }
+        if chunk_stream_id != chunk_stream::COMMAND {
+            debug!("Client sends commands on chunk stream {chunk_stream_id}");
+        }
+        self.control_chunk_stream_id = Some(chunk_stream_id);
+    }
+
+    /// The parameters the client connected with, [`None`] until a valid connect is received
+    #[cfg(test)]
+    pub fn connect_params(&self) -> Option<&ConnectParams> {
+        self.connect_params.as_ref()
+    }
+
+    /// Count bytes sent to the peer against the window it set with SetPeerBandwidth
+    pub fn record_bytes_sent(&mut self, len: usize) {
+        self.output_window.record_sent(len);
+    }
+
+    /// Whether media has to wait until the peer acknowledges what was already sent
+    pub fn is_output_blocked(&self) -> bool {
+        self.output_window.is_blocked()
+    }
+
+    /// Whether a player on this connection paused a stream, players send nothing while paused
+    pub fn has_paused_stream(&self) -> bool {
+        self.streams.values().any(NetStream::is_paused)
+    }
+
+    /// Packets skipped because a player on this connection fell behind
+    pub fn dropped_packets(&self) -> u64 {
+        self.dropped_packets
+    }
+
+    /// Fill in the connection's state, streams and window sizes
+    pub fn describe(&self, info: &mut ConnectionInfo) {
+        let mut publishing: Vec<String> = self
+            .streams
+            .values()
+            .filter_map(|stream| {
+                stream
+                    .publisher()
+                    .map(|publisher| publisher.key().to_owned())
+            })
+            .collect();
+        publishing.sort();
+        let mut playing: Vec<String> = self
+            .streams
+            .values()
+            .filter_map(|stream| stream.player().map(|player| player.key().to_owned()))
+            .collect();
+        playing.sort();
+
+        info.state = self.state;
+        info.app = self
+            .connect_params
+            .as_ref()
+            .map(|params| params.app.clone());
+        info.in_chunk_size = self.max_chunk_size;
+        // the server only switches to its chunk size once connect succeeds
+        info.out_chunk_size = if self.state.is_connected() {
+            self.chunk_size
+        } else {
+            DEFAULT_CHUNK_SIZE as u32
+        };
+        info.control_chunk_stream_id = self.control_chunk_stream_id;
+        info.ack_window_size = self.ack_window_size;
+        info.peer_bandwidth = self.output_window.window_size();
+        info.unacked_bytes = self.output_window.unacked_bytes();
+        info.publishing = publishing;
+        info.playing = playing;
+        info.dropped_packets = self.dropped_packets;
+    }
+
+    /// Count bytes read from the peer, returning an Acknowledgement once a full window has been
+    /// received since the last one
+    pub fn record_bytes_received(&mut self, len: usize) -> Option<OutgoingMessage> {
+        self.bytes_received += len as u64;
+        self.unacked_bytes += len as u64;
+        if self.unacked_bytes < u64::from(self.ack_window_size) {
+            return None;
+        }
+
+        self.unacked_bytes = 0;
+        // the sequence number wraps around once it no longer fits in 32 bits
+        Some(OutgoingMessage::Protocol(ProtolControlMessage::Ack(
+            self.bytes_received as u32,
+        )))
+    }
+
+    /// Handle a message the peer sent on `message_stream_id`, returning the messages to send back
+    pub fn handle_message(
+        &mut self,
+        message: &Message,
+        message_stream_id: u32,
+    ) -> Result<Vec<OutgoingMessage>, EncodeError> {
+        if let Err(violation) = self.state.check(message) {
+            return self.handle_violation(violation, message_stream_id);
+        }
+
+        Ok(match message {
+            Message::Protocol(ProtolControlMessage::SetChunkSize(chunk_size)) => {
+                self.handle_set_chunk_size(*chunk_size);
+                vec![]
+            }
+            Message::Protocol(ProtolControlMessage::AckWindowSize(window_size)) => {
+                self.handle_ack_window_size(*window_size);
+                vec![]
+            }
+            Message::Protocol(ProtolControlMessage::SetPeerBandwidth {
+                limit_type,
+                window_size,
+            }) => self
+                .output_window
+                .set_peer_bandwidth(*window_size, *limit_type)
+                // ask to be acknowledged as often as the window needs
+                .map(|window_size| {
+                    OutgoingMessage::Protocol(ProtolControlMessage::AckWindowSize(window_size))
+                })
+                .into_iter()
+                .collect(),
+            Message::Protocol(ProtolControlMessage::Ack(sequence_number)) => {
+                self.output_window.ack(*sequence_number);
+                vec![]
+            }
+            Message::UserControl(UserControlMessage::PingRequest(timestamp)) => {
+                vec![OutgoingMessage::UserControl(
+                    UserControlMessage::PingResponse(*timestamp),
+                )]
+            }
+            Message::Command(CommandMessage::NetConnectionCommand {
+                command_type: NetConnectionCommandType::Connect,
+                transaction_id,
+                command_object,
+            }) => self.handle_connect(*transaction_id, command_object)?,
+            Message::Command(CommandMessage::NetConnectionCommand {
+                command_type: NetConnectionCommandType::CreateStream,
+                transaction_id,
+                ..
+            }) => vec![self.handle_create_stream(*transaction_id)?],
+            Message::Command(CommandMessage::NetConnectionCommand {
+                command_type: NetConnectionCommandType::GetStreamLength { stream_name },
+                transaction_id,
+                ..
+            }) => {
+                // every stream is live, which players expect a length of 0 for
+                debug!("Reporting a length of 0 for live stream {stream_name}");
+                vec![stream_length_result(*transaction_id)?]
+            }
+            Message::Command(CommandMessage::NetConnectionCommand {
+                command_type: NetConnectionCommandType::ReleaseStream { stream_name },
+                transaction_id,
+                ..
+            }) => {
+                self.unpublish_by_name(stream_name);
+                vec![command_result(*transaction_id)?]
+            }
+            Message::Command(CommandMessage::NetConnectionCommand {
+                command_type: NetConnectionCommandType::FCPublish { stream_name },
+                transaction_id,
+                ..
+            }) => vec![
+                fc_status("onFCPublish", "NetStream.Publish.Start", stream_name)?,
+                command_result(*transaction_id)?,
+            ],
+            Message::Command(CommandMessage::NetConnectionCommand {
+                command_type: NetConnectionCommandType::FCUnpublish { stream_name },
+                transaction_id,
+                ..
+            }) => {
+                self.unpublish_by_name(stream_name);
+                vec![
+                    fc_status("onFCUnpublish", "NetStream.Unpublish.Success", stream_name)?,
+                    command_result(*transaction_id)?,
+                ]
+            }
+            Message::Command(CommandMessage::NetStreamCommand {
+                command:
+                    NetStreamCommand::Publish {
+                        publishing_name, ..
+                    },
+                ..
+            }) => self.handle_publish(message_stream_id, publishing_name)?,
+            Message::Command(CommandMessage::NetStreamCommand {
+                command:
+                    NetStreamCommand::Play {
+                        stream_name, reset, ..
+                    },
+                ..
+            }) => self.handle_play(message_stream_id, stream_name, *reset)?,
+            Message::Command(CommandMessage::NetStreamCommand {
+                command: NetStreamCommand::DeleteStream { stream_id },
+                ..
+            }) => {
+                self.handle_delete_stream(*stream_id);
+                vec![]
+            }
+            Message::Command(CommandMessage::NetStreamCommand {
+                command: NetStreamCommand::CloseStream { .. },
+                ..
+            }) => {
+                self.handle_close_stream(message_stream_id);
+                vec![]
+            }
+            Message::Command(CommandMessage::NetStreamCommand {
+                command: NetStreamCommand::ReceiveAudio { should_receive },
+                ..
+            }) => {
+                if let Some(stream) = self.streams.get_mut(&message_stream_id) {
+                    stream.set_receive_audio(*should_receive);
+                }
+                vec![]
+            }
+            Message::Command(CommandMessage::NetStreamCommand {
+                command: NetStreamCommand::ReceiveVideo { should_receive },
+                ..
+            }) => {
+                if let Some(stream) = self.streams.get_mut(&message_stream_id) {
+                    stream.set_receive_video(*should_receive);
+                }
+                vec![]
+            }
+            Message::Command(CommandMessage::NetStreamCommand {
+                command: NetStreamCommand::Pause { is_paused, .. },
+                ..
+            }) => self.handle_pause(message_stream_id, *is_paused)?,
+            Message::Command(CommandMessage::Data {
+                name: "onMetaData",
+                value,
+            }) => {
+                self.handle_metadata(message_stream_id, value)?;
+                vec![]
+            }
+            Message::Command(CommandMessage::NetConnectionCommand {
+                command_type: NetConnectionCommandType::Call(procedure_name),
+                ..
+            }) => {
+                debug!("Ignoring call to unsupported procedure {procedure_name}");
+                vec![]
+            }
+            _ => vec![],
+        })
+    }
+
+    /// Remember what the publisher on `message_stream_id` described its stream as, and pass the
+    /// metadata on to players
+    fn handle_metadata(
+        &self,
+        message_stream_id: u32,
+        value: &AMF0Value,
+    ) -> Result<(), EncodeError> {
+        let Some(publisher) = self
+            .streams
+            .get(&message_stream_id)
+            .and_then(NetStream::publisher)
+        else {
+            warn!(
+                "Ignoring onMetaData on message stream {message_stream_id}, which isn't publishing"
+            );
+            return Ok(());
+        };
+
+        publisher.set_metadata(StreamMetadata::from_properties(value));
+
+        // players get the metadata without the @setDataFrame wrapper publishers send it in
+        let mut encoder = Encoder::new();
+        encoder.encode(&AMF0Value::String("onMetaData"))?;
+        encoder.encode(value)?;
+        publisher.send(MediaPacket {
+            message_type_id: command_message_type::DATA_AMF0,
+            timestamp: 0,
+            payload: encoder.finish().into(),
+        });
+        Ok(())
+    }
+
+    fn handle_violation(
+        &mut self,
+        violation: Violation,
+        message_stream_id: u32,
+    ) -> Result<Vec<OutgoingMessage>, EncodeError> {
+        match violation {
+            Violation::Close => {
+                warn!(
+                    "Closing connection after a command it can't send while {:?}",
+                    self.state
+                );
+                self.closing = true;
+                Ok(vec![])
+            }
+            Violation::Reject(code) => {
+                warn!(
+                    "Rejecting a command the connection can't send while {:?}",
+                    self.state
+                );
+                Ok(vec![netstream::on_status(
+                    message_stream_id,
+                    "error",
+                    code,
+                    &format!("Not allowed while {:?}", self.state),
+                )?])
+            }
+        }
+    }
+
+    /// Go back to connected once nothing is being published or played
+    fn update_stream_state(&mut self) {
+        if !self.state.is_connected() {
+            return;
+        }
+
+        self.state = if self.streams.values().any(NetStream::is_publishing) {
+            ConnectionState::Publishing
+        } else if self.streams.values().any(NetStream::is_playing) {
+            ConnectionState::Playing
+        } else {
+            ConnectionState::Connected
+        };
+    }
+
+    fn handle_set_chunk_size(&mut self, chunk_size: u32) {
+        if chunk_size == 0 {
+            warn!("Ignoring SetChunkSize of 0");
+            return;
+        }
+
+        self.max_chunk_size = chunk_size.min(MAX_CHUNK_SIZE);
+        debug!("Peer chunk size set to {}", self.max_chunk_size);
+    }
+
+    fn handle_ack_window_size(&mut self, window_size: u32) {
+        if window_size == 0 {
+            warn!("Ignoring Window Acknowledgement Size of 0");
+            return;
+        }
+
+        self.ack_window_size = window_size;
+        debug!("Acknowledgement window set to {}", self.ack_window_size);
+    }
+
+    fn handle_connect(
+        &mut self,
+        transaction_id: f64,
+        command_object: &AMF0Value,
+    ) -> Result<Vec<OutgoingMessage>, EncodeError> {
+        let params = match ConnectParams::try_from(command_object) {
+            Ok(params) => params,
+            Err(e) => {
+                warn!("Invalid connect command object: {e}");
+                return Ok(vec![self.reject_connect(transaction_id, &e.to_string())?]);
+            }
+        };
+        if self.apps.get(&params.app).is_none() {
+            warn!("Rejecting connect to unknown app {}", params.app);
+            let description = format!("Application {} is not registered", params.app);
+            return Ok(vec![self.reject_connect(transaction_id, &description)?]);
+        }
+        // clients that asked for AMF3 go on to send AMF3 commands, which can't be parsed, so
+        // turn them away now instead of failing partway through the session
+        if params
+            .object_encoding
+            .is_some_and(|encoding| encoding >= object_encoding::AMF3)
+        {
+            warn!("Rejecting connect requesting AMF3 object encoding");
+            return Ok(vec![self.reject_connect(
+                transaction_id,
+                "AMF3 object encoding is not supported, connect with objectEncoding 0",
+            )?]);
+        }
+
+        match params.tc_url.as_deref().map(RtmpUrl::try_from) {
+            Some(Ok(tc_url)) => debug!("Connected to {tc_url}"),
+            Some(Err(_)) => debug!("Connected with a tcUrl that isn't an RTMP URL"),
+            None => {}
+        }
+
+        self.connect_params = Some(params);
+        self.state = ConnectionState::Connected;
+
+        let properties = AMF0Value::Object(Properties::from([
+            ("fmsVer", AMF0Value::String(FMS_VERSION)),
+            ("capabilities", AMF0Value::Number(CAPABILITIES)),
+        ]));
+        let information = AMF0Value::Object(Properties::from([
+            ("level", AMF0Value::String("status")),
+            ("code", AMF0Value::String("NetConnection.Connect.Success")),
+            ("description", AMF0Value::String("Connection succeeded.")),
+            ("objectEncoding", AMF0Value::Number(object_encoding::AMF0)),
+        ]));
+
+        Ok(vec![
+            OutgoingMessage::Protocol(ProtolControlMessage::AckWindowSize(WINDOW_ACK_SIZE)),
+            OutgoingMessage::Protocol(ProtolControlMessage::SetPeerBandwidth {
+                limit_type: peer_bandwidth_limit::DYNAMIC,
+                window_size: WINDOW_ACK_SIZE,
+            }),
+            OutgoingMessage::Protocol(ProtolControlMessage::SetChunkSize(self.chunk_size)),
+            OutgoingMessage::Command {
+                message_stream_id: 0,
+                payload: encode_command(&[
+                    AMF0Value::String("_result"),
+                    AMF0Value::Number(transaction_id),
+                    properties,
+                    information,
+                ])?,
+            },
+        ])
+    }
+
+    /// Reply to a connect with `_error` and close the connection
+    fn reject_connect(
+        &mut self,
+        transaction_id: f64,
+        description: &str,
+    ) -> Result<OutgoingMessage, EncodeError> {
+        self.closing = true;
+        Ok(OutgoingMessage::Command {
+            message_stream_id: 0,
+            payload: encode_command(&[
+                AMF0Value::String("_error"),
+                AMF0Value::Number(transaction_id),
+                AMF0Value::Null,
+                AMF0Value::Object(Properties::from([
+                    ("level", AMF0Value::String("error")),
+                    ("code", AMF0Value::String("NetConnection.Connect.Rejected")),
+                    ("description", AMF0Value::String(description)),
+                ])),
+            ])?,
+        })
+    }
+
+    fn handle_create_stream(
+        &mut self,
+        transaction_id: f64,
+    ) -> Result<OutgoingMessage, EncodeError> {
+        let stream_id = self.next_stream_id;
+        self.next_stream_id += 1;
+        self.streams.insert(stream_id, NetStream::new());
+        debug!("Created message stream {stream_id}");
+
+        Ok(OutgoingMessage::Command {
+            message_stream_id: 0,
+            payload: encode_command(&[
+                AMF0Value::String("_result"),
+                AMF0Value::Number(transaction_id),
+                AMF0Value::Null,
+                AMF0Value::Number(stream_id.into()),
+            ])?,
+        })
+    }
+
+    /// Stop publishing `stream_name` if this connection is publishing it. Streams published by
+    /// other connections are left alone, they are unpublished when that connection closes
+    fn unpublish_by_name(&mut self, stream_name: &str) {
+        let Some(params) = &self.connect_params else {
+            return;
+        };
+
+        let key = stream_key(&params.app, StreamName::parse(stream_name).name);
+        for (message_stream_id, stream) in &mut self.streams {
+            if stream
+                .publisher()
+                .is_some_and(|publisher| publisher.key() == key)
+            {
+                debug!("Unpublishing {key} from message stream {message_stream_id}");
+                stream_stopped(&self.events, stream.stop_publishing(), None);
+            }
+        }
+        self.update_stream_state();
+    }
+
+    /// Free up a message stream, unpublishing or stopping playback of whatever was on it
+    fn handle_delete_stream(&mut self, stream_id: u32) {
+        match self.streams.remove(&stream_id) {
+            Some(mut stream) => {
+                stream_stopped(
+                    &self.events,
+                    stream.stop_publishing(),
+                    stream.stop_playing(),
+                );
+                debug!("Deleted message stream {stream_id}");
+            }
+            None => warn!("Ignoring deleteStream of unknown message stream {stream_id}"),
+        }
+        self.update_stream_state();
+    }
+
+    /// Stop publishing or playing on a message stream, keeping it around to be used again
+    fn handle_close_stream(&mut self, message_stream_id: u32) {
+        let Some(stream) = self.streams.get_mut(&message_stream_id) else {
+            warn!("Ignoring closeStream on unknown message stream {message_stream_id}");
+            return;
+        };
+        stream_stopped(
+            &self.events,
+            stream.stop_publishing(),
+            stream.stop_playing(),
+        );
+        debug!("Closed message stream {message_stream_id}");
+        self.update_stream_state();
+    }
+
+    /// Whether `stream_id` was created by createStream and hasn't been deleted
+    #[cfg(test)]
+    pub fn has_stream(&self, stream_id: u32) -> bool {
+        self.streams.contains_key(&stream_id)
+    }
+
+    /// Send a packet from the peer to everyone playing the stream it is publishing on
+    /// `message_stream_id`. The connection is closed once the publisher goes over its bitrate
+    /// limit
+    pub fn forward_media(&mut self, message_stream_id: u32, packet: MediaPacket) {
+        let Some(stream) = self
+            .streams
+            .get_mut(&message_stream_id)
+            .filter(|stream| stream.is_publishing())
+        else {
+            warn!(
+                "Dropping media sent on message stream {message_stream_id}, which isn't publishing"
+            );
+            return;
+        };
+
+        if let Some(kbps) = stream.exceeded_bitrate(&packet) {
+            warn!(
+                "Closing connection publishing at {kbps} kbps on message stream {message_stream_id}, over its limit"
+            );
+            self.closing = true;
+            return;
+        }
+        if let Some(publisher) = stream.publisher() {
+            publisher.send(packet);
+        }
+    }
+
+    /// Wait for the next packet of a stream this connection is playing, never completes if
+    /// nothing is being played
+    pub async fn next_media(&mut self) -> OutgoingMessage {
+        loop {
+            let (message_stream_id, received) = {
+                let mut playing: Vec<_> = self
+                    .streams
+                    .iter_mut()
+                    .filter(|(_, stream)| stream.is_playing())
+                    .map(|(&id, stream)| Box::pin(async move { (id, stream.recv().await) }))
+                    .collect();
+                if playing.is_empty() {
+                    return std::future::pending().await;
+                }
+
+                // every stream's recv is cancel safe, so the ones that lose are simply polled
+                // again on the next call
+                std::future::poll_fn(|cx| {
+                    playing
+                        .iter_mut()
+                        .find_map(|recv| match recv.as_mut().poll(cx) {
+                            Poll::Ready(received) => Some(received),
+                            Poll::Pending => None,
+                        })
+                        .map_or(Poll::Pending, Poll::Ready)
+                })
+                .await
+            };
+
+            match received {
+                Ok(packet) => {
+                    return OutgoingMessage::Media {
+                        message_stream_id,
+                        packet,
+                    };
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    let Some(stream) = self.streams.get_mut(&message_stream_id) else {
+                        continue;
+                    };
+                    let (lags, dropped) = stream.lagged();
+                    self.dropped_packets += skipped + dropped;
+                    if lags <= MAX_PLAYER_LAGS {
+                        warn!(
+                            "Player on message stream {message_stream_id} fell behind, skipped {} packets and resuming from the last keyframe",
+                            skipped + dropped
+                        );
+                        continue;
+                    }
+
+                    warn!(
+                        "Player on message stream {message_stream_id} fell behind {lags} times, disconnecting"
+                    );
+                    if let Some(stream) = self.streams.get_mut(&message_stream_id) {
+                        stream_stopped(&self.events, None, stream.stop_playing());
+                    }
+                    self.update_stream_state();
+                    self.closing = true;
+                    match netstream::on_status(
+                        message_stream_id,
+                        "error",
+                        "NetStream.Play.InsufficientBW",
+                        "Unable to keep up with the stream",
+                    ) {
+                        Ok(message) => return message,
+                        Err(e) => error!("unable to encode InsufficientBW: {e}"),
+                    }
+                }
+                Err(RecvError::Closed) => {
+                    debug!("Stream played on message stream {message_stream_id} was unpublished");
+                    if let Some(stream) = self.streams.get_mut(&message_stream_id) {
+                        stream_stopped(&self.events, None, stream.stop_playing());
+                    }
+                    self.update_stream_state();
+                    match netstream::on_status(
+                        message_stream_id,
+                        "status",
+                        "NetStream.Play.UnpublishNotify",
+                        "The stream was unpublished",
+                    ) {
+                        Ok(message) => return message,
+                        Err(e) => error!("unable to encode UnpublishNotify: {e}"),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Start playing a live stream. `reset` asks for the stream's playlist to be cleared first,
+    /// which players are told about with NetStream.Play.Reset ahead of NetStream.Play.Start.
+    /// Every stream is live, so StreamIsRecord is never sent
+    fn handle_play(
+        &mut self,
+        message_stream_id: u32,
+        stream_name: &str,
+        reset: bool,
+    ) -> Result<Vec<OutgoingMessage>, EncodeError> {
+        let Some(params) = &self.connect_params else {
+            warn!("Ignoring play before connect");
+            return Ok(vec![]);
+        };
+        let Some(stream) = self.streams.get_mut(&message_stream_id) else {
+            warn!("Ignoring play on unknown message stream {message_stream_id}");
+            return Ok(vec![]);
+        };
+
+        let StreamName {
+            name: stream_name,
+            query,
+        } = StreamName::parse(stream_name);
+        if !self.apps.get(&params.app).is_some_and(|app| app.allow_play) {
+            warn!(
+                "Rejecting play from {}, which doesn't allow playing",
+                params.app
+            );
+            return Ok(vec![netstream::on_status(
+                message_stream_id,
+                "error",
+                "NetStream.Play.Failed",
+                &format!("Playing from {} is not allowed", params.app),
+            )?]);
+        }
+        let key = stream_key(&params.app, stream_name);
+        let player = match self.stream_registry.subscribe(&key) {
+            Some(player) => Some(player),
+            // a stream published to another node is pulled from it and played from here
+            None if relay::pull_remote(&self.stream_registry, &key) => {
+                self.stream_registry.subscribe(&key)
+            }
+            None => None,
+        };
+        let Some(player) = player else {
+            debug!("Stream {key} is not live");
+            return Ok(vec![netstream::on_status(
+                message_stream_id,
+                "error",
+                "NetStream.Play.StreamNotFound",
+                &format!("{stream_name} is not live"),
+            )?]);
+        };
+        if !query.is_empty() {
+            debug!("Playing {key} with query parameters {:?}", query.keys());
+        }
+        debug!("Playing {key} on message stream {message_stream_id}");
+        if let Some(events) = &self.events {
+            events.send(|peer_addr| ServerEvent::PlayerJoined {
+                peer_addr,
+                stream_key: player.key().to_owned(),
+            });
+        }
+        stream.set_player(player);
+        self.state = ConnectionState::Playing;
+        let settings = self.apps.settings_for(&params.app, stream_name);
+
+        let mut responses = vec![OutgoingMessage::UserControl(
+            UserControlMessage::StreamBegin(message_stream_id),
+        )];
+        if reset {
+            responses.push(netstream::on_status(
+                message_stream_id,
+                "status",
+                "NetStream.Play.Reset",
+                &format!("Playing and resetting {stream_name}"),
+            )?);
+        }
+        responses.extend([
+            netstream::on_status(
+                message_stream_id,
+                "status",
+                "NetStream.Play.Start",
+                &format!("Started playing {stream_name}"),
+            )?,
+            OutgoingMessage::Data {
+                message_stream_id,
+                // whether players can access the raw audio and video samples
+                payload: encode_command(&[
+                    AMF0Value::String("|RtmpSampleAccess"),
+                    AMF0Value::Boolean(settings.audio_sample_access),
+                    AMF0Value::Boolean(settings.video_sample_access),
+                ])?,
+            },
+        ]);
+        Ok(responses)
+    }
+
+    /// Stop or resume forwarding the live stream played on `message_stream_id`. Playing a live
+    /// stream can't pick up where it was paused, so the position in `pause` is ignored
+    fn handle_pause(
+        &mut self,
+        message_stream_id: u32,
+        paused: bool,
+    ) -> Result<Vec<OutgoingMessage>, EncodeError> {
+        let Some(stream) = self
+            .streams
+            .get_mut(&message_stream_id)
+            .filter(|stream| stream.is_playing())
+        else {
+            warn!("Ignoring pause on message stream {message_stream_id}, which isn't playing");
+            return Ok(vec![]);
+        };
+
+        debug!("Setting paused to {paused} on message stream {message_stream_id}");
+        stream.set_paused(paused);
+        Ok(vec![if paused {
+            netstream::on_status(
+                message_stream_id,
+                "status",
+                "NetStream.Pause.Notify",
+                "Paused live stream",
+            )?
+        } else {
+            netstream::on_status(
+                message_stream_id,
+                "status",
+                "NetStream.Unpause.Notify",
+                "Unpaused live stream",
+            )?
+        }])
+    }
+
+    fn handle_publish(
+        &mut self,
+        message_stream_id: u32,
+        publishing_name: &str,
+    ) -> Result<Vec<OutgoingMessage>, EncodeError> {
+        let Some(params) = &self.connect_params else {
+            warn!("Ignoring publish before connect");
+            return Ok(vec![]);
+        };
+        let Some(stream) = self.streams.get_mut(&message_stream_id) else {
+            warn!("Ignoring publish on unknown message stream {message_stream_id}");
+            return Ok(vec![]);
+        };
+        // streams already published keep going, but nothing new starts on a draining server
+        if self.drain.as_ref().is_some_and(DrainHandle::is_draining) {
+            warn!("Rejecting publish to {publishing_name}, the server is draining");
+            return Ok(vec![netstream::on_status(
+                message_stream_id,
+                "error",
+                "NetStream.Publish.Denied",
+                "The server is draining and not accepting new streams",
+            )?]);
+        }
+
+        let bad_name = |description: &str| {
+            netstream::on_status(
+                message_stream_id,
+                "error",
+                "NetStream.Publish.BadName",
+                description,
+            )
+        };
+        // the query is where encoders put credentials, it isn't part of the key
+        let StreamName {
+            name: publishing_name,
+            query,
+        } = StreamName::parse(publishing_name);
+        if publishing_name.is_empty() {
+            return Ok(vec![bad_name("Stream name must not be empty")?]);
+        }
+        if stream.is_publishing() {
+            return Ok(vec![bad_name("Stream is already publishing")?]);
+        }
+
+        if !self
+            .apps
+            .get(&params.app)
+            .is_some_and(|app| app.allow_publish)
+        {
+            warn!(
+                "Rejecting publish to {}, which doesn't allow publishing",
+                params.app
+            );
+            return Ok(vec![bad_name(&format!(
+                "Publishing to {} is not allowed",
+                params.app
+            ))?]);
+        }
+        let settings = self.apps.settings_for(&params.app, publishing_name);
+        if settings.auth_required
+            && !self
+                .apps
+                .authorize_publish(&params.app, publishing_name, &query)
+        {
+            warn!("Rejecting unauthorized publish to {publishing_name}");
+            return Ok(vec![netstream::on_status(
+                message_stream_id,
+                "error",
+                "NetStream.Publish.Unauthorized",
+                &format!("Not authorized to publish {publishing_name}"),
+            )?]);
+        }
+
+        let key = stream_key(&params.app, publishing_name);
+        let Some(publisher) = self.stream_registry.publish(&key) else {
+            warn!("Rejecting publish to {key}, it is already being published");
+            return Ok(vec![bad_name(&format!(
+                "{publishing_name} is already being published"
+            ))?]);
+        };
+        debug!("Publishing {key} on message stream {message_stream_id}");
+        if !query.is_empty() {
+            debug!("Publishing {key} with query parameters {:?}", query.keys());
+        }
+        if let Some(events) = &self.events {
+            events.send(|peer_addr| ServerEvent::StreamPublished {
+                peer_addr,
+                stream_key: publisher.key().to_owned(),
+            });
+        }
+        stream.set_publisher(publisher, settings.max_bitrate_kbps);
+        self.state = ConnectionState::Publishing;
+
+        if settings.record
+            && let Some(subscriber) = self.stream_registry.subscribe_internal(&key)
+        {
+            record::spawn(subscriber, self.apps.record_dir().to_owned());
+        }
+
+        Ok(vec![netstream::on_status(
+            message_stream_id,
+            "status",
+            "NetStream.Publish.Start",
+            &format!("{publishing_name} is now published"),
+        )?])
+    }
+}
+
+impl Drop for NetConnection {
+    fn drop(&mut self) {
+        for stream in self.streams.values_mut() {
+            stream_stopped(
+                &self.events,
+                stream.stop_publishing(),
+                stream.stop_playing(),
+            );
+        }
+    }
+}
+
+/// Report that a stream stopped being published or played, before dropping the publisher or
+/// player
+fn stream_stopped(
+    events: &Option<ConnectionEvents>,
+    publisher: Option<Publisher>,
+    player: Option<Subscriber>,
+) {
+    let Some(events) = events else {
+        return;
+    };
+    if let Some(publisher) = publisher {
+        events.send(|peer_addr| ServerEvent::StreamUnpublished {
+            peer_addr,
+            stream_key: publisher.key().to_owned(),
+        });
+    }
+    if let Some(player) = player {
+        events.send(|peer_addr| ServerEvent::PlayerLeft {
+            peer_addr,
+            stream_key: player.key().to_owned(),
+        });
+    }
+}
+
+/// The key a stream is published under in the [`StreamRegistry`]
+fn stream_key(app: &str, stream_name: &str) -> String {
+    format!("{app}/{stream_name}")
+}
+
+/// A `_result` for a command that has nothing to return
+fn command_result(transaction_id: f64) -> Result<OutgoingMessage, EncodeError> {
+    Ok(OutgoingMessage::Command {
+        message_stream_id: 0,
+        payload: encode_command(&[
+            AMF0Value::String("_result"),
+            AMF0Value::Number(transaction_id),
+            AMF0Value::Null,
+            AMF0Value::Undefined,
+        ])?,
+    })
+}
+
+/// The `_result` for getStreamLength, which is always 0 since every stream is live
+fn stream_length_result(transaction_id: f64) -> Result<OutgoingMessage, EncodeError> {
+    Ok(OutgoingMessage::Command {
+        message_stream_id: 0,
+        payload: encode_command(&[
+            AMF0Value::String("_result"),
+            AMF0Value::Number(transaction_id),
+            AMF0Value::Null,
+            AMF0Value::Number(0.0),
+        ])?,
+    })
+}
+
+/// The `onFCPublish` or `onFCUnpublish` status FMLE style encoders wait for
+fn fc_status(command: &str, code: &str, stream_name: &str) -> Result<OutgoingMessage, EncodeError> {
+    Ok(OutgoingMessage::Command {
+        message_stream_id: 0,
+        payload: encode_command(&[
+            AMF0Value::String(command),
+            AMF0Value::Number(0.0),
+            AMF0Value::Null,
+            AMF0Value::Object(Properties::from([
+                ("code", AMF0Value::String(code)),
+                ("description", AMF0Value::String(stream_name)),
+            ])),
+        ])?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        amf::Decoder,
+        app::{AppOptions, Authorizer},
+        config::{AppConfig, ServerConfig, SettingsOverride},
+        stream_registry::{MEDIA_CHANNEL_CAPACITY, RemoteStream, RemoteStreams},
+    };
+
+    fn net_connection() -> NetConnection {
+        let mut net_connection = NetConnection::new(
+            Arc::new(AppRegistry::new().with_app("live", AppOptions::default())),
+            StreamRegistry::new(),
+        );
+        net_connection.handshake_complete();
+        net_connection
+    }
+
+    fn connect_message<'a>(command_object: AMF0Value<'a>) -> Message<'a> {
+        Message::Command(CommandMessage::NetConnectionCommand {
+            command_type: NetConnectionCommandType::Connect,
+            transaction_id: 1.0,
+            command_object,
+        })
+    }
+
+    #[test]
+    fn test_amf3_connect_is_rejected() {
+        let mut net_connection = net_connection();
+        let responses = net_connection
+            .handle_message(
+                &connect_message(AMF0Value::Object(Properties::from([
+                    ("app", AMF0Value::String("live")),
+                    ("objectEncoding", AMF0Value::Number(object_encoding::AMF3)),
+                ]))),
+                0,
+            )
+            .unwrap();
+
+        let [OutgoingMessage::Command { payload, .. }] = &responses[..] else {
+            assert!(matches!(responses[..], [OutgoingMessage::Command { .. }]));
+            return;
+        };
+        let mut decoder = Decoder::new(payload);
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::String("_error"));
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::Number(1.0));
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::Null);
+        let information = decoder.decode().unwrap();
+        assert_eq!(
+            information.get("code"),
+            Some(&AMF0Value::String("NetConnection.Connect.Rejected"))
+        );
+        assert!(
+            information
+                .get("description")
+                .and_then(AMF0Value::as_str)
+                .unwrap()
+                .contains("AMF3")
+        );
+
+        assert!(net_connection.is_closing());
+        assert_eq!(net_connection.connect_params(), None);
+    }
+
+    #[test]
+    fn test_connect_response() {
+        let mut net_connection = net_connection();
+        let responses = net_connection
+            .handle_message(
+                &connect_message(AMF0Value::Object(Properties::from([(
+                    "app",
+                    AMF0Value::String("live"),
+                )]))),
+                0,
+            )
+            .unwrap();
+
+        assert_eq!(
+            responses[..3],
+            [
+                OutgoingMessage::Protocol(ProtolControlMessage::AckWindowSize(WINDOW_ACK_SIZE)),
+                OutgoingMessage::Protocol(ProtolControlMessage::SetPeerBandwidth {
+                    limit_type: peer_bandwidth_limit::DYNAMIC,
+                    window_size: WINDOW_ACK_SIZE,
+                }),
+                OutgoingMessage::Protocol(ProtolControlMessage::SetChunkSize(SERVER_CHUNK_SIZE)),
+            ]
+        );
+
+        let OutgoingMessage::Command {
+            message_stream_id,
+            payload,
+        } = &responses[3]
+        else {
+            assert!(matches!(responses[3], OutgoingMessage::Command { .. }));
+            return;
+        };
+        assert_eq!(*message_stream_id, 0);
+
+        let mut decoder = Decoder::new(payload);
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::String("_result"));
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::Number(1.0));
+        assert_eq!(
+            decoder.decode().unwrap(),
+            AMF0Value::Object(Properties::from([
+                ("fmsVer", AMF0Value::String(FMS_VERSION)),
+                ("capabilities", AMF0Value::Number(CAPABILITIES)),
+            ]))
+        );
+        assert_eq!(
+            decoder.decode().unwrap(),
+            AMF0Value::Object(Properties::from([
+                ("level", AMF0Value::String("status")),
+                ("code", AMF0Value::String("NetConnection.Connect.Success")),
+                ("description", AMF0Value::String("Connection succeeded.")),
+                ("objectEncoding", AMF0Value::Number(object_encoding::AMF0)),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_connect_to_unregistered_app_is_rejected() {
+        let mut net_connection = net_connection();
+        let responses = net_connection
+            .handle_message(
+                &connect_message(AMF0Value::Object(Properties::from([(
+                    "app",
+                    AMF0Value::String("private"),
+                )]))),
+                0,
+            )
+            .unwrap();
+
+        let [OutgoingMessage::Command { payload, .. }] = &responses[..] else {
+            assert!(matches!(responses[..], [OutgoingMessage::Command { .. }]));
+            return;
+        };
+        let mut decoder = Decoder::new(payload);
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::String("_error"));
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::Number(1.0));
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::Null);
+        assert_eq!(
+            decoder.decode().unwrap().get("code"),
+            Some(&AMF0Value::String("NetConnection.Connect.Rejected"))
+        );
+
+        assert!(net_connection.is_closing());
+        assert_eq!(net_connection.connect_params(), None);
+    }
+
+    #[test]
+    fn test_create_stream_ids_increase() {
+        let mut net_connection = net_connection();
+        net_connection
+            .handle_message(
+                &connect_message(AMF0Value::Object(Properties::from([(
+                    "app",
+                    AMF0Value::String("live"),
+                )]))),
+                0,
+            )
+            .unwrap();
+        let create_stream = |transaction_id| {
+            Message::Command(CommandMessage::NetConnectionCommand {
+                command_type: NetConnectionCommandType::CreateStream,
+                transaction_id,
+                command_object: AMF0Value::Null,
+            })
+        };
+
+        let mut stream_ids = vec![];
+        for transaction_id in [2.0, 3.0] {
+            let responses = net_connection
+                .handle_message(&create_stream(transaction_id), 0)
+                .unwrap();
+            let [OutgoingMessage::Command { payload, .. }] = &responses[..] else {
+                assert!(matches!(responses[..], [OutgoingMessage::Command { .. }]));
+                return;
+            };
+
+            let mut decoder = Decoder::new(payload);
+            assert_eq!(decoder.decode().unwrap(), AMF0Value::String("_result"));
+            assert_eq!(decoder.decode().unwrap(), AMF0Value::Number(transaction_id));
+            assert_eq!(decoder.decode().unwrap(), AMF0Value::Null);
+            let stream_id: u32 = decoder.decode().unwrap().try_into().unwrap();
+            stream_ids.push(stream_id);
+        }
+
+        assert_eq!(stream_ids, [1, 2]);
+        assert!(net_connection.has_stream(1));
+        assert!(net_connection.has_stream(2));
+        assert!(!net_connection.has_stream(0));
+    }
+
+    fn publish_message(publishing_name: &str) -> Message<'_> {
+        Message::Command(CommandMessage::NetStreamCommand {
+            command: NetStreamCommand::Publish {
+                publishing_name,
+                publishing_type: "live",
+            },
+            transaction_id: 0.0,
+            command_object: AMF0Value::Null,
+        })
+    }
+
+    /// Connect to the live app and create message stream 1
+    fn connected(stream_registry: StreamRegistry) -> NetConnection {
+        connected_to(
+            AppRegistry::new().with_app("live", AppOptions::default()),
+            stream_registry,
+        )
+    }
+
+    /// Connect to the live app of `apps` and create message stream 1
+    fn connected_to(apps: AppRegistry, stream_registry: StreamRegistry) -> NetConnection {
+        let mut net_connection = NetConnection::new(Arc::new(apps), stream_registry);
+        net_connection.handshake_complete();
+        net_connection
+            .handle_message(
+                &connect_message(AMF0Value::Object(Properties::from([(
+                    "app",
+                    AMF0Value::String("live"),
+                )]))),
+                0,
+            )
+            .unwrap();
+        net_connection.handle_create_stream(2.0).unwrap();
+        net_connection
+    }
+
+    fn on_status_code(responses: &[OutgoingMessage]) -> Option<String> {
+        let [OutgoingMessage::Command { payload, .. }] = responses else {
+            return None;
+        };
+        let information = Decoder::new(payload).decode_all().nth(3)?.ok()?;
+        information.get("code")?.as_str().map(str::to_owned)
+    }
+
+    #[test]
+    fn test_duplicate_publish_is_rejected() {
+        let stream_registry = StreamRegistry::new();
+        let mut publisher = connected(stream_registry.clone());
+        let mut duplicate = connected(stream_registry.clone());
+
+        let responses = publisher
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+        assert_eq!(
+            on_status_code(&responses).as_deref(),
+            Some("NetStream.Publish.Start")
+        );
+
+        let responses = duplicate
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+        assert_eq!(
+            on_status_code(&responses).as_deref(),
+            Some("NetStream.Publish.BadName")
+        );
+
+        // the key is free again once the publisher goes away
+        drop(publisher);
+        assert!(!stream_registry.is_live("live/mystream"));
+    }
+
+    #[test]
+    fn test_set_chunk_size() {
+        let mut net_connection = net_connection();
+        assert_eq!(net_connection.max_chunk_size(), 128);
+
+        net_connection
+            .handle_message(
+                &Message::Protocol(ProtolControlMessage::SetChunkSize(4096)),
+                0,
+            )
+            .unwrap();
+        assert_eq!(net_connection.max_chunk_size(), 4096);
+
+        net_connection
+            .handle_message(&Message::Protocol(ProtolControlMessage::SetChunkSize(0)), 0)
+            .unwrap();
+        assert_eq!(net_connection.max_chunk_size(), 4096);
+
+        net_connection
+            .handle_message(
+                &Message::Protocol(ProtolControlMessage::SetChunkSize(u32::MAX)),
+                0,
+            )
+            .unwrap();
+        assert_eq!(net_connection.max_chunk_size(), MAX_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn test_ping_request_is_answered() {
+        let responses = net_connection()
+            .handle_message(
+                &Message::UserControl(UserControlMessage::PingRequest(0x12345678)),
+                0,
+            )
+            .unwrap();
+
+        assert_eq!(
+            responses,
+            [OutgoingMessage::UserControl(
+                UserControlMessage::PingResponse(0x12345678)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_ack_after_window() {
+        let mut net_connection = net_connection();
+        net_connection
+            .handle_message(
+                &Message::Protocol(ProtolControlMessage::AckWindowSize(1000)),
+                0,
+            )
+            .unwrap();
+        assert_eq!(net_connection.record_bytes_received(400), None);
+        assert_eq!(
+            net_connection.record_bytes_received(600),
+            Some(OutgoingMessage::Protocol(ProtolControlMessage::Ack(1000)))
+        );
+        // the window starts over after each ack
+        assert_eq!(net_connection.record_bytes_received(999), None);
+        assert_eq!(
+            net_connection.record_bytes_received(1),
+            Some(OutgoingMessage::Protocol(ProtolControlMessage::Ack(2000)))
+        );
+    }
+
+    #[test]
+    fn test_parse_get_stream_length() {
+        let stream_name = "vod";
+        let bytes = [
+            &[0x02],
+            (stream_name.len() as u16).to_be_bytes().as_slice(),
+            stream_name.as_bytes(),
+        ]
+        .concat();
+
+        for command in ["getStreamLength", "getMovLen"] {
+            assert!(matches!(
+                NetConnectionCommandType::parse(command, &bytes),
+                Ok(NetConnectionCommandType::GetStreamLength { stream_name: "vod" })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_get_stream_length_is_zero() {
+        let mut net_connection = connected(StreamRegistry::new());
+        let responses = net_connection
+            .handle_message(
+                &Message::Command(CommandMessage::NetConnectionCommand {
+                    command_type: NetConnectionCommandType::GetStreamLength {
+                        stream_name: "mystream",
+                    },
+                    transaction_id: 3.0,
+                    command_object: AMF0Value::Null,
+                }),
+                0,
+            )
+            .unwrap();
+
+        let [OutgoingMessage::Command { payload, .. }] = &responses[..] else {
+            assert!(matches!(responses[..], [OutgoingMessage::Command { .. }]));
+            return;
+        };
+        let mut decoder = Decoder::new(payload);
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::String("_result"));
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::Number(3.0));
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::Null);
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::Number(0.0));
+    }
+
+    #[tokio::test]
+    async fn test_delete_stream_unpublishes() {
+        let stream_registry = StreamRegistry::new();
+        let mut publisher = connected(stream_registry.clone());
+        let mut player = connected(stream_registry.clone());
+        publisher
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+        player
+            .handle_message(
+                &Message::Command(CommandMessage::NetStreamCommand {
+                    command: NetStreamCommand::Play {
+                        stream_name: "mystream",
+                        start: -2.0,
+                        duration: -1.0,
+                        reset: true,
+                    },
+                    transaction_id: 0.0,
+                    command_object: AMF0Value::Null,
+                }),
+                1,
+            )
+            .unwrap();
+
+        publisher
+            .handle_message(
+                &Message::Command(CommandMessage::NetStreamCommand {
+                    command: NetStreamCommand::DeleteStream { stream_id: 1 },
+                    transaction_id: 0.0,
+                    command_object: AMF0Value::Null,
+                }),
+                0,
+            )
+            .unwrap();
+        assert!(!publisher.has_stream(1));
+        assert!(!stream_registry.is_live("live/mystream"));
+
+        assert_eq!(
+            on_status_code(&[player.next_media().await]).as_deref(),
+            Some("NetStream.Play.UnpublishNotify")
+        );
+    }
+
+    fn receive_video_message(should_receive: bool) -> Message<'static> {
+        Message::Command(CommandMessage::NetStreamCommand {
+            command: NetStreamCommand::ReceiveVideo { should_receive },
+            transaction_id: 0.0,
+            command_object: AMF0Value::Null,
+        })
+    }
+
+    fn media_packet(message_type_id: u8, timestamp: u32, payload: &'static [u8]) -> MediaPacket {
+        MediaPacket {
+            message_type_id,
+            timestamp,
+            payload: bytes::Bytes::from_static(payload),
+        }
+    }
+
+    async fn next_packet(player: &mut NetConnection) -> Option<MediaPacket> {
+        match player.next_media().await {
+            OutgoingMessage::Media { packet, .. } => Some(packet),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_receive_video_toggle() {
+        let stream_registry = StreamRegistry::new();
+        let mut publisher = connected(stream_registry.clone());
+        let mut player = connected(stream_registry);
+        publisher
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+        player.handle_message(&play_message(false), 1).unwrap();
+
+        let keyframe = media_packet(command_message_type::VIDEO, 0, &[0x17, 0x01, 0, 0, 0]);
+        let interframe = media_packet(command_message_type::VIDEO, 40, &[0x27, 0x01, 0, 0, 0]);
+        let audio = media_packet(command_message_type::AUDIO, 20, &[0xAF, 0x01, 0x21]);
+
+        assert!(
+            player
+                .handle_message(&receive_video_message(false), 1)
+                .unwrap()
+                .is_empty()
+        );
+        publisher.forward_media(1, keyframe.clone());
+        publisher.forward_media(1, audio.clone());
+        publisher.forward_media(1, interframe.clone());
+        publisher.forward_media(1, audio.clone());
+        assert_eq!(next_packet(&mut player).await.as_ref(), Some(&audio));
+        assert_eq!(next_packet(&mut player).await.as_ref(), Some(&audio));
+
+        // video resumes at the next keyframe
+        player
+            .handle_message(&receive_video_message(true), 1)
+            .unwrap();
+        publisher.forward_media(1, interframe.clone());
+        publisher.forward_media(1, audio.clone());
+        publisher.forward_media(1, keyframe.clone());
+        publisher.forward_media(1, interframe.clone());
+        assert_eq!(next_packet(&mut player).await.as_ref(), Some(&audio));
+        assert_eq!(next_packet(&mut player).await.as_ref(), Some(&keyframe));
+        assert_eq!(next_packet(&mut player).await.as_ref(), Some(&interframe));
+    }
+
+    fn pause_message(is_paused: bool) -> Message<'static> {
+        Message::Command(CommandMessage::NetStreamCommand {
+            command: NetStreamCommand::Pause {
+                is_paused,
+                milliseconds: 1000.0,
+            },
+            transaction_id: 0.0,
+            command_object: AMF0Value::Null,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_unpause() {
+        let stream_registry = StreamRegistry::new();
+        let mut publisher = connected(stream_registry.clone());
+        let mut player = connected(stream_registry);
+        publisher
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+        player.handle_message(&play_message(false), 1).unwrap();
+
+        let keyframe = media_packet(command_message_type::VIDEO, 0, &[0x17, 0x01, 0, 0, 0]);
+        let interframe = media_packet(command_message_type::VIDEO, 40, &[0x27, 0x01, 0, 0, 0]);
+        let audio = media_packet(command_message_type::AUDIO, 20, &[0xAF, 0x01, 0x21]);
+
+        let responses = player.handle_message(&pause_message(true), 1).unwrap();
+        assert_eq!(
+            on_status_code(&responses).as_deref(),
+            Some("NetStream.Pause.Notify")
+        );
+        publisher.forward_media(1, keyframe.clone());
+        publisher.forward_media(1, audio.clone());
+        publisher.forward_media(1, interframe.clone());
+
+        let responses = player.handle_message(&pause_message(false), 1).unwrap();
+        assert_eq!(
+            on_status_code(&responses).as_deref(),
+            Some("NetStream.Unpause.Notify")
+        );
+        // nothing sent while paused is forwarded, and video resyncs on the next keyframe
+        publisher.forward_media(1, interframe.clone());
+        publisher.forward_media(1, audio.clone());
+        publisher.forward_media(1, keyframe.clone());
+        assert_eq!(next_packet(&mut player).await.as_ref(), Some(&audio));
+        assert_eq!(next_packet(&mut player).await.as_ref(), Some(&keyframe));
+    }
+
+    #[test]
+    fn test_pause_without_playing_is_ignored() {
+        let mut net_connection = connected(StreamRegistry::new());
+        assert!(
+            net_connection
+                .handle_message(&pause_message(true), 1)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    /// Send more interframes than a player can fall behind by, followed by a keyframe
+    fn overflow_player(publisher: &mut NetConnection, keyframe: &MediaPacket) {
+        for timestamp in 0..MEDIA_CHANNEL_CAPACITY as u32 + 10 {
+            publisher.forward_media(
+                1,
+                media_packet(
+                    command_message_type::VIDEO,
+                    timestamp,
+                    &[0x27, 0x01, 0, 0, 0],
+                ),
+            );
+        }
+        publisher.forward_media(1, keyframe.clone());
+    }
+
+    #[tokio::test]
+    async fn test_lagging_player_resyncs_on_keyframe() {
+        let stream_registry = StreamRegistry::new();
+        let mut publisher = connected(stream_registry.clone());
+        let mut player = connected(stream_registry);
+        publisher
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+        player.handle_message(&play_message(false), 1).unwrap();
+
+        let keyframe = media_packet(command_message_type::VIDEO, 5000, &[0x17, 0x01, 0, 0, 0]);
+        overflow_player(&mut publisher, &keyframe);
+        assert_eq!(next_packet(&mut player).await, Some(keyframe));
+        // every packet sent, the keyframe is replayed from the GOP cache
+        assert_eq!(player.dropped_packets(), MEDIA_CHANNEL_CAPACITY as u64 + 11);
+        assert!(!player.is_closing());
+    }
+
+    #[tokio::test]
+    async fn test_lagging_player_restarts_from_gop() {
+        let stream_registry = StreamRegistry::new();
+        let mut publisher = connected(stream_registry.clone());
+        let mut player = connected(stream_registry);
+        publisher
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+        player.handle_message(&play_message(false), 1).unwrap();
+
+        let sequence_header = media_packet(command_message_type::VIDEO, 0, &[0x17, 0x00, 0, 0, 0]);
+        let keyframe = media_packet(command_message_type::VIDEO, 0, &[0x17, 0x01, 0, 0, 0]);
+        let interframe = media_packet(command_message_type::VIDEO, 40, &[0x27, 0x01, 0, 0, 0]);
+        publisher.forward_media(1, sequence_header.clone());
+        publisher.forward_media(1, keyframe.clone());
+        // without a keyframe after the lag, only the cached GOP has one
+        for _ in 0..MEDIA_CHANNEL_CAPACITY {
+            publisher.forward_media(1, interframe.clone());
+        }
+
+        assert_eq!(next_packet(&mut player).await, Some(sequence_header));
+        assert_eq!(next_packet(&mut player).await, Some(keyframe));
+        assert_eq!(next_packet(&mut player).await, Some(interframe));
+        assert!(!player.is_closing());
+    }
+
+    #[tokio::test]
+    async fn test_media_of_every_played_stream_is_received() {
+        let stream_registry = StreamRegistry::new();
+        let first = stream_registry.publish("live/mystream").unwrap();
+        let second = stream_registry.publish("live/other").unwrap();
+        let mut player = connected(stream_registry);
+        player.handle_create_stream(3.0).unwrap();
+        player.handle_message(&play_message(false), 1).unwrap();
+        let play_other = Message::Command(CommandMessage::NetStreamCommand {
+            command: NetStreamCommand::Play {
+                stream_name: "other",
+                start: -2.0,
+                duration: -1.0,
+                reset: false,
+            },
+            transaction_id: 0.0,
+            command_object: AMF0Value::Null,
+        });
+        player.handle_message(&play_other, 2).unwrap();
+
+        // each stream is fed while the other stays idle, whichever one is polled first
+        for (publisher, played_on) in [(&second, 2), (&first, 1)] {
+            let audio = media_packet(command_message_type::AUDIO, 0, &[0xaf, 0x01]);
+            publisher.send(audio.clone());
+            let received = tokio::time::timeout(Duration::from_secs(1), player.next_media())
+                .await
+                .unwrap();
+            assert!(matches!(
+                received,
+                OutgoingMessage::Media { message_stream_id, packet }
+                    if message_stream_id == played_on && packet == audio
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_player_lagging_repeatedly_is_disconnected() {
+        let stream_registry = StreamRegistry::new();
+        let mut publisher = connected(stream_registry.clone());
+        let mut player = connected(stream_registry);
+        publisher
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+        player.handle_message(&play_message(false), 1).unwrap();
+
+        let keyframe = media_packet(command_message_type::VIDEO, 5000, &[0x17, 0x01, 0, 0, 0]);
+        for _ in 0..MAX_PLAYER_LAGS {
+            overflow_player(&mut publisher, &keyframe);
+            assert_eq!(next_packet(&mut player).await.as_ref(), Some(&keyframe));
+        }
+
+        overflow_player(&mut publisher, &keyframe);
+        assert_eq!(
+            on_status_code(&[player.next_media().await]).as_deref(),
+            Some("NetStream.Play.InsufficientBW")
+        );
+        assert!(player.is_closing());
+        assert_eq!(player.state(), ConnectionState::Connected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_player_lags_are_forgotten_after_a_clean_window() {
+        let stream_registry = StreamRegistry::new();
+        let mut publisher = connected(stream_registry.clone());
+        let mut player = connected(stream_registry);
+        publisher
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+        player.handle_message(&play_message(false), 1).unwrap();
+
+        let keyframe = media_packet(command_message_type::VIDEO, 5000, &[0x17, 0x01, 0, 0, 0]);
+        for _ in 0..MAX_PLAYER_LAGS {
+            overflow_player(&mut publisher, &keyframe);
+            assert_eq!(next_packet(&mut player).await.as_ref(), Some(&keyframe));
+        }
+
+        tokio::time::advance(netstream::PLAYER_LAG_WINDOW).await;
+        overflow_player(&mut publisher, &keyframe);
+        assert_eq!(next_packet(&mut player).await, Some(keyframe));
+        assert!(!player.is_closing());
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn test_stream_level_record_override() {
+        let record_dir =
+            std::env::temp_dir().join(format!("castelia-record-override-{}", std::process::id()));
+        std::fs::create_dir_all(&record_dir).unwrap();
+        let config = ServerConfig::parse(&format!(
+            r#"{{
+                "apps": {{ "live": {{ "streams": {{ "recorded": {{ "record": true }} }} }} }},
+                "record_dir": {:?}
+            }}"#,
+            record_dir.to_str().unwrap()
+        ))
+        .unwrap();
+        let stream_registry = StreamRegistry::new();
+        for publishing_name in ["recorded?token=abc123", "other"] {
+            let mut publisher = connected_to(AppRegistry::from(&config), stream_registry.clone());
+            publisher
+                .handle_message(&publish_message(publishing_name), 1)
+                .unwrap();
+        }
+
+        let mut recordings = vec![];
+        for _ in 0..100 {
+            recordings = std::fs::read_dir(&record_dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            if !recordings.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        std::fs::remove_dir_all(&record_dir).unwrap();
+
+        let [recording] = recordings.as_slice() else {
+            assert_eq!(recordings.len(), 1);
+            return;
+        };
+        assert!(recording.starts_with("live_recorded-"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_publisher_over_max_bitrate_is_closed() {
+        let config = ServerConfig::parse(
+            r#"{ "apps": { "live": { "streams": { "limited": { "max_bitrate_kbps": 100 } } } } }"#,
+        )
+        .unwrap();
+        let apps = AppRegistry::from(&config);
+        let mut limited = connected_to(apps.clone(), StreamRegistry::new());
+        let mut unlimited = connected_to(apps, StreamRegistry::new());
+        limited
+            .handle_message(&publish_message("limited"), 1)
+            .unwrap();
+        unlimited
+            .handle_message(&publish_message("unlimited"), 1)
+            .unwrap();
+
+        // 2000 bytes every 100ms is 160 kbps
+        for timestamp in (0..=5000).step_by(100) {
+            let packet = media_packet(command_message_type::VIDEO, timestamp, &[0x27; 2000]);
+            limited.forward_media(1, packet.clone());
+            unlimited.forward_media(1, packet);
+        }
+        assert!(limited.is_closing());
+        assert!(!unlimited.is_closing());
+    }
+
+    #[test]
+    fn test_close_stream_keeps_stream_id() {
+        let stream_registry = StreamRegistry::new();
+        let mut net_connection = connected(stream_registry.clone());
+        net_connection
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+
+        net_connection
+            .handle_message(
+                &Message::Command(CommandMessage::NetStreamCommand {
+                    command: NetStreamCommand::CloseStream { stream_id: 1 },
+                    transaction_id: 0.0,
+                    command_object: AMF0Value::Null,
+                }),
+                1,
+            )
+            .unwrap();
+        assert!(net_connection.has_stream(1));
+        assert!(!stream_registry.is_live("live/mystream"));
+    }
+
+    #[test]
+    fn test_state_follows_valid_sequence() {
+        let mut net_connection = net_connection();
+        assert_eq!(net_connection.state(), ConnectionState::Connecting);
+
+        net_connection
+            .handle_message(
+                &connect_message(AMF0Value::Object(Properties::from([(
+                    "app",
+                    AMF0Value::String("live"),
+                )]))),
+                0,
+            )
+            .unwrap();
+        assert_eq!(net_connection.state(), ConnectionState::Connected);
+
+        net_connection.handle_create_stream(2.0).unwrap();
+        net_connection
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+        assert_eq!(net_connection.state(), ConnectionState::Publishing);
+
+        net_connection.handle_close_stream(1);
+        assert_eq!(net_connection.state(), ConnectionState::Connected);
+        assert!(!net_connection.is_closing());
+    }
+
+    #[test]
+    fn test_publish_with_query() {
+        let stream_registry = StreamRegistry::new();
+        let mut publisher = connected(stream_registry.clone());
+
+        let responses = publisher
+            .handle_message(&publish_message("mystream?token=abc123"), 1)
+            .unwrap();
+        assert_eq!(
+            on_status_code(&responses).as_deref(),
+            Some("NetStream.Publish.Start")
+        );
+        assert!(stream_registry.is_live("live/mystream"));
+
+        // a different token doesn't make it a different stream
+        let mut duplicate = connected(stream_registry.clone());
+        let responses = duplicate
+            .handle_message(&publish_message("mystream?token=other"), 1)
+            .unwrap();
+        assert_eq!(
+            on_status_code(&responses).as_deref(),
+            Some("NetStream.Publish.BadName")
+        );
+    }
+
+    /// Lets publishers through with `?token=` followed by the token
+    #[derive(Debug)]
+    struct AllowToken(&'static str);
+
+    impl Authorizer for AllowToken {
+        fn authorize_publish(
+            &self,
+            _app: &str,
+            _stream_name: &str,
+            query: &HashMap<&str, &str>,
+        ) -> bool {
+            query.get("token") == Some(&self.0)
+        }
+    }
+
+    fn auth_required_apps() -> AppRegistry {
+        let live = AppConfig {
+            settings: SettingsOverride {
+                auth_required: Some(true),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let config = ServerConfig {
+            apps: HashMap::from([("live".to_owned(), live)]),
+            ..Default::default()
+        };
+        AppRegistry::from(&config)
+    }
+
+    #[test]
+    fn test_publish_requires_authorization() {
+        let mut publisher = connected_to(auth_required_apps(), StreamRegistry::new());
+        let responses = publisher
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+        assert_eq!(
+            on_status_code(&responses).as_deref(),
+            Some("NetStream.Publish.Unauthorized")
+        );
+
+        let stream_registry = StreamRegistry::new();
+        let apps = auth_required_apps().with_authorizer(AllowToken("abc123"));
+        let mut publisher = connected_to(apps, stream_registry.clone());
+        for publishing_name in ["mystream", "mystream?token=other"] {
+            let responses = publisher
+                .handle_message(&publish_message(publishing_name), 1)
+                .unwrap();
+            assert_eq!(
+                on_status_code(&responses).as_deref(),
+                Some("NetStream.Publish.Unauthorized")
+            );
+        }
+        let responses = publisher
+            .handle_message(&publish_message("mystream?token=abc123"), 1)
+            .unwrap();
+        assert_eq!(
+            on_status_code(&responses).as_deref(),
+            Some("NetStream.Publish.Start")
+        );
+        assert!(stream_registry.is_live("live/mystream"));
+    }
+
+    #[test]
+    fn test_app_that_doesnt_allow_publishing() {
+        let apps = AppRegistry::new().with_app(
+            "live",
+            AppOptions {
+                allow_publish: false,
+                ..AppOptions::default()
+            },
+        );
+        let stream_registry = StreamRegistry::new();
+        let mut publisher = connected_to(apps, stream_registry.clone());
+
+        let responses = publisher
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+        assert_eq!(
+            on_status_code(&responses).as_deref(),
+            Some("NetStream.Publish.BadName")
+        );
+        assert!(!stream_registry.is_live("live/mystream"));
+    }
+
+    #[test]
+    fn test_app_that_doesnt_allow_playing() {
+        let apps = AppRegistry::new().with_app(
+            "live",
+            AppOptions {
+                allow_play: false,
+                ..AppOptions::default()
+            },
+        );
+        let stream_registry = StreamRegistry::new();
+        let _publisher = stream_registry.publish("live/mystream").unwrap();
+        let mut player = connected_to(apps, stream_registry.clone());
+
+        let responses = player.handle_message(&play_message(false), 1).unwrap();
+        assert_eq!(
+            on_status_code(&responses).as_deref(),
+            Some("NetStream.Play.Failed")
+        );
+        assert!(!player.streams[&1].is_playing());
+    }
+
+    #[test]
+    fn test_publish_before_connect_closes() {
+        let mut net_connection = net_connection();
+        let responses = net_connection
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+
+        assert!(responses.is_empty());
+        assert!(net_connection.is_closing());
+    }
+
+    #[test]
+    fn test_second_connect_closes() {
+        let mut net_connection = connected(StreamRegistry::new());
+        net_connection
+            .handle_message(
+                &connect_message(AMF0Value::Object(Properties::from([(
+                    "app",
+                    AMF0Value::String("live"),
+                )]))),
+                0,
+            )
+            .unwrap();
+
+        assert!(net_connection.is_closing());
+    }
+
+    fn play_message(reset: bool) -> Message<'static> {
+        Message::Command(CommandMessage::NetStreamCommand {
+            command: NetStreamCommand::Play {
+                stream_name: "mystream",
+                start: -2.0,
+                duration: -1.0,
+                reset,
+            },
+            transaction_id: 0.0,
+            command_object: AMF0Value::Null,
+        })
+    }
+
+    /// Every stream is published to the node listening at the address
+    #[derive(Debug)]
+    struct OnNode(std::net::SocketAddr);
+
+    impl RemoteStreams for OnNode {
+        fn locate(&self, key: &str) -> Option<RemoteStream> {
+            Some(RemoteStream {
+                node_id: "origin".to_owned(),
+                url: format!("rtmp://{}/{key}", self.0).parse().unwrap(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_play_remote_stream_pulls_it() {
+        let origin = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream_registry =
+            StreamRegistry::new().with_remote_streams(OnNode(origin.local_addr().unwrap()));
+        let mut player = connected(stream_registry.clone());
+
+        let responses = player.handle_message(&play_message(false), 1).unwrap();
+        assert_eq!(
+            on_status_code(&responses[1..2]).as_deref(),
+            Some("NetStream.Play.Start")
+        );
+        assert!(stream_registry.is_live("live/mystream"));
+
+        // the relay connects to the origin to pull the stream
+        tokio::time::timeout(Duration::from_secs(5), origin.accept())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    /// The onStatus code of each command response, or the kind of any other response
+    fn response_kinds(responses: &[OutgoingMessage]) -> Vec<String> {
+        responses
+            .iter()
+            .map(|response| match response {
+                OutgoingMessage::Command { .. } => {
+                    on_status_code(std::slice::from_ref(response)).unwrap_or_default()
+                }
+                OutgoingMessage::UserControl(UserControlMessage::StreamBegin(_)) => {
+                    "StreamBegin".to_owned()
+                }
+                OutgoingMessage::Data { .. } => "Data".to_owned(),
+                response => format!("{response:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_play_with_reset() {
+        let stream_registry = StreamRegistry::new();
+        let _publisher = stream_registry.publish("live/mystream").unwrap();
+        let mut player = connected(stream_registry);
+
+        let responses = player.handle_message(&play_message(true), 1).unwrap();
+        assert_eq!(
+            response_kinds(&responses),
+            [
+                "StreamBegin",
+                "NetStream.Play.Reset",
+                "NetStream.Play.Start",
+                "Data"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_play_without_reset() {
+        let stream_registry = StreamRegistry::new();
+        let _publisher = stream_registry.publish("live/mystream").unwrap();
+        let mut player = connected(stream_registry);
+
+        let responses = player.handle_message(&play_message(false), 1).unwrap();
+        assert_eq!(
+            response_kinds(&responses),
+            ["StreamBegin", "NetStream.Play.Start", "Data"]
+        );
+    }
+
+    /// The audio and video booleans of the `|RtmpSampleAccess` sent when play starts
+    fn sample_access(apps: AppRegistry) -> Vec<bool> {
+        let stream_registry = StreamRegistry::new();
+        let _publisher = stream_registry.publish("live/mystream").unwrap();
+        let mut player = connected_to(apps, stream_registry);
+
+        let responses = player.handle_message(&play_message(false), 1).unwrap();
+        let Some(OutgoingMessage::Data { payload, .. }) = responses.last() else {
+            assert_eq!(response_kinds(&responses).last().unwrap(), "Data");
+            return vec![];
+        };
+        let values = Decoder::new(payload)
+            .decode_all()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(values[0], AMF0Value::String("|RtmpSampleAccess"));
+        values[1..]
+            .iter()
+            .filter_map(|value| match value {
+                AMF0Value::Boolean(access) => Some(*access),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sample_access() {
+        let apps = AppRegistry::new().with_app("live", AppOptions::default());
+        assert_eq!(sample_access(apps), [true, true]);
+
+        let live = AppConfig {
+            settings: SettingsOverride {
+                audio_sample_access: Some(false),
+                video_sample_access: Some(false),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let config = ServerConfig {
+            apps: HashMap::from([("live".to_owned(), live)]),
+            ..Default::default()
+        };
+        assert_eq!(sample_access(AppRegistry::from(&config)), [false, false]);
+    }
+
+    #[test]
+    fn test_play_while_publishing_is_rejected() {
+        let stream_registry = StreamRegistry::new();
+        let mut net_connection = connected(stream_registry.clone());
+        net_connection
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+        net_connection.handle_create_stream(3.0).unwrap();
+
+        let responses = net_connection
+            .handle_message(
+                &Message::Command(CommandMessage::NetStreamCommand {
+                    command: NetStreamCommand::Play {
+                        stream_name: "mystream",
+                        start: -2.0,
+                        duration: -1.0,
+                        reset: true,
+                    },
+                    transaction_id: 0.0,
+                    command_object: AMF0Value::Null,
+                }),
+                2,
+            )
+            .unwrap();
+        assert_eq!(
+            on_status_code(&responses).as_deref(),
+            Some("NetStream.Play.Failed")
+        );
+        assert!(!net_connection.is_closing());
+        assert_eq!(net_connection.state(), ConnectionState::Publishing);
+    }
+
+    #[tokio::test]
+    async fn test_metadata_is_stored() {
+        let stream_registry = StreamRegistry::new();
+        let mut net_connection = connected(stream_registry.clone());
+        net_connection
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+
+        net_connection
+            .handle_message(
+                &Message::Command(CommandMessage::Data {
+                    name: "onMetaData",
+                    value: AMF0Value::EcmaArray {
+                        count: 4,
+                        properties: Properties::from([
+                            ("width", AMF0Value::Number(1280.0)),
+                            ("height", AMF0Value::Number(720.0)),
+                            ("videocodecid", AMF0Value::Number(7.0)),
+                            ("encoder", AMF0Value::String("obs-output module")),
+                        ]),
+                    },
+                }),
+                1,
+            )
+            .unwrap();
+
+        assert_eq!(
+            stream_registry.live_streams()[0].metadata,
+            StreamMetadata {
+                width: Some(1280.0),
+                height: Some(720.0),
+                video_codec_id: Some(7.0),
+                encoder: Some("obs-output module".to_owned()),
+                ..StreamMetadata::default()
+            }
+        );
+
+        // players joining later get the metadata first, re-encoded as a plain onMetaData
+        let mut player = stream_registry.subscribe("live/mystream").unwrap();
+        let packet = player.recv().await.unwrap();
+        assert_eq!(packet.message_type_id, command_message_type::DATA_AMF0);
+        assert_eq!(packet.payload[..13], *b"\x02\x00\x0aonMetaData");
+    }
+
+    #[test]
+    fn test_parse_fmle_commands() {
+        let bytes = [&[0x02, 0x00, 0x03][..], b"key"].concat();
+
+        assert!(matches!(
+            NetConnectionCommandType::parse("releaseStream", &bytes),
+            Ok(NetConnectionCommandType::ReleaseStream { stream_name: "key" })
+        ));
+        assert!(matches!(
+            NetConnectionCommandType::parse("FCPublish", &bytes),
+            Ok(NetConnectionCommandType::FCPublish { stream_name: "key" })
+        ));
+        assert!(matches!(
+            NetConnectionCommandType::parse("FCUnpublish", &bytes),
+            Ok(NetConnectionCommandType::FCUnpublish { stream_name: "key" })
+        ));
+    }
+
+    fn fmle_message(command_type: NetConnectionCommandType<'_>) -> Message<'_> {
+        Message::Command(CommandMessage::NetConnectionCommand {
+            command_type,
+            transaction_id: 4.0,
+            command_object: AMF0Value::Null,
+        })
+    }
+
+    #[test]
+    fn test_fc_unpublish_and_release_stream_unpublish() {
+        let stream_registry = StreamRegistry::new();
+        let mut net_connection = connected(stream_registry.clone());
+        net_connection
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+        assert!(stream_registry.is_live("live/mystream"));
+
+        // unpublishing a stream this connection isn't publishing does nothing
+        net_connection
+            .handle_message(
+                &fmle_message(NetConnectionCommandType::FCUnpublish {
+                    stream_name: "other",
+                }),
+                0,
+            )
+            .unwrap();
+        assert!(stream_registry.is_live("live/mystream"));
+
+        let responses = net_connection
+            .handle_message(
+                &fmle_message(NetConnectionCommandType::FCUnpublish {
+                    stream_name: "mystream",
+                }),
+                0,
+            )
+            .unwrap();
+        assert_eq!(responses.len(), 2);
+        assert!(!stream_registry.is_live("live/mystream"));
+
+        // the message stream can publish again once released
+        net_connection
+            .handle_message(&publish_message("mystream"), 1)
+            .unwrap();
+        assert!(stream_registry.is_live("live/mystream"));
+        net_connection
+            .handle_message(
+                &fmle_message(NetConnectionCommandType::ReleaseStream {
+                    stream_name: "mystream",
+                }),
+                0,
+            )
+            .unwrap();
+        assert!(!stream_registry.is_live("live/mystream"));
+    }
+
+    #[test]
+    fn test_connect_without_object_encoding() {
+        let mut net_connection = net_connection();
+        net_connection
+            .handle_message(
+                &connect_message(AMF0Value::Object(Properties::from([(
+                    "app",
+                    AMF0Value::String("live"),
+                )]))),
+                0,
+            )
+            .unwrap();
+
+        assert_eq!(net_connection.state(), ConnectionState::Connected);
+        assert_eq!(net_connection.connect_params().unwrap().app, "live");
+    }
 }
@@ -0,0 +1,135 @@
+use crate::{
+    messages::{Message, command::CommandMessage},
+    netconnection::NetConnectionCommandType,
+    netstream::NetStreamCommand,
+};
+
+/// Where a connection is in the connect, createStream and publish or play sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Exchanging the RTMP handshake, no messages can be handled yet
+    Handshaking,
+    /// Waiting for the client to connect to an app
+    Connecting,
+    /// Connected to an app, but not publishing or playing anything
+    Connected,
+    Publishing,
+    Playing,
+}
+
+/// How to respond to a message that isn't allowed in the current state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// The client is out of step with the connection, e.g. it skipped connect, so close it
+    Close,
+    /// Reply with an error onStatus with this code and carry on
+    Reject(&'static str),
+}
+
+impl ConnectionState {
+    /// Whether connect has succeeded
+    pub fn is_connected(&self) -> bool {
+        matches!(self, Self::Connected | Self::Publishing | Self::Playing)
+    }
+
+    /// Check that `message` can be handled in this state
+    pub(crate) fn check(&self, message: &Message) -> Result<(), Violation> {
+        if *self == Self::Handshaking {
+            return Err(Violation::Close);
+        }
+
+        match message {
+            Message::Command(CommandMessage::NetConnectionCommand {
+                command_type: NetConnectionCommandType::Connect,
+                ..
+            }) if *self != Self::Connecting => Err(Violation::Close),
+            Message::Command(CommandMessage::NetConnectionCommand {
+                command_type: NetConnectionCommandType::Connect,
+                ..
+            }) => Ok(()),
+            Message::Command(
+                CommandMessage::NetConnectionCommand { .. }
+                | CommandMessage::NetStreamCommand { .. },
+            ) if !self.is_connected() => Err(Violation::Close),
+            Message::Command(CommandMessage::NetStreamCommand {
+                command: NetStreamCommand::Publish { .. },
+                ..
+            }) if *self == Self::Playing => {
+                Err(Violation::Reject("NetStream.Publish.BadConnection"))
+            }
+            Message::Command(CommandMessage::NetStreamCommand {
+                command: NetStreamCommand::Play { .. } | NetStreamCommand::Play2 { .. },
+                ..
+            }) if *self == Self::Publishing => Err(Violation::Reject("NetStream.Play.Failed")),
+            // media sent without publishing is dropped when it is forwarded
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{amf::AMF0Value, messages::protocol_control::ProtolControlMessage};
+
+    fn net_connection_command(command_type: NetConnectionCommandType<'_>) -> Message<'_> {
+        Message::Command(CommandMessage::NetConnectionCommand {
+            command_type,
+            transaction_id: 1.0,
+            command_object: AMF0Value::Null,
+        })
+    }
+
+    fn net_stream_command(command: NetStreamCommand<'_>) -> Message<'_> {
+        Message::Command(CommandMessage::NetStreamCommand {
+            command,
+            transaction_id: 0.0,
+            command_object: AMF0Value::Null,
+        })
+    }
+
+    #[test]
+    fn test_check() {
+        let connect = net_connection_command(NetConnectionCommandType::Connect);
+        let create_stream = net_connection_command(NetConnectionCommandType::CreateStream);
+        let publish = net_stream_command(NetStreamCommand::Publish {
+            publishing_name: "mystream",
+            publishing_type: "live",
+        });
+        let play = net_stream_command(NetStreamCommand::Play {
+            stream_name: "mystream",
+            start: -2.0,
+            duration: -1.0,
+            reset: true,
+        });
+        let set_chunk_size = Message::Protocol(ProtolControlMessage::SetChunkSize(4096));
+
+        use ConnectionState::*;
+        let cases = [
+            (Handshaking, &set_chunk_size, Err(Violation::Close)),
+            (Connecting, &set_chunk_size, Ok(())),
+            (Connecting, &connect, Ok(())),
+            (Connecting, &create_stream, Err(Violation::Close)),
+            (Connecting, &publish, Err(Violation::Close)),
+            (Connected, &connect, Err(Violation::Close)),
+            (Connected, &create_stream, Ok(())),
+            (Connected, &publish, Ok(())),
+            (Connected, &play, Ok(())),
+            (Publishing, &publish, Ok(())),
+            (
+                Publishing,
+                &play,
+                Err(Violation::Reject("NetStream.Play.Failed")),
+            ),
+            (Playing, &play, Ok(())),
+            (
+                Playing,
+                &publish,
+                Err(Violation::Reject("NetStream.Publish.BadConnection")),
+            ),
+        ];
+        for (state, message, expected) in cases {
+            assert_eq!(state.check(message), expected, "{state:?} {message:?}");
+        }
+    }
+}
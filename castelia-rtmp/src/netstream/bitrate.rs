@@ -0,0 +1,68 @@
+/// How much media a bitrate is measured over, long enough that a large keyframe doesn't count
+/// as going over the limit
+const WINDOW_MS: u32 = 5000;
+
+/// Measures the bitrate a publisher sends at from the timestamps of its media
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BitrateMeter {
+    /// The timestamp the current window started at, [`None`] until the first packet
+    window_start: Option<u32>,
+    window_bytes: u64,
+}
+
+impl BitrateMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a packet, returning the bitrate in kbps each time a window is complete
+    pub fn record(&mut self, timestamp: u32, len: usize) -> Option<u32> {
+        let start = *self.window_start.get_or_insert(timestamp);
+        // timestamps going backwards, e.g. when the publisher restarts them, start over
+        let elapsed = timestamp.wrapping_sub(start);
+        if (elapsed as i32) < 0 {
+            *self = Self {
+                window_start: Some(timestamp),
+                window_bytes: len as u64,
+            };
+            return None;
+        }
+
+        self.window_bytes += len as u64;
+        if elapsed < WINDOW_MS {
+            return None;
+        }
+
+        // bits per millisecond is kilobits per second
+        let kbps = self.window_bytes * 8 / u64::from(elapsed);
+        *self = Self {
+            window_start: Some(timestamp),
+            window_bytes: 0,
+        };
+        Some(kbps.try_into().unwrap_or(u32::MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitrate_per_window() {
+        let mut meter = BitrateMeter::new();
+        // 1000 bytes every 100ms is 80 kbps
+        for timestamp in (0..WINDOW_MS).step_by(100) {
+            assert_eq!(meter.record(timestamp, 1000), None);
+        }
+        assert_eq!(meter.record(WINDOW_MS, 1000), Some(81));
+        assert_eq!(meter.record(WINDOW_MS + 100, 1000), None);
+    }
+
+    #[test]
+    fn test_timestamps_going_backwards_start_over() {
+        let mut meter = BitrateMeter::new();
+        meter.record(10_000, 1000);
+        assert_eq!(meter.record(0, 1000), None);
+        assert_eq!(meter.record(WINDOW_MS, 1000), Some(3));
+    }
+}
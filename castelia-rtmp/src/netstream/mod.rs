@@ -1,8 +1,260 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::{sync::broadcast::error::RecvError, time::Instant};
+
 use crate::{
-    amf::{AMF0Value, Decoder},
-    messages,
+    amf::{AMF0Value, Decoder, EncodeError, Properties},
+    messages::{
+        self, OutgoingMessage,
+        command::{command_decoder, command_message_type, encode_command},
+        media::{VideoTagHeader, avc_packet_type},
+    },
+    netstream::bitrate::BitrateMeter,
+    stream_registry::{MediaPacket, Publisher, Subscriber},
 };
 
+pub mod bitrate;
+pub mod stream_name;
+
+/// How long a player has to keep up before the times it fell behind are forgotten
+pub(crate) const PLAYER_LAG_WINDOW: Duration = Duration::from_secs(60);
+
+/// A message stream created with createStream
+#[derive(Debug)]
+pub struct NetStream {
+    publisher: Option<Publisher>,
+    /// The most the publisher is allowed to send, in kbps
+    max_bitrate_kbps: Option<u32>,
+    bitrate: BitrateMeter,
+    /// Packets from the publisher of the stream being played
+    player: Option<Subscriber>,
+    /// Set with receiveAudio, whether audio is forwarded to the player
+    receive_audio: bool,
+    /// Set with receiveVideo, whether video is forwarded to the player
+    receive_video: bool,
+    /// Set with pause, nothing but sequence headers is forwarded while paused
+    paused: bool,
+    /// Set when video is turned back on, the player unpauses or falls behind, so the player
+    /// doesn't get frames it can't decode
+    awaiting_keyframe: bool,
+    /// How many times the player fell behind, each less than [`PLAYER_LAG_WINDOW`] after the
+    /// previous one
+    lags: u32,
+    last_lag: Option<Instant>,
+}
+
+impl Default for NetStream {
+    fn default() -> Self {
+        Self {
+            publisher: None,
+            max_bitrate_kbps: None,
+            bitrate: BitrateMeter::new(),
+            player: None,
+            receive_audio: true,
+            receive_video: true,
+            paused: false,
+            awaiting_keyframe: false,
+            lags: 0,
+            last_lag: None,
+        }
+    }
+}
+
+impl NetStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_publishing(&self) -> bool {
+        self.publisher.is_some()
+    }
+
+    /// Publish the stream, limited to `max_bitrate_kbps` if it is set
+    pub fn set_publisher(&mut self, publisher: Publisher, max_bitrate_kbps: Option<u32>) {
+        self.publisher = Some(publisher);
+        self.max_bitrate_kbps = max_bitrate_kbps;
+        self.bitrate = BitrateMeter::new();
+    }
+
+    pub fn publisher(&self) -> Option<&Publisher> {
+        self.publisher.as_ref()
+    }
+
+    /// Count a packet from the publisher, returning the bitrate it is sending at if that is over
+    /// its limit
+    pub fn exceeded_bitrate(&mut self, packet: &MediaPacket) -> Option<u32> {
+        let max_bitrate_kbps = self.max_bitrate_kbps?;
+        self.bitrate
+            .record(packet.timestamp, packet.payload.len())
+            .filter(|kbps| *kbps > max_bitrate_kbps)
+    }
+
+    /// Unpublish the stream, closing it for everyone playing it once the returned publisher is
+    /// dropped
+    pub fn stop_publishing(&mut self) -> Option<Publisher> {
+        self.publisher.take()
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.player.is_some()
+    }
+
+    pub fn set_player(&mut self, player: Subscriber) {
+        self.player = Some(player);
+        self.lags = 0;
+        self.last_lag = None;
+    }
+
+    pub fn player(&self) -> Option<&Subscriber> {
+        self.player.as_ref()
+    }
+
+    pub fn stop_playing(&mut self) -> Option<Subscriber> {
+        self.player.take()
+    }
+
+    pub fn set_receive_audio(&mut self, receive_audio: bool) {
+        self.receive_audio = receive_audio;
+    }
+
+    /// Turning video back on resumes it at the next keyframe
+    pub fn set_receive_video(&mut self, receive_video: bool) {
+        if receive_video && !self.receive_video {
+            self.awaiting_keyframe = true;
+        }
+        self.receive_video = receive_video;
+    }
+
+    /// Packets sent while paused are dropped, so unpausing resumes at the live point, starting
+    /// with the next keyframe
+    pub fn set_paused(&mut self, paused: bool) {
+        if !paused && self.paused {
+            if let Some(player) = &mut self.player {
+                player.skip_to_live();
+            }
+            self.awaiting_keyframe = true;
+        }
+        self.paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.is_playing() && self.paused
+    }
+
+    /// Restart the player from the cached GOP after it fell behind and missed packets. Returns
+    /// how many times in a row it has fallen behind and how many more packets were dropped to
+    /// catch up
+    pub fn lagged(&mut self) -> (u32, u64) {
+        let now = Instant::now();
+        if self
+            .last_lag
+            .is_some_and(|last_lag| now - last_lag >= PLAYER_LAG_WINDOW)
+        {
+            self.lags = 0;
+        }
+        self.last_lag = Some(now);
+        self.lags += 1;
+
+        // the GOP starts with a keyframe, without one cached this waits for the next live one
+        self.awaiting_keyframe = true;
+        let dropped = self.player.as_mut().map_or(0, Subscriber::replay_gop);
+        (self.lags, dropped)
+    }
+
+    /// Wait for the next packet the player wants of the stream being played, never completes if
+    /// nothing is being played
+    pub async fn recv(&mut self) -> Result<MediaPacket, RecvError> {
+        let Some(player) = &mut self.player else {
+            return std::future::pending().await;
+        };
+
+        loop {
+            let packet = player.recv().await?;
+            match packet.message_type_id {
+                command_message_type::AUDIO if self.paused || !self.receive_audio => continue,
+                command_message_type::VIDEO => {
+                    let Ok(header) = VideoTagHeader::parse(&packet.payload) else {
+                        continue;
+                    };
+                    // sequence headers always go through, so the player can decode the stream
+                    // whenever video is turned back on
+                    if header.avc_packet_type != Some(avc_packet_type::SEQUENCE_HEADER) {
+                        if self.paused
+                            || !self.receive_video
+                            || (self.awaiting_keyframe && !header.is_keyframe())
+                        {
+                            continue;
+                        }
+                        self.awaiting_keyframe = false;
+                    }
+                }
+                _ => {}
+            }
+            return Ok(packet);
+        }
+    }
+}
+
+/// Encode an `onStatus` command reporting a change in the state of a stream
+pub fn build_on_status(
+    transaction_id: f64,
+    level: &str,
+    code: &str,
+    description: &str,
+) -> Result<Bytes, EncodeError> {
+    encode_command(&[
+        AMF0Value::String("onStatus"),
+        AMF0Value::Number(transaction_id),
+        AMF0Value::Null,
+        AMF0Value::Object(Properties::from([
+            ("level", AMF0Value::String(level)),
+            ("code", AMF0Value::String(code)),
+            ("description", AMF0Value::String(description)),
+        ])),
+    ])
+}
+
+/// An `onStatus` command to send on `message_stream_id`, see [`build_on_status`]
+pub fn on_status(
+    message_stream_id: u32,
+    level: &str,
+    code: &str,
+    description: &str,
+) -> Result<OutgoingMessage, EncodeError> {
+    Ok(OutgoingMessage::Command {
+        message_stream_id,
+        payload: build_on_status(0.0, level, code, description)?,
+    })
+}
+
+/// What play defaults to for the arguments players leave out
+pub mod play_defaults {
+    /// Play a live stream, falling back to a recorded one
+    pub const START: f64 = -2.0;
+    /// Play until the stream ends
+    pub const DURATION: f64 = -1.0;
+    pub const RESET: bool = true;
+}
+
+/// Decode the next argument of a command, `default` if the command ends before it
+fn decode_or<'a, T>(
+    decoder: &mut Decoder<'a>,
+    default: T,
+) -> Result<T, messages::command::ParseError>
+where
+    T: TryFrom<AMF0Value<'a>>,
+    messages::command::ParseError: From<T::Error>,
+{
+    if decoder.remaining().is_empty() {
+        return Ok(default);
+    }
+    Ok(decoder.decode()?.try_into()?)
+}
+
+/// A command sent on a message stream. Every argument of the command is parsed, including the
+/// ones that don't apply to live streams
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum NetStreamCommand<'a> {
     Play {
@@ -41,12 +293,13 @@ pub enum NetStreamCommand<'a> {
 }
 
 impl<'a> NetStreamCommand<'a> {
+    /// Everything after the stream name is optional, ffmpeg only sends the start
     fn parse_play(buf: &'a [u8]) -> Result<Self, messages::command::ParseError> {
-        let mut decoder = Decoder::new(buf);
+        let mut decoder = command_decoder(buf);
         let stream_name = decoder.decode()?.try_into()?;
-        let start = decoder.decode()?.try_into()?;
-        let duration = decoder.decode()?.try_into()?;
-        let reset = decoder.decode()?.try_into()?;
+        let start = decode_or(&mut decoder, play_defaults::START)?;
+        let duration = decode_or(&mut decoder, play_defaults::DURATION)?;
+        let reset = decode_or(&mut decoder, play_defaults::RESET)?;
         Ok(Self::Play {
             stream_name,
             start,
@@ -56,42 +309,40 @@ impl<'a> NetStreamCommand<'a> {
     }
 
     fn parse_play2(buf: &'a [u8]) -> Result<Self, messages::command::ParseError> {
-        let mut decoder = Decoder::new(buf);
+        let mut decoder = command_decoder(buf);
         Ok(Self::Play2 {
             parameters: decoder.decode()?,
         })
     }
 
     fn parse_delete_stream(buf: &'a [u8]) -> Result<Self, messages::command::ParseError> {
-        let mut decoder = Decoder::new(buf);
-        let stream_id: f64 = decoder.decode()?.try_into()?;
+        let mut decoder = command_decoder(buf);
         Ok(Self::DeleteStream {
-            stream_id: stream_id as u32,
+            stream_id: decoder.decode()?.try_into()?,
         })
     }
 
     fn parse_close_stream(buf: &'a [u8]) -> Result<Self, messages::command::ParseError> {
-        let mut decoder = Decoder::new(buf);
-        let stream_id: f64 = decoder.decode()?.try_into()?;
+        let mut decoder = command_decoder(buf);
         Ok(Self::CloseStream {
-            stream_id: stream_id as u32,
+            stream_id: decoder.decode()?.try_into()?,
         })
     }
 
     fn parse_receive_audio(buf: &'a [u8]) -> Result<Self, messages::command::ParseError> {
         Ok(Self::ReceiveAudio {
-            should_receive: Decoder::new(buf).decode()?.try_into()?,
+            should_receive: command_decoder(buf).decode()?.try_into()?,
         })
     }
 
     fn parse_receive_video(buf: &'a [u8]) -> Result<Self, messages::command::ParseError> {
         Ok(Self::ReceiveVideo {
-            should_receive: Decoder::new(buf).decode()?.try_into()?,
+            should_receive: command_decoder(buf).decode()?.try_into()?,
         })
     }
 
     fn parse_publish(buf: &'a [u8]) -> Result<Self, messages::command::ParseError> {
-        let mut decoder = Decoder::new(buf);
+        let mut decoder = command_decoder(buf);
         let publishing_name = decoder.decode()?.try_into()?;
         let publishing_type = decoder.decode()?.try_into()?;
 
@@ -103,12 +354,12 @@ impl<'a> NetStreamCommand<'a> {
 
     fn parse_seek(buf: &'a [u8]) -> Result<Self, messages::command::ParseError> {
         Ok(Self::Seek {
-            milliseconds: Decoder::new(buf).decode()?.try_into()?,
+            milliseconds: command_decoder(buf).decode()?.try_into()?,
         })
     }
 
     fn parse_pause(buf: &'a [u8]) -> Result<Self, messages::command::ParseError> {
-        let mut decoder = Decoder::new(buf);
+        let mut decoder = command_decoder(buf);
         let is_paused = decoder.decode()?.try_into()?;
         let milliseconds = decoder.decode()?.try_into()?;
         Ok(Self::Pause {
@@ -136,3 +387,86 @@ impl<'a> NetStreamCommand<'a> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amf::Encoder;
+
+    fn encode(values: &[AMF0Value]) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        for value in values {
+            encoder.encode(value).unwrap();
+        }
+        encoder.finish()
+    }
+
+    #[test]
+    fn test_parse_play_with_every_argument() {
+        let bytes = encode(&[
+            AMF0Value::String("mystream"),
+            AMF0Value::Number(0.0),
+            AMF0Value::Number(5.0),
+            AMF0Value::Boolean(false),
+        ]);
+        assert!(matches!(
+            NetStreamCommand::parse("play", &bytes),
+            Ok(NetStreamCommand::Play {
+                stream_name: "mystream",
+                start: 0.0,
+                duration: 5.0,
+                reset: false,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_play_from_ffmpeg() {
+        // ffmpeg and ffplay only send the stream name and start
+        let bytes = encode(&[AMF0Value::String("mystream"), AMF0Value::Number(-2000.0)]);
+        assert!(matches!(
+            NetStreamCommand::parse("play", &bytes),
+            Ok(NetStreamCommand::Play {
+                stream_name: "mystream",
+                start: -2000.0,
+                duration: play_defaults::DURATION,
+                reset: play_defaults::RESET,
+            })
+        ));
+
+        let bytes = encode(&[AMF0Value::String("mystream")]);
+        assert!(matches!(
+            NetStreamCommand::parse("play", &bytes),
+            Ok(NetStreamCommand::Play {
+                start: play_defaults::START,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_build_on_status() {
+        let bytes = build_on_status(
+            0.0,
+            "status",
+            "NetStream.Publish.Start",
+            "mystream is now published",
+        )
+        .unwrap();
+
+        let mut decoder = Decoder::new(&bytes);
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::String("onStatus"));
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::Number(0.0));
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::Null);
+        let information = decoder.decode().unwrap();
+        assert_eq!(
+            information.get("code").and_then(AMF0Value::as_str),
+            Some("NetStream.Publish.Start")
+        );
+        assert_eq!(
+            information.get("level").and_then(AMF0Value::as_str),
+            Some("status")
+        );
+        assert!(decoder.remaining().is_empty());
+    }
+}
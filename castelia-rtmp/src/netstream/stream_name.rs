@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+
+/// A stream name from publish or play, split into the name the stream is keyed by and the
+/// query encoders append to it, e.g. `mystream?token=abc123`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamName<'a> {
+    pub name: &'a str,
+    /// Parameters without a value map to an empty string. Nothing is percent-decoded
+    pub query: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> StreamName<'a> {
+    pub fn parse(stream_name: &'a str) -> Self {
+        let (name, query) = stream_name.split_once('?').unwrap_or((stream_name, ""));
+        Self {
+            name,
+            query: query
+                .split('&')
+                .filter(|parameter| !parameter.is_empty())
+                .map(|parameter| parameter.split_once('=').unwrap_or((parameter, "")))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            StreamName::parse("mystream"),
+            StreamName {
+                name: "mystream",
+                query: HashMap::new(),
+            }
+        );
+        assert_eq!(
+            StreamName::parse("mystream?token=abc123&record&"),
+            StreamName {
+                name: "mystream",
+                query: HashMap::from([("token", "abc123"), ("record", "")]),
+            }
+        );
+        assert_eq!(StreamName::parse("?token=abc123").name, "");
+    }
+}
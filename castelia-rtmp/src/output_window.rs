@@ -0,0 +1,141 @@
+use crate::messages::protocol_control::peer_bandwidth_limit;
+
+/// Limits how much can be sent before the peer acknowledges it, as the peer asks for with
+/// SetPeerBandwidth. Nothing is limited until the peer sends one
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct OutputWindow {
+    /// The window size and the limit type it was set with
+    limit: Option<(u32, u8)>,
+    /// Bytes sent so far, wrapping around like the sequence numbers of Acknowledgements
+    bytes_sent: u32,
+    /// The sequence number of the last Acknowledgement
+    bytes_acked: u32,
+}
+
+impl OutputWindow {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply a SetPeerBandwidth from the peer, returning the new window size if it changed.
+    ///
+    /// A hard limit replaces the window, a soft one only shrinks it and a dynamic one only
+    /// applies if the window was last set with a hard limit
+    pub fn set_peer_bandwidth(&mut self, window_size: u32, limit_type: u8) -> Option<u32> {
+        let limit = match (limit_type, self.limit) {
+            (peer_bandwidth_limit::HARD, _) => (window_size, peer_bandwidth_limit::HARD),
+            (peer_bandwidth_limit::SOFT, Some((current, current_type))) => {
+                if window_size < current {
+                    (window_size, peer_bandwidth_limit::SOFT)
+                } else {
+                    (current, current_type)
+                }
+            }
+            (peer_bandwidth_limit::SOFT, None) => (window_size, peer_bandwidth_limit::SOFT),
+            (peer_bandwidth_limit::DYNAMIC, Some((_, peer_bandwidth_limit::HARD))) => {
+                (window_size, peer_bandwidth_limit::HARD)
+            }
+            _ => return None,
+        };
+
+        let changed = self.limit.map(|(current, _)| current) != Some(limit.0);
+        self.limit = Some(limit);
+        changed.then_some(limit.0)
+    }
+
+    pub fn record_sent(&mut self, len: usize) {
+        // sequence numbers wrap around once they no longer fit in 32 bits
+        self.bytes_sent = self.bytes_sent.wrapping_add(len as u32);
+    }
+
+    pub fn ack(&mut self, sequence_number: u32) {
+        self.bytes_acked = sequence_number;
+    }
+
+    /// Bytes sent that the peer hasn't acknowledged yet. Peers that count the handshake can
+    /// acknowledge more than has been counted as sent, which counts as nothing unacknowledged
+    pub fn unacked_bytes(&self) -> u32 {
+        let unacked = self.bytes_sent.wrapping_sub(self.bytes_acked) as i32;
+        unacked.max(0) as u32
+    }
+
+    /// The window size set by the last SetPeerBandwidth that applied, if any did
+    pub fn window_size(&self) -> Option<u32> {
+        self.limit.map(|(window_size, _)| window_size)
+    }
+
+    /// Whether sending has to wait for an Acknowledgement
+    pub fn is_blocked(&self) -> bool {
+        self.limit
+            .is_some_and(|(window_size, _)| self.unacked_bytes() >= window_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks_until_ack() {
+        let mut window = OutputWindow::new();
+        window.record_sent(5000);
+        assert!(!window.is_blocked());
+
+        assert_eq!(
+            window.set_peer_bandwidth(1000, peer_bandwidth_limit::HARD),
+            Some(1000)
+        );
+        assert!(window.is_blocked());
+
+        window.ack(4500);
+        assert_eq!(window.unacked_bytes(), 500);
+        assert!(!window.is_blocked());
+
+        window.record_sent(500);
+        assert!(window.is_blocked());
+    }
+
+    #[test]
+    fn test_limit_types() {
+        let mut window = OutputWindow::new();
+        // dynamic only applies on top of a hard limit
+        assert_eq!(
+            window.set_peer_bandwidth(1000, peer_bandwidth_limit::DYNAMIC),
+            None
+        );
+        assert_eq!(
+            window.set_peer_bandwidth(2000, peer_bandwidth_limit::SOFT),
+            Some(2000)
+        );
+        assert_eq!(
+            window.set_peer_bandwidth(3000, peer_bandwidth_limit::SOFT),
+            None
+        );
+        assert_eq!(
+            window.set_peer_bandwidth(3000, peer_bandwidth_limit::DYNAMIC),
+            None
+        );
+        assert_eq!(
+            window.set_peer_bandwidth(3000, peer_bandwidth_limit::HARD),
+            Some(3000)
+        );
+        assert_eq!(
+            window.set_peer_bandwidth(4000, peer_bandwidth_limit::DYNAMIC),
+            Some(4000)
+        );
+    }
+
+    #[test]
+    fn test_sequence_numbers_wrap() {
+        let mut window = OutputWindow::new();
+        window.set_peer_bandwidth(1000, peer_bandwidth_limit::HARD);
+        window.record_sent(u32::MAX as usize);
+        window.ack(u32::MAX - 100);
+        window.record_sent(200);
+        assert_eq!(window.unacked_bytes(), 300);
+
+        // acknowledging bytes that weren't counted, like the handshake
+        window.ack(1000);
+        assert_eq!(window.unacked_bytes(), 0);
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::broadcast::error::RecvError,
+};
+use tracing::{debug, error, warn};
+
+use crate::{
+    flv::{self, FlvMuxer},
+    stream_registry::{MediaPacket, Subscriber},
+};
+
+/// Where the recording of `stream_key` started at `started_at` is written in `dir`, e.g.
+/// `live_mystream-1700000000.flv` for `live/mystream`
+pub fn recording_path(dir: &Path, stream_key: &str, started_at: SystemTime) -> PathBuf {
+    let started_at = started_at
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs())
+        .unwrap_or_default();
+    dir.join(format!("{}-{started_at}.flv", stream_key.replace('/', "_")))
+}
+
+/// Record a stream to an FLV file in `dir` until it is unpublished, returning the path of the
+/// file. Timestamps are rebased so the recording starts at 0
+pub async fn record(mut subscriber: Subscriber, dir: &Path) -> io::Result<PathBuf> {
+    let path = recording_path(dir, subscriber.key(), SystemTime::now());
+    debug!("Recording {} to {}", subscriber.key(), path.display());
+    let mut file = BufWriter::new(File::create(&path).await?);
+    file.write_all(&flv::encode_header(true, true)).await?;
+
+    let mut muxer = FlvMuxer::new();
+    let mut start = None;
+    loop {
+        let packet = match subscriber.recv().await {
+            Ok(packet) => packet,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(
+                    "Recording of {} fell behind, skipped {skipped} packets",
+                    subscriber.key()
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        let start = *start.get_or_insert(packet.timestamp);
+        let packet = MediaPacket {
+            timestamp: packet.timestamp.saturating_sub(start),
+            ..packet
+        };
+        if let Some(tag) = muxer.tag(&packet) {
+            file.write_all(&tag).await?;
+        }
+    }
+
+    file.flush().await?;
+    file.into_inner().sync_all().await?;
+    Ok(path)
+}
+
+/// Record a stream in the background. Recording failing, e.g. because the disk is full, only
+/// stops the recording and leaves the stream alone
+pub fn spawn(subscriber: Subscriber, dir: PathBuf) {
+    let key = subscriber.key().to_owned();
+    tokio::spawn(async move {
+        match record(subscriber, &dir).await {
+            Ok(path) => debug!("Finished recording {key} to {}", path.display()),
+            Err(e) => error!("Stopped recording {key}: {e}"),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use super::*;
+    use crate::{stream_registry::StreamRegistry, test_support::video};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("castelia-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_recording_path() {
+        assert_eq!(
+            recording_path(
+                Path::new("recordings"),
+                "live/mystream",
+                UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+            ),
+            Path::new("recordings/live_mystream-1700000000.flv")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let dir = test_dir("record");
+        let streams = StreamRegistry::new();
+        let publisher = streams.publish("live/mystream").unwrap();
+        let recording = tokio::spawn({
+            let subscriber = streams.subscribe("live/mystream").unwrap();
+            let dir = dir.clone();
+            async move { record(subscriber, &dir).await }
+        });
+
+        publisher.send(video(1000, &[0x17, 0x00, 0x00, 0x00, 0x00, 0x01]));
+        publisher.send(video(1000, &[0x17, 0x01, 0x00, 0x00, 0x00, 0x65]));
+        publisher.send(video(1040, &[0x27, 0x01, 0x00, 0x00, 0x00, 0x41]));
+        drop(publisher);
+
+        let path = recording.await.unwrap().unwrap();
+        let file = fs::read(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(file[..13], flv::encode_header(true, true)[..]);
+
+        let mut timestamps = vec![];
+        let mut tags = &file[13..];
+        while !tags.is_empty() {
+            let data_size = u32::from_be_bytes([0, tags[1], tags[2], tags[3]]) as usize;
+            timestamps.push(u32::from_be_bytes([tags[7], tags[4], tags[5], tags[6]]));
+            // tag header, data and PreviousTagSize
+            tags = &tags[11 + data_size + 4..];
+        }
+        assert_eq!(timestamps, [0, 0, 40]);
+    }
+
+    #[tokio::test]
+    async fn test_record_into_missing_dir() {
+        let streams = StreamRegistry::new();
+        let _publisher = streams.publish("live/mystream").unwrap();
+        let subscriber = streams.subscribe("live/mystream").unwrap();
+
+        let dir = std::env::temp_dir().join(format!("castelia-missing-{}", std::process::id()));
+        assert!(record(subscriber, &dir).await.is_err());
+    }
+}
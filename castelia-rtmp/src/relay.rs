@@ -0,0 +1,667 @@
+use std::{io, time::Duration};
+
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::TcpStream,
+    sync::broadcast::error::RecvError,
+    time::timeout,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    amf::{AMF0Value, Decoder, EncodeError, Properties},
+    chunks::{
+        Chunk, DEFAULT_CHUNK_SIZE,
+        chunk_mux::{AssembledMessage, ChunkMultiplexer},
+        chunk_writer::ChunkWriter,
+    },
+    handshake::client_handshake,
+    messages::{
+        Message, OutgoingMessage,
+        command::{command_message_type, encode_command},
+        protocol_control::ProtolControlMessage,
+        user_control::UserControlMessage,
+    },
+    netconnection::SERVER_CHUNK_SIZE,
+    output_window::OutputWindow,
+    rtmp::send_message,
+    stream_registry::{MediaPacket, Publisher, StreamMetadata, StreamRegistry},
+    url::{RtmpUrl, Scheme},
+};
+
+/// How long to wait for the upstream server to answer a command
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the relay calls itself in connect, encoders identify themselves the same way
+const FLASH_VERSION: &str = "FMLE/3.0 (compatible; castelia)";
+
+#[derive(Error, Debug)]
+pub enum RelayError {
+    #[error("Relay URL {0} has no stream key")]
+    MissingStreamKey(String),
+    #[error("Relaying over {0} is not supported")]
+    UnsupportedScheme(Scheme),
+    #[error("Stream {0} is not live")]
+    NotLive(String),
+    #[error("Upstream server rejected {command}: {description}")]
+    Rejected {
+        command: &'static str,
+        description: String,
+    },
+    #[error("Upstream server didn't answer {0} in time")]
+    Timeout(&'static str),
+    #[error("Upstream server closed the connection")]
+    Closed,
+    #[error("Failed to talk to the upstream server")]
+    Io(
+        #[source]
+        #[from]
+        io::Error,
+    ),
+    #[error("Failed to encode a command")]
+    Encode(
+        #[source]
+        #[from]
+        EncodeError,
+    ),
+}
+
+/// Push the live stream `key` to an upstream server until the stream ends, e.g. to restream to
+/// another platform. The upstream server sees an encoder publishing to it
+pub async fn relay(
+    streams: &StreamRegistry,
+    key: &str,
+    target: &RtmpUrl,
+) -> Result<(), RelayError> {
+    if target.scheme != Scheme::Rtmp {
+        return Err(RelayError::UnsupportedScheme(target.scheme));
+    }
+    let stream_name = target
+        .stream_name()
+        .ok_or_else(|| RelayError::MissingStreamKey(target.to_string()))?;
+    let mut subscriber = streams
+        .subscribe_internal(key)
+        .ok_or_else(|| RelayError::NotLive(key.to_owned()))?;
+
+    let mut upstream = Upstream::connect(target).await?;
+    let stream_id = upstream.publish(&stream_name).await?;
+    info!("Relaying {key} to {target}");
+
+    loop {
+        tokio::select! {
+            // stop sending once the upstream server's window is full until it acknowledges
+            packet = subscriber.recv(), if !upstream.output_window.is_blocked() => match packet {
+                Ok(packet) => upstream.send_media(stream_id, packet).await?,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Relay of {key} fell behind, skipped {skipped} packets");
+                }
+                Err(RecvError::Closed) => break,
+            },
+            // fill_buf doesn't consume anything, so it is safe to cancel unlike read_message
+            buffered = upstream.reader.fill_buf() => {
+                if buffered?.is_empty() {
+                    return Err(RelayError::Closed);
+                }
+                upstream.read_message().await?;
+            }
+        }
+    }
+
+    debug!("{key} ended, unpublishing it upstream");
+    upstream
+        .send_command(
+            0,
+            &[
+                AMF0Value::String("deleteStream"),
+                AMF0Value::Number(0.0),
+                AMF0Value::Null,
+                AMF0Value::Number(stream_id.into()),
+            ],
+        )
+        .await
+}
+
+/// Pull the stream at `origin` from another server and publish it here with `publisher` until the
+/// origin unpublishes it, e.g. to play a stream published to another node of a cluster
+pub async fn pull(publisher: Publisher, origin: &RtmpUrl) -> Result<(), RelayError> {
+    if origin.scheme != Scheme::Rtmp {
+        return Err(RelayError::UnsupportedScheme(origin.scheme));
+    }
+    let stream_name = origin
+        .stream_name()
+        .ok_or_else(|| RelayError::MissingStreamKey(origin.to_string()))?;
+
+    let mut upstream = Upstream::connect(origin).await?;
+    let stream_id = upstream.play(&stream_name).await?;
+    info!("Pulling {} from {origin}", publisher.key());
+
+    loop {
+        let message = upstream.read_message().await?;
+        if message.message_stream_id != stream_id {
+            continue;
+        }
+        let packet = MediaPacket {
+            message_type_id: message.message_type_id,
+            timestamp: message.timestamp,
+            payload: message.payload,
+        };
+        match packet.message_type_id {
+            command_message_type::AUDIO | command_message_type::VIDEO => publisher.send(packet),
+            command_message_type::DATA_AMF0 => {
+                // other data, like |RtmpSampleAccess, is for the player rather than the stream
+                let Some(metadata) = metadata(&packet.payload) else {
+                    continue;
+                };
+                publisher.set_metadata(metadata);
+                publisher.send(packet);
+            }
+            command_message_type::COMMAND_AMF0
+                if is_status(&packet.payload, "NetStream.Play.UnpublishNotify") =>
+            {
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    debug!("{} was unpublished at {origin}", publisher.key());
+    Ok(())
+}
+
+/// Start pulling `key` from the node it is published to, if the registry knows it is on another
+/// node. Returns whether it is, in which case `key` is live here by the time this returns, and
+/// gets the origin's packets once the pull is connected
+pub fn pull_remote(streams: &StreamRegistry, key: &str) -> bool {
+    let Some(remote) = streams.locate_remote(key) else {
+        return false;
+    };
+    let Some(publisher) = streams.publish(key) else {
+        // published here in the meantime
+        return true;
+    };
+
+    debug!(
+        "{key} is on node {}, pulling it from {}",
+        remote.node_id, remote.url
+    );
+    tokio::spawn(async move {
+        let key = publisher.key().to_owned();
+        if let Err(e) = pull(publisher, &remote.url).await {
+            warn!("Failed to pull {key} from node {}: {e}", remote.node_id);
+        }
+    });
+    true
+}
+
+/// What an onMetaData data message describes the stream as
+fn metadata(payload: &[u8]) -> Option<StreamMetadata> {
+    let mut decoder = Decoder::new(payload);
+    let mut values = decoder.decode_all();
+    let (Some(Ok(AMF0Value::String("onMetaData"))), Some(Ok(properties))) =
+        (values.next(), values.next())
+    else {
+        return None;
+    };
+    Some(StreamMetadata::from_properties(&properties))
+}
+
+/// Whether a command is an onStatus with `code`
+fn is_status(payload: &[u8], code: &str) -> bool {
+    let mut decoder = Decoder::new(payload);
+    let mut values = decoder.decode_all();
+    matches!(values.next(), Some(Ok(AMF0Value::String("onStatus"))))
+        && values.nth(2).is_some_and(|information| {
+            information.is_ok_and(|information| {
+                information.get("code").and_then(AMF0Value::as_str) == Some(code)
+            })
+        })
+}
+
+/// The client side of a connection to the upstream server
+struct Upstream {
+    reader: BufReader<TcpStream>,
+    chunk_mux: ChunkMultiplexer,
+    chunk_writer: ChunkWriter,
+    /// Chunk size the upstream server sends with
+    chunk_size: usize,
+    next_transaction_id: f64,
+    output_window: OutputWindow,
+    /// How many bytes can be received before the upstream server expects an Acknowledgement,
+    /// [`None`] until it says
+    ack_window_size: Option<u32>,
+    bytes_received: u64,
+    unacked_bytes: u64,
+}
+
+impl Upstream {
+    async fn connect(target: &RtmpUrl) -> Result<Self, RelayError> {
+        let mut socket = TcpStream::connect((target.host.as_str(), target.port)).await?;
+        client_handshake(&mut socket)
+            .await
+            .map_err(io::Error::from)?;
+
+        let mut upstream = Self {
+            reader: BufReader::new(socket),
+            chunk_mux: ChunkMultiplexer::new(),
+            chunk_writer: ChunkWriter::new(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            next_transaction_id: 1.0,
+            output_window: OutputWindow::new(),
+            ack_window_size: None,
+            bytes_received: 0,
+            unacked_bytes: 0,
+        };
+        upstream
+            .send(OutgoingMessage::Protocol(
+                ProtolControlMessage::SetChunkSize(SERVER_CHUNK_SIZE),
+            ))
+            .await?;
+
+        let tc_url = target.tc_url();
+        upstream
+            .call(
+                "connect",
+                AMF0Value::Object(Properties::from([
+                    ("app", AMF0Value::String(&target.app)),
+                    ("type", AMF0Value::String("nonprivate")),
+                    ("flashVer", AMF0Value::String(FLASH_VERSION)),
+                    ("tcUrl", AMF0Value::String(&tc_url)),
+                ])),
+            )
+            .await?;
+        Ok(upstream)
+    }
+
+    /// Create a message stream and publish `stream_name` on it, returning the message stream id
+    async fn publish(&mut self, stream_name: &str) -> Result<u32, RelayError> {
+        self.start_stream("publish", stream_name, "NetStream.Publish.Start")
+            .await
+    }
+
+    /// Create a message stream and play `stream_name` on it, returning the message stream id
+    async fn play(&mut self, stream_name: &str) -> Result<u32, RelayError> {
+        self.start_stream("play", stream_name, "NetStream.Play.Start")
+            .await
+    }
+
+    /// Create a message stream and send `command` for `stream_name` on it, waiting for the
+    /// `started` status
+    async fn start_stream(
+        &mut self,
+        command: &'static str,
+        stream_name: &str,
+        started: &str,
+    ) -> Result<u32, RelayError> {
+        let stream_id =
+            self.call("createStream", AMF0Value::Null)
+                .await?
+                .ok_or(RelayError::Rejected {
+                    command: "createStream",
+                    description: "No message stream id in the result".to_owned(),
+                })?;
+
+        let mut values = vec![
+            AMF0Value::String(command),
+            AMF0Value::Number(0.0),
+            AMF0Value::Null,
+            AMF0Value::String(stream_name),
+        ];
+        if command == "publish" {
+            values.push(AMF0Value::String("live"));
+        }
+        self.send_command(stream_id, &values).await?;
+        timeout(RESPONSE_TIMEOUT, self.wait_for_status(command, started))
+            .await
+            .map_err(|_| RelayError::Timeout(command))?
+    }
+
+    async fn wait_for_status(
+        &mut self,
+        command: &'static str,
+        started: &str,
+    ) -> Result<u32, RelayError> {
+        loop {
+            let message = self.read_command().await?;
+            let mut decoder = Decoder::new(&message.payload);
+            let mut values = decoder.decode_all();
+            if !matches!(values.next(), Some(Ok(AMF0Value::String("onStatus")))) {
+                continue;
+            }
+            let Some(Ok(information)) = values.nth(2) else {
+                continue;
+            };
+            let code = information.get("code").and_then(AMF0Value::as_str);
+            if information.get("level").and_then(AMF0Value::as_str) == Some("error") {
+                return Err(RelayError::Rejected {
+                    command,
+                    description: code.unwrap_or("unknown error").to_owned(),
+                });
+            }
+            if code == Some(started) {
+                return Ok(message.message_stream_id);
+            }
+        }
+    }
+
+    /// Send a command and wait for its `_result`, returning the value after the command object
+    /// if it is a whole number, like the message stream id createStream returns
+    async fn call(
+        &mut self,
+        command: &'static str,
+        command_object: AMF0Value<'_>,
+    ) -> Result<Option<u32>, RelayError> {
+        let transaction_id = self.next_transaction_id;
+        self.next_transaction_id += 1.0;
+
+        self.send_command(
+            0,
+            &[
+                AMF0Value::String(command),
+                AMF0Value::Number(transaction_id),
+                command_object,
+            ],
+        )
+        .await?;
+        timeout(
+            RESPONSE_TIMEOUT,
+            self.wait_for_result(command, transaction_id),
+        )
+        .await
+        .map_err(|_| RelayError::Timeout(command))?
+    }
+
+    async fn wait_for_result(
+        &mut self,
+        command: &'static str,
+        transaction_id: f64,
+    ) -> Result<Option<u32>, RelayError> {
+        loop {
+            let message = self.read_command().await?;
+            let mut decoder = Decoder::new(&message.payload);
+            let mut values = decoder.decode_all();
+            let (Some(Ok(AMF0Value::String(name))), Some(Ok(AMF0Value::Number(id)))) =
+                (values.next(), values.next())
+            else {
+                continue;
+            };
+            if id != transaction_id {
+                continue;
+            }
+
+            // skip the command object or properties
+            let value = values.nth(1).and_then(Result::ok);
+            match name {
+                "_result" => return Ok(value.and_then(|value| u32::try_from(value).ok())),
+                "_error" => {
+                    let description = value
+                        .as_ref()
+                        .and_then(|information| information.get("description"))
+                        .and_then(AMF0Value::as_str)
+                        .unwrap_or("unknown error");
+                    return Err(RelayError::Rejected {
+                        command,
+                        description: description.to_owned(),
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn send_media(&mut self, stream_id: u32, mut packet: MediaPacket) -> io::Result<()> {
+        // the registry keeps metadata as a plain onMetaData, publishers wrap it in @setDataFrame
+        // for the server to store
+        if packet.message_type_id == command_message_type::DATA_AMF0 {
+            let mut payload = encode_command(&[AMF0Value::String("@setDataFrame")])
+                .map_err(io::Error::other)?
+                .to_vec();
+            payload.extend_from_slice(&packet.payload);
+            packet.payload = payload.into();
+        }
+        self.send(OutgoingMessage::Media {
+            message_stream_id: stream_id,
+            packet,
+        })
+        .await
+    }
+
+    async fn send_command(
+        &mut self,
+        message_stream_id: u32,
+        values: &[AMF0Value<'_>],
+    ) -> Result<(), RelayError> {
+        let payload = encode_command(values)?;
+        self.send(OutgoingMessage::Command {
+            message_stream_id,
+            payload,
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn send(&mut self, message: OutgoingMessage) -> io::Result<()> {
+        let sent = send_message(&mut self.chunk_writer, self.reader.get_mut(), message).await?;
+        self.output_window.record_sent(sent);
+        Ok(())
+    }
+
+    /// Acknowledge what was received once a window's worth has arrived, or the upstream server
+    /// stops sending
+    async fn record_bytes_received(&mut self, len: usize) -> io::Result<()> {
+        self.bytes_received += len as u64;
+        self.unacked_bytes += len as u64;
+        let Some(window_size) = self.ack_window_size else {
+            return Ok(());
+        };
+        if self.unacked_bytes < u64::from(window_size) {
+            return Ok(());
+        }
+
+        self.unacked_bytes = 0;
+        // the sequence number wraps around once it no longer fits in 32 bits
+        self.send(OutgoingMessage::Protocol(ProtolControlMessage::Ack(
+            self.bytes_received as u32,
+        )))
+        .await
+    }
+
+    /// Read messages until an AMF0 command arrives
+    async fn read_command(&mut self) -> Result<AssembledMessage, RelayError> {
+        loop {
+            let message = self.read_message().await?;
+            if message.message_type_id == command_message_type::COMMAND_AMF0 {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Read the next message, handling the protocol and user control messages that affect the
+    /// connection
+    async fn read_message(&mut self) -> Result<AssembledMessage, RelayError> {
+        loop {
+            let chunk = Chunk::read_chunk(&mut self.reader, &self.chunk_size, &self.chunk_mux)
+                .await
+                .map_err(io::Error::from)?;
+            self.record_bytes_received(chunk.header.len() + chunk.payload.len())
+                .await?;
+            let Some(message) = self
+                .chunk_mux
+                .receive_chunk(chunk)
+                .map_err(io::Error::from)?
+            else {
+                continue;
+            };
+
+            match Message::parse_message(&message.payload, message.message_type_id) {
+                Ok(Message::Protocol(ProtolControlMessage::SetChunkSize(chunk_size))) => {
+                    self.chunk_size = chunk_size as usize;
+                }
+                Ok(Message::Protocol(ProtolControlMessage::Abort(cs_id))) => {
+                    self.chunk_mux.abort(cs_id);
+                }
+                Ok(Message::Protocol(ProtolControlMessage::AckWindowSize(window_size))) => {
+                    self.ack_window_size = Some(window_size);
+                }
+                Ok(Message::Protocol(ProtolControlMessage::SetPeerBandwidth {
+                    limit_type,
+                    window_size,
+                })) => {
+                    // ask to be acknowledged as often as the window needs
+                    if let Some(window_size) = self
+                        .output_window
+                        .set_peer_bandwidth(window_size, limit_type)
+                    {
+                        self.send(OutgoingMessage::Protocol(
+                            ProtolControlMessage::AckWindowSize(window_size),
+                        ))
+                        .await?;
+                    }
+                }
+                Ok(Message::Protocol(ProtolControlMessage::Ack(sequence_number))) => {
+                    self.output_window.ack(sequence_number);
+                }
+                Ok(Message::UserControl(UserControlMessage::PingRequest(timestamp))) => {
+                    self.send(OutgoingMessage::UserControl(
+                        UserControlMessage::PingResponse(timestamp),
+                    ))
+                    .await?;
+                }
+                _ => {}
+            }
+            return Ok(message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use bytes::Bytes;
+    use tokio::time::sleep;
+
+    use super::*;
+    use crate::rtmp::RTMPSever;
+
+    async fn spawn_server() -> (SocketAddr, StreamRegistry) {
+        let server = RTMPSever::builder()
+            .bind_addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .build()
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let streams = server.stream_registry();
+        tokio::spawn(async move { server.run().await });
+        (addr, streams)
+    }
+
+    #[tokio::test]
+    async fn test_relay_between_servers() {
+        let (_, source) = spawn_server().await;
+        let (upstream_addr, upstream) = spawn_server().await;
+        let publisher = source.publish("live/mystream").unwrap();
+
+        let target: RtmpUrl = format!("rtmp://{upstream_addr}/live/relayed")
+            .parse()
+            .unwrap();
+        let relay = tokio::spawn(async move { relay(&source, "live/mystream", &target).await });
+
+        for _ in 0..100 {
+            if upstream.is_live("live/relayed") {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        let mut player = upstream.subscribe("live/relayed").unwrap();
+
+        let keyframe = MediaPacket {
+            message_type_id: command_message_type::VIDEO,
+            timestamp: 40,
+            payload: Bytes::from_static(&[0x17, 0x01, 0x00, 0x00, 0x00, 0x65]),
+        };
+        publisher.send(keyframe.clone());
+        assert_eq!(player.recv().await.unwrap(), keyframe);
+
+        // unpublishing at the source unpublishes upstream
+        drop(publisher);
+        relay.await.unwrap().unwrap();
+        assert_eq!(player.recv().await, Err(RecvError::Closed));
+        assert!(!upstream.is_live("live/relayed"));
+    }
+
+    #[tokio::test]
+    async fn test_pull_from_origin() {
+        let (origin_addr, origin) = spawn_server().await;
+        let origin_publisher = origin.publish("live/mystream").unwrap();
+        let edge = StreamRegistry::new();
+
+        let url: RtmpUrl = format!("rtmp://{origin_addr}/live/mystream")
+            .parse()
+            .unwrap();
+        let publisher = edge.publish("live/pulled").unwrap();
+        let pull = tokio::spawn(async move { pull(publisher, &url).await });
+        let mut player = edge.subscribe("live/pulled").unwrap();
+        for _ in 0..100 {
+            if origin.viewers("live/mystream") == Some(1) {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        let keyframe = MediaPacket {
+            message_type_id: command_message_type::VIDEO,
+            timestamp: 40,
+            payload: Bytes::from_static(&[0x17, 0x01, 0x00, 0x00, 0x00, 0x65]),
+        };
+        origin_publisher.send(keyframe.clone());
+        assert_eq!(player.recv().await.unwrap(), keyframe);
+
+        // unpublishing at the origin ends the pulled stream
+        drop(origin_publisher);
+        pull.await.unwrap().unwrap();
+        assert_eq!(player.recv().await, Err(RecvError::Closed));
+        assert!(!edge.is_live("live/pulled"));
+    }
+
+    #[tokio::test]
+    async fn test_pull_stream_not_live_at_origin() {
+        let (origin_addr, _origin) = spawn_server().await;
+        let edge = StreamRegistry::new();
+        let url: RtmpUrl = format!("rtmp://{origin_addr}/live/mystream")
+            .parse()
+            .unwrap();
+
+        assert!(matches!(
+            pull(edge.publish("live/pulled").unwrap(), &url).await,
+            Err(RelayError::Rejected {
+                command: "play",
+                ..
+            })
+        ));
+        assert!(!edge.is_live("live/pulled"));
+    }
+
+    #[tokio::test]
+    async fn test_relay_stream_not_live() {
+        let streams = StreamRegistry::new();
+        let target: RtmpUrl = "rtmp://127.0.0.1/live/relayed".parse().unwrap();
+
+        assert!(matches!(
+            relay(&streams, "live/mystream", &target).await,
+            Err(RelayError::NotLive(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_relay_needs_stream_key() {
+        let streams = StreamRegistry::new();
+        let _publisher = streams.publish("live/mystream").unwrap();
+        let target: RtmpUrl = "rtmp://127.0.0.1/live".parse().unwrap();
+
+        assert!(matches!(
+            relay(&streams, "live/mystream", &target).await,
+            Err(RelayError::MissingStreamKey(_))
+        ));
+    }
+}
@@ -1,94 +1,2079 @@
-use std::io;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
 
+use socket2::{SockRef, TcpKeepalive};
+use thiserror::Error;
 use tokio::{
-    io::BufReader,
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader},
     net::{TcpListener, TcpStream},
+    sync::{Semaphore, mpsc},
+    time::{Instant, sleep, sleep_until, timeout},
 };
-use tracing::{debug, error, instrument, trace};
+#[cfg(feature = "tls")]
+use tokio_rustls::{TlsAcceptor, rustls::ServerConfig};
+use tracing::{debug, error, instrument, trace, warn};
 
 use crate::{
-    chunks::{Chunk, chunk_mux::ChunkMultiplexer},
-    handshake::handshake,
-    messages::Message,
-    netconnection::NetConnection,
+    app::{AppOptions, AppRegistry},
+    chunks::{
+        Chunk, MAX_CHUNK_SIZE, ParseChunkError,
+        chunk_mux::{ChunkMultiplexer, ReceiveChunkError},
+        chunk_writer::ChunkWriter,
+    },
+    connection_registry::{ConnectionHandle, ConnectionRegistry},
+    events::{ConnectionErrorKind, ConnectionEvents, ServerEvent},
+    handshake::{HandshakeError, handshake},
+    messages::{
+        Message, OutgoingMessage, ParseMessageError,
+        command::{CommandMessage, RetainedCommand, command_message_type},
+        protocol_control::ProtolControlMessage,
+    },
+    netconnection::{NetConnection, NetConnectionCommandType, SERVER_CHUNK_SIZE},
+    stats::ConnectionStats,
+    stream_registry::{MediaPacket, StreamRegistry},
 };
 
+/// TCP keepalive settings applied to accepted sockets
+#[derive(Debug, Clone, Copy)]
+pub struct KeepaliveConfig {
+    /// How long a connection must be idle before keepalive probes are sent
+    pub idle: Duration,
+    /// Time between keepalive probes
+    pub interval: Duration,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// How long the accept loop waits before trying again after a transient accept error, such as
+/// running out of file descriptors. The wait doubles with every error in a row, starting at `min`
+/// and capped at `max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcceptBackoffConfig {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl Default for AcceptBackoffConfig {
+    fn default() -> Self {
+        Self {
+            min: Duration::from_millis(5),
+            max: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Puts a server into drain mode, where new connections and new publishes are rejected but
+/// existing connections and the streams already published on them are left alone to finish
+#[derive(Debug, Clone, Default)]
+pub struct DrainHandle {
+    draining: Arc<AtomicBool>,
+}
+
+impl DrainHandle {
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+/// What to do with a new connection when the server is at its connection limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionLimitPolicy {
+    /// Hold on to the connection until another connection closes
+    Wait,
+    /// Close the connection straight away
+    Reject,
+}
+
+#[derive(Debug)]
+struct ConnectionLimit {
+    permits: Arc<Semaphore>,
+    policy: ConnectionLimitPolicy,
+}
+
+/// How long to wait for the rest of a chunk once the peer starts sending it
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a connection can go without receiving a message or being sent media before it is
+/// closed
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Settings applied to every connection
+#[derive(Debug, Clone, Copy)]
+struct ConnectionConfig {
+    read_timeout: Duration,
+    idle_timeout: Duration,
+    chunk_size: u32,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            chunk_size: SERVER_CHUNK_SIZE,
+        }
+    }
+}
+
+/// The app that is registered when no registry is set with [`RTMPSever::with_apps`]
+pub const DEFAULT_APP: &str = "live";
+
 pub struct RTMPSever {
     listener: TcpListener,
+    keepalive: Option<KeepaliveConfig>,
+    accept_backoff: AcceptBackoffConfig,
+    drain: DrainHandle,
+    apps: Arc<AppRegistry>,
+    streams: StreamRegistry,
+    connection_limit: Option<ConnectionLimit>,
+    connection_config: ConnectionConfig,
+    events: Option<mpsc::Sender<ServerEvent>>,
+    connections: Option<ConnectionRegistry>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsAcceptor>,
 }
 
 impl RTMPSever {
+    pub fn builder() -> RTMPServerBuilder {
+        RTMPServerBuilder::new()
+    }
+
     pub fn new(listener: TcpListener) -> Self {
-        Self { listener }
+        Self {
+            listener,
+            keepalive: Some(KeepaliveConfig::default()),
+            accept_backoff: AcceptBackoffConfig::default(),
+            drain: DrainHandle::default(),
+            apps: Arc::new(AppRegistry::new().with_app(DEFAULT_APP, AppOptions::default())),
+            streams: StreamRegistry::new(),
+            connection_limit: None,
+            connection_config: ConnectionConfig::default(),
+            events: None,
+            connections: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Handle used to put the server into drain mode
+    pub fn drain_handle(&self) -> DrainHandle {
+        self.drain.clone()
+    }
+
+    /// Set the TCP keepalive applied to accepted sockets, [`None`] disables keepalive
+    pub fn with_keepalive(mut self, keepalive: Option<KeepaliveConfig>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Set how long to wait before accepting again after a transient accept error
+    pub fn with_accept_backoff(mut self, accept_backoff: AcceptBackoffConfig) -> Self {
+        self.accept_backoff = accept_backoff;
+        self
+    }
+
+    /// Limit the number of connections handled at once, `policy` decides what happens to
+    /// connections over the limit
+    pub fn with_connection_limit(
+        mut self,
+        max_connections: usize,
+        policy: ConnectionLimitPolicy,
+    ) -> Self {
+        self.connection_limit = Some(ConnectionLimit {
+            permits: Arc::new(Semaphore::new(max_connections)),
+            policy,
+        });
+        self
+    }
+
+    /// Set how long to wait for the rest of a chunk once the peer starts sending it
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.connection_config.read_timeout = read_timeout;
+        self
+    }
+
+    /// Set how long a connection can go without receiving a message or being sent media
+    /// before it is closed
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.connection_config.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Set the chunk size the server sends chunks with after connect
+    pub fn with_chunk_size(mut self, chunk_size: u32) -> Self {
+        self.connection_config.chunk_size = chunk_size.clamp(1, MAX_CHUNK_SIZE);
+        self
+    }
+
+    /// Share `streams` with the server, e.g. to look up live streams from another service
+    pub fn with_stream_registry(mut self, streams: StreamRegistry) -> Self {
+        self.streams = streams;
+        self
+    }
+
+    /// The streams published to this server
+    pub fn stream_registry(&self) -> StreamRegistry {
+        self.streams.clone()
+    }
+
+    /// Serve RTMPS, every accepted connection does a TLS handshake before the RTMP handshake
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: Arc<ServerConfig>) -> Self {
+        self.tls = Some(TlsAcceptor::from(config));
+        self
+    }
+
+    /// Send connection and stream lifecycle events to `events`. Events are dropped rather than
+    /// holding up connections if the channel is full
+    pub fn with_events(mut self, events: mpsc::Sender<ServerEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Keep track of every connection in `connections`, e.g. to inspect them while debugging.
+    /// Connections aren't tracked unless this is set
+    pub fn with_connection_registry(mut self, connections: ConnectionRegistry) -> Self {
+        self.connections = Some(connections);
+        self
+    }
+
+    /// Set the apps clients are allowed to connect to
+    pub fn with_apps(mut self, apps: AppRegistry) -> Self {
+        self.apps = Arc::new(apps);
+        self
+    }
+
+    /// Serve a single connection over any transport, e.g. an in-memory one, with the server's
+    /// apps, streams and connection settings. Returns once the connection closes
+    pub async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        socket: S,
+    ) -> io::Result<()> {
+        let tracker = self
+            .connections
+            .as_ref()
+            .map(|connections| connections.register(None));
+        let mut connection = RTMPConnection::new(
+            socket,
+            self.apps.clone(),
+            self.streams.clone(),
+            self.connection_config,
+        )
+        .with_drain(self.drain.clone())
+        .with_tracker(tracker);
+        connection.process().await.map_err(|e| {
+            connection.record_error(&e);
+            io::Error::from(e)
+        })
     }
 
     pub async fn run(&self) -> io::Result<()> {
+        let mut backoff = AcceptBackoff::new(self.accept_backoff);
         loop {
-            let (socket, addr) = self.listener.accept().await?;
+            let (socket, addr) = match self.listener.accept().await {
+                Ok(accepted) => {
+                    backoff.reset();
+                    accepted
+                }
+                Err(e) => {
+                    let delay = backoff.on_error(e)?;
+                    sleep(delay).await;
+                    continue;
+                }
+            };
             debug!("Accepted connection from {addr}");
 
+            if self.drain.is_draining() {
+                debug!("Server is draining, rejecting connection from {addr}");
+                continue;
+            }
+
+            let permit = match &self.connection_limit {
+                None => None,
+                Some(limit) => match limit.policy {
+                    ConnectionLimitPolicy::Wait => limit.permits.clone().acquire_owned().await.ok(),
+                    ConnectionLimitPolicy::Reject => {
+                        match limit.permits.clone().try_acquire_owned() {
+                            Ok(permit) => Some(permit),
+                            Err(_) => {
+                                debug!(
+                                    "Connection limit reached, rejecting connection from {addr}"
+                                );
+                                continue;
+                            }
+                        }
+                    }
+                },
+            };
+
+            if let Some(keepalive) = &self.keepalive
+                && let Err(e) = set_keepalive(&socket, keepalive)
+            {
+                warn!("Failed to set TCP keepalive for {addr}: {e}");
+            }
+
+            let apps = self.apps.clone();
+            let streams = self.streams.clone();
+            let config = self.connection_config;
+            let events = self
+                .events
+                .clone()
+                .map(|sender| ConnectionEvents::new(sender, addr));
+            let tracker = self
+                .connections
+                .as_ref()
+                .map(|connections| connections.register(Some(addr)));
+            let drain = self.drain.clone();
+            #[cfg(feature = "tls")]
+            let tls = self.tls.clone();
             tokio::spawn(async move {
-                handle_rtmp_connection(RTMPConnection::new(socket)).await;
+                #[cfg(feature = "tls")]
+                if let Some(tls) = tls {
+                    // the TLS handshake happens in the connection's task so a slow client
+                    // can't hold up the accept loop
+                    match timeout(config.read_timeout, tls.accept(socket)).await {
+                        Ok(Ok(stream)) => {
+                            let connection = RTMPConnection::new(stream, apps, streams, config)
+                                .with_events(events)
+                                .with_drain(drain)
+                                .with_tracker(tracker);
+                            handle_rtmp_connection(connection, addr).await;
+                        }
+                        Ok(Err(e)) => warn!("TLS handshake with {addr} failed: {e}"),
+                        Err(_) => warn!("TLS handshake with {addr} timed out"),
+                    }
+                    drop(permit);
+                    return;
+                }
+
+                let connection = RTMPConnection::new(socket, apps, streams, config)
+                    .with_events(events)
+                    .with_drain(drain)
+                    .with_tracker(tracker);
+                handle_rtmp_connection(connection, addr).await;
+                // free up a slot for the next connection
+                drop(permit);
             });
         }
     }
 }
 
-#[instrument(
-    name = "RTMP connection",
-    skip_all,
-    fields(
-        address = connection
-                    .socket
-                    .peer_addr()
-                    .map(|addr| addr.to_string())
-                    .unwrap_or("unknown address".to_owned())
+/// Creates an [`RTMPSever`] bound to an address
+#[derive(Debug)]
+pub struct RTMPServerBuilder {
+    bind_addr: SocketAddr,
+    max_connections: Option<(usize, ConnectionLimitPolicy)>,
+    keepalive: Option<KeepaliveConfig>,
+    accept_backoff: AcceptBackoffConfig,
+    connection_config: ConnectionConfig,
+}
+
+impl RTMPServerBuilder {
+    /// The address the server binds to unless [`RTMPServerBuilder::bind_addr`] is set
+    pub const DEFAULT_BIND_ADDR: SocketAddr =
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 1935);
+
+    pub fn new() -> Self {
+        Self {
+            bind_addr: Self::DEFAULT_BIND_ADDR,
+            max_connections: None,
+            keepalive: Some(KeepaliveConfig::default()),
+            accept_backoff: AcceptBackoffConfig::default(),
+            connection_config: ConnectionConfig::default(),
+        }
+    }
+
+    pub fn bind_addr(mut self, bind_addr: SocketAddr) -> Self {
+        self.bind_addr = bind_addr;
+        self
+    }
+
+    /// See [`RTMPSever::with_connection_limit`]
+    pub fn max_connections(
+        mut self,
+        max_connections: usize,
+        policy: ConnectionLimitPolicy,
+    ) -> Self {
+        self.max_connections = Some((max_connections, policy));
+        self
+    }
+
+    /// See [`RTMPSever::with_read_timeout`]
+    pub fn read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.connection_config.read_timeout = read_timeout;
+        self
+    }
+
+    /// See [`RTMPSever::with_idle_timeout`]
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.connection_config.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// See [`RTMPSever::with_chunk_size`]
+    pub fn default_chunk_size(mut self, chunk_size: u32) -> Self {
+        self.connection_config.chunk_size = chunk_size;
+        self
+    }
+
+    /// See [`RTMPSever::with_keepalive`]
+    pub fn keepalive(mut self, keepalive: Option<KeepaliveConfig>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// See [`RTMPSever::with_accept_backoff`]
+    pub fn accept_backoff(mut self, accept_backoff: AcceptBackoffConfig) -> Self {
+        self.accept_backoff = accept_backoff;
+        self
+    }
+
+    /// Bind the listener and create the server
+    pub async fn build(self) -> io::Result<RTMPSever> {
+        let mut server = RTMPSever::new(TcpListener::bind(self.bind_addr).await?)
+            .with_keepalive(self.keepalive)
+            .with_accept_backoff(self.accept_backoff)
+            .with_read_timeout(self.connection_config.read_timeout)
+            .with_idle_timeout(self.connection_config.idle_timeout)
+            .with_chunk_size(self.connection_config.chunk_size);
+        if let Some((max_connections, policy)) = self.max_connections {
+            server = server.with_connection_limit(max_connections, policy);
+        }
+
+        Ok(server)
+    }
+}
+
+impl Default for RTMPServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decides whether the accept loop should keep going after an accept error, and how long to
+/// wait before trying again
+#[derive(Debug)]
+struct AcceptBackoff {
+    config: AcceptBackoffConfig,
+    delay: Duration,
+}
+
+impl AcceptBackoff {
+    fn new(config: AcceptBackoffConfig) -> Self {
+        Self {
+            config,
+            delay: config.min,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.delay = self.config.min;
+    }
+
+    /// Returns how long to wait before accepting again if the error is transient,
+    /// otherwise returns the error back
+    fn on_error(&mut self, e: io::Error) -> io::Result<Duration> {
+        if !is_transient_accept_error(&e) {
+            error!("Fatal error accepting connection: {e}");
+            return Err(e);
+        }
+
+        let delay = self.delay;
+        warn!("Failed to accept connection, retrying in {delay:?}: {e}");
+        self.delay = (self.delay * 2).min(self.config.max);
+        Ok(delay)
+    }
+}
+
+/// Errors that clear up on their own, like running out of file descriptors or a peer resetting
+/// the connection before we get to accept it
+fn is_transient_accept_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::OutOfMemory
+    ) || matches!(
+        e.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    )
+}
+
+fn set_keepalive(socket: &TcpStream, keepalive: &KeepaliveConfig) -> io::Result<()> {
+    SockRef::from(socket).set_tcp_keepalive(
+        &TcpKeepalive::new()
+            .with_time(keepalive.idle)
+            .with_interval(keepalive.interval),
     )
-)]
-async fn handle_rtmp_connection(mut connection: RTMPConnection) {
+}
+
+#[instrument(name = "RTMP connection", skip(connection))]
+async fn handle_rtmp_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut connection: RTMPConnection<S>,
+    address: SocketAddr,
+) {
+    let events = connection.events.take();
+    if let Some(events) = &events {
+        events.send(|peer_addr| ServerEvent::ConnectionOpened { peer_addr });
+    }
+
     if let Err(e) = connection.process().await {
-        error!("Failed to process rtmp connection: {e}");
+        match e.kind() {
+            _ if e.is_disconnect() => debug!("Peer disconnected: {e}"),
+            ConnectionErrorKind::Handshake | ConnectionErrorKind::Timeout => {
+                warn!("Failed to process rtmp connection: {e}")
+            }
+            ConnectionErrorKind::Protocol | ConnectionErrorKind::Io => {
+                error!("Failed to process rtmp connection: {e}")
+            }
+        }
+        if let Some(events) = &events {
+            let kind = e.kind();
+            events.send(|peer_addr| ServerEvent::ConnectionFailed { peer_addr, kind });
+        }
+        connection.record_error(&e);
+    }
+
+    let stats = connection.stats;
+    let dropped_packets = connection.net_connection.dropped_packets();
+    // the streams on the connection are unpublished before it is reported closed
+    drop(connection);
+    if let Some(events) = &events {
+        events.send(|peer_addr| ServerEvent::ConnectionClosed { peer_addr, stats });
+    }
+    debug!(
+        audio_bytes = stats.audio_bytes,
+        video_bytes = stats.video_bytes,
+        control_bytes = stats.control_bytes,
+        dropped_packets,
+        "Connection closed after receiving {} bytes",
+        stats.total_bytes()
+    );
+}
+
+/// Why a connection ended, keeping apart the handshake, framing and socket failures that used to
+/// all come back as an [`io::Error`]
+#[derive(Error, Debug)]
+pub(crate) enum RtmpConnectionError {
+    #[error("Handshake failed")]
+    Handshake(
+        #[source]
+        #[from]
+        HandshakeError,
+    ),
+    #[error("Failed to read chunk")]
+    Chunk(
+        #[source]
+        #[from]
+        ParseChunkError,
+    ),
+    #[error("Failed to assemble message")]
+    Reassembly(
+        #[source]
+        #[from]
+        ReceiveChunkError,
+    ),
+    /// [`RTMPConnection::process`] skips messages it can't parse since the chunk framing is
+    /// still intact, so this only comes up for callers that can't carry on past one
+    #[error("Failed to parse message")]
+    Message(
+        #[source]
+        #[from]
+        ParseMessageError,
+    ),
+    #[error("Failed to write to socket")]
+    Io(
+        #[source]
+        #[from]
+        io::Error,
+    ),
+}
+
+impl RtmpConnectionError {
+    pub fn kind(&self) -> ConnectionErrorKind {
+        match self {
+            Self::Handshake(_) => ConnectionErrorKind::Handshake,
+            Self::Chunk(ParseChunkError::Timeout(_)) => ConnectionErrorKind::Timeout,
+            Self::Chunk(e) if e.io_error().is_some() => ConnectionErrorKind::Io,
+            Self::Chunk(_) | Self::Reassembly(_) | Self::Message(_) => {
+                ConnectionErrorKind::Protocol
+            }
+            Self::Io(_) => ConnectionErrorKind::Io,
+        }
+    }
+
+    /// Whether the peer went away, see [`is_disconnect`]
+    pub fn is_disconnect(&self) -> bool {
+        let error = match self {
+            Self::Handshake(HandshakeError::ReadError(e) | HandshakeError::WriteError(e)) => e,
+            Self::Chunk(e) => match e.io_error() {
+                Some(e) => e,
+                None => return false,
+            },
+            Self::Io(e) => e,
+            _ => return false,
+        };
+        is_disconnect(error)
+    }
+}
+
+impl From<RtmpConnectionError> for io::Error {
+    fn from(value: RtmpConnectionError) -> Self {
+        match value {
+            RtmpConnectionError::Handshake(e) => e.into(),
+            RtmpConnectionError::Chunk(e) => e.into(),
+            RtmpConnectionError::Reassembly(e) => e.into(),
+            RtmpConnectionError::Message(e) => io::Error::new(io::ErrorKind::InvalidData, e),
+            RtmpConnectionError::Io(e) => e,
+        }
     }
 }
 
+/// A single client connection over any transport, usually a [`TcpStream`] or a TLS stream
+/// wrapping one
 #[derive(Debug)]
-struct RTMPConnection {
-    socket: TcpStream,
+struct RTMPConnection<S> {
+    socket: S,
     chunk_mux: ChunkMultiplexer,
+    chunk_writer: ChunkWriter,
     net_connection: NetConnection,
+    stats: ConnectionStats,
+    read_timeout: Duration,
+    idle_timeout: Duration,
+    events: Option<ConnectionEvents>,
+    /// Where the connection's state is kept up to date, if connections are being tracked
+    tracker: Option<ConnectionHandle>,
 }
 
-impl RTMPConnection {
-    pub fn new(socket: TcpStream) -> Self {
+impl<S: AsyncRead + AsyncWrite + Unpin> RTMPConnection<S> {
+    pub fn new(
+        socket: S,
+        apps: Arc<AppRegistry>,
+        streams: StreamRegistry,
+        config: ConnectionConfig,
+    ) -> Self {
         Self {
             socket,
             chunk_mux: ChunkMultiplexer::new(),
-            net_connection: NetConnection::new(),
+            chunk_writer: ChunkWriter::new(),
+            net_connection: NetConnection::new(apps, streams).with_chunk_size(config.chunk_size),
+            stats: ConnectionStats::default(),
+            read_timeout: config.read_timeout,
+            idle_timeout: config.idle_timeout,
+            events: None,
+            tracker: None,
+        }
+    }
+
+    fn with_drain(self, drain: DrainHandle) -> Self {
+        Self {
+            net_connection: self.net_connection.with_drain(drain),
+            ..self
+        }
+    }
+
+    fn with_tracker(self, tracker: Option<ConnectionHandle>) -> Self {
+        Self { tracker, ..self }
+    }
+
+    fn record_error(&self, error: &RtmpConnectionError) {
+        if let Some(tracker) = &self.tracker {
+            tracker.record_error(error.kind(), error);
+        }
+    }
+
+    fn with_events(self, events: Option<ConnectionEvents>) -> Self {
+        Self {
+            net_connection: match &events {
+                Some(events) => self.net_connection.with_events(events.clone()),
+                None => self.net_connection,
+            },
+            events,
+            ..self
         }
     }
 
-    async fn process(&mut self) -> io::Result<()> {
+    async fn process(&mut self) -> Result<(), RtmpConnectionError> {
         handshake(&mut self.socket).await?;
+        self.net_connection.handshake_complete();
 
         let mut reader = BufReader::new(&mut self.socket);
+        let mut last_activity = Instant::now();
         loop {
-            let chunk = Chunk::read_chunk(
-                &mut reader,
-                &(self.net_connection.max_chunk_size() as usize),
+            if let Some(tracker) = &self.tracker {
+                // inline rather than a method, the reader is holding on to the socket
+                tracker.update(|info| {
+                    self.net_connection.describe(info);
+                    info.buffered_bytes = self.chunk_mux.buffered_bytes();
+                    info.stats = self.stats;
+                });
+            }
+
+            // wait for either the peer to send something or for media to forward to the peer.
+            // fill_buf doesn't consume anything, so it is safe to cancel unlike read_chunk
+            let media = tokio::select! {
+                buffered = reader.fill_buf() => match buffered {
+                    Ok([]) => {
+                        debug!("Peer closed the connection");
+                        return Ok(());
+                    }
+                    Err(e) if is_disconnect(&e) => {
+                        debug!("Peer disconnected: {e}");
+                        return Ok(());
+                    }
+                    // any other error will come up again when reading the chunk
+                    _ => None,
+                },
+                // hold media back once the peer's window is full until it acknowledges
+                media = self.net_connection.next_media(),
+                    if !self.net_connection.is_output_blocked() => Some(media),
+                // a paused player is quiet but still there, however long the pause is
+                _ = sleep_until(last_activity + self.idle_timeout),
+                    if !self.net_connection.has_paused_stream() => {
+                    debug!("Closing connection after being idle for {:?}", self.idle_timeout);
+                    return Ok(());
+                }
+            };
+            if let Some(media) = media {
+                let sent = send_message(&mut self.chunk_writer, reader.get_mut(), media).await?;
+                self.net_connection.record_bytes_sent(sent);
+                last_activity = Instant::now();
+                if self.net_connection.is_closing() {
+                    debug!("Closing connection");
+                    return Ok(());
+                }
+                continue;
+            }
+
+            // failing to read a chunk means we've lost track of the chunk framing,
+            // so there is no way to recover the connection
+            let chunk = match timeout(
+                self.read_timeout,
+                Chunk::read_chunk(
+                    &mut reader,
+                    &(self.net_connection.max_chunk_size() as usize),
+                    &self.chunk_mux,
+                ),
             )
-            .await?;
+            .await
+            .map_err(ParseChunkError::from)?
+            {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    let e = RtmpConnectionError::from(e);
+                    // hanging up partway through a chunk is still just a hang up
+                    if e.is_disconnect() {
+                        debug!("Peer disconnected: {e}");
+                        return Ok(());
+                    }
+                    return Err(e);
+                }
+            };
             trace!("finished reading chunk");
 
-            if let Some((message_bytes, message_type_id, message_stream_id)) =
-                self.chunk_mux.receive_chunk(chunk)
+            if let Some(ack) = self
+                .net_connection
+                .record_bytes_received(chunk.header.len() + chunk.payload.len())
             {
-                match Message::parse_message(&message_bytes, message_type_id) {
+                let sent = send_message(&mut self.chunk_writer, reader.get_mut(), ack).await?;
+                self.net_connection.record_bytes_sent(sent);
+            }
+
+            if let Some(message) = self.chunk_mux.receive_chunk(chunk)? {
+                last_activity = Instant::now();
+                self.stats
+                    .record(message.message_type_id, message.payload.len());
+                let parsed = if command_message_type::is_command(message.message_type_id) {
+                    // keep the payload, so media goes to players as it was received rather
+                    // than being encoded again
+                    RetainedCommand::parse(&message.payload, message.message_type_id)
+                        .map(|command| {
+                            if let CommandMessage::Audio { .. } | CommandMessage::Video { .. } =
+                                command.message
+                            {
+                                self.net_connection.forward_media(
+                                    message.message_stream_id,
+                                    MediaPacket {
+                                        message_type_id: message.message_type_id,
+                                        timestamp: message.timestamp,
+                                        payload: command.raw_bytes().clone(),
+                                    },
+                                );
+                            }
+                            Message::Command(command.message)
+                        })
+                        .map_err(ParseMessageError::from)
+                } else {
+                    Message::parse_message(&message.payload, message.message_type_id)
+                };
+                match parsed {
                     Ok(msg) => {
                         debug!("message received:\n{:#?}", msg);
+                        match msg {
+                            Message::Protocol(ProtolControlMessage::Abort(cs_id)) => {
+                                self.chunk_mux.abort(cs_id)
+                            }
+                            Message::Command(CommandMessage::NetConnectionCommand {
+                                command_type: NetConnectionCommandType::Connect,
+                                ..
+                            }) => self.net_connection.record_connect_chunk_stream(
+                                message.chunk_stream_id,
+                                message.message_stream_id,
+                            ),
+                            _ => {}
+                        }
+                        match self
+                            .net_connection
+                            .handle_message(&msg, message.message_stream_id)
+                        {
+                            Ok(responses) => {
+                                for response in responses {
+                                    let sent = send_message(
+                                        &mut self.chunk_writer,
+                                        reader.get_mut(),
+                                        response,
+                                    )
+                                    .await?;
+                                    self.net_connection.record_bytes_sent(sent);
+                                }
+                            }
+                            Err(e) => error!("unable to encode response: {e}"),
+                        }
+
+                        if self.net_connection.is_closing() {
+                            debug!("Closing connection");
+                            return Ok(());
+                        }
                     }
+                    // the message was framed correctly, so we can skip it and keep going
                     Err(e) => error!("unable to parse message: {e}"),
                 };
             }
         }
     }
 }
+
+/// Whether an error means the peer went away, rather than anything going wrong with the
+/// connection worth logging as an error
+fn is_disconnect(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// Write a message to the peer, switching to the new chunk size once the peer has been told
+/// about it. Returns how many bytes were written
+pub(crate) async fn send_message<W: AsyncWrite + Unpin>(
+    chunk_writer: &mut ChunkWriter,
+    socket: &mut W,
+    message: OutgoingMessage,
+) -> io::Result<usize> {
+    trace!("sending message:\n{:#?}", message);
+    let written = chunk_writer
+        .write_message(
+            socket,
+            message.chunk_stream_id(),
+            message.message_type_id(),
+            message.message_stream_id(),
+            message.timestamp(),
+            &message.payload(),
+        )
+        .await?;
+
+    if let OutgoingMessage::Protocol(ProtolControlMessage::SetChunkSize(chunk_size)) = message {
+        chunk_writer.set_chunk_size(chunk_size as usize);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{
+        amf::{AMF0Value, Decoder, Properties},
+        chunks::chunk_mux::AssembledMessage,
+        connection_registry::ConnectionState,
+        messages::{
+            chunk_stream,
+            command::{command_message_type, encode_command},
+            protocol_control::{peer_bandwidth_limit, protocol_control_type},
+            user_control::{USER_CONTROL_TYPE, UserControlMessage},
+        },
+    };
+
+    fn test_apps() -> Arc<AppRegistry> {
+        Arc::new(AppRegistry::new().with_app("live", AppOptions::default()))
+    }
+
+    async fn client_handshake<S: AsyncRead + AsyncWrite + Unpin>(client: &mut S) {
+        client.write_u8(3).await.unwrap();
+        client.write_all(&[0; 1536]).await.unwrap();
+
+        let mut s0_s1_s2 = [0; 1 + 1536 * 2];
+        client.read_exact(&mut s0_s1_s2).await.unwrap();
+        client.write_all(&s0_s1_s2[1..1537]).await.unwrap();
+    }
+
+    fn type0_chunk(cs_id: u8, message_type_id: u8, payload: &[u8]) -> Vec<u8> {
+        [
+            &[cs_id],
+            [0, 0, 0].as_slice(),
+            &(payload.len() as u32).to_be_bytes()[1..],
+            &[message_type_id],
+            &[0, 0, 0, 0],
+            payload,
+        ]
+        .concat()
+    }
+
+    #[tokio::test]
+    async fn test_unparseable_message_keeps_connection_alive() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        tokio::spawn(async move {
+            client_handshake(&mut client).await;
+
+            let window_ack_size = 2500000u32.to_be_bytes();
+            let bytes = [
+                type0_chunk(2, protocol_control_type::WINDOW_ACK_SIZE, &window_ack_size),
+                type0_chunk(3, command_message_type::COMMAND_AMF0, &[0xff, 0xff, 0xff]),
+                type0_chunk(2, protocol_control_type::WINDOW_ACK_SIZE, &window_ack_size),
+            ]
+            .concat();
+            client.write_all(&bytes).await.unwrap();
+        });
+
+        let (stream, _) = server.accept().await.unwrap();
+        let mut connection = RTMPConnection::new(
+            stream,
+            test_apps(),
+            StreamRegistry::new(),
+            ConnectionConfig::default(),
+        );
+        let _ = connection.process().await;
+
+        // all three messages were received despite the bad command in the middle
+        assert_eq!(connection.stats.control_bytes, 4 + 3 + 4);
+    }
+
+    #[tokio::test]
+    async fn test_set_chunk_size_applies_to_later_chunks() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        tokio::spawn(async move {
+            client_handshake(&mut client).await;
+
+            let bytes = [
+                type0_chunk(
+                    2,
+                    protocol_control_type::SET_CHUNK_SIZE,
+                    &4096u32.to_be_bytes(),
+                ),
+                // larger than the default chunk size, but sent as a single chunk
+                type0_chunk(6, command_message_type::VIDEO, &[0x17; 1000]),
+            ]
+            .concat();
+            client.write_all(&bytes).await.unwrap();
+        });
+
+        let (stream, _) = server.accept().await.unwrap();
+        let mut connection = RTMPConnection::new(
+            stream,
+            test_apps(),
+            StreamRegistry::new(),
+            ConnectionConfig::default(),
+        );
+        let _ = connection.process().await;
+
+        assert_eq!(connection.net_connection.max_chunk_size(), 4096);
+        assert_eq!(connection.stats.video_bytes, 1000);
+    }
+
+    /// Accept a single connection and process it in the background
+    async fn spawn_connection(streams: StreamRegistry) -> TcpStream {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = server.accept().await.unwrap();
+            let _ = RTMPConnection::new(stream, test_apps(), streams, ConnectionConfig::default())
+                .process()
+                .await;
+        });
+
+        client
+    }
+
+    /// The client side of a connection, after the handshake
+    struct TestClient<'a, S = TcpStream> {
+        reader: BufReader<&'a mut S>,
+        chunk_mux: ChunkMultiplexer,
+        chunk_size: usize,
+    }
+
+    impl<'a, S: AsyncRead + AsyncWrite + Unpin> TestClient<'a, S> {
+        async fn new(client: &'a mut S) -> Self {
+            client_handshake(client).await;
+            Self {
+                reader: BufReader::new(client),
+                chunk_mux: ChunkMultiplexer::new(),
+                chunk_size: 128,
+            }
+        }
+
+        async fn send_command(&mut self, message_stream_id: u32, values: &[AMF0Value<'_>]) {
+            ChunkWriter::new()
+                .write_message(
+                    self.reader.get_mut(),
+                    3,
+                    command_message_type::COMMAND_AMF0,
+                    message_stream_id,
+                    0,
+                    &encode_command(values).unwrap(),
+                )
+                .await
+                .unwrap();
+        }
+
+        async fn send_protocol(&mut self, message: ProtolControlMessage) {
+            ChunkWriter::new()
+                .write_message(
+                    self.reader.get_mut(),
+                    2,
+                    message.message_type_id(),
+                    0,
+                    0,
+                    &message.encode(),
+                )
+                .await
+                .unwrap();
+        }
+
+        async fn read_message(&mut self) -> AssembledMessage {
+            loop {
+                let chunk = Chunk::read_chunk(&mut self.reader, &self.chunk_size, &self.chunk_mux)
+                    .await
+                    .unwrap();
+                if let Some(message) = self.chunk_mux.receive_chunk(chunk).unwrap() {
+                    if message.message_type_id == protocol_control_type::SET_CHUNK_SIZE {
+                        self.chunk_size =
+                            u32::from_be_bytes(message.payload[..4].try_into().unwrap()) as usize;
+                    }
+                    return message;
+                }
+            }
+        }
+
+        /// Connect to the live app, returning the messages the server replied with
+        async fn connect(&mut self) -> Vec<AssembledMessage> {
+            self.send_command(
+                0,
+                &[
+                    AMF0Value::String("connect"),
+                    AMF0Value::Number(1.0),
+                    AMF0Value::Object(Properties::from([("app", AMF0Value::String("live"))])),
+                ],
+            )
+            .await;
+
+            let mut messages = vec![];
+            for _ in 0..4 {
+                messages.push(self.read_message().await);
+            }
+            messages
+        }
+
+        /// Send createStream, returning the message stream id the server created
+        async fn create_stream(&mut self) -> u32 {
+            self.send_command(
+                0,
+                &[
+                    AMF0Value::String("createStream"),
+                    AMF0Value::Number(2.0),
+                    AMF0Value::Null,
+                ],
+            )
+            .await;
+            let create_stream_result = self.read_message().await;
+            let mut decoder = Decoder::new(&create_stream_result.payload);
+            assert_eq!(decoder.decode().unwrap(), AMF0Value::String("_result"));
+            decoder
+                .decode_all()
+                .nth(2)
+                .unwrap()
+                .unwrap()
+                .try_into()
+                .unwrap()
+        }
+
+        /// Create a stream and publish `stream_name` on it, returning the onStatus reply
+        async fn publish(&mut self, stream_name: &str) -> AssembledMessage {
+            let stream_id = self.create_stream().await;
+            self.send_command(
+                stream_id,
+                &[
+                    AMF0Value::String("publish"),
+                    AMF0Value::Number(3.0),
+                    AMF0Value::Null,
+                    AMF0Value::String(stream_name),
+                    AMF0Value::String("live"),
+                ],
+            )
+            .await;
+            self.read_message().await
+        }
+
+        /// Create a stream and play `stream_name` on it
+        async fn play(&mut self, stream_name: &str) -> u32 {
+            let stream_id = self.create_stream().await;
+            self.send_command(
+                stream_id,
+                &[
+                    AMF0Value::String("play"),
+                    AMF0Value::Number(3.0),
+                    AMF0Value::Null,
+                    AMF0Value::String(stream_name),
+                    AMF0Value::Number(-2.0),
+                    AMF0Value::Number(-1.0),
+                    AMF0Value::Boolean(true),
+                ],
+            )
+            .await;
+            stream_id
+        }
+    }
+
+    /// Decode the code of the status object of an onStatus or _result command
+    fn status_code(message: &AssembledMessage) -> Option<String> {
+        let information = Decoder::new(&message.payload).decode_all().nth(3)?.ok()?;
+        information.get("code")?.as_str().map(str::to_owned)
+    }
+
+    #[tokio::test]
+    async fn test_connect_response() {
+        let mut client = spawn_connection(StreamRegistry::new()).await;
+        let mut client = TestClient::new(&mut client).await;
+        let messages = client.connect().await;
+
+        assert_eq!(
+            messages
+                .iter()
+                .map(|message| message.message_type_id)
+                .collect::<Vec<_>>(),
+            [
+                protocol_control_type::WINDOW_ACK_SIZE,
+                protocol_control_type::SET_PEER_BANDWIDTH,
+                protocol_control_type::SET_CHUNK_SIZE,
+                command_message_type::COMMAND_AMF0,
+            ]
+        );
+        assert_eq!(messages[1].payload[..], [0x00, 0x26, 0x25, 0xA0, 0x02]);
+        assert_eq!(messages[2].payload[..], 4096u32.to_be_bytes());
+
+        let mut decoder = Decoder::new(&messages[3].payload);
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::String("_result"));
+        assert_eq!(decoder.decode().unwrap(), AMF0Value::Number(1.0));
+        assert!(matches!(
+            decoder.decode().unwrap(),
+            AMF0Value::Object(properties) if properties.contains_key("fmsVer")
+        ));
+        assert_eq!(
+            status_code(&messages[3]).as_deref(),
+            Some("NetConnection.Connect.Success")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_on_unusual_chunk_stream() {
+        let connections = ConnectionRegistry::new();
+        let server = RTMPSever::new(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .with_connection_registry(connections.clone());
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let mut client = TestClient::new(&mut socket).await;
+        let connect = encode_command(&[
+            AMF0Value::String("connect"),
+            AMF0Value::Number(1.0),
+            AMF0Value::Object(Properties::from([("app", AMF0Value::String("live"))])),
+        ])
+        .unwrap();
+        ChunkWriter::new()
+            .write_message(
+                client.reader.get_mut(),
+                12,
+                command_message_type::COMMAND_AMF0,
+                0,
+                0,
+                &connect,
+            )
+            .await
+            .unwrap();
+
+        let mut messages = vec![];
+        for _ in 0..4 {
+            messages.push(client.read_message().await);
+        }
+        assert_eq!(
+            messages
+                .iter()
+                .map(|message| message.chunk_stream_id)
+                .collect::<Vec<_>>(),
+            [
+                chunk_stream::PROTOCOL_CONTROL,
+                chunk_stream::PROTOCOL_CONTROL,
+                chunk_stream::PROTOCOL_CONTROL,
+                chunk_stream::COMMAND,
+            ]
+        );
+        assert_eq!(messages[3].message_stream_id, 0);
+        assert_eq!(
+            status_code(&messages[3]).as_deref(),
+            Some("NetConnection.Connect.Success")
+        );
+
+        // the connection records it once it is done with the connect
+        for _ in 0..100 {
+            if connections.connections()[0]
+                .control_chunk_stream_id
+                .is_some()
+            {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            connections.connections()[0].control_chunk_stream_id,
+            Some(12)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let streams = StreamRegistry::new();
+        let mut client = spawn_connection(streams.clone()).await;
+        let mut client = TestClient::new(&mut client).await;
+        client.connect().await;
+
+        let on_status = client.publish("mystream").await;
+        assert_eq!(on_status.message_stream_id, 1);
+        assert_eq!(
+            status_code(&on_status).as_deref(),
+            Some("NetStream.Publish.Start")
+        );
+        assert!(streams.is_live("live/mystream"));
+    }
+
+    #[tokio::test]
+    async fn test_play_receives_published_video() {
+        let streams = StreamRegistry::new();
+        let mut publisher = spawn_connection(streams.clone()).await;
+        let mut publisher = TestClient::new(&mut publisher).await;
+        publisher.connect().await;
+        publisher.publish("mystream").await;
+
+        let mut player = spawn_connection(streams.clone()).await;
+        let mut player = TestClient::new(&mut player).await;
+        player.connect().await;
+        let stream_id = player.play("mystream").await;
+
+        let stream_begin = player.read_message().await;
+        assert_eq!(stream_begin.message_type_id, USER_CONTROL_TYPE);
+        assert_eq!(
+            stream_begin.payload[..],
+            UserControlMessage::StreamBegin(stream_id).encode()[..]
+        );
+        let on_status = player.read_message().await;
+        assert_eq!(
+            status_code(&on_status).as_deref(),
+            Some("NetStream.Play.Reset")
+        );
+        let on_status = player.read_message().await;
+        assert_eq!(on_status.message_stream_id, stream_id);
+        assert_eq!(
+            status_code(&on_status).as_deref(),
+            Some("NetStream.Play.Start")
+        );
+        let sample_access = player.read_message().await;
+        assert_eq!(
+            sample_access.message_type_id,
+            command_message_type::DATA_AMF0
+        );
+
+        let keyframe = [0x17, 0x01, 0x00, 0x00, 0x00, 0x65, 0x88];
+        ChunkWriter::new()
+            .write_message(
+                publisher.reader.get_mut(),
+                6,
+                command_message_type::VIDEO,
+                1,
+                40,
+                &keyframe,
+            )
+            .await
+            .unwrap();
+
+        let video = player.read_message().await;
+        assert_eq!(video.message_type_id, command_message_type::VIDEO);
+        assert_eq!(video.message_stream_id, stream_id);
+        assert_eq!(video.timestamp, 40);
+        assert_eq!(video.payload[..], keyframe);
+    }
+
+    #[tokio::test]
+    async fn test_media_waits_for_ack_once_peer_window_is_full() {
+        let streams = StreamRegistry::new();
+        let mut publisher = spawn_connection(streams.clone()).await;
+        let mut publisher = TestClient::new(&mut publisher).await;
+        publisher.connect().await;
+        publisher.publish("mystream").await;
+
+        let mut player = spawn_connection(streams.clone()).await;
+        let mut player = TestClient::new(&mut player).await;
+        player.connect().await;
+        player.play("mystream").await;
+        for _ in 0..4 {
+            player.read_message().await;
+        }
+
+        player
+            .send_protocol(ProtolControlMessage::SetPeerBandwidth {
+                limit_type: peer_bandwidth_limit::HARD,
+                window_size: 100,
+            })
+            .await;
+        let ack_window_size = player.read_message().await;
+        assert_eq!(
+            ack_window_size.message_type_id,
+            protocol_control_type::WINDOW_ACK_SIZE
+        );
+
+        let keyframe = [0x17, 0x01, 0x00, 0x00, 0x00, 0x65, 0x88];
+        ChunkWriter::new()
+            .write_message(
+                publisher.reader.get_mut(),
+                6,
+                command_message_type::VIDEO,
+                1,
+                40,
+                &keyframe,
+            )
+            .await
+            .unwrap();
+        assert!(
+            timeout(Duration::from_millis(100), player.read_message())
+                .await
+                .is_err()
+        );
+
+        player
+            .send_protocol(ProtolControlMessage::Ack(u32::MAX / 2))
+            .await;
+        let video = player.read_message().await;
+        assert_eq!(video.payload[..], keyframe);
+    }
+
+    #[tokio::test]
+    async fn test_connections_share_stream_registry() {
+        let server = RTMPSever::new(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let addr = server.listener.local_addr().unwrap();
+        let streams = server.stream_registry();
+        tokio::spawn(async move { server.run().await });
+
+        let mut publisher = TcpStream::connect(addr).await.unwrap();
+        let mut publisher = TestClient::new(&mut publisher).await;
+        publisher.connect().await;
+        publisher.publish("mystream").await;
+        assert!(streams.is_live("live/mystream"));
+
+        let mut duplicate = TcpStream::connect(addr).await.unwrap();
+        let mut duplicate = TestClient::new(&mut duplicate).await;
+        duplicate.connect().await;
+        assert_eq!(
+            status_code(&duplicate.publish("mystream").await).as_deref(),
+            Some("NetStream.Publish.BadName")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_events() {
+        let (sender, mut events) = mpsc::channel(16);
+        let server =
+            RTMPSever::new(TcpListener::bind("127.0.0.1:0").await.unwrap()).with_events(sender);
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let peer_addr = socket.local_addr().unwrap();
+        let mut publisher = TestClient::new(&mut socket).await;
+        publisher.connect().await;
+        publisher.publish("mystream").await;
+        let media = [
+            type0_chunk(6, command_message_type::AUDIO, &[0xAF; 100]),
+            type0_chunk(6, command_message_type::VIDEO, &[0x27; 120]),
+        ]
+        .concat();
+        publisher.reader.get_mut().write_all(&media).await.unwrap();
+
+        assert_eq!(
+            events.recv().await,
+            Some(ServerEvent::ConnectionOpened { peer_addr })
+        );
+        assert_eq!(
+            events.recv().await,
+            Some(ServerEvent::StreamPublished {
+                peer_addr,
+                stream_key: "live/mystream".to_owned()
+            })
+        );
+
+        drop(publisher);
+        drop(socket);
+        assert_eq!(
+            events.recv().await,
+            Some(ServerEvent::StreamUnpublished {
+                peer_addr,
+                stream_key: "live/mystream".to_owned()
+            })
+        );
+        let event = events.recv().await;
+        let Some(ServerEvent::ConnectionClosed {
+            peer_addr: closed_addr,
+            stats,
+        }) = event
+        else {
+            assert!(matches!(event, Some(ServerEvent::ConnectionClosed { .. })));
+            return;
+        };
+        assert_eq!(closed_addr, peer_addr);
+        assert_eq!(stats.audio_bytes, 100);
+        assert_eq!(stats.video_bytes, 120);
+    }
+
+    #[tokio::test]
+    async fn test_play_stream_not_found() {
+        let mut player = spawn_connection(StreamRegistry::new()).await;
+        let mut player = TestClient::new(&mut player).await;
+        player.connect().await;
+        player.play("missing").await;
+
+        assert_eq!(
+            status_code(&player.read_message().await).as_deref(),
+            Some("NetStream.Play.StreamNotFound")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_obs_publish_preamble() {
+        let mut client = spawn_connection(StreamRegistry::new()).await;
+        let mut client = TestClient::new(&mut client).await;
+        client.connect().await;
+
+        for (command, transaction_id) in [("releaseStream", 2.0), ("FCPublish", 3.0)] {
+            client
+                .send_command(
+                    0,
+                    &[
+                        AMF0Value::String(command),
+                        AMF0Value::Number(transaction_id),
+                        AMF0Value::Null,
+                        AMF0Value::String("obs"),
+                    ],
+                )
+                .await;
+        }
+
+        let decode_name = |message: &AssembledMessage| {
+            Decoder::new(&message.payload)
+                .decode()
+                .unwrap()
+                .as_str()
+                .map(str::to_owned)
+        };
+        let release_stream_result = client.read_message().await;
+        assert_eq!(
+            decode_name(&release_stream_result).as_deref(),
+            Some("_result")
+        );
+        let on_fc_publish = client.read_message().await;
+        assert_eq!(decode_name(&on_fc_publish).as_deref(), Some("onFCPublish"));
+        let fc_publish_result = client.read_message().await;
+        assert_eq!(decode_name(&fc_publish_result).as_deref(), Some("_result"));
+
+        assert_eq!(
+            status_code(&client.publish("obs").await).as_deref(),
+            Some("NetStream.Publish.Start")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_before_connect_closes_connection() {
+        let mut client = spawn_connection(StreamRegistry::new()).await;
+        let mut client = TestClient::new(&mut client).await;
+        client
+            .send_command(
+                1,
+                &[
+                    AMF0Value::String("publish"),
+                    AMF0Value::Number(3.0),
+                    AMF0Value::Null,
+                    AMF0Value::String("mystream"),
+                    AMF0Value::String("live"),
+                ],
+            )
+            .await;
+
+        assert_eq!(client.reader.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_ack_sent_after_window() {
+        let mut client = spawn_connection(StreamRegistry::new()).await;
+        let mut client = TestClient::new(&mut client).await;
+
+        let bytes = [
+            type0_chunk(
+                2,
+                protocol_control_type::WINDOW_ACK_SIZE,
+                &50u32.to_be_bytes(),
+            ),
+            type0_chunk(6, command_message_type::VIDEO, &[0x17; 100]),
+        ]
+        .concat();
+        client.reader.get_mut().write_all(&bytes).await.unwrap();
+
+        let ack = client.read_message().await;
+        assert_eq!(ack.message_type_id, protocol_control_type::ACK);
+        assert_eq!(ack.payload[..], (bytes.len() as u32).to_be_bytes());
+    }
+
+    // handle_rtmp_connection only logs an error when processing fails, so a disconnect has to
+    // end processing successfully
+    #[tokio::test]
+    async fn test_disconnect_mid_stream_is_a_clean_close() {
+        let (mut client, stream) = tokio::io::duplex(8192);
+        let connection = tokio::spawn(async move {
+            RTMPConnection::new(
+                stream,
+                test_apps(),
+                StreamRegistry::new(),
+                ConnectionConfig::default(),
+            )
+            .process()
+            .await
+        });
+
+        let mut test_client = TestClient::new(&mut client).await;
+        test_client.connect().await;
+        test_client.publish("mystream").await;
+        client
+            .write_all(&type0_chunk(6, command_message_type::VIDEO, &[0x17, 0x01]))
+            .await
+            .unwrap();
+        drop(client);
+
+        let result = timeout(Duration::from_secs(5), connection).await.unwrap();
+        assert!(result.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_mid_chunk_is_a_clean_close() {
+        let (mut client, stream) = tokio::io::duplex(8192);
+        let connection = tokio::spawn(async move {
+            RTMPConnection::new(
+                stream,
+                test_apps(),
+                StreamRegistry::new(),
+                ConnectionConfig::default(),
+            )
+            .process()
+            .await
+        });
+
+        client_handshake(&mut client).await;
+        let chunk = type0_chunk(6, command_message_type::VIDEO, &[0x17; 100]);
+        client.write_all(&chunk[..chunk.len() / 2]).await.unwrap();
+        drop(client);
+
+        let result = timeout(Duration::from_secs(5), connection).await.unwrap();
+        assert!(result.unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_is_disconnect() {
+        assert!(is_disconnect(&io::ErrorKind::UnexpectedEof.into()));
+        assert!(is_disconnect(&io::ErrorKind::ConnectionReset.into()));
+        assert!(!is_disconnect(&io::ErrorKind::InvalidData.into()));
+        assert!(!is_disconnect(&io::ErrorKind::TimedOut.into()));
+    }
+
+    #[tokio::test]
+    async fn test_connection_error_variants() {
+        let elapsed = || async {
+            timeout(Duration::ZERO, std::future::pending::<()>())
+                .await
+                .unwrap_err()
+        };
+
+        let e = RtmpConnectionError::from(HandshakeError::UnsupportedVersion(6));
+        assert!(matches!(e, RtmpConnectionError::Handshake(_)));
+        assert_eq!(e.kind(), ConnectionErrorKind::Handshake);
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::Unsupported);
+
+        let e = RtmpConnectionError::from(HandshakeError::ReadError(
+            io::ErrorKind::UnexpectedEof.into(),
+        ));
+        assert_eq!(e.kind(), ConnectionErrorKind::Handshake);
+        assert!(e.is_disconnect());
+
+        let e = RtmpConnectionError::from(ParseChunkError::from(elapsed().await));
+        assert!(matches!(e, RtmpConnectionError::Chunk(_)));
+        assert_eq!(e.kind(), ConnectionErrorKind::Timeout);
+        assert!(!e.is_disconnect());
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::TimedOut);
+
+        let e = RtmpConnectionError::from(ParseChunkError::MessageReadFailure(
+            io::ErrorKind::ConnectionReset.into(),
+        ));
+        assert_eq!(e.kind(), ConnectionErrorKind::Io);
+        assert!(e.is_disconnect());
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::ConnectionReset);
+
+        let e = RtmpConnectionError::from(ReceiveChunkError::EmptyMessage { message_type: 9 });
+        assert!(matches!(e, RtmpConnectionError::Reassembly(_)));
+        assert_eq!(e.kind(), ConnectionErrorKind::Protocol);
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::InvalidData);
+
+        let e = RtmpConnectionError::from(ParseMessageError::InvalidMessageTypeId(0xFF));
+        assert!(matches!(e, RtmpConnectionError::Message(_)));
+        assert_eq!(e.kind(), ConnectionErrorKind::Protocol);
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::InvalidData);
+
+        let e = RtmpConnectionError::from(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert!(matches!(e, RtmpConnectionError::Io(_)));
+        assert_eq!(e.kind(), ConnectionErrorKind::Io);
+        assert_eq!(io::Error::from(e).kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn test_failed_handshake_is_reported() {
+        let (sender, mut events) = mpsc::channel(16);
+        let server =
+            RTMPSever::new(TcpListener::bind("127.0.0.1:0").await.unwrap()).with_events(sender);
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let peer_addr = socket.local_addr().unwrap();
+        socket.write_all(&[6]).await.unwrap();
+
+        assert_eq!(
+            events.recv().await,
+            Some(ServerEvent::ConnectionOpened { peer_addr })
+        );
+        assert_eq!(
+            events.recv().await,
+            Some(ServerEvent::ConnectionFailed {
+                peer_addr,
+                kind: ConnectionErrorKind::Handshake
+            })
+        );
+        assert!(matches!(
+            events.recv().await,
+            Some(ServerEvent::ConnectionClosed { peer_addr: closed_addr, .. }) if closed_addr == peer_addr
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_connection_registry() {
+        let connections = ConnectionRegistry::new();
+        let server = RTMPSever::new(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .with_connection_registry(connections.clone());
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let mut publisher_socket = TcpStream::connect(addr).await.unwrap();
+        let publisher_addr = publisher_socket.local_addr().unwrap();
+        let mut publisher = TestClient::new(&mut publisher_socket).await;
+        publisher.connect().await;
+        publisher.publish("mystream").await;
+        let mut player_socket = TcpStream::connect(addr).await.unwrap();
+        let mut player = TestClient::new(&mut player_socket).await;
+        player.connect().await;
+        player.play("mystream").await;
+
+        // connections update the registry once they are done with the last message
+        let mut open = connections.connections();
+        for _ in 0..100 {
+            if open
+                .iter()
+                .any(|info| info.state == ConnectionState::Playing)
+            {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+            open = connections.connections();
+        }
+        let [publishing, playing] = open.as_slice() else {
+            assert_eq!(open.len(), 2);
+            return;
+        };
+        assert_eq!(publishing.peer_addr, Some(publisher_addr));
+        assert_eq!(publishing.state, ConnectionState::Publishing);
+        assert_eq!(publishing.app.as_deref(), Some("live"));
+        assert_eq!(publishing.publishing, ["live/mystream"]);
+        assert_eq!(publishing.out_chunk_size, SERVER_CHUNK_SIZE);
+        assert_eq!(playing.playing, ["live/mystream"]);
+        assert!(playing.publishing.is_empty());
+
+        drop(publisher);
+        drop(publisher_socket);
+        for _ in 0..100 {
+            if connections.connections().len() == 1 {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(connections.connections().len(), 1);
+
+        let mut failed = TcpStream::connect(addr).await.unwrap();
+        failed.write_all(&[6]).await.unwrap();
+        for _ in 0..100 {
+            if !connections.recent_errors().is_empty() {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        let errors = connections.recent_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ConnectionErrorKind::Handshake);
+        assert_eq!(errors[0].peer_addr, failed.local_addr().ok());
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_is_closed() {
+        let (mut client, stream) = tokio::io::duplex(8192);
+        let config = ConnectionConfig {
+            idle_timeout: Duration::from_millis(100),
+            ..ConnectionConfig::default()
+        };
+        let connection = tokio::spawn(async move {
+            RTMPConnection::new(stream, test_apps(), StreamRegistry::new(), config)
+                .process()
+                .await
+        });
+
+        client_handshake(&mut client).await;
+        let result = timeout(Duration::from_secs(5), connection).await.unwrap();
+        assert!(result.unwrap().is_ok());
+        assert_eq!(client.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_paused_player_is_not_idle() {
+        let (mut client, stream) = tokio::io::duplex(8192);
+        let streams = StreamRegistry::new();
+        let _publisher = streams.publish("live/mystream").unwrap();
+        let config = ConnectionConfig {
+            idle_timeout: Duration::from_millis(100),
+            ..ConnectionConfig::default()
+        };
+        let connection = tokio::spawn(async move {
+            RTMPConnection::new(stream, test_apps(), streams, config)
+                .process()
+                .await
+        });
+
+        let mut client = TestClient::new(&mut client).await;
+        client.connect().await;
+        let stream_id = client.play("mystream").await;
+        client
+            .send_command(
+                stream_id,
+                &[
+                    AMF0Value::String("pause"),
+                    AMF0Value::Number(0.0),
+                    AMF0Value::Null,
+                    AMF0Value::Boolean(true),
+                    AMF0Value::Number(0.0),
+                ],
+            )
+            .await;
+
+        // paused for many times the idle timeout
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!connection.is_finished());
+    }
+
+    #[test]
+    fn test_accept_backoff_retries_transient_errors() {
+        let config = AcceptBackoffConfig::default();
+        let mut backoff = AcceptBackoff::new(config);
+        let emfile = || io::Error::from_raw_os_error(libc::EMFILE);
+
+        assert_eq!(backoff.on_error(emfile()).unwrap(), config.min);
+        assert_eq!(backoff.on_error(emfile()).unwrap(), config.min * 2);
+        assert_eq!(backoff.on_error(emfile()).unwrap(), config.min * 4);
+
+        backoff.reset();
+        assert_eq!(
+            backoff
+                .on_error(io::ErrorKind::ConnectionAborted.into())
+                .unwrap(),
+            config.min
+        );
+    }
+
+    #[test]
+    fn test_accept_backoff_is_capped() {
+        let config = AcceptBackoffConfig {
+            min: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+        };
+        let mut backoff = AcceptBackoff::new(config);
+        for _ in 0..20 {
+            backoff
+                .on_error(io::Error::from_raw_os_error(libc::ENFILE))
+                .unwrap();
+        }
+        assert_eq!(
+            backoff
+                .on_error(io::Error::from_raw_os_error(libc::ENFILE))
+                .unwrap(),
+            config.max
+        );
+    }
+
+    #[test]
+    fn test_accept_backoff_fatal_error() {
+        let mut backoff = AcceptBackoff::new(AcceptBackoffConfig::default());
+        assert_eq!(
+            backoff
+                .on_error(io::Error::from_raw_os_error(libc::EBADF))
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EBADF)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_drain_rejects_new_connections() {
+        let server = RTMPSever::new(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        let addr = server.listener.local_addr().unwrap();
+        let drain = server.drain_handle();
+        let streams = server.stream_registry();
+        tokio::spawn(async move { server.run().await });
+
+        let mut publisher = TcpStream::connect(addr).await.unwrap();
+        let mut publisher = TestClient::new(&mut publisher).await;
+        publisher.connect().await;
+        publisher.publish("mystream").await;
+        let mut subscriber = streams.subscribe("live/mystream").unwrap();
+
+        let mut existing = TcpStream::connect(addr).await.unwrap();
+        existing.write_u8(3).await.unwrap();
+        existing.write_all(&[0; 1536]).await.unwrap();
+        let mut s0_s1_s2 = [0; 1 + 1536 * 2];
+        existing.read_exact(&mut s0_s1_s2).await.unwrap();
+
+        drain.drain();
+
+        let mut rejected = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(rejected.read(&mut [0; 1]).await.unwrap(), 0);
+
+        // the existing connection finishes its handshake and stays open
+        existing.write_all(&s0_s1_s2[1..1537]).await.unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), existing.read(&mut [0; 1]))
+                .await
+                .is_err()
+        );
+        // publishing anything new is turned away, but the stream already published keeps going
+        assert_eq!(
+            status_code(&publisher.publish("other").await).as_deref(),
+            Some("NetStream.Publish.Denied")
+        );
+        assert!(!streams.is_live("live/other"));
+        let keyframe = [0x17, 0x01, 0x00, 0x00, 0x00, 0x65];
+        ChunkWriter::new()
+            .write_message(
+                publisher.reader.get_mut(),
+                6,
+                command_message_type::VIDEO,
+                1,
+                40,
+                &keyframe,
+            )
+            .await
+            .unwrap();
+        assert_eq!(subscriber.recv().await.unwrap().payload[..], keyframe);
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit_rejects_extra_connections() {
+        let server = RTMPSever::new(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .with_connection_limit(1, ConnectionLimitPolicy::Reject);
+        let addr = server.listener.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        client_handshake(&mut first).await;
+
+        let mut rejected = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(rejected.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit_queues_extra_connections() {
+        let server = RTMPSever::new(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .with_connection_limit(1, ConnectionLimitPolicy::Wait);
+        let addr = server.listener.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        client_handshake(&mut first).await;
+
+        let mut queued = TcpStream::connect(addr).await.unwrap();
+        queued.write_u8(3).await.unwrap();
+        queued.write_all(&[0; 1536]).await.unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), queued.read(&mut [0; 1]))
+                .await
+                .is_err()
+        );
+
+        // the queued connection is handled once the first one closes
+        drop(first);
+        let mut s0 = [0; 1];
+        queued.read_exact(&mut s0).await.unwrap();
+        assert_eq!(s0, [3]);
+    }
+
+    #[tokio::test]
+    async fn test_builder_binds_and_accepts() {
+        let server = RTMPSever::builder()
+            .bind_addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .max_connections(10, ConnectionLimitPolicy::Reject)
+            .read_timeout(Duration::from_secs(5))
+            .default_chunk_size(8192)
+            .build()
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        tokio::spawn(async move { server.run().await });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut client = TestClient::new(&mut client).await;
+        let messages = client.connect().await;
+        assert_eq!(messages[2].payload[..], 8192u32.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_set_keepalive() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(server.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = server.accept().await.unwrap();
+
+        let keepalive = KeepaliveConfig {
+            idle: Duration::from_secs(30),
+            interval: Duration::from_secs(5),
+        };
+        set_keepalive(&socket, &keepalive).unwrap();
+
+        let sock_ref = SockRef::from(&socket);
+        assert!(sock_ref.keepalive().unwrap());
+        assert_eq!(sock_ref.tcp_keepalive_time().unwrap(), keepalive.idle);
+        assert_eq!(
+            sock_ref.tcp_keepalive_interval().unwrap(),
+            keepalive.interval
+        );
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn test_tls_handshake_then_rtmp_handshake() {
+        use tokio_rustls::{
+            TlsConnector,
+            rustls::{
+                ClientConfig, RootCertStore,
+                pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+            },
+        };
+
+        let certified = rcgen::generate_simple_self_signed(["localhost".to_owned()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+            certified.signing_key.serialize_der(),
+        ));
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+
+        let server = RTMPSever::new(TcpListener::bind("127.0.0.1:0").await.unwrap())
+            .with_tls(Arc::new(server_config));
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut stream = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), socket)
+            .await
+            .unwrap();
+
+        let mut client = TestClient::new(&mut stream).await;
+        let messages = client.connect().await;
+        assert_eq!(
+            status_code(&messages[3]).as_deref(),
+            Some("NetConnection.Connect.Success")
+        );
+    }
+}
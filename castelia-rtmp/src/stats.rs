@@ -0,0 +1,52 @@
+use crate::messages::command::command_message_type;
+
+/// Bytes received on a connection, broken down by media type
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ConnectionStats {
+    pub audio_bytes: u64,
+    pub video_bytes: u64,
+    /// Everything that isn't audio or video: protocol control, user control, commands and data
+    pub control_bytes: u64,
+}
+
+impl ConnectionStats {
+    /// Record a fully received message of the given type
+    pub(crate) fn record(&mut self, message_type_id: u8, length: usize) {
+        let counter = match message_type_id {
+            command_message_type::AUDIO => &mut self.audio_bytes,
+            command_message_type::VIDEO => &mut self.video_bytes,
+            _ => &mut self.control_bytes,
+        };
+        *counter += length as u64;
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.audio_bytes + self.video_bytes + self.control_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::protocol_control::protocol_control_type;
+
+    #[test]
+    fn test_record_by_media_type() {
+        let mut stats = ConnectionStats::default();
+        stats.record(command_message_type::AUDIO, 100);
+        stats.record(command_message_type::AUDIO, 50);
+        stats.record(command_message_type::VIDEO, 4000);
+        stats.record(command_message_type::COMMAND_AMF0, 200);
+        stats.record(protocol_control_type::SET_CHUNK_SIZE, 4);
+
+        assert_eq!(
+            stats,
+            ConnectionStats {
+                audio_bytes: 150,
+                video_bytes: 4000,
+                control_bytes: 204,
+            }
+        );
+        assert_eq!(stats.total_bytes(), 4354);
+    }
+}
@@ -0,0 +1,631 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    amf::AMF0Value,
+    messages::{
+        command::command_message_type,
+        media::{AudioTagHeader, VideoTagHeader, aac_packet_type, avc_packet_type},
+    },
+    sync::lock,
+    url::RtmpUrl,
+};
+
+/// How many packets a subscriber can fall behind before it starts missing packets
+pub(crate) const MEDIA_CHANNEL_CAPACITY: usize = 1024;
+
+/// Most packets kept in a GOP cache. A GOP longer than this isn't cached, so players joining
+/// during it start at the next keyframe instead
+const MAX_GOP_PACKETS: usize = 4096;
+
+/// An audio, video or data message sent by a publisher
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaPacket {
+    pub message_type_id: u8,
+    pub timestamp: u32,
+    pub payload: Bytes,
+}
+
+/// The packets a player joining mid-stream needs before it can start decoding: the metadata,
+/// codec sequence headers and everything since the last keyframe
+#[derive(Debug, Default)]
+struct GopCache {
+    /// The latest onMetaData
+    metadata: Option<MediaPacket>,
+    video_sequence_header: Option<MediaPacket>,
+    audio_sequence_header: Option<MediaPacket>,
+    /// Starts with a keyframe, empty until the first keyframe arrives
+    gop: Vec<MediaPacket>,
+}
+
+impl GopCache {
+    fn push(&mut self, packet: &MediaPacket) {
+        let keyframe = match packet.message_type_id {
+            command_message_type::VIDEO => {
+                let Ok(header) = VideoTagHeader::parse(&packet.payload) else {
+                    return;
+                };
+                if header.avc_packet_type == Some(avc_packet_type::SEQUENCE_HEADER) {
+                    self.video_sequence_header = Some(packet.clone());
+                    return;
+                }
+                header.is_keyframe()
+            }
+            command_message_type::AUDIO => {
+                let Ok(header) = AudioTagHeader::parse(&packet.payload) else {
+                    return;
+                };
+                if header.aac_packet_type == Some(aac_packet_type::SEQUENCE_HEADER) {
+                    self.audio_sequence_header = Some(packet.clone());
+                    return;
+                }
+                false
+            }
+            command_message_type::DATA_AMF0 => {
+                self.metadata = Some(packet.clone());
+                return;
+            }
+            _ => return,
+        };
+
+        if keyframe {
+            self.gop.clear();
+        } else if self.gop.is_empty() {
+            // anything before the first keyframe can't be decoded
+            return;
+        } else if self.gop.len() >= MAX_GOP_PACKETS {
+            self.gop.clear();
+            return;
+        }
+        self.gop.push(packet.clone());
+    }
+
+    fn packets(&self) -> VecDeque<MediaPacket> {
+        self.metadata
+            .iter()
+            .chain(&self.video_sequence_header)
+            .chain(&self.audio_sequence_header)
+            .chain(&self.gop)
+            .cloned()
+            .collect()
+    }
+}
+
+/// The latest codec sequence headers of a stream, which every player needs before it can decode
+/// any frame
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SequenceHeaders {
+    /// The video message holding the AVCDecoderConfigurationRecord
+    pub video: Option<MediaPacket>,
+    /// The audio message holding the AAC AudioSpecificConfig
+    pub audio: Option<MediaPacket>,
+}
+
+/// What the publisher described the stream as in its onMetaData
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamMetadata {
+    pub width: Option<f64>,
+    pub height: Option<f64>,
+    pub frame_rate: Option<f64>,
+    /// See [`crate::messages::media::video_codec`]
+    pub video_codec_id: Option<f64>,
+    /// See [`crate::messages::media::sound_format`]
+    pub audio_codec_id: Option<f64>,
+    pub encoder: Option<String>,
+}
+
+impl StreamMetadata {
+    /// Take what the registry keeps from the properties of an onMetaData
+    pub(crate) fn from_properties(value: &AMF0Value) -> Self {
+        let number = |key| value.get(key).and_then(AMF0Value::as_f64);
+        Self {
+            width: number("width"),
+            height: number("height"),
+            frame_rate: number("framerate"),
+            video_codec_id: number("videocodecid"),
+            audio_codec_id: number("audiocodecid"),
+            encoder: value
+                .get("encoder")
+                .and_then(AMF0Value::as_str)
+                .map(str::to_owned),
+        }
+    }
+}
+
+/// A stream published to another node of a cluster
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteStream {
+    pub node_id: String,
+    /// Where the stream can be played from on its node, e.g. `rtmp://origin:1935/live/mystream`
+    pub url: RtmpUrl,
+}
+
+/// Tells the registry which streams are published to other nodes of a cluster, e.g. by asking a
+/// service discovery backend or from what the nodes gossip to each other
+pub trait RemoteStreams: fmt::Debug + Send + Sync {
+    fn locate(&self, key: &str) -> Option<RemoteStream>;
+}
+
+/// A snapshot of a live stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiveStreamInfo {
+    pub key: String,
+    pub published_at: SystemTime,
+    pub metadata: StreamMetadata,
+    /// How many players are subscribed to the stream
+    pub viewers: usize,
+}
+
+#[derive(Debug)]
+struct LiveStream {
+    sender: broadcast::Sender<MediaPacket>,
+    /// Players subscribed to the stream, the server's own subscribers aren't counted
+    viewers: Arc<AtomicUsize>,
+    gop_cache: GopCache,
+    published_at: SystemTime,
+    metadata: StreamMetadata,
+}
+
+type LiveStreams = HashMap<String, LiveStream>;
+
+/// Keeps track of the streams that are currently being published, shared by every connection
+#[derive(Debug, Clone, Default)]
+pub struct StreamRegistry {
+    live: Arc<Mutex<LiveStreams>>,
+    /// This node's id in a cluster
+    node_id: Option<String>,
+    remote: Option<Arc<dyn RemoteStreams>>,
+}
+
+impl StreamRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_node_id(mut self, node_id: impl Into<String>) -> Self {
+        self.node_id = Some(node_id.into());
+        self
+    }
+
+    /// Look up streams that aren't published to this node with `remote`, so playing them pulls
+    /// them from the node they are published to
+    pub fn with_remote_streams(mut self, remote: impl RemoteStreams + 'static) -> Self {
+        self.remote = Some(Arc::new(remote));
+        self
+    }
+
+    pub fn node_id(&self) -> Option<&str> {
+        self.node_id.as_deref()
+    }
+
+    /// The node `key` is published to, if it isn't live on this one but is on another node.
+    /// Always [`None`] without [`Self::with_remote_streams`]
+    pub fn locate_remote(&self, key: &str) -> Option<RemoteStream> {
+        if self.is_live(key) {
+            return None;
+        }
+        self.remote
+            .as_ref()?
+            .locate(key)
+            .filter(|remote| self.node_id.as_deref() != Some(remote.node_id.as_str()))
+    }
+
+    /// Start publishing `key`, returns [`None`] if someone is already publishing it
+    pub fn publish(&self, key: &str) -> Option<Publisher> {
+        let mut live = lock(&self.live);
+        if live.contains_key(key) {
+            return None;
+        }
+
+        let (sender, _) = broadcast::channel(MEDIA_CHANNEL_CAPACITY);
+        live.insert(
+            key.to_owned(),
+            LiveStream {
+                sender,
+                viewers: Arc::default(),
+                gop_cache: GopCache::default(),
+                published_at: SystemTime::now(),
+                metadata: StreamMetadata::default(),
+            },
+        );
+        Some(Publisher {
+            key: key.to_owned(),
+            live: self.live.clone(),
+        })
+    }
+
+    /// Receive the packets published to `key` as a player, starting with the cached sequence
+    /// headers and GOP. Returns [`None`] if `key` isn't live.
+    pub fn subscribe(&self, key: &str) -> Option<Subscriber> {
+        self.subscribe_as(key, true)
+    }
+
+    /// Receive the packets published to `key` for the server's own use, e.g. recording or
+    /// relaying it, without counting as a viewer
+    pub fn subscribe_internal(&self, key: &str) -> Option<Subscriber> {
+        self.subscribe_as(key, false)
+    }
+
+    fn subscribe_as(&self, key: &str, viewer: bool) -> Option<Subscriber> {
+        // the cache is read under the same lock packets are sent under, so the subscriber
+        // neither misses nor repeats packets between the cache and the channel
+        lock(&self.live).get(key).map(|stream| Subscriber {
+            key: key.to_owned(),
+            backlog: stream.gop_cache.packets(),
+            receiver: stream.sender.subscribe(),
+            live: self.live.clone(),
+            viewers: viewer.then(|| {
+                stream.viewers.fetch_add(1, Ordering::Relaxed);
+                stream.viewers.clone()
+            }),
+        })
+    }
+
+    pub fn is_live(&self, key: &str) -> bool {
+        lock(&self.live).contains_key(key)
+    }
+
+    /// How many players are subscribed to `key`, returns [`None`] if it isn't live. Players are
+    /// counted until their [`Subscriber`] is dropped, which happens however they disconnect
+    pub fn viewers(&self, key: &str) -> Option<usize> {
+        lock(&self.live)
+            .get(key)
+            .map(|stream| stream.viewers.load(Ordering::Relaxed))
+    }
+
+    /// The sequence headers `key` was last sent, returns [`None`] if it isn't live. Publishers
+    /// can send new ones mid-stream, e.g. when the resolution changes, which replace these
+    pub fn sequence_headers(&self, key: &str) -> Option<SequenceHeaders> {
+        lock(&self.live).get(key).map(|stream| SequenceHeaders {
+            video: stream.gop_cache.video_sequence_header.clone(),
+            audio: stream.gop_cache.audio_sequence_header.clone(),
+        })
+    }
+
+    /// Every live stream, ordered by key
+    pub fn live_streams(&self) -> Vec<LiveStreamInfo> {
+        let mut streams: Vec<_> = lock(&self.live)
+            .iter()
+            .map(|(key, stream)| LiveStreamInfo {
+                key: key.clone(),
+                published_at: stream.published_at,
+                metadata: stream.metadata.clone(),
+                viewers: stream.viewers.load(Ordering::Relaxed),
+            })
+            .collect();
+        streams.sort_by(|a, b| a.key.cmp(&b.key));
+        streams
+    }
+
+    /// Whether something panicked while holding the registry's lock. The registry keeps
+    /// working, but whatever panicked is likely to again
+    pub fn is_poisoned(&self) -> bool {
+        self.live.is_poisoned()
+    }
+}
+
+/// A claim on a stream key, the stream is unpublished when this is dropped
+#[derive(Debug)]
+pub struct Publisher {
+    key: String,
+    live: Arc<Mutex<LiveStreams>>,
+}
+
+impl Publisher {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn set_metadata(&self, metadata: StreamMetadata) {
+        if let Some(stream) = lock(&self.live).get_mut(&self.key) {
+            stream.metadata = metadata;
+        }
+    }
+
+    /// Send a packet to every subscriber
+    pub fn send(&self, packet: MediaPacket) {
+        let mut live = lock(&self.live);
+        let Some(stream) = live.get_mut(&self.key) else {
+            return;
+        };
+        stream.gop_cache.push(&packet);
+        // no subscribers isn't an error, the packet is just dropped
+        let _ = stream.sender.send(packet);
+    }
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        lock(&self.live).remove(&self.key);
+    }
+}
+
+/// Receives the packets of a live stream. Closed once the publisher goes away
+#[derive(Debug)]
+pub struct Subscriber {
+    key: String,
+    /// Cached packets to replay before the live ones
+    backlog: VecDeque<MediaPacket>,
+    receiver: broadcast::Receiver<MediaPacket>,
+    live: Arc<Mutex<LiveStreams>>,
+    /// The viewer count of the stream this player is counted in, [`None`] for the server's own
+    /// subscribers
+    viewers: Option<Arc<AtomicUsize>>,
+}
+
+impl Subscriber {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub async fn recv(&mut self) -> Result<MediaPacket, RecvError> {
+        match self.backlog.pop_front() {
+            Some(packet) => Ok(packet),
+            None => self.receiver.recv().await,
+        }
+    }
+
+    /// Drop every packet that hasn't been received yet, so the next one is the next sent live
+    pub fn skip_to_live(&mut self) {
+        self.backlog.clear();
+        self.receiver = self.receiver.resubscribe();
+    }
+
+    /// Drop every packet that hasn't been received yet and start over from the cached sequence
+    /// headers and GOP, so a player that fell behind resumes at the latest keyframe without
+    /// waiting for the next one. Returns how many packets were dropped
+    pub fn replay_gop(&mut self) -> u64 {
+        // under the lock packets are sent under, like when subscribing
+        let live = lock(&self.live);
+        let dropped = self.backlog.len() + self.receiver.len();
+        self.backlog = live
+            .get(&self.key)
+            .map(|stream| stream.gop_cache.packets())
+            .unwrap_or_default();
+        self.receiver = self.receiver.resubscribe();
+        dropped as u64
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        if let Some(viewers) = &self.viewers {
+            viewers.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{audio, video};
+
+    /// Every stream is on node `origin`
+    #[derive(Debug)]
+    struct OnOrigin;
+
+    impl RemoteStreams for OnOrigin {
+        fn locate(&self, key: &str) -> Option<RemoteStream> {
+            Some(RemoteStream {
+                node_id: "origin".to_owned(),
+                url: format!("rtmp://origin/{key}").parse().unwrap(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_locate_remote() {
+        assert_eq!(StreamRegistry::new().locate_remote("live/stream"), None);
+
+        let registry = StreamRegistry::new()
+            .with_node_id("edge")
+            .with_remote_streams(OnOrigin);
+        assert_eq!(registry.node_id(), Some("edge"));
+        let remote = registry.locate_remote("live/stream").unwrap();
+        assert_eq!(remote.node_id, "origin");
+        assert_eq!(remote.url.host, "origin");
+        assert_eq!(remote.url.stream_key.as_deref(), Some("stream"));
+
+        // a stream published here is played from here
+        let _publisher = registry.publish("live/stream").unwrap();
+        assert_eq!(registry.locate_remote("live/stream"), None);
+
+        // the origin itself doesn't pull from itself
+        let origin = StreamRegistry::new()
+            .with_node_id("origin")
+            .with_remote_streams(OnOrigin);
+        assert_eq!(origin.locate_remote("live/stream"), None);
+    }
+
+    #[test]
+    fn test_duplicate_publish() {
+        let registry = StreamRegistry::new();
+
+        let publisher = registry.publish("live/stream").unwrap();
+        assert_eq!(publisher.key(), "live/stream");
+        assert!(registry.is_live("live/stream"));
+        assert!(registry.publish("live/stream").is_none());
+
+        drop(publisher);
+        assert!(!registry.is_live("live/stream"));
+        assert!(registry.publish("live/stream").is_some());
+    }
+
+    #[test]
+    fn test_live_streams() {
+        let registry = StreamRegistry::new();
+        let second = registry.publish("live/b").unwrap();
+        let _first = registry.publish("live/a").unwrap();
+        let metadata = StreamMetadata {
+            width: Some(1920.0),
+            height: Some(1080.0),
+            ..StreamMetadata::default()
+        };
+        second.set_metadata(metadata.clone());
+        let _player = registry.subscribe("live/b").unwrap();
+
+        let streams = registry.live_streams();
+        assert_eq!(
+            streams
+                .iter()
+                .map(|stream| &stream.key[..])
+                .collect::<Vec<_>>(),
+            ["live/a", "live/b"]
+        );
+        assert_eq!(streams[0].viewers, 0);
+        assert_eq!(streams[1].viewers, 1);
+        assert_eq!(streams[1].metadata, metadata);
+    }
+
+    #[test]
+    fn test_viewers() {
+        let registry = StreamRegistry::new();
+        assert_eq!(registry.viewers("live/stream"), None);
+
+        let _publisher = registry.publish("live/stream").unwrap();
+        let first = registry.subscribe("live/stream").unwrap();
+        let _second = registry.subscribe("live/stream").unwrap();
+        assert_eq!(registry.viewers("live/stream"), Some(2));
+
+        drop(first);
+        assert_eq!(registry.viewers("live/stream"), Some(1));
+
+        let _recorder = registry.subscribe_internal("live/stream").unwrap();
+        assert_eq!(registry.viewers("live/stream"), Some(1));
+        assert_eq!(registry.live_streams()[0].viewers, 1);
+    }
+
+    fn packet(timestamp: u32) -> MediaPacket {
+        video(timestamp, &[0x27, 0x01, 0x00, 0x00, 0x00])
+    }
+
+    #[tokio::test]
+    async fn test_late_subscriber_starts_at_keyframe() {
+        let registry = StreamRegistry::new();
+        let publisher = registry.publish("live/stream").unwrap();
+
+        let video_sequence_header = video(0, &[0x17, 0x00, 0x00, 0x00, 0x00, 0x01, 0x64]);
+        let audio_sequence_header = audio(0, &[0xAF, 0x00, 0x12, 0x10]);
+        let first_keyframe = video(0, &[0x17, 0x01, 0x00, 0x00, 0x00, 0x65]);
+        let second_keyframe = video(120, &[0x17, 0x01, 0x00, 0x00, 0x00, 0x65, 0x88]);
+        let gop = [
+            second_keyframe.clone(),
+            audio(130, &[0xAF, 0x01, 0x21]),
+            video(160, &[0x27, 0x01, 0x00, 0x00, 0x00, 0x41]),
+        ];
+
+        publisher.send(video_sequence_header.clone());
+        publisher.send(audio_sequence_header.clone());
+        publisher.send(first_keyframe);
+        publisher.send(video(40, &[0x27, 0x01, 0x00, 0x00, 0x00, 0x41]));
+        publisher.send(audio(60, &[0xAF, 0x01, 0x21]));
+        for packet in &gop {
+            publisher.send(packet.clone());
+        }
+
+        let mut subscriber = registry.subscribe("live/stream").unwrap();
+        publisher.send(video(200, &[0x27, 0x01, 0x00, 0x00, 0x00, 0x42]));
+
+        let expected = [
+            &[video_sequence_header, audio_sequence_header][..],
+            &gop,
+            &[video(200, &[0x27, 0x01, 0x00, 0x00, 0x00, 0x42])],
+        ]
+        .concat();
+        for packet in expected {
+            assert_eq!(subscriber.recv().await.unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn test_sequence_headers() {
+        let registry = StreamRegistry::new();
+        assert_eq!(registry.sequence_headers("live/stream"), None);
+
+        let publisher = registry.publish("live/stream").unwrap();
+        assert_eq!(
+            registry.sequence_headers("live/stream"),
+            Some(SequenceHeaders::default())
+        );
+
+        let video_sequence_header = video(0, &[0x17, 0x00, 0x00, 0x00, 0x00, 0x01, 0x64]);
+        let audio_sequence_header = audio(0, &[0xAF, 0x00, 0x12, 0x10]);
+        publisher.send(video_sequence_header.clone());
+        publisher.send(audio_sequence_header.clone());
+        publisher.send(video(0, &[0x17, 0x01, 0x00, 0x00, 0x00, 0x65]));
+        publisher.send(audio(20, &[0xAF, 0x01, 0x21]));
+        assert_eq!(
+            registry.sequence_headers("live/stream"),
+            Some(SequenceHeaders {
+                video: Some(video_sequence_header),
+                audio: Some(audio_sequence_header.clone()),
+            })
+        );
+
+        // a re-sent header replaces the old one
+        let new_video_sequence_header = video(500, &[0x17, 0x00, 0x00, 0x00, 0x00, 0x01, 0x4D]);
+        publisher.send(new_video_sequence_header.clone());
+        assert_eq!(
+            registry.sequence_headers("live/stream"),
+            Some(SequenceHeaders {
+                video: Some(new_video_sequence_header),
+                audio: Some(audio_sequence_header),
+            })
+        );
+    }
+
+    #[test]
+    fn test_gop_cache_waits_for_keyframe() {
+        let mut gop_cache = GopCache::default();
+        gop_cache.push(&video(0, &[0x27, 0x01, 0x00, 0x00, 0x00, 0x41]));
+        gop_cache.push(&audio(0, &[0xAF, 0x01, 0x21]));
+        assert!(gop_cache.packets().is_empty());
+
+        gop_cache.push(&video(40, &[0x17, 0x01, 0x00, 0x00, 0x00, 0x65]));
+        assert_eq!(gop_cache.packets().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_to_subscribers() {
+        // inter frames aren't cached, so subscribers only see packets sent after subscribing
+        let registry = StreamRegistry::new();
+        assert!(registry.subscribe("live/stream").is_none());
+
+        let publisher = registry.publish("live/stream").unwrap();
+        let mut first = registry.subscribe("live/stream").unwrap();
+        let mut second = registry.clone().subscribe("live/stream").unwrap();
+
+        publisher.send(packet(0));
+        publisher.send(packet(40));
+
+        for subscriber in [&mut first, &mut second] {
+            assert_eq!(subscriber.recv().await.unwrap(), packet(0));
+            assert_eq!(subscriber.recv().await.unwrap(), packet(40));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_are_closed_when_publisher_drops() {
+        let registry = StreamRegistry::new();
+        let publisher = registry.publish("live/stream").unwrap();
+        let mut subscriber = registry.subscribe("live/stream").unwrap();
+
+        publisher.send(packet(0));
+        drop(publisher);
+
+        // packets sent before the publisher went away are still delivered
+        assert_eq!(subscriber.recv().await.unwrap(), packet(0));
+        assert_eq!(subscriber.recv().await, Err(RecvError::Closed));
+        assert!(registry.subscribe("live/stream").is_none());
+    }
+}
@@ -0,0 +1,7 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Lock a mutex whose value every operation leaves consistent, so it is still usable if a
+/// holder of the lock panicked
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
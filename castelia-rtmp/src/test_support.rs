@@ -0,0 +1,33 @@
+//! Media packets for the tests of this crate and of the services built on it
+
+use bytes::Bytes;
+
+use crate::{messages::command::command_message_type, stream_registry::MediaPacket};
+
+/// An AVC sequence header with a single SPS and PPS
+pub const VIDEO_SEQUENCE_HEADER: &[u8] = &[
+    0x17, 0x00, 0x00, 0x00, 0x00, 0x01, 0x64, 0x00, 0x1F, 0xFF, 0xE1, 0x00, 0x03, 0x67, 0x64, 0x00,
+    0x01, 0x00, 0x02, 0x68, 0xEE,
+];
+/// An AVC keyframe holding a single IDR NAL unit
+pub const KEYFRAME: &[u8] = &[0x17, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x65];
+pub const INTER_FRAME: &[u8] = &[0x27, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x41];
+/// An AAC sequence header for 44.1kHz stereo
+pub const AUDIO_SEQUENCE_HEADER: &[u8] = &[0xAF, 0x00, 0x12, 0x10];
+pub const AUDIO_FRAME: &[u8] = &[0xAF, 0x01, 0x21, 0x10, 0x04];
+
+pub fn video(timestamp: u32, payload: &'static [u8]) -> MediaPacket {
+    MediaPacket {
+        message_type_id: command_message_type::VIDEO,
+        timestamp,
+        payload: Bytes::from_static(payload),
+    }
+}
+
+pub fn audio(timestamp: u32, payload: &'static [u8]) -> MediaPacket {
+    MediaPacket {
+        message_type_id: command_message_type::AUDIO,
+        timestamp,
+        payload: Bytes::from_static(payload),
+    }
+}
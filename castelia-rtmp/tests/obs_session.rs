@@ -0,0 +1,335 @@
+//! Replays the session OBS opens when it starts streaming, with the commands, chunk streams and
+//! chunk sizes librtmp uses, against a server over an in-memory transport: the digest handshake,
+//! connect, publish and the first media frames.
+
+// the helpers aren't tests themselves, but failing in them fails the test all the same
+#![allow(clippy::unwrap_used)]
+
+use std::time::Duration;
+
+use castelia_rtmp::{
+    amf::{AMF0Value, Encoder, Properties},
+    rtmp::RTMPSever,
+    stream_registry::{StreamMetadata, StreamRegistry},
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream, duplex},
+    time::timeout,
+};
+
+const HANDSHAKE_CHUNK_SIZE: usize = 1536;
+const DIGEST_LENGTH: usize = 32;
+const FP_KEY_TEXT: &[u8] = b"Genuine Adobe Flash Player 001";
+const FMS_KEY_TEXT: &[u8] = b"Genuine Adobe Flash Media Server 001";
+const KEY_SUFFIX: [u8; 32] = [
+    0xF0, 0xEE, 0xC2, 0x4A, 0x80, 0x68, 0xBE, 0xE8, 0x2E, 0x00, 0xD0, 0xD1, 0x02, 0x9E, 0x7E, 0x57,
+    0x6E, 0xEC, 0x5D, 0x2D, 0x29, 0x80, 0x6F, 0xAB, 0x93, 0xB8, 0xE6, 0x36, 0xCF, 0xEB, 0x31, 0xAE,
+];
+
+/// The Flash Player version librtmp puts in C1
+const CLIENT_VERSION: [u8; 4] = [0x80, 0x00, 0x07, 0x02];
+
+/// librtmp sends commands on chunk stream 3 and the stream's data and media on 4
+const COMMAND_CHUNK_STREAM: u8 = 3;
+const MEDIA_CHUNK_STREAM: u8 = 4;
+/// The chunk size OBS switches to after connecting
+const OBS_CHUNK_SIZE: usize = 4096;
+
+const VIDEO_SEQUENCE_HEADER: &[u8] = &[
+    0x17, 0x00, 0x00, 0x00, 0x00, 0x01, 0x64, 0x00, 0x1F, 0xFF, 0xE1, 0x00, 0x03, 0x67, 0x64, 0x00,
+    0x01, 0x00, 0x02, 0x68, 0xEE,
+];
+const KEYFRAME: &[u8] = &[0x17, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x65];
+const AUDIO_SEQUENCE_HEADER: &[u8] = &[0xAF, 0x00, 0x12, 0x10];
+const AUDIO_FRAME: &[u8] = &[0xAF, 0x01, 0x21, 0x10, 0x04];
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; DIGEST_LENGTH] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Where the digest of a C1 or S1 with the key block first is
+fn digest_position(chunk: &[u8]) -> usize {
+    let start = 8 + 764;
+    let offset: usize = chunk[start..start + 4]
+        .iter()
+        .map(|&b| usize::from(b))
+        .sum();
+    start + 4 + offset % (764 - DIGEST_LENGTH - 4)
+}
+
+fn digest(chunk: &[u8], key: &[u8]) -> [u8; DIGEST_LENGTH] {
+    let position = digest_position(chunk);
+    hmac(
+        key,
+        &[&chunk[..position], &chunk[position + DIGEST_LENGTH..]],
+    )
+}
+
+/// The digest handshake librtmp does, checking the server signed S1 and S2
+async fn handshake(client: &mut DuplexStream) {
+    let mut c1 = [0; HANDSHAKE_CHUNK_SIZE];
+    c1[4..8].copy_from_slice(&CLIENT_VERSION);
+    for (i, byte) in c1[8..].iter_mut().enumerate() {
+        *byte = (i * 7 % 251) as u8;
+    }
+    let position = digest_position(&c1);
+    let client_digest = digest(&c1, FP_KEY_TEXT);
+    c1[position..position + DIGEST_LENGTH].copy_from_slice(&client_digest);
+    client.write_u8(3).await.unwrap();
+    client.write_all(&c1).await.unwrap();
+
+    assert_eq!(client.read_u8().await.unwrap(), 3);
+    let mut s1 = [0; HANDSHAKE_CHUNK_SIZE];
+    client.read_exact(&mut s1).await.unwrap();
+    let position = digest_position(&s1);
+    assert_eq!(
+        s1[position..position + DIGEST_LENGTH],
+        digest(&s1, FMS_KEY_TEXT)
+    );
+
+    let mut s2 = [0; HANDSHAKE_CHUNK_SIZE];
+    client.read_exact(&mut s2).await.unwrap();
+    let signature_start = HANDSHAKE_CHUNK_SIZE - DIGEST_LENGTH;
+    let signing_key = hmac(&[FMS_KEY_TEXT, &KEY_SUFFIX].concat(), &[&client_digest]);
+    assert_eq!(
+        s2[signature_start..],
+        hmac(&signing_key, &[&s2[..signature_start]])
+    );
+
+    // C2 is random data signed with a key derived from the server digest
+    let server_digest: [u8; DIGEST_LENGTH] =
+        s1[position..position + DIGEST_LENGTH].try_into().unwrap();
+    let mut c2 = [0x5A; HANDSHAKE_CHUNK_SIZE];
+    let signing_key = hmac(&[FP_KEY_TEXT, &KEY_SUFFIX].concat(), &[&server_digest]);
+    let signature = hmac(&signing_key, &[&c2[..signature_start]]);
+    c2[signature_start..].copy_from_slice(&signature);
+    client.write_all(&c2).await.unwrap();
+}
+
+fn encode(values: &[AMF0Value]) -> Vec<u8> {
+    let mut encoder = Encoder::new();
+    for value in values {
+        encoder.encode(value).unwrap();
+    }
+    encoder.finish()
+}
+
+/// A message as chunks of `chunk_size`: a type 0 chunk followed by type 3 chunks
+fn chunks(
+    cs_id: u8,
+    message_type_id: u8,
+    message_stream_id: u32,
+    timestamp: u32,
+    payload: &[u8],
+    chunk_size: usize,
+) -> Vec<u8> {
+    let mut buf = vec![cs_id];
+    buf.extend_from_slice(&timestamp.to_be_bytes()[1..]);
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    buf.push(message_type_id);
+    buf.extend_from_slice(&message_stream_id.to_le_bytes());
+    for (i, chunk) in payload.chunks(chunk_size).enumerate() {
+        if i > 0 {
+            buf.push(0xC0 | cs_id);
+        }
+        buf.extend_from_slice(chunk);
+    }
+    buf
+}
+
+/// Read from the server until it has sent `needle`
+async fn read_until(client: &mut DuplexStream, received: &mut Vec<u8>, needle: &[u8]) {
+    timeout(Duration::from_secs(5), async {
+        while !received
+            .windows(needle.len())
+            .any(|window| window == needle)
+        {
+            let mut buf = [0; 4096];
+            let read = client.read(&mut buf).await.unwrap();
+            assert_ne!(read, 0, "server closed the connection");
+            received.extend_from_slice(&buf[..read]);
+        }
+    })
+    .await
+    .unwrap();
+}
+
+fn obs_metadata() -> AMF0Value<'static> {
+    let properties = Properties::from([
+        ("duration", AMF0Value::Number(0.0)),
+        ("fileSize", AMF0Value::Number(0.0)),
+        ("width", AMF0Value::Number(1920.0)),
+        ("height", AMF0Value::Number(1080.0)),
+        ("videocodecid", AMF0Value::Number(7.0)),
+        ("videodatarate", AMF0Value::Number(6000.0)),
+        ("framerate", AMF0Value::Number(60.0)),
+        ("audiocodecid", AMF0Value::Number(10.0)),
+        ("audiodatarate", AMF0Value::Number(160.0)),
+        ("audiosamplerate", AMF0Value::Number(48000.0)),
+        ("audiosamplesize", AMF0Value::Number(16.0)),
+        ("audiochannels", AMF0Value::Number(2.0)),
+        ("stereo", AMF0Value::Boolean(true)),
+        (
+            "encoder",
+            AMF0Value::String("obs-output module (libobs version 30.0.2)"),
+        ),
+    ]);
+    AMF0Value::EcmaArray {
+        count: properties.len() as u32,
+        properties,
+    }
+}
+
+#[tokio::test]
+async fn test_obs_publish_session() {
+    let streams = StreamRegistry::new();
+    let server = RTMPSever::builder()
+        .bind_addr("127.0.0.1:0".parse().unwrap())
+        .build()
+        .await
+        .unwrap()
+        .with_stream_registry(streams.clone());
+    let (mut client, socket) = duplex(64 * 1024);
+    tokio::spawn(async move { server.serve_connection(socket).await });
+
+    handshake(&mut client).await;
+
+    // connect is longer than the initial chunk size, so it is split
+    let connect = encode(&[
+        AMF0Value::String("connect"),
+        AMF0Value::Number(1.0),
+        AMF0Value::Object(Properties::from([
+            ("app", AMF0Value::String("live")),
+            ("type", AMF0Value::String("nonprivate")),
+            (
+                "flashVer",
+                AMF0Value::String("FMLE/3.0 (compatible; FMSc/1.0)"),
+            ),
+            ("swfUrl", AMF0Value::String("rtmp://localhost/live")),
+            ("tcUrl", AMF0Value::String("rtmp://localhost/live")),
+        ])),
+    ]);
+    assert!(connect.len() > 128);
+    client
+        .write_all(&chunks(COMMAND_CHUNK_STREAM, 20, 0, 0, &connect, 128))
+        .await
+        .unwrap();
+    let mut received = vec![];
+    read_until(&mut client, &mut received, b"NetConnection.Connect.Success").await;
+
+    let mut session = chunks(2, 1, 0, 0, &(OBS_CHUNK_SIZE as u32).to_be_bytes(), 128);
+    for (transaction_id, command) in [(2.0, "releaseStream"), (3.0, "FCPublish")] {
+        session.extend(chunks(
+            COMMAND_CHUNK_STREAM,
+            20,
+            0,
+            0,
+            &encode(&[
+                AMF0Value::String(command),
+                AMF0Value::Number(transaction_id),
+                AMF0Value::Null,
+                AMF0Value::String("obs"),
+            ]),
+            OBS_CHUNK_SIZE,
+        ));
+    }
+    session.extend(chunks(
+        COMMAND_CHUNK_STREAM,
+        20,
+        0,
+        0,
+        &encode(&[
+            AMF0Value::String("createStream"),
+            AMF0Value::Number(4.0),
+            AMF0Value::Null,
+        ]),
+        OBS_CHUNK_SIZE,
+    ));
+    // the first stream the server creates is 1
+    session.extend(chunks(
+        MEDIA_CHUNK_STREAM,
+        20,
+        1,
+        0,
+        &encode(&[
+            AMF0Value::String("publish"),
+            AMF0Value::Number(5.0),
+            AMF0Value::Null,
+            AMF0Value::String("obs"),
+            AMF0Value::String("live"),
+        ]),
+        OBS_CHUNK_SIZE,
+    ));
+    client.write_all(&session).await.unwrap();
+    read_until(&mut client, &mut received, b"NetStream.Publish.Start").await;
+
+    let mut media = chunks(
+        MEDIA_CHUNK_STREAM,
+        18,
+        1,
+        0,
+        &encode(&[
+            AMF0Value::String("@setDataFrame"),
+            AMF0Value::String("onMetaData"),
+            obs_metadata(),
+        ]),
+        OBS_CHUNK_SIZE,
+    );
+    for (message_type_id, timestamp, payload) in [
+        (9, 0, VIDEO_SEQUENCE_HEADER),
+        (8, 0, AUDIO_SEQUENCE_HEADER),
+        (9, 0, KEYFRAME),
+        (8, 21, AUDIO_FRAME),
+        (8, 42, AUDIO_FRAME),
+    ] {
+        media.extend(chunks(
+            MEDIA_CHUNK_STREAM,
+            message_type_id,
+            1,
+            timestamp,
+            payload,
+            OBS_CHUNK_SIZE,
+        ));
+    }
+    client.write_all(&media).await.unwrap();
+
+    let expected = StreamMetadata {
+        width: Some(1920.0),
+        height: Some(1080.0),
+        frame_rate: Some(60.0),
+        video_codec_id: Some(7.0),
+        audio_codec_id: Some(10.0),
+        encoder: Some("obs-output module (libobs version 30.0.2)".to_owned()),
+    };
+    let mut live = vec![];
+    for _ in 0..100 {
+        live = streams.live_streams();
+        if live
+            .first()
+            .is_some_and(|stream| stream.metadata == expected)
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(live.len(), 1);
+    assert_eq!(live[0].key, "live/obs");
+    assert_eq!(live[0].metadata, expected);
+
+    // a player joining now gets the metadata, sequence headers and the keyframe first
+    let mut subscriber = streams.subscribe("live/obs").unwrap();
+    let mut payloads = vec![];
+    for _ in 0..4 {
+        payloads.push(subscriber.recv().await.unwrap().payload);
+    }
+    assert_eq!(
+        payloads[1..],
+        [VIDEO_SEQUENCE_HEADER, AUDIO_SEQUENCE_HEADER, KEYFRAME]
+    );
+}
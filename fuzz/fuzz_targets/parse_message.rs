@@ -0,0 +1,11 @@
+#![no_main]
+
+use castelia_rtmp::parse_message_from_bytes;
+use libfuzzer_sys::fuzz_target;
+
+// The first byte picks the message type, the rest is the message payload
+fuzz_target!(|data: &[u8]| {
+    if let Some((&message_type_id, buf)) = data.split_first() {
+        let _ = parse_message_from_bytes(buf, message_type_id);
+    }
+});